@@ -0,0 +1,81 @@
+use crate::{http::BUFFER_SIZE, http::MAX_RANGES, DEFAULT_MAX_CONNECTIONS, PKG_NAME, PKG_VERSION};
+
+/// Where clients can ask the proxy what it was built with
+pub(crate) const VERSION_PATH: &str = "/version";
+
+/// What this build of rproxy can do, for `rproxy --features` and the `/version` path
+pub(crate) fn build_report() -> String {
+    let yes_no = |b: bool| match b {
+        true => "yes",
+        false => "no",
+    };
+
+    let mut report = format!("{PKG_NAME} {PKG_VERSION}\n");
+    report.push_str(&format!(
+        "target: {}-{}\n",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    ));
+    report.push_str(&format!(
+        "profile: {}\n",
+        match cfg!(debug_assertions) {
+            true => "debug",
+            false => "release",
+        }
+    ));
+
+    report.push_str("features:\n");
+    report.push_str(&format!("  https: {}\n", yes_no(cfg!(feature = "https"))));
+    report.push_str(&format!("  http3: {}\n", yes_no(cfg!(feature = "http3"))));
+    report.push_str(&format!(
+        "  bundled-roots: {}\n",
+        yes_no(cfg!(feature = "bundled-roots"))
+    ));
+    report.push_str(&format!(
+        "  compress: {}\n",
+        yes_no(cfg!(feature = "compress"))
+    ));
+    report.push_str(&format!("  keylog: {}\n", yes_no(cfg!(feature = "keylog"))));
+    report.push_str(&format!("  geoip: {}\n", yes_no(cfg!(feature = "geoip"))));
+    report.push_str(&format!(
+        "  sendfile: {}\n",
+        yes_no(cfg!(all(target_os = "linux", feature = "sendfile")))
+    ));
+    report.push_str(&format!("  web-ui: {}\n", yes_no(cfg!(feature = "web-ui"))));
+    report.push_str(&format!("  ldap: {}\n", yes_no(cfg!(feature = "ldap"))));
+    report.push_str(&format!(
+        "  netns: {}\n",
+        yes_no(cfg!(all(target_os = "linux", feature = "netns")))
+    ));
+    report.push_str(&format!(
+        "  transparent: {}\n",
+        yes_no(cfg!(all(target_os = "linux", feature = "transparent")))
+    ));
+    report.push_str(&format!(
+        "  privdrop: {}\n",
+        yes_no(cfg!(all(target_os = "linux", feature = "privdrop")))
+    ));
+
+    report.push_str("limits:\n");
+    report.push_str(&format!("  buffer size: {BUFFER_SIZE} bytes\n"));
+    report.push_str(&format!("  ranges per request: {MAX_RANGES}\n"));
+    report.push_str(&format!(
+        "  default max connections: {DEFAULT_MAX_CONNECTIONS}\n"
+    ));
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_report() {
+        let report = build_report();
+        assert!(report.starts_with(&format!("{PKG_NAME} {PKG_VERSION}\n")));
+        assert!(report.contains(&format!(
+            "  https: {}\n",
+            if cfg!(feature = "https") { "yes" } else { "no" }
+        )));
+    }
+}
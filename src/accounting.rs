@@ -0,0 +1,301 @@
+use {
+    crate::{
+        auth::authenticated_user,
+        cli::setting,
+        clock::{civil, now},
+        conn::Client,
+        http::HttpRequestHeader,
+        zerocopy::ZeroCopy,
+    },
+    std::{
+        collections::HashMap,
+        io,
+        path::PathBuf,
+        pin::Pin,
+        sync::{Mutex, OnceLock},
+        task::{Context, Poll},
+        time::SystemTime,
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf},
+        time::{sleep, Duration},
+    },
+    tracing::{info, warn},
+};
+
+pub const X_PROXY_ACCOUNTING_PATH: &str = "X_PROXY_ACCOUNTING_PATH";
+
+const FLUSH_INTERVAL_SECONDS: u64 = 60;
+
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    cache_bytes: u64,
+    origin_bytes: u64,
+}
+
+struct Accounting {
+    path: PathBuf,
+    /* Keyed by day then identity */
+    usage: Mutex<HashMap<String, HashMap<String, Usage>>>,
+}
+
+static ACCOUNTING: OnceLock<Accounting> = OnceLock::new();
+
+/// Convert a time into a `YYYY-MM-DD` UTC date
+pub(crate) fn civil_date(time: SystemTime) -> String {
+    let (year, month, day) = civil(time);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn parse_report(contents: &str) -> HashMap<String, Usage> {
+    let mut usage = HashMap::new();
+
+    for line in contents.lines().skip(1) {
+        let mut fields = line.rsplitn(3, ',');
+        if let (Some(origin), Some(cache), Some(identity)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            if let (Ok(cache_bytes), Ok(origin_bytes)) = (cache.parse(), origin.parse()) {
+                usage.insert(
+                    identity.to_string(),
+                    Usage {
+                        cache_bytes,
+                        origin_bytes,
+                    },
+                );
+            }
+        }
+    }
+
+    usage
+}
+
+fn write_report(usage: &HashMap<String, Usage>) -> String {
+    let mut identities: Vec<&String> = usage.keys().collect();
+    identities.sort();
+
+    let mut report = String::from("identity,cache_bytes,origin_bytes\n");
+    for identity in identities {
+        let u = usage[identity];
+        report.push_str(&format!(
+            "{identity},{},{}\n",
+            u.cache_bytes, u.origin_bytes
+        ));
+    }
+    report
+}
+
+/// Start accounting if `X_PROXY_ACCOUNTING_PATH` is set.
+/// Today's report is loaded so a restart carries on from where it left off.
+pub(crate) async fn setup_accounting() {
+    let path = match setting(X_PROXY_ACCOUNTING_PATH) {
+        Ok(p) => PathBuf::from(p),
+        Err(_) => return,
+    };
+
+    if let Err(e) = tokio::fs::create_dir_all(&path).await {
+        warn!(
+            "couldn't create accounting directory '{}': {e}",
+            path.to_string_lossy()
+        );
+        return;
+    }
+
+    let today = civil_date(now());
+    let mut usage = HashMap::new();
+    if let Ok(contents) = tokio::fs::read_to_string(path.join(format!("{today}.csv"))).await {
+        usage.insert(today, parse_report(&contents));
+    }
+
+    info!("accounting path: {}", path.to_string_lossy());
+
+    let _ = ACCOUNTING.set(Accounting {
+        path,
+        usage: Mutex::new(usage),
+    });
+
+    tokio::spawn(async {
+        loop {
+            sleep(Duration::from_secs(FLUSH_INTERVAL_SECONDS)).await;
+            flush_accounting().await;
+        }
+    });
+}
+
+/// Write every day with usage to its report, then forget all but today
+pub(crate) async fn flush_accounting() {
+    let accounting = match ACCOUNTING.get() {
+        None => return,
+        Some(a) => a,
+    };
+
+    let today = civil_date(now());
+    let reports: Vec<(String, String)> = match accounting.usage.lock() {
+        Err(_) => return,
+        Ok(mut usage) => {
+            let reports = usage
+                .iter()
+                .map(|(day, u)| (day.clone(), write_report(u)))
+                .collect();
+            usage.retain(|day, _| *day == today);
+            reports
+        }
+    };
+
+    for (day, report) in reports {
+        let file = accounting.path.join(format!("{day}.csv"));
+        if let Err(e) = tokio::fs::write(&file, report).await {
+            warn!("couldn't write '{}': {e}", file.to_string_lossy());
+        }
+    }
+}
+
+pub(crate) fn accounting_enabled() -> bool {
+    ACCOUNTING.get().is_some()
+}
+
+/// The name usage is recorded under: the service account or proxy user when the client authenticated,
+/// otherwise its address. A name is only trusted when an authentication backend checked it.
+pub(crate) fn request_identity(client: &Client, header: &HttpRequestHeader) -> String {
+    match authenticated_user(header) {
+        Some(user) => user.replace(',', "_"),
+        None => client.address.ip().to_string(),
+    }
+}
+
+pub(crate) fn record_usage(identity: &str, bytes: u64, from_cache: bool) {
+    let accounting = match ACCOUNTING.get() {
+        None => return,
+        Some(a) => a,
+    };
+
+    if let Ok(mut usage) = accounting.usage.lock() {
+        let entry = usage
+            .entry(civil_date(now()))
+            .or_default()
+            .entry(identity.to_string())
+            .or_default();
+
+        match from_cache {
+            true => entry.cache_bytes += bytes,
+            false => entry.origin_bytes += bytes,
+        }
+    }
+}
+
+/// Counts the bytes written to a client stream
+pub(crate) struct Metered<S> {
+    inner: S,
+    written: u64,
+}
+
+impl<S> Metered<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Metered { inner, written: 0 }
+    }
+
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncBufRead + Unpin> AsyncBufRead for Metered<S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.inner).consume(amt)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.written += n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: ZeroCopy> ZeroCopy for Metered<S> {
+    async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        length: u64,
+    ) -> Option<io::Result<u64>> {
+        let sent = self.inner.send_file(file, offset, length).await;
+        if let Some(Ok(n)) = sent {
+            self.written += n;
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::UNIX_EPOCH};
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(UNIX_EPOCH), "1970-01-01");
+        assert_eq!(
+            civil_date(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "2000-02-29"
+        );
+        assert_eq!(
+            civil_date(UNIX_EPOCH + Duration::from_secs(1735689599)),
+            "2024-12-31"
+        );
+    }
+
+    #[test]
+    fn test_report_round_trip() {
+        let mut usage = HashMap::new();
+        usage.insert(
+            "alice".to_string(),
+            Usage {
+                cache_bytes: 10,
+                origin_bytes: 20,
+            },
+        );
+        usage.insert(
+            "192.168.1.2".to_string(),
+            Usage {
+                cache_bytes: 30,
+                origin_bytes: 0,
+            },
+        );
+
+        let parsed = parse_report(&write_report(&usage));
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["alice"].cache_bytes, 10);
+        assert_eq!(parsed["alice"].origin_bytes, 20);
+        assert_eq!(parsed["192.168.1.2"].cache_bytes, 30);
+    }
+}
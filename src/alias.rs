@@ -0,0 +1,106 @@
+use crate::{cli::setting, evict::matches_pattern};
+
+pub const X_PROXY_MIRROR_ALIASES: &str = "X_PROXY_MIRROR_ALIASES";
+pub const X_PROXY_MIRROR_PRESETS: &str = "X_PROXY_MIRROR_PRESETS";
+
+pub(crate) struct MirrorAlias {
+    pub(crate) canonical: String,
+    pub(crate) patterns: Vec<String>,
+}
+
+fn preset(name: &str) -> Option<MirrorAlias> {
+    let (canonical, patterns): (&str, &[&str]) = match name.trim().to_lowercase().as_str() {
+        "debian" => (
+            "deb.debian.org",
+            &["ftp.debian.org", "ftp.*.debian.org", "http.debian.net"],
+        ),
+        "ubuntu" => ("archive.ubuntu.com", &["*.archive.ubuntu.com"]),
+        "alpine" => ("dl-cdn.alpinelinux.org", &["dl-*.alpinelinux.org"]),
+        _ => return None,
+    };
+
+    Some(MirrorAlias {
+        canonical: canonical.to_string(),
+        patterns: patterns.iter().map(|p| p.to_string()).collect(),
+    })
+}
+
+/// Rules are separated by `;` and written as `canonical=pattern,pattern`
+pub(crate) fn parse_aliases(value: &str) -> Vec<MirrorAlias> {
+    value
+        .split(';')
+        .filter_map(|rule| {
+            let (canonical, patterns) = rule.split_once('=')?;
+            let canonical = canonical.trim();
+            if canonical.is_empty() {
+                return None;
+            }
+
+            Some(MirrorAlias {
+                canonical: canonical.to_lowercase(),
+                patterns: patterns
+                    .split(',')
+                    .map(|p| p.trim().to_lowercase())
+                    .filter(|p| !p.is_empty())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn mirror_aliases() -> Vec<MirrorAlias> {
+    let mut aliases = match setting(X_PROXY_MIRROR_ALIASES) {
+        Ok(s) => parse_aliases(&s),
+        Err(_) => Vec::new(),
+    };
+
+    if let Ok(s) = setting(X_PROXY_MIRROR_PRESETS) {
+        aliases.extend(s.split(',').filter_map(preset));
+    }
+
+    aliases
+}
+
+/// The host a cache entry is stored under. Mirrors matching an alias share the canonical host.
+pub(crate) fn canonical_host(aliases: &[MirrorAlias], host: &str) -> String {
+    let host = host.to_lowercase();
+
+    for alias in aliases {
+        if alias.patterns.iter().any(|p| matches_pattern(p, &host)) {
+            return alias.canonical.clone();
+        }
+    }
+
+    host
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_aliases() {
+        let aliases = parse_aliases("deb.debian.org=ftp.*.debian.org, mirror.example.com;bad");
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(aliases[0].canonical, "deb.debian.org");
+        assert_eq!(
+            aliases[0].patterns,
+            vec!["ftp.*.debian.org", "mirror.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_canonical_host() {
+        let aliases = vec![preset("debian").unwrap(), preset("ubuntu").unwrap()];
+        assert_eq!(
+            canonical_host(&aliases, "ftp.au.debian.org"),
+            "deb.debian.org"
+        );
+        assert_eq!(canonical_host(&aliases, "deb.debian.org"), "deb.debian.org");
+        assert_eq!(
+            canonical_host(&aliases, "AU.archive.ubuntu.com"),
+            "archive.ubuntu.com"
+        );
+        assert_eq!(canonical_host(&aliases, "example.com"), "example.com");
+    }
+}
@@ -0,0 +1,552 @@
+use {
+    crate::{
+        cli::setting,
+        http::{
+            decode_base64, ConnectionReturn, HttpRequestHeader, HttpResponseHeader,
+            HttpResponseStatus, HttpVersion,
+        },
+        token::{request_token, setup_tokens, token_valid},
+        PKG_NAME,
+    },
+    std::{
+        collections::HashMap,
+        convert::TryInto,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, OnceLock, RwLock},
+    },
+    tokio::io::AsyncWriteExt,
+    tracing::{error, info, warn},
+};
+
+#[cfg(feature = "ldap")]
+use crate::ldap::ldap_backend;
+
+#[cfg(all(feature = "ldap", feature = "https"))]
+use crate::cert::CertificateSetup;
+
+pub const X_PROXY_AUTH_USERS: &str = "X_PROXY_AUTH_USERS";
+pub const X_PROXY_AUTH_HTPASSWD: &str = "X_PROXY_AUTH_HTPASSWD";
+#[cfg(feature = "web-ui")]
+pub const X_PROXY_AUTH_ADMINS: &str = "X_PROXY_AUTH_ADMINS";
+
+/// Whether a backend accepted the credentials it was given
+pub(crate) type Checked<'a> = Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+/// Checks the user name and password a client sent in `Proxy-Authorization`.
+/// Backends that have to ask another server, such as LDAP or OIDC, do so in the future they return.
+pub(crate) trait AuthBackend: Send + Sync {
+    /// Describes the backend in the log
+    fn name(&self) -> String;
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> Checked<'a>;
+}
+
+fn backends() -> &'static RwLock<Vec<Arc<dyn AuthBackend>>> {
+    static BACKENDS: OnceLock<RwLock<Vec<Arc<dyn AuthBackend>>>> = OnceLock::new();
+    BACKENDS.get_or_init(|| RwLock::new(Vec::new()))
+}
+
+/// Once any backend is registered every client has to authenticate with one of them
+pub(crate) fn register_auth_backend(backend: Arc<dyn AuthBackend>) {
+    info!("authenticating clients with {}", backend.name());
+    if let Ok(mut backends) = backends().write() {
+        backends.push(backend);
+    }
+}
+
+/* Compares every byte so how long it takes doesn't give away how much of a password was right */
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+/// Users and passwords given in `X_PROXY_AUTH_USERS`
+struct StaticUsers {
+    users: HashMap<String, String>,
+}
+
+impl AuthBackend for StaticUsers {
+    fn name(&self) -> String {
+        format!("{} user(s) from {X_PROXY_AUTH_USERS}", self.users.len())
+    }
+
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> Checked<'a> {
+        let accepted = self
+            .users
+            .get(user)
+            .is_some_and(|p| same(p.as_bytes(), password.as_bytes()));
+        Box::pin(async move { accepted })
+    }
+}
+
+/* Comma separated `user:password` pairs */
+fn parse_users(value: &str) -> Option<HashMap<String, String>> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((user, password)) if !user.is_empty() => {
+                Some((user.to_string(), password.to_string()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Secret {
+    /// Written by `htpasswd -p`
+    Plain(String),
+    /// Written by `htpasswd -s`
+    Sha1([u8; 20]),
+}
+
+impl Secret {
+    fn matches(&self, password: &str) -> bool {
+        match self {
+            Secret::Plain(p) => same(p.as_bytes(), password.as_bytes()),
+            Secret::Sha1(digest) => same(digest, &sha1(password.as_bytes())),
+        }
+    }
+}
+
+/// Users from an Apache style password file
+struct Htpasswd {
+    path: String,
+    users: HashMap<String, Secret>,
+}
+
+impl AuthBackend for Htpasswd {
+    fn name(&self) -> String {
+        format!("{} user(s) from '{}'", self.users.len(), self.path)
+    }
+
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> Checked<'a> {
+        let accepted = self.users.get(user).is_some_and(|s| s.matches(password));
+        Box::pin(async move { accepted })
+    }
+}
+
+/* Users whose password is hashed in a way rproxy can't check are returned separately */
+fn parse_htpasswd(contents: &str) -> (HashMap<String, Secret>, Vec<String>) {
+    let mut users = HashMap::new();
+    let mut unsupported = Vec::new();
+
+    for line in contents.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (user, hash) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        /* bcrypt, Apache MD5 and crypt() */
+        if hash.starts_with('$') {
+            unsupported.push(user.to_string());
+            continue;
+        }
+
+        let secret = match hash.strip_prefix("{SHA}") {
+            None => Secret::Plain(hash.to_string()),
+            Some(encoded) => match decode_base64(encoded).and_then(|d| d.try_into().ok()) {
+                Some(digest) => Secret::Sha1(digest),
+                None => {
+                    unsupported.push(user.to_string());
+                    continue;
+                }
+            },
+        };
+        users.insert(user.to_string(), secret);
+    }
+
+    (users, unsupported)
+}
+
+/* The built in backends that have been configured */
+fn configured_backends(
+    #[cfg(all(feature = "ldap", feature = "https"))] certificates: &Arc<CertificateSetup>,
+) -> Result<Vec<Arc<dyn AuthBackend>>, String> {
+    let mut configured: Vec<Arc<dyn AuthBackend>> = Vec::new();
+
+    if let Ok(value) = setting(X_PROXY_AUTH_USERS) {
+        match parse_users(&value) {
+            Some(users) if !users.is_empty() => configured.push(Arc::new(StaticUsers { users })),
+            _ => {
+                return Err(format!(
+                    "'{X_PROXY_AUTH_USERS}' must be comma separated user:password"
+                ))
+            }
+        }
+    }
+
+    if let Ok(path) = setting(X_PROXY_AUTH_HTPASSWD) {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("couldn't read '{path}': {e}")),
+        };
+
+        let (users, unsupported) = parse_htpasswd(&contents);
+        for user in unsupported {
+            warn!("can't check the password of '{user}' in '{path}', use htpasswd -s");
+        }
+        configured.push(Arc::new(Htpasswd { path, users }));
+    }
+
+    if let Some(tokens) = setup_tokens()? {
+        configured.push(tokens);
+    }
+
+    #[cfg(feature = "ldap")]
+    if let Some(ldap) = ldap_backend(
+        #[cfg(feature = "https")]
+        certificates,
+    )? {
+        configured.push(Arc::new(ldap));
+    }
+
+    Ok(configured)
+}
+
+/// Register the built in backends that have been configured, false if one of them couldn't be
+pub(crate) fn setup_auth(
+    #[cfg(all(feature = "ldap", feature = "https"))] certificates: &Arc<CertificateSetup>,
+) -> bool {
+    match configured_backends(
+        #[cfg(all(feature = "ldap", feature = "https"))]
+        certificates,
+    ) {
+        Ok(configured) => {
+            configured.into_iter().for_each(register_auth_backend);
+            true
+        }
+        Err(e) => {
+            error!("{e}");
+            false
+        }
+    }
+}
+
+/// Replace the built in backends with those configured now, such as after users were added to
+/// the htpasswd file. When one of them can't be set up the ones already in use are kept.
+pub(crate) fn reload_auth(
+    #[cfg(all(feature = "ldap", feature = "https"))] certificates: &Arc<CertificateSetup>,
+) {
+    let configured = match configured_backends(
+        #[cfg(all(feature = "ldap", feature = "https"))]
+        certificates,
+    ) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("kept the client logins it had, {e}");
+            return;
+        }
+    };
+
+    for backend in &configured {
+        info!("authenticating clients with {}", backend.name());
+    }
+    if let Ok(mut backends) = backends().write() {
+        *backends = configured;
+    }
+}
+
+/// The user name and password of `Basic` proxy credentials
+pub(crate) fn basic_credentials(header: &HttpRequestHeader) -> Option<(String, String)> {
+    basic_credentials_in(header, "Proxy-Authorization")
+}
+
+fn basic_credentials_in(header: &HttpRequestHeader, name: &str) -> Option<(String, String)> {
+    let credentials = header.headers.get(name)?;
+    let encoded = credentials.trim().strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(decode_base64(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Whether any backend accepts the client's credentials, always true when there are no backends
+pub(crate) async fn authorized(header: &HttpRequestHeader<'_>) -> bool {
+    authorized_in(header, "Proxy-Authorization").await
+}
+
+#[cfg(feature = "web-ui")]
+/// Whether any backend accepts the credentials of a client asking rproxy itself for a page,
+/// which it sends as `Authorization` rather than as proxy credentials. Always true when there are no backends.
+pub(crate) async fn authorized_locally(header: &HttpRequestHeader<'_>) -> bool {
+    authorized_in(header, "Authorization").await
+}
+
+async fn authorized_in(header: &HttpRequestHeader<'_>, name: &str) -> bool {
+    /* Cloned so the lock isn't held while a backend asks another server */
+    let backends = match backends().read() {
+        Ok(b) => b.clone(),
+        Err(_) => return false,
+    };
+
+    if backends.is_empty() {
+        return true;
+    }
+
+    let bearer = header
+        .headers
+        .get(name)
+        .and_then(|c| c.trim().strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return token_valid(token).is_some();
+    }
+
+    let (user, password) = match basic_credentials_in(header, name) {
+        Some(c) => c,
+        None => return false,
+    };
+
+    check_backends(&backends, &user, &password).await
+}
+
+/// Who sent the request, for telling clients apart in what rproxy reports.
+/// Nothing when clients don't have to authenticate, as a name nobody checked could be anyone's.
+pub(crate) fn authenticated_user(header: &HttpRequestHeader) -> Option<String> {
+    if backends().read().map_or(true, |b| b.is_empty()) {
+        return None;
+    }
+
+    /* Service accounts are told apart from people who might share their name */
+    if let Some(id) = request_token(header) {
+        return Some(format!("token:{id}"));
+    }
+    basic_credentials(header)
+        .map(|(user, _)| user)
+        .filter(|user| !user.is_empty())
+}
+
+async fn check_backends(backends: &[Arc<dyn AuthBackend>], user: &str, password: &str) -> bool {
+    for backend in backends {
+        if backend.check(user, password).await {
+            return true;
+        }
+    }
+
+    false
+}
+
+#[cfg(feature = "web-ui")]
+/// Whether `X_PROXY_AUTH_ADMINS` names anyone
+pub(crate) fn administrators_configured() -> bool {
+    setting(X_PROXY_AUTH_ADMINS).is_ok_and(|a| a.split(',').any(|a| !a.trim().is_empty()))
+}
+
+#[cfg(feature = "web-ui")]
+/// Whether the request was sent by one of the users in `X_PROXY_AUTH_ADMINS`, who authenticate
+/// to rproxy itself with `Authorization` and may issue and revoke service account tokens.
+/// `FORBIDDEN` when there are no administrators, `UNAUTHORIZED` when the credentials weren't theirs.
+pub(crate) async fn administrator(
+    header: &HttpRequestHeader<'_>,
+) -> Result<(), HttpResponseStatus> {
+    let admins = match setting(X_PROXY_AUTH_ADMINS) {
+        Ok(a) => a,
+        Err(_) => return Err(HttpResponseStatus::FORBIDDEN),
+    };
+
+    let (user, password) =
+        basic_credentials_in(header, "Authorization").ok_or(HttpResponseStatus::UNAUTHORIZED)?;
+    if !admins.split(',').any(|a| a.trim() == user) {
+        return Err(HttpResponseStatus::UNAUTHORIZED);
+    }
+
+    let backends = match backends().read() {
+        Ok(b) => b.clone(),
+        Err(_) => return Err(HttpResponseStatus::UNAUTHORIZED),
+    };
+    match check_backends(&backends, &user, &password).await {
+        true => Ok(()),
+        false => Err(HttpResponseStatus::UNAUTHORIZED),
+    }
+}
+
+#[cfg(feature = "web-ui")]
+/// Ask for credentials to rproxy itself, such as an administrator's
+pub(crate) async fn respond_admin_required<T>(stream: &mut T) -> ConnectionReturn
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::UNAUTHORIZED,
+        headers: Default::default(),
+        version: HttpVersion::HTTP_V11,
+    };
+    header.headers.insert(
+        "WWW-Authenticate".to_string(),
+        format!("Basic realm=\"{PKG_NAME}\", charset=\"UTF-8\""),
+    );
+    header
+        .headers
+        .insert("Content-Length".to_string(), "0".to_string());
+    header
+        .headers
+        .insert("Connection".to_string(), "close".to_string());
+
+    let _ = stream.write_all(header.generate().as_bytes()).await;
+    ConnectionReturn::Close
+}
+
+/// Ask the client for credentials
+pub(crate) async fn respond_auth_required<T>(
+    return_type: ConnectionReturn,
+    stream: &mut T,
+) -> ConnectionReturn
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::PROXY_AUTHENTICATION_REQUIRED,
+        headers: Default::default(),
+        version: HttpVersion::HTTP_V11,
+    };
+    header.headers.insert(
+        "Proxy-Authenticate".to_string(),
+        format!("Basic realm=\"{PKG_NAME}\", charset=\"UTF-8\""),
+    );
+    header
+        .headers
+        .insert("Content-Length".to_string(), "0".to_string());
+    header.close_if(&return_type);
+
+    match stream.write_all(header.generate().as_bytes()).await {
+        Ok(_) => return_type,
+        Err(_) => ConnectionReturn::Close,
+    }
+}
+
+/* Only used to check passwords hashed by `htpasswd -s` */
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, h) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::conn::Uri, crate::http::HttpRequestMethod};
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            crate::digest::to_hex(&sha1(b"")),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            crate::digest::to_hex(&sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn test_parse_htpasswd() {
+        /* htpasswd -nbs alice secret and htpasswd -nbB bob secret */
+        let (users, unsupported) = parse_htpasswd(
+            "# users\n\
+             alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n\
+             bob:$2y$05$LQGiSc3zpmzOkWI8KBqOXuuF/6vNUpFWLDaQjfhSZFNrsdmP0i.Ti\n\
+             carol:plain\n\
+             \n",
+        );
+        assert_eq!(users.len(), 2);
+        assert!(users["alice"].matches("secret"));
+        assert!(!users["alice"].matches("Secret"));
+        assert_eq!(users["carol"], Secret::Plain("plain".to_string()));
+        assert_eq!(unsupported, vec!["bob"]);
+    }
+
+    #[test]
+    fn test_parse_users() {
+        let users = parse_users("alice:secret, bob:p:w").unwrap();
+        assert_eq!(users["alice"], "secret");
+        assert_eq!(users["bob"], "p:w");
+        assert!(parse_users("alice").is_none());
+        assert!(parse_users(":secret").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_authorized() {
+        let mut header = HttpRequestHeader {
+            method: HttpRequestMethod::Get,
+            request: Uri::from("http://example.com/".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers: Default::default(),
+        };
+        assert!(authorized(&header).await);
+        assert_eq!(authenticated_user(&header), None);
+
+        register_auth_backend(Arc::new(StaticUsers {
+            users: parse_users("alice:secret").unwrap(),
+        }));
+        assert!(!authorized(&header).await);
+
+        /* alice:secret */
+        header.headers.insert(
+            "Proxy-Authorization".to_string(),
+            "Basic YWxpY2U6c2VjcmV0".to_string(),
+        );
+        assert_eq!(
+            basic_credentials(&header),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+        assert!(authorized(&header).await);
+        assert_eq!(authenticated_user(&header), Some("alice".to_string()));
+
+        /* alice:wrong */
+        header.headers.insert(
+            "Proxy-Authorization".to_string(),
+            "Basic YWxpY2U6d3Jvbmc=".to_string(),
+        );
+        assert!(!authorized(&header).await);
+    }
+}
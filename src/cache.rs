@@ -0,0 +1,181 @@
+use {
+    crate::{
+        cli::setting, dedup::prune_blobs, evict::matches_pattern, http::X_PROXY_CACHE_PATH,
+        journal::journal_paths, layout::cache_entries,
+    },
+    std::{
+        collections::BTreeMap,
+        path::{Path, PathBuf},
+    },
+    tokio::fs::{metadata, remove_file},
+    tracing::{error, warn},
+};
+
+struct Entry {
+    key: String,
+    host: String,
+    path: PathBuf,
+    length: u64,
+}
+
+async fn collect_entries(store_path: &Path) -> Vec<Entry> {
+    let mut entries = Vec::new();
+
+    for (host, file, path) in cache_entries(store_path).await {
+        let length = match metadata(&path).await {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+
+        entries.push(Entry {
+            key: format!("{host}/{file}"),
+            host,
+            path,
+            length,
+        });
+    }
+
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+/// Patterns are written like cache pins, a leading scheme is ignored
+fn matches_any(patterns: &[String], key: &str) -> bool {
+    patterns.is_empty()
+        || patterns.iter().any(|p| {
+            matches_pattern(
+                p.trim_start_matches("http://")
+                    .trim_start_matches("https://"),
+                key,
+            )
+        })
+}
+
+/// Bytes used by each host followed by the total, in the style of `du`
+fn disk_usage(entries: &[Entry]) -> String {
+    let mut hosts = BTreeMap::<&str, u64>::new();
+    for entry in entries {
+        *hosts.entry(&entry.host).or_default() += entry.length;
+    }
+
+    let mut report: String = hosts
+        .iter()
+        .map(|(host, bytes)| format!("{bytes}\t{host}\n"))
+        .collect();
+    report.push_str(&format!(
+        "{}\ttotal\n",
+        entries.iter().map(|e| e.length).sum::<u64>()
+    ));
+    report
+}
+
+/// Entry point for `rproxy cache ls|du|purge`
+pub(crate) async fn cache_command(args: &[String]) -> i32 {
+    let store_path = match setting(X_PROXY_CACHE_PATH) {
+        Ok(s) => PathBuf::from(s),
+        Err(_) => {
+            error!("'{X_PROXY_CACHE_PATH}' has not been set");
+            return 1;
+        }
+    };
+
+    let (command, patterns) = match args.split_first() {
+        Some((c, p)) => (c.as_str(), p),
+        None => {
+            error!("expected 'ls', 'du' or 'purge'");
+            return 1;
+        }
+    };
+
+    let entries: Vec<Entry> = collect_entries(&store_path)
+        .await
+        .into_iter()
+        .filter(|e| matches_any(patterns, &e.key))
+        .collect();
+
+    match command {
+        "ls" => {
+            for entry in entries {
+                println!("{}\t{}", entry.length, entry.key);
+            }
+            0
+        }
+        "du" => {
+            print!("{}", disk_usage(&entries));
+            0
+        }
+        "purge" if patterns.is_empty() => {
+            error!("'purge' needs at least one pattern, use '*' to empty the cache");
+            1
+        }
+        "purge" => {
+            /* A running instance is still writing these, removing them would corrupt its journal */
+            let in_progress = journal_paths(&store_path).await;
+            let mut failed = 0;
+
+            for entry in entries {
+                if in_progress.contains(&entry.path) {
+                    warn!("skipping '{}', it's downloading", entry.key);
+                    continue;
+                }
+
+                match remove_file(&entry.path).await {
+                    Ok(_) => println!("{}", entry.key),
+                    Err(e) => {
+                        warn!("couldn't remove '{}': {e}", entry.key);
+                        failed += 1;
+                    }
+                }
+            }
+            /* Bodies only freed once every entry sharing them is gone */
+            prune_blobs(&store_path).await;
+
+            match failed {
+                0 => 0,
+                _ => 1,
+            }
+        }
+        _ => {
+            error!("unknown cache command '{command}', expected 'ls', 'du' or 'purge'");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(host: &str, file: &str, length: u64) -> Entry {
+        Entry {
+            key: format!("{host}/{file}"),
+            host: host.to_string(),
+            path: PathBuf::from(format!("/cache/{host}/{file}")),
+            length,
+        }
+    }
+
+    #[test]
+    fn test_matches_any() {
+        assert!(matches_any(&[], "example.com/a.deb"));
+        assert!(matches_any(
+            &["http://example.com/*.deb".to_string()],
+            "example.com/a.deb"
+        ));
+        assert!(!matches_any(&["*.iso".to_string()], "example.com/a.deb"));
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let entries = vec![
+            entry("b.example.com", "1", 10),
+            entry("a.example.com", "1", 5),
+            entry("b.example.com", "2", 20),
+        ];
+        assert_eq!(
+            disk_usage(&entries),
+            "5\ta.example.com\n30\tb.example.com\n35\ttotal\n"
+        );
+        assert_eq!(disk_usage(&[]), "0\ttotal\n");
+    }
+}
@@ -0,0 +1,212 @@
+use {
+    crate::zerocopy::ZeroCopy,
+    std::{
+        future::Future,
+        io,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, Weak,
+        },
+        task::{Context, Poll},
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        sync::Notify,
+    },
+};
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    notify: Notify,
+    children: Mutex<Vec<Weak<TokenState>>>,
+}
+
+fn cancel_inner(inner: &TokenState) {
+    if inner.cancelled.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    inner.notify.notify_waiters();
+
+    let children = match inner.children.lock() {
+        Ok(mut c) => std::mem::take(&mut *c),
+        Err(_) => return,
+    };
+
+    for child in children.iter().filter_map(|c| c.upgrade()) {
+        cancel_inner(&child);
+    }
+}
+
+/// Tells everything working on behalf of a connection or request that it should stop.
+/// Cancelling a token cancels every token made from it with [`Cancellation::child`].
+#[derive(Clone, Default)]
+pub(crate) struct Cancellation {
+    inner: Arc<TokenState>,
+}
+
+impl Cancellation {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token that's cancelled along with this one but can also be cancelled on its own
+    pub(crate) fn child(&self) -> Self {
+        let child = Self::new();
+
+        match self.inner.children.lock() {
+            Ok(mut children) if !self.is_cancelled() => {
+                children.retain(|c| c.strong_count() > 0);
+                children.push(Arc::downgrade(&child.inner));
+            }
+            _ => child.cancel(),
+        }
+
+        child
+    }
+
+    pub(crate) fn cancel(&self) {
+        cancel_inner(&self.inner);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the token is cancelled
+    pub(crate) async fn cancelled(self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+fn aborted() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "cancelled")
+}
+
+/// A stream that fails every read and write once its token is cancelled,
+/// waking any read or write that was waiting on the other end
+pub(crate) struct Cancellable<S> {
+    inner: S,
+    token: Cancellation,
+    cancelled: Pin<Box<dyn Future<Output = ()> + Send + Sync>>,
+}
+
+impl<S> Cancellable<S> {
+    pub(crate) fn new(inner: S, token: &Cancellation) -> Self {
+        Cancellable {
+            inner,
+            token: token.clone(),
+            cancelled: Box::pin(token.clone().cancelled()),
+        }
+    }
+
+    fn poll_cancelled(&mut self, cx: &mut Context<'_>) -> bool {
+        self.token.is_cancelled() || self.cancelled.as_mut().poll(cx).is_ready()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Cancellable<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.poll_cancelled(cx) {
+            true => Poll::Ready(Err(aborted())),
+            false => Pin::new(&mut self.inner).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Cancellable<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.poll_cancelled(cx) {
+            true => Poll::Ready(Err(aborted())),
+            false => Pin::new(&mut self.inner).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_cancelled(cx) {
+            true => Poll::Ready(Err(aborted())),
+            false => Pin::new(&mut self.inner).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: ZeroCopy> ZeroCopy for Cancellable<S> {
+    async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        length: u64,
+    ) -> Option<io::Result<u64>> {
+        let token = self.token.clone();
+        tokio::select! {
+            r = self.inner.send_file(file, offset, length) => r,
+            _ = token.cancelled() => Some(Err(aborted())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::io::{duplex, AsyncReadExt},
+    };
+
+    #[test]
+    fn test_child_cancellation() {
+        let parent = Cancellation::new();
+        let child = parent.child();
+        let sibling = parent.child();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!sibling.is_cancelled());
+
+        parent.cancel();
+        assert!(sibling.is_cancelled());
+        assert!(parent.child().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_wakes_read() {
+        let token = Cancellation::new();
+        let (_writer, reader) = duplex(64);
+        let mut reader = Cancellable::new(reader, &token);
+
+        let read = tokio::spawn(async move {
+            let mut buf = [0u8; 8];
+            reader.read(&mut buf).await
+        });
+
+        tokio::task::yield_now().await;
+        token.cancel();
+
+        let result = read.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+    }
+}
@@ -0,0 +1,390 @@
+use {
+    crate::{cli::setting, evict::matches_pattern, evict::parse_size, rules::parse_duration},
+    std::{
+        io,
+        path::PathBuf,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            OnceLock,
+        },
+        task::{Context, Poll},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf},
+    tracing::{error, info},
+};
+
+pub const X_PROXY_BODY_LOG: &str = "X_PROXY_BODY_LOG";
+pub const X_PROXY_BODY_LOG_PATH: &str = "X_PROXY_BODY_LOG_PATH";
+pub const X_PROXY_BODY_LOG_SIZE: &str = "X_PROXY_BODY_LOG_SIZE";
+pub const X_PROXY_BODY_LOG_RETENTION: &str = "X_PROXY_BODY_LOG_RETENTION";
+
+/// How much of each body is kept when `X_PROXY_BODY_LOG_SIZE` isn't defined
+const DEFAULT_CAPTURE_SIZE: usize = 4096;
+
+/// The most of a body that may be kept, captures are for looking at, not for mirroring
+const MAX_CAPTURE_SIZE: usize = 1024 * 1024;
+
+/// How long captures are kept when `X_PROXY_BODY_LOG_RETENTION` isn't defined
+const DEFAULT_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// The most captures kept at once however recent, the oldest are removed first
+const MAX_CAPTURES: usize = 1000;
+
+const CAPTURE_EXTENSION: &str = "body";
+
+/// Which bodies are captured, where to and how much of them
+struct BodyLog {
+    patterns: Vec<String>,
+    path: PathBuf,
+    size: usize,
+    retention: Duration,
+}
+
+static BODY_LOG: OnceLock<BodyLog> = OnceLock::new();
+
+/* Tells captures made in the same millisecond apart */
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+fn env_or<T>(variable: &str, default: T, parse: fn(&str) -> Option<T>) -> Result<T, String> {
+    match setting(variable) {
+        Err(_) => Ok(default),
+        Ok(s) => parse(&s).ok_or(s),
+    }
+}
+
+/// Read `X_PROXY_BODY_LOG` and the settings that go with it, creating the capture directory.
+/// False when any of them can't be used.
+pub(crate) fn setup_body_log() -> bool {
+    let patterns: Vec<String> = match setting(X_PROXY_BODY_LOG) {
+        Err(_) => return true,
+        Ok(s) => s
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    };
+    if patterns.is_empty() {
+        return true;
+    }
+
+    let path = match setting(X_PROXY_BODY_LOG_PATH) {
+        Ok(p) if !p.trim().is_empty() => PathBuf::from(p.trim()),
+        _ => {
+            error!("'{X_PROXY_BODY_LOG}' needs a directory in '{X_PROXY_BODY_LOG_PATH}'");
+            return false;
+        }
+    };
+    if let Err(e) = std::fs::create_dir_all(&path) {
+        error!("unable to create '{}': {e}", path.display());
+        return false;
+    }
+
+    let size = match env_or(
+        X_PROXY_BODY_LOG_SIZE,
+        DEFAULT_CAPTURE_SIZE as u64,
+        parse_size,
+    ) {
+        Ok(s) if s > 0 && s <= MAX_CAPTURE_SIZE as u64 => s as usize,
+        Ok(s) => {
+            error!(
+                "'{X_PROXY_BODY_LOG_SIZE}' must be between 1 and {MAX_CAPTURE_SIZE} bytes: '{s}'"
+            );
+            return false;
+        }
+        Err(s) => {
+            error!("'{X_PROXY_BODY_LOG_SIZE}' is not a valid size: '{s}'");
+            return false;
+        }
+    };
+    let retention = match env_or(
+        X_PROXY_BODY_LOG_RETENTION,
+        DEFAULT_RETENTION,
+        parse_duration,
+    ) {
+        Ok(r) => r,
+        Err(s) => {
+            error!("'{X_PROXY_BODY_LOG_RETENTION}' is not a valid duration: '{s}'");
+            return false;
+        }
+    };
+
+    info!(
+        "capturing the first {size} bytes of bodies for {} to '{}' for {} seconds",
+        patterns.join(","),
+        path.display(),
+        retention.as_secs()
+    );
+    let _ = BODY_LOG.set(BodyLog {
+        patterns,
+        path,
+        size,
+        retention,
+    });
+    true
+}
+
+/* Letters, digits, dots and dashes of the URL so a capture can be found by looking at the directory */
+fn capture_name(uri: &str) -> String {
+    uri.trim_start_matches("http://")
+        .trim_start_matches("https://")
+        .chars()
+        .take(80)
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '-') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+/// The start of a body being kept for debugging, written out when dropped
+pub(crate) struct Capture {
+    file: PathBuf,
+    limit: usize,
+    data: Vec<u8>,
+    /* Where to remove old captures from once this one is written */
+    log: Option<(&'static PathBuf, Duration)>,
+}
+
+impl Capture {
+    fn new(file: PathBuf, limit: usize) -> Self {
+        Capture {
+            file,
+            limit,
+            data: Vec::new(),
+            log: None,
+        }
+    }
+
+    fn room(&self) -> usize {
+        self.limit.saturating_sub(self.data.len())
+    }
+
+    /// Keep as much of `data` as still fits
+    pub(crate) fn take(&mut self, data: &[u8]) {
+        let room = self.room();
+        self.data.extend_from_slice(&data[..data.len().min(room)]);
+    }
+}
+
+impl Drop for Capture {
+    /* Written straight away, a capture is small and only made while debugging */
+    fn drop(&mut self) {
+        if self.data.is_empty() {
+            return;
+        }
+        if let Err(e) = std::fs::write(&self.file, &self.data) {
+            error!("unable to write '{}': {e}", self.file.display());
+        }
+        if let Some((path, retention)) = self.log {
+            prune_captures(path, retention, MAX_CAPTURES);
+        }
+    }
+}
+
+/// Start capturing the `kind` body, `request` or `response`, of an exchange with `uri`
+/// when it matches one of the `X_PROXY_BODY_LOG` patterns. `*` on its own captures every body.
+pub(crate) fn capture_body(kind: &str, uri: &str) -> Option<Capture> {
+    let log = BODY_LOG.get()?;
+    let target = uri
+        .trim_start_matches("http://")
+        .trim_start_matches("https://");
+    if !log
+        .patterns
+        .iter()
+        .any(|p| matches_pattern(p, target) || matches_pattern(p, uri))
+    {
+        return None;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let file = log.path.join(format!(
+        "{now:013}-{sequence}-{kind}-{}.{CAPTURE_EXTENSION}",
+        capture_name(uri)
+    ));
+
+    let mut capture = Capture::new(file, log.size);
+    capture.log = Some((&log.path, log.retention));
+    Some(capture)
+}
+
+/// Remove captures older than `retention`, then the oldest until no more than `keep` are left.
+/// Names start with the time they were made so the oldest sort first.
+fn prune_captures(path: &PathBuf, retention: Duration, keep: usize) {
+    let entries = match std::fs::read_dir(path) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    let mut captures = Vec::new();
+    for entry in entries.flatten() {
+        let file = entry.path();
+        if file.extension().is_none_or(|e| e != CAPTURE_EXTENSION) {
+            continue;
+        }
+        let expired = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|m| m.elapsed().ok())
+            .is_some_and(|age| age > retention);
+        match expired {
+            true => {
+                let _ = std::fs::remove_file(&file);
+            }
+            false => captures.push(file),
+        }
+    }
+
+    if captures.len() > keep {
+        captures.sort();
+        for file in &captures[..captures.len() - keep] {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+/// A stream that passes what's read from it to a [`Capture`] on the way through, writes are untouched
+pub(crate) struct Capturing<S> {
+    inner: S,
+    capture: Option<Capture>,
+    /* What the last fill of the buffer showed that could still be captured, kept until it's consumed */
+    filled: Vec<u8>,
+}
+
+impl<S> Capturing<S> {
+    pub(crate) fn new(inner: S, capture: Option<Capture>) -> Self {
+        Capturing {
+            inner,
+            capture,
+            filled: Vec::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Capturing<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(_)), Some(capture)) = (&poll, this.capture.as_mut()) {
+            capture.take(&buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncBufRead + Unpin> AsyncBufRead for Capturing<S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_fill_buf(cx);
+        if let (Poll::Ready(Ok(buffer)), Some(capture)) = (&poll, this.capture.as_ref()) {
+            this.filled.clear();
+            this.filled
+                .extend_from_slice(&buffer[..buffer.len().min(capture.room())]);
+        }
+        poll
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        /* Only what's taken from the buffer is part of the body, the rest may be the next message */
+        if let Some(capture) = this.capture.as_mut() {
+            capture.take(&this.filled[..amt.min(this.filled.len())]);
+            this.filled.clear();
+        }
+        Pin::new(&mut this.inner).consume(amt)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Capturing<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::PKG_NAME,
+        tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    };
+
+    #[test]
+    fn test_capture_name() {
+        assert_eq!(
+            capture_name("https://deb.debian.org/dists/Release?x=1"),
+            "deb.debian.org_dists_Release_x_1"
+        );
+        assert_eq!(capture_name(&"a".repeat(200)).len(), 80);
+    }
+
+    #[tokio::test]
+    async fn test_capturing() {
+        let path = std::env::temp_dir().join(format!("{PKG_NAME}-test-capture.body"));
+        let body = b"5\r\nhello\r\n0\r\n\r\nNEXT";
+        let mut reader = Capturing::new(
+            BufReader::new(&body[..]),
+            Some(Capture::new(path.clone(), 12)),
+        );
+
+        /* Lines taken from the buffer and reads are captured alike, up to the limit */
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line).await.unwrap();
+        let mut data = [0; 7];
+        reader.read_exact(&mut data).await.unwrap();
+        reader.read_until(b'\n', &mut line).await.unwrap();
+        drop(reader);
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"5\r\nhello\r\n0\r");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prune_captures() {
+        let path = std::env::temp_dir().join(format!("{PKG_NAME}-test-prune"));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        for name in ["1-a.body", "2-b.body", "3-c.body", "notes.txt"] {
+            std::fs::write(path.join(name), b"x").unwrap();
+        }
+
+        /* The oldest go first and files that aren't captures are left alone */
+        prune_captures(&path, DEFAULT_RETENTION, 2);
+        let mut left: Vec<String> = std::fs::read_dir(&path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["2-b.body", "3-c.body", "notes.txt"]);
+
+        std::thread::sleep(Duration::from_millis(20));
+        prune_captures(&path, Duration::from_millis(10), MAX_CAPTURES);
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 1);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,25 +1,534 @@
 use {
-    crate::{http::X_PROXY_CACHE_PATH, PKG_NAME},
-    pnet::datalink,
-    rcgen::{generate_simple_self_signed, CertifiedKey},
+    crate::{
+        cli::setting,
+        clock::{civil, now},
+        digest::Sha256,
+        evict::{matches_pattern, parse_size},
+        http::X_PROXY_CACHE_PATH,
+        PKG_NAME,
+    },
+    rcgen::{
+        date_time_ymd, BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+        ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+    },
     rustls::{
-        pki_types::pem::PemObject,
-        pki_types::{CertificateDer, PrivateKeyDer},
-        ClientConfig, RootCertStore, ServerConfig,
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            WebPkiServerVerifier,
+        },
+        crypto::{
+            ring::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+            WebPkiSupportedAlgorithms,
+        },
+        pki_types::{
+            pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+        },
+        version::{TLS12, TLS13},
+        CertificateError, ClientConfig, DigitallySignedStruct, Error, RootCertStore, ServerConfig,
+        SignatureScheme, SupportedProtocolVersion,
     },
     rustls_native_certs::load_native_certs,
-    std::{net::IpAddr, path::PathBuf, sync::Arc},
-    tokio_rustls::{TlsAcceptor, TlsConnector},
+    std::{
+        collections::HashMap,
+        io::Write,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex, OnceLock, RwLock},
+        time::{Duration, SystemTime},
+    },
+    tokio::time::sleep,
+    tokio_rustls::TlsConnector,
+    tracing::{debug, error, info, warn},
+};
+
+#[cfg(feature = "keylog")]
+use {
+    crate::digest::to_hex,
+    rustls::{KeyLog, KeyLogFile},
 };
 
 pub const X_PROXY_TLS_PATH: &str = "X_PROXY_TLS_PATH";
 
-pub const CERT_QUERY: &str = "?cert";
+/// The query clients download the certificate authority with, see `certificate_download` for others
+pub const CERT_QUERY: &str = "?ca.crt";
+
+pub const X_PROXY_INTERCEPT_HOSTS: &str = "X_PROXY_INTERCEPT_HOSTS";
+
+pub const X_PROXY_TLS_ROOTS: &str = "X_PROXY_TLS_ROOTS";
+pub const X_PROXY_TLS_ROOT_STORE: &str = "X_PROXY_TLS_ROOT_STORE";
+pub const X_PROXY_TLS_PINS: &str = "X_PROXY_TLS_PINS";
+pub const X_PROXY_TLS_INSECURE_HOSTS: &str = "X_PROXY_TLS_INSECURE_HOSTS";
+pub const X_PROXY_TLS_CLIENT_CERTS: &str = "X_PROXY_TLS_CLIENT_CERTS";
+
+pub const X_PROXY_TLS_MIN_VERSION: &str = "X_PROXY_TLS_MIN_VERSION";
+pub const X_PROXY_TLS_CIPHERS: &str = "X_PROXY_TLS_CIPHERS";
+pub const X_PROXY_UPSTREAM_TLS_MIN_VERSION: &str = "X_PROXY_UPSTREAM_TLS_MIN_VERSION";
+pub const X_PROXY_UPSTREAM_TLS_CIPHERS: &str = "X_PROXY_UPSTREAM_TLS_CIPHERS";
+pub const X_PROXY_TLS_MAX_RECORD_SIZE: &str = "X_PROXY_TLS_MAX_RECORD_SIZE";
+
+/// The most a TLS record may carry, and what intercepted connections send when not told otherwise
+const MAX_RECORD_SIZE: usize = 16384;
+
+/* Smaller records spend more on their headers and authentication tags than they carry */
+const MIN_RECORD_SIZE: usize = 512;
+
+/// The five bytes of type, version and length in front of every TLS record
+const RECORD_HEADER_SIZE: usize = 5;
+
+/// Where TLS secrets are written for tools such as Wireshark, the variable browsers and curl use
+pub const SSLKEYLOGFILE: &str = "SSLKEYLOGFILE";
+/// Where the secrets of connections to origin servers alone are written, rather than to `SSLKEYLOGFILE`
+pub const X_PROXY_UPSTREAM_KEY_LOG: &str = "X_PROXY_UPSTREAM_KEY_LOG";
+
+/// Upper bound on minted certificates kept for reuse, each intercepted host needs its own
+const MAX_MINTED_CERTIFICATES: usize = 256;
+
+/// Minted certificates are kept in this directory next to the certificate authority
+const LEAF_DIRECTORY: &str = "leaves";
+
+/// Minted certificates are valid for a year and replaced a month before they expire
+const LEAF_ROTATION_AGE: Duration = Duration::from_secs(334 * 24 * 60 * 60);
+
+/// How often certificate and key files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A certificate minted for a host and when it was
+struct Leaf {
+    config: Arc<ServerConfig>,
+    minted: SystemTime,
+}
+
+impl Leaf {
+    fn is_due_rotation(&self) -> bool {
+        SystemTime::now()
+            .duration_since(self.minted)
+            .is_ok_and(|age| age >= LEAF_ROTATION_AGE)
+    }
+}
+
+/// Signs the certificates rproxy presents for intercepted hosts
+struct CertificateAuthority {
+    /// Rebuilt from the key at each start, only used to sign with
+    cert: Certificate,
+    key: KeyPair,
+    /// The certificate as saved, which is the one clients trust
+    pem: String,
+    der: CertificateDer<'static>,
+}
+
+/// Everything read from certificate and key files, replaced as a whole when any of them change
+struct Material {
+    client_config: Arc<TlsConnector>,
+    /* Host patterns in the order they were given */
+    client_identities: Vec<(String, Arc<TlsConnector>)>,
+    authority: CertificateAuthority,
+    /* What intercepted clients may negotiate */
+    server_tls: TlsSettings,
+}
 
 pub(crate) struct CertificateSetup {
-    pub(crate) client_config: Arc<TlsConnector>,
-    pub(crate) server_config: Arc<TlsAcceptor>,
-    pub(crate) server_cert_path: PathBuf,
+    /* Connections already made keep the configuration they started with */
+    material: RwLock<Arc<Material>>,
+    /* Keyed by host */
+    minted: Mutex<HashMap<String, Leaf>>,
+    /* Where minted certificates are saved, not at all when `None` */
+    leaves: Option<PathBuf>,
+}
+
+impl CertificateSetup {
+    fn new(material: Material, leaves: Option<PathBuf>) -> Self {
+        CertificateSetup {
+            material: RwLock::new(Arc::new(material)),
+            minted: Mutex::new(HashMap::new()),
+            leaves,
+        }
+    }
+
+    fn material(&self) -> Arc<Material> {
+        match self.material.read() {
+            Ok(m) => Arc::clone(&m),
+            Err(e) => Arc::clone(&e.into_inner()),
+        }
+    }
+
+    /* Certificates minted by an authority that's been replaced are no use to anyone,
+     * the lock on them is held so none can be minted by the old one in the meantime */
+    fn replace(&self, material: Material) {
+        let mut minted = match self.minted.lock() {
+            Ok(m) => m,
+            Err(e) => e.into_inner(),
+        };
+
+        let current = self.material();
+        if material.authority.der != current.authority.der
+            || material.authority.key.serialize_der() != current.authority.key.serialize_der()
+        {
+            minted.clear();
+            if let Some(leaves) = &self.leaves {
+                let _ = std::fs::remove_dir_all(leaves);
+                let _ = std::fs::create_dir_all(leaves);
+            }
+        }
+
+        match self.material.write() {
+            Ok(mut m) => *m = Arc::new(material),
+            Err(e) => *e.into_inner() = Arc::new(material),
+        }
+    }
+
+    /// Connects to `host` presenting the client certificate configured for it, if there is one
+    pub(crate) fn connector_for(&self, host: &str) -> Arc<TlsConnector> {
+        let host = host.to_lowercase();
+        let material = self.material();
+        let connector = material
+            .client_identities
+            .iter()
+            .find(|(p, _)| matches_pattern(p, &host))
+            .map(|(_, c)| c)
+            .unwrap_or(&material.client_config);
+        Arc::clone(connector)
+    }
+
+    /// The certificate authority clients need to trust and its `Content-Type`,
+    /// in the format asked for by a `/?<query>` request, or nothing when the query isn't for it
+    pub(crate) fn certificate_download(&self, query: &str) -> Option<(Vec<u8>, &'static str)> {
+        let material = self.material();
+        let authority = &material.authority;
+        match query {
+            "ca.crt" | "cert" => Some((
+                authority.pem.clone().into_bytes(),
+                "application/x-x509-ca-cert",
+            )),
+            "ca.pem" => Some((authority.pem.clone().into_bytes(), "application/x-pem-file")),
+            "ca.der" | "ca.cer" => Some((authority.der.to_vec(), "application/pkix-cert")),
+            _ => None,
+        }
+    }
+
+    /// Accepts TLS from a client with a certificate for `host` signed by rproxy's certificate authority.
+    /// Certificates are minted once and reused from memory or disk until they're due to be rotated.
+    pub(crate) fn server_config_for(&self, host: &str) -> Option<Arc<ServerConfig>> {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase();
+
+        if let Some(leaf) = self
+            .minted
+            .lock()
+            .ok()?
+            .get(&host)
+            .filter(|l| !l.is_due_rotation())
+        {
+            return Some(Arc::clone(&leaf.config));
+        }
+
+        /* Generating a key takes a while, handshakes for other hosts shouldn't wait on it */
+        let material = self.material();
+        let (leaf, pem) = match self.load_leaf(&material, &host) {
+            Some(l) => (l, None),
+            None => mint(&material, &host).map(|(l, p)| (l, Some(p)))?,
+        };
+        let config = Arc::clone(&leaf.config);
+
+        /* One minted by an authority replaced in the meantime only does for this connection */
+        let mut minted = self.minted.lock().ok()?;
+        if material.authority.der != self.material().authority.der {
+            return Some(config);
+        }
+
+        if let Some(pem) = pem {
+            self.save_leaf(&host, pem);
+        }
+        if minted.len() >= MAX_MINTED_CERTIFICATES {
+            minted.clear();
+        }
+        minted.insert(host, leaf);
+        Some(config)
+    }
+
+    /* Hosts that wouldn't make a plain file name are only kept in memory */
+    fn leaf_path(&self, host: &str) -> Option<PathBuf> {
+        let valid = !host.is_empty()
+            && !host.starts_with('.')
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':'));
+
+        match valid {
+            true => Some(
+                self.leaves
+                    .as_ref()?
+                    .join(format!("{}.pem", host.replace(':', "_"))),
+            ),
+            false => None,
+        }
+    }
+
+    fn load_leaf(&self, material: &Material, host: &str) -> Option<Leaf> {
+        let path = self.leaf_path(host)?;
+        let minted = std::fs::metadata(&path).ok()?.modified().ok()?;
+        let pem = std::fs::read(&path).ok()?;
+
+        let cert = CertificateDer::from_pem_slice(&pem).ok()?;
+        let key = PrivateKeyDer::from_pem_slice(&pem).ok()?;
+        let leaf = Leaf {
+            config: server_config(material, host, cert, key)?,
+            minted,
+        };
+
+        match leaf.is_due_rotation() {
+            true => None,
+            false => Some(leaf),
+        }
+    }
+
+    /* Only as many are kept on disk as in memory, the ones minted longest ago go first */
+    fn save_leaf(&self, host: &str, pem: String) {
+        let path = match self.leaf_path(host) {
+            Some(p) => p,
+            None => return,
+        };
+
+        if let Err(e) = write_private(&path, pem.as_bytes()) {
+            warn!("couldn't save '{}': {e}", path.to_string_lossy());
+        }
+
+        let mut saved: Vec<(SystemTime, PathBuf)> = match path.parent().map(std::fs::read_dir) {
+            Some(Ok(d)) => d
+                .flatten()
+                .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+                .filter(|(_, p)| p.extension().is_some_and(|e| e == "pem"))
+                .collect(),
+            _ => return,
+        };
+
+        if saved.len() > MAX_MINTED_CERTIFICATES {
+            saved.sort();
+            for (_, oldest) in &saved[..saved.len() - MAX_MINTED_CERTIFICATES] {
+                let _ = std::fs::remove_file(oldest);
+            }
+        }
+    }
+}
+
+/* The certificate and its key are also returned as PEM for saving */
+fn mint(material: &Material, host: &str) -> Option<(Leaf, String)> {
+    let authority = &material.authority;
+    let mut params = CertificateParams::new(vec![host.to_string()]).ok()?;
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, host);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+
+    /* Clients refuse server certificates valid for much more than a year */
+    let (year, month, day) = civil(now());
+    params.not_before = date_time_ymd(year, month, day);
+    params.not_after = date_time_ymd(year + 1, month, day.min(28));
+
+    let key = KeyPair::generate().ok()?;
+    let cert = params
+        .signed_by(&key, &authority.cert, &authority.key)
+        .ok()?;
+
+    let config = server_config(
+        material,
+        host,
+        cert.der().clone(),
+        PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+    )?;
+
+    debug!("Minted a certificate for {host}");
+    Some((
+        Leaf {
+            config,
+            minted: SystemTime::now(),
+        },
+        cert.pem() + &key.serialize_pem(),
+    ))
+}
+
+fn server_config(
+    material: &Material,
+    host: &str,
+    cert: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+) -> Option<Arc<ServerConfig>> {
+    let tls = &material.server_tls;
+    match ServerConfig::builder_with_provider(Arc::clone(&tls.provider))
+        .with_protocol_versions(&tls.versions)
+        .and_then(|b| {
+            b.with_no_client_auth()
+                .with_single_cert(vec![cert, material.authority.der.clone()], key)
+        }) {
+        Ok(mut c) => {
+            #[cfg(feature = "keylog")]
+            {
+                c.key_log = key_log();
+            }
+            c.max_fragment_size = Some(tls_record_size() + RECORD_HEADER_SIZE);
+            Some(Arc::new(c))
+        }
+        Err(e) => {
+            warn!("unable to create server https config for {host}: {e}");
+            None
+        }
+    }
+}
+
+static RECORD_SIZE: OnceLock<usize> = OnceLock::new();
+
+/// The most data intercepted connections put in one TLS record, writes are held
+/// until there's this much or the response is done so small ones share a record
+pub(crate) fn tls_record_size() -> usize {
+    RECORD_SIZE.get().copied().unwrap_or(MAX_RECORD_SIZE)
+}
+
+fn parse_record_size(value: &str) -> Option<usize> {
+    let size = parse_size(value)?;
+    (MIN_RECORD_SIZE as u64..=MAX_RECORD_SIZE as u64)
+        .contains(&size)
+        .then_some(size as usize)
+}
+
+/* Read `X_PROXY_TLS_MAX_RECORD_SIZE` */
+fn setup_record_size() -> Result<(), String> {
+    let value = match setting(X_PROXY_TLS_MAX_RECORD_SIZE) {
+        Ok(v) => v,
+        Err(_) => return Ok(()),
+    };
+
+    match parse_record_size(&value) {
+        Some(s) => {
+            let _ = RECORD_SIZE.set(s);
+            Ok(())
+        }
+        None => Err(format!(
+            "'{X_PROXY_TLS_MAX_RECORD_SIZE}' must be a size from {MIN_RECORD_SIZE} to {MAX_RECORD_SIZE} bytes: '{value}'"
+        )),
+    }
+}
+
+#[cfg(feature = "keylog")]
+/* Every config shares one file rather than each opening their own */
+fn key_log() -> Arc<dyn KeyLog> {
+    static KEY_LOG: OnceLock<Arc<KeyLogFile>> = OnceLock::new();
+    Arc::clone(KEY_LOG.get_or_init(|| Arc::new(KeyLogFile::new()))) as Arc<dyn KeyLog>
+}
+
+#[cfg(feature = "keylog")]
+/// Appends the secrets of connections to origin servers to `X_PROXY_UPSTREAM_KEY_LOG`
+/// in the same format [`KeyLogFile`] writes
+#[derive(Debug)]
+struct UpstreamKeyLog(Mutex<std::fs::File>);
+
+#[cfg(feature = "keylog")]
+impl KeyLog for UpstreamKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{label} {} {}\n", to_hex(client_random), to_hex(secret));
+        if let Ok(mut file) = self.0.lock() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+#[cfg(feature = "keylog")]
+static UPSTREAM_KEY_LOG: OnceLock<Arc<UpstreamKeyLog>> = OnceLock::new();
+
+#[cfg(feature = "keylog")]
+/* Upstream connections only share the file with intercepted ones when no file of their own is set */
+fn upstream_key_log() -> Arc<dyn KeyLog> {
+    match UPSTREAM_KEY_LOG.get() {
+        Some(k) => Arc::clone(k) as Arc<dyn KeyLog>,
+        None => key_log(),
+    }
+}
+
+#[cfg(feature = "keylog")]
+/* Created so only rproxy can read it, like minted certificates */
+fn open_key_log(variable: &str, path: &Path) -> Result<std::fs::File, String> {
+    let mut options = std::fs::OpenOptions::new();
+    options.append(true).create(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options
+        .open(path)
+        .map_err(|e| format!("couldn't open {variable} '{}': {e}", path.to_string_lossy()))
+}
+
+#[cfg(feature = "keylog")]
+/// Warn on stderr whatever the log level that TLS secrets are being written when `SSLKEYLOGFILE`
+/// or `X_PROXY_UPSTREAM_KEY_LOG` is set, rustls gives up quietly when it can't write there so the file is checked here
+fn check_key_log() -> Result<(), String> {
+    if let Some(path) = setting(X_PROXY_UPSTREAM_KEY_LOG).ok().map(PathBuf::from) {
+        let file = open_key_log(X_PROXY_UPSTREAM_KEY_LOG, &path)?;
+        let _ = UPSTREAM_KEY_LOG.set(Arc::new(UpstreamKeyLog(Mutex::new(file))));
+
+        eprintln!(
+            "WARNING: writing the TLS secrets of every connection to an origin server to '{}', \
+            anyone who can read it can decrypt them. Unset {X_PROXY_UPSTREAM_KEY_LOG} once done debugging",
+            path.to_string_lossy()
+        );
+    }
+
+    let path = match std::env::var_os(SSLKEYLOGFILE) {
+        Some(p) => PathBuf::from(p),
+        None => return Ok(()),
+    };
+    open_key_log(SSLKEYLOGFILE, &path)?;
+
+    let connections = match UPSTREAM_KEY_LOG.get() {
+        Some(_) => "intercepted connections",
+        None => "intercepted and upstream connections",
+    };
+    eprintln!(
+        "WARNING: writing TLS secrets to '{}', anyone who can read it can decrypt \
+        {connections}. Unset {SSLKEYLOGFILE} once done debugging",
+        path.to_string_lossy()
+    );
+    Ok(())
+}
+
+/* Minted certificates come with their private key so only rproxy should read them */
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents)
+}
+
+fn intercept_hosts() -> &'static Vec<String> {
+    static HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+    HOSTS.get_or_init(|| match setting(X_PROXY_INTERCEPT_HOSTS) {
+        Err(_) => Vec::new(),
+        Ok(s) => s
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    })
+}
+
+/// Whether `CONNECT` requests to `host` are answered by rproxy itself so the requests
+/// inside can be cached, rather than tunnelled to the host untouched
+pub(crate) fn intercepts(host: &str) -> bool {
+    let host = host.to_lowercase();
+    intercept_hosts().iter().any(|p| matches_pattern(p, &host))
+}
+
+/// Whether any host is intercepted, so clients have HTTPS downloads cached if they trust the authority
+pub(crate) fn intercepting() -> bool {
+    !intercept_hosts().is_empty()
 }
 
 #[cfg(debug_assertions)]
@@ -28,7 +537,7 @@ pub(crate) struct CertificateSetup {
 /// including man-in-the-middle attacks.
 /// This function should only be used
 /// to simplify debugging of HTTPS connections during development.
-fn treat_certificates_as_gospel() -> Arc<TlsConnector> {
+fn treat_certificates_as_gospel() -> Arc<dyn ServerCertVerifier> {
     use {
         rustls::{
             client::danger::ServerCertVerifier,
@@ -89,98 +598,533 @@ fn treat_certificates_as_gospel() -> Arc<TlsConnector> {
         }
     }
 
-    eprintln!(
-        "{PKG_NAME} will treat all HTTPS certificates as gospel for debugging purposes...\
+    warn!(
+        "will treat all HTTPS certificates as gospel for debugging purposes...\
         \n\nDO NOT USE THIS VERSION IN PRODUCTION!\n"
     );
 
-    let config = ClientConfig::builder()
-        .dangerous()
-        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
-        .with_no_client_auth();
+    Arc::new(NoCertificateVerification)
+}
 
-    // Create a ClientConfig with safe defaults and no client authentication
-    let config = Arc::new(config);
+/// How the certificate a host presents is checked
+#[derive(Debug, PartialEq)]
+enum Verification {
+    /// Signed by a trusted root, the usual way
+    Roots,
+    /// Exactly one of these certificates, by SHA-256 digest, whoever signed it
+    Pinned(Vec<[u8; 32]>),
+    /// Not at all
+    Insecure,
+}
 
-    // Wrap the configuration in an Arc and return the TlsConnector
-    Arc::new(TlsConnector::from(config))
+#[derive(Debug, Default)]
+struct UpstreamPolicy {
+    /* Host patterns and the digests pinned for them */
+    pins: Vec<(String, [u8; 32])>,
+    insecure: Vec<String>,
 }
 
-fn load_system_certificates() -> Arc<TlsConnector> {
+impl UpstreamPolicy {
+    /// The policy from `X_PROXY_TLS_PINS` and `X_PROXY_TLS_INSECURE_HOSTS`,
+    /// or an error naming the variable that couldn't be read
+    fn from_env() -> Result<Self, String> {
+        let pins = match setting(X_PROXY_TLS_PINS) {
+            Ok(v) => parse_pins(&v).ok_or(X_PROXY_TLS_PINS.to_string())?,
+            Err(_) => Vec::new(),
+        };
+
+        let insecure = match setting(X_PROXY_TLS_INSECURE_HOSTS) {
+            Ok(v) => v
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        Ok(UpstreamPolicy { pins, insecure })
+    }
+
+    /* A pin is stricter than skipping checks so it wins when a host is given both */
+    fn verification(&self, host: &str) -> Verification {
+        let host = host.to_lowercase();
+
+        let pinned: Vec<[u8; 32]> = self
+            .pins
+            .iter()
+            .filter(|(p, _)| matches_pattern(p, &host))
+            .map(|(_, d)| *d)
+            .collect();
+
+        match pinned.is_empty() {
+            false => Verification::Pinned(pinned),
+            true if self.insecure.iter().any(|p| matches_pattern(p, &host)) => {
+                Verification::Insecure
+            }
+            true => Verification::Roots,
+        }
+    }
+}
+
+/* A SHA-256 digest in hex, with or without the colons `openssl x509 -fingerprint` puts in */
+fn parse_digest(value: &str) -> Option<[u8; 32]> {
+    let hex: Vec<u8> = value.bytes().filter(|b| *b != b':').collect();
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut digest = [0u8; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/* Comma separated `host=digest` pairs, a host can be given more than once */
+fn parse_pins(value: &str) -> Option<Vec<(String, [u8; 32])>> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|pin| {
+            let (host, digest) = pin.split_once('=')?;
+            Some((host.trim().to_lowercase(), parse_digest(digest.trim())?))
+        })
+        .collect()
+}
+
+/// Checks upstream certificates against the roots unless the policy says otherwise for the host.
+/// Handshake signatures are always checked so a pinned or unchecked certificate still has to
+/// belong to the server presenting it.
+#[derive(Debug)]
+struct PolicyVerifier {
+    roots: Arc<WebPkiServerVerifier>,
+    policy: UpstreamPolicy,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PolicyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        match self.policy.verification(&server_name.to_str()) {
+            Verification::Roots => self.roots.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            ),
+            Verification::Pinned(digests) => {
+                let mut hasher = Sha256::default();
+                hasher.update(end_entity);
+                match digests.contains(&hasher.finish()) {
+                    true => Ok(ServerCertVerified::assertion()),
+                    false => Err(Error::InvalidCertificate(
+                        CertificateError::ApplicationVerificationFailure,
+                    )),
+                }
+            }
+            Verification::Insecure => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/* Every certificate in a PEM file, or in each file of a directory */
+fn load_root_certificates(path: &Path) -> Vec<CertificateDer<'static>> {
+    let files = match std::fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|e| e.ok().map(|e| e.path())).collect(),
+        Err(_) => vec![path.to_path_buf()],
+    };
+
+    let mut certs = Vec::new();
+    for file in files {
+        match std::fs::read(&file) {
+            Ok(pem) => certs.extend(CertificateDer::pem_slice_iter(&pem).filter_map(|c| c.ok())),
+            Err(e) => warn!("couldn't read '{}': {e}", file.to_string_lossy()),
+        }
+    }
+    certs
+}
+
+/// Where the roots upstream certificates are checked against come from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum RootSource {
+    /// The system's store, or the bundled roots when it has none and they were built in
+    #[default]
+    System,
+    /// The Mozilla roots built in with the `bundled-roots` feature
+    Bundled,
+    /// Both of them
+    Both,
+}
+
+impl RootSource {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "system" => Some(RootSource::System),
+            "bundled" => Some(RootSource::Bundled),
+            "both" => Some(RootSource::Both),
+            _ => None,
+        }
+    }
+
+    fn from_env() -> Result<Self, String> {
+        let source = match setting(X_PROXY_TLS_ROOT_STORE) {
+            Err(_) => return Ok(RootSource::default()),
+            Ok(s) => RootSource::from_name(&s).ok_or(format!(
+                "'{X_PROXY_TLS_ROOT_STORE}' must be 'system', 'bundled' or 'both': '{s}'"
+            ))?,
+        };
+
+        match source {
+            RootSource::Bundled | RootSource::Both if !cfg!(feature = "bundled-roots") => Err(
+                format!("'{X_PROXY_TLS_ROOT_STORE}' needs the bundled-roots feature"),
+            ),
+            s => Ok(s),
+        }
+    }
+}
+
+/* The number of roots added, none when they weren't built in */
+fn add_bundled_roots(_root_store: &mut RootCertStore) -> usize {
+    #[cfg(feature = "bundled-roots")]
+    {
+        _root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        webpki_roots::TLS_SERVER_ROOTS.len()
+    }
+    #[cfg(not(feature = "bundled-roots"))]
+    0
+}
+
+fn upstream_verifier() -> Result<Arc<dyn ServerCertVerifier>, String> {
+    let source = RootSource::from_env()?;
     let mut root_store = RootCertStore::empty();
-    let certs = load_native_certs();
 
-    for error in certs.errors {
-        eprintln!("{PKG_NAME} couldn't load a system certificate: {}", error);
+    if source != RootSource::Bundled {
+        let certs = load_native_certs();
+
+        for error in certs.errors {
+            warn!("couldn't load a system certificate: {}", error);
+        }
+
+        for cert in certs.certs {
+            let _ = root_store.add(cert);
+        }
+        info!("loaded {} system certificates", root_store.len());
     }
 
-    for cert in certs.certs {
-        let _ = root_store.add(cert);
+    /* Containers and small systems often have no store of their own */
+    if source != RootSource::System || root_store.is_empty() {
+        let added = add_bundled_roots(&mut root_store);
+        if added > 0 {
+            info!("loaded {added} bundled certificates");
+        }
+    }
+
+    if let Ok(paths) = setting(X_PROXY_TLS_ROOTS) {
+        for path in paths.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (added, _) =
+                root_store.add_parsable_certificates(load_root_certificates(Path::new(path)));
+            info!("loaded {added} certificates from '{path}'");
+        }
     }
 
     if root_store.is_empty() {
-        eprintln!("{PKG_NAME} couldn't load any system certificates");
-        std::process::exit(1);
+        return Err("couldn't load any root certificates".to_string());
     }
-    eprintln!("{PKG_NAME} loaded {} system certificates", root_store.len());
-    let config = Arc::new(
-        ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth(),
-    );
 
-    Arc::new(TlsConnector::from(config))
+    let policy = UpstreamPolicy::from_env().map_err(|e| format!("couldn't understand {e}"))?;
+    if !policy.insecure.is_empty() {
+        warn!(
+            "will not check the certificates of {}, \
+            anyone between here and them can read and change what's downloaded",
+            policy.insecure.join(", ")
+        );
+    }
+
+    let provider = Arc::new(default_provider());
+    let roots =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(root_store), Arc::clone(&provider))
+            .build()
+            .map_err(|e| format!("unable to create a certificate verifier: {e}"))?;
+
+    Ok(Arc::new(PolicyVerifier {
+        roots,
+        policy,
+        algorithms: provider.signature_verification_algorithms,
+    }))
 }
 
-fn load_server_certificates(cert_path: &PathBuf, key_path: &PathBuf) -> Arc<TlsAcceptor> {
-    let cert = match CertificateDer::from_pem_file(cert_path) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!(
-                "{PKG_NAME} error loading '{}': {}",
-                cert_path.to_str().unwrap_or("?"),
-                e
-            );
-            std::process::exit(1);
+/// The TLS versions and cipher suites one side of rproxy may negotiate
+#[derive(Clone)]
+struct TlsSettings {
+    versions: Vec<&'static SupportedProtocolVersion>,
+    /* Only holds the allowed cipher suites, most preferred first */
+    provider: Arc<CryptoProvider>,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            versions: vec![&TLS13, &TLS12],
+            provider: Arc::new(default_provider()),
         }
-    };
+    }
+}
 
-    let key = match PrivateKeyDer::from_pem_file(key_path) {
-        Ok(k) => k,
-        Err(e) => {
-            eprintln!(
-                "{PKG_NAME} error loading '{}': {}",
-                key_path.to_str().unwrap_or("?"),
-                e
+impl TlsSettings {
+    /// Settings from a minimum version such as `1.2` or `1.3` and a comma separated list of
+    /// cipher suite names in the order they're preferred, either left out allows everything rproxy supports
+    fn parse(min_version: Option<&str>, ciphers: Option<&str>) -> Result<Self, String> {
+        let mut settings = TlsSettings::default();
+
+        if let Some(v) = min_version {
+            let v = v.trim();
+            let number = v
+                .strip_prefix("TLSv")
+                .or_else(|| v.strip_prefix("TLS"))
+                .unwrap_or(v)
+                .trim();
+            settings.versions = match number {
+                "1.2" => vec![&TLS13, &TLS12],
+                "1.3" => vec![&TLS13],
+                _ => return Err(format!("'{v}' isn't a TLS version rproxy supports")),
+            };
+        }
+
+        let mut provider = default_provider();
+        if let Some(c) = ciphers {
+            let mut chosen = Vec::new();
+            for name in c.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+                match provider
+                    .cipher_suites
+                    .iter()
+                    .find(|s| format!("{:?}", s.suite()).eq_ignore_ascii_case(name))
+                {
+                    Some(s) => chosen.push(*s),
+                    None => return Err(format!("'{name}' isn't a cipher suite rproxy supports")),
+                }
+            }
+            provider.cipher_suites = chosen;
+        }
+
+        provider.cipher_suites.retain(|s| {
+            settings
+                .versions
+                .iter()
+                .any(|v| v.version == s.version().version)
+        });
+        if provider.cipher_suites.is_empty() {
+            return Err(
+                "no cipher suites are left to use with the allowed TLS versions".to_string(),
             );
-            std::process::exit(1);
         }
-    };
 
-    let config = match ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(vec![cert], key)
+        settings.provider = Arc::new(provider);
+        Ok(settings)
+    }
+
+    /// Settings from the `version` and `ciphers` variables, or from `fallback` where one isn't set
+    fn from_env(
+        version: &str,
+        ciphers: &str,
+        fallback: Option<(&str, &str)>,
+    ) -> Result<Self, String> {
+        let read = |name: &str, fallback: Option<&str>| {
+            setting(name)
+                .ok()
+                .or_else(|| fallback.and_then(|f| setting(f).ok()))
+        };
+
+        TlsSettings::parse(
+            read(version, fallback.map(|f| f.0)).as_deref(),
+            read(ciphers, fallback.map(|f| f.1)).as_deref(),
+        )
+        .map_err(|e| format!("couldn't understand {version} or {ciphers}: {e}"))
+    }
+}
+
+/// A client certificate chain and the key that goes with it
+type ClientIdentity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/* Comma separated `host=file` pairs, the first pattern matching a host decides its certificate */
+fn parse_client_certs(value: &str) -> Option<Vec<(String, PathBuf)>> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|pair| {
+            let (host, path) = pair.split_once('=')?;
+            let (host, path) = (host.trim().to_lowercase(), path.trim());
+            match host.is_empty() || path.is_empty() {
+                true => None,
+                false => Some((host, PathBuf::from(path))),
+            }
+        })
+        .collect()
+}
+
+/* A PEM file holding the certificate, any intermediates after it and the private key */
+fn load_client_identity(pem: &[u8]) -> Result<ClientIdentity, String> {
+    let chain: Vec<CertificateDer<'static>> = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    if chain.is_empty() {
+        return Err("no certificate".to_string());
+    }
+
+    let key = PrivateKeyDer::from_pem_slice(pem).map_err(|e| e.to_string())?;
+    Ok((chain, key))
+}
+
+fn connector(
+    tls: &TlsSettings,
+    verifier: &Arc<dyn ServerCertVerifier>,
+    identity: Option<ClientIdentity>,
+) -> Result<Arc<TlsConnector>, String> {
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&tls.provider))
+        .with_protocol_versions(&tls.versions)
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::clone(verifier));
+
+    #[cfg_attr(not(feature = "keylog"), allow(unused_mut))]
+    let mut config = match identity {
+        None => builder.with_no_client_auth(),
+        Some((chain, key)) => builder
+            .with_client_auth_cert(chain, key)
+            .map_err(|e| e.to_string())?,
+    };
+    #[cfg(feature = "keylog")]
     {
-        Ok(c) => {
-            eprintln!(
-                "{PKG_NAME} using server https cert '{}' and key '{}'",
-                cert_path.to_str().unwrap_or("?"),
-                key_path.to_str().unwrap_or("?")
-            );
-            c
+        config.key_log = upstream_key_log();
+    }
+
+    Ok(Arc::new(TlsConnector::from(Arc::new(config))))
+}
+
+/// A connector that presents a client certificate for each host in `X_PROXY_TLS_CLIENT_CERTS`
+fn client_identities(
+    tls: &TlsSettings,
+    verifier: &Arc<dyn ServerCertVerifier>,
+) -> Result<Vec<(String, Arc<TlsConnector>)>, String> {
+    let pairs = client_cert_files()?;
+
+    let mut identities = Vec::new();
+    for (host, path) in pairs {
+        let identity = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| load_client_identity(&pem))
+            .and_then(|identity| connector(tls, verifier, Some(identity)));
+
+        match identity {
+            Ok(c) => {
+                info!("will present '{}' to {host}", path.to_string_lossy());
+                identities.push((host, c));
+            }
+            Err(e) => return Err(format!("error loading '{}': {e}", path.to_string_lossy())),
         }
-        Err(e) => {
-            eprintln!("{PKG_NAME} unable to create server https config: {e}");
-            std::process::exit(1);
+    }
+    Ok(identities)
+}
+
+fn client_cert_files() -> Result<Vec<(String, PathBuf)>, String> {
+    match setting(X_PROXY_TLS_CLIENT_CERTS) {
+        Err(_) => Ok(Vec::new()),
+        Ok(v) => parse_client_certs(&v)
+            .ok_or_else(|| format!("couldn't understand {X_PROXY_TLS_CLIENT_CERTS}")),
+    }
+}
+
+/* Issuers are matched by name and key, so the authority's certificate can be rebuilt from its key */
+fn authority_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(
+        DnType::CommonName,
+        format!("{PKG_NAME} certificate authority"),
+    );
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params
+}
+
+fn tls_path() -> Result<PathBuf, String> {
+    match setting(X_PROXY_TLS_PATH) {
+        Ok(p) => {
+            let path = PathBuf::from(&p);
+            match path.is_dir() {
+                true => Ok(path),
+                false => Err(format!(
+                    "X_PROXY_TLS_PATH ({p}) should be set to a directory"
+                )),
+            }
         }
-    };
+        Err(_) => setting(X_PROXY_CACHE_PATH)
+            .map(PathBuf::from)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/* The certificate is rebuilt from the key to sign with, the one saved is what clients trust */
+fn load_authority(path: &Path) -> Result<CertificateAuthority, String> {
+    let cert_path = path.join("ca.pem");
+    let key_path = path.join("ca.key");
+    let error = |path: &Path, e: String| format!("error loading '{}': {e}", path.to_string_lossy());
+
+    let key = std::fs::read_to_string(&key_path)
+        .map_err(|e| e.to_string())
+        .and_then(|pem| KeyPair::from_pem(&pem).map_err(|e| e.to_string()))
+        .map_err(|e| error(&key_path, e))?;
+    let cert = authority_params()
+        .self_signed(&key)
+        .map_err(|e| format!("unable to create a certificate authority: {e}"))?;
 
-    let config = Arc::new(config);
+    let pem = std::fs::read_to_string(&cert_path).map_err(|e| error(&cert_path, e.to_string()))?;
+    let der = CertificateDer::from_pem_slice(pem.as_bytes())
+        .map_err(|e| error(&cert_path, e.to_string()))?;
 
-    Arc::new(TlsAcceptor::from(config))
+    Ok(CertificateAuthority {
+        cert,
+        key,
+        pem,
+        der,
+    })
 }
 
-fn check_or_create_tls() -> (PathBuf, PathBuf) {
+fn check_or_create_tls() -> (CertificateAuthority, PathBuf) {
     #[cfg(unix)]
     fn set_read_only(path: &PathBuf) {
         match std::fs::metadata(path) {
@@ -189,7 +1133,7 @@ fn check_or_create_tls() -> (PathBuf, PathBuf) {
                 m.permissions().set_mode(0o400);
             }
             Err(e) => {
-                eprintln!("{e}");
+                error!("{e}");
                 std::process::exit(1);
             }
         }
@@ -200,105 +1144,651 @@ fn check_or_create_tls() -> (PathBuf, PathBuf) {
         todo!("Windows file permission nonsense")
     }
 
-    let path = match std::env::var(X_PROXY_TLS_PATH) {
-        Ok(p) => {
-            let path = PathBuf::from(&p);
-            if !path.is_dir() {
-                eprintln!(
-                    "{PKG_NAME} X_PROXY_TLS_PATH ({}) should be set to a directory",
-                    p
-                );
-                std::process::exit(1);
-            }
-            path
-        }
-        Err(_) => {
-            let p = match std::env::var(X_PROXY_CACHE_PATH) {
-                Ok(p) => p,
-                Err(e) => {
-                    eprintln!("{e}");
-                    std::process::exit(1);
-                }
-            };
-            PathBuf::from(p)
+    let path = match tls_path() {
+        Ok(p) => p,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
         }
     };
 
-    let cert_path = path.join("cert.pem");
-    let key_path = path.join("priv.key");
-
-    if cert_path.exists() && key_path.exists() {
-        eprintln!(
-            "{PKG_NAME} using existing key and certificate in '{}'",
-            path.to_str().unwrap()
-        );
-        return (cert_path, key_path);
-    }
-
-    let mut subject_alt_names = Vec::<String>::new();
+    let cert_path = path.join("ca.pem");
+    let key_path = path.join("ca.key");
 
-    subject_alt_names.push("*.local".to_string());
-
-    let interfaces = datalink::interfaces();
+    let (key, existing) = match std::fs::read_to_string(&key_path) {
+        Ok(pem) => match KeyPair::from_pem(&pem) {
+            Ok(k) => (k, true),
+            Err(e) => {
+                error!("error loading '{}': {e}", key_path.to_str().unwrap_or("?"));
+                std::process::exit(1);
+            }
+        },
+        Err(_) => match KeyPair::generate() {
+            Ok(k) => (k, false),
+            Err(e) => {
+                error!("unable to generate a key: {e}");
+                std::process::exit(1);
+            }
+        },
+    };
 
-    for interface in interfaces {
-        for ip in interface.ips {
-            if let IpAddr::V4(ipv4_addr) = ip.ip() {
-                if ipv4_addr.is_private() {
-                    subject_alt_names.push(ipv4_addr.to_string());
-                }
+    if !existing {
+        match std::fs::write(&key_path, key.serialize_pem()) {
+            Ok(_) => {
+                set_read_only(&key_path);
+            }
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
             }
         }
     }
 
-    let CertifiedKey { cert, key_pair } =
-        generate_simple_self_signed(subject_alt_names.to_vec()).unwrap();
+    if !existing || !cert_path.exists() {
+        let cert = match authority_params().self_signed(&key) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("unable to create a certificate authority: {e}");
+                std::process::exit(1);
+            }
+        };
 
-    match std::fs::write(&cert_path, cert.pem()) {
-        Ok(_) => {
-            set_read_only(&cert_path);
+        match std::fs::write(&cert_path, cert.pem()) {
+            Ok(_) => set_read_only(&cert_path),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
         }
+    }
+
+    let authority = match load_authority(&path) {
+        Ok(a) => a,
         Err(e) => {
-            eprintln!("{e}");
+            error!("{e}");
             std::process::exit(1);
         }
+    };
+
+    match existing {
+        true => info!(
+            "using existing certificate authority in '{}'",
+            path.to_str().unwrap()
+        ),
+        false => info!(
+            "generated a certificate authority in '{}'. \
+            Clients must trust it for intercepted hosts, \
+            it can be downloaded from the servers '/{}' path",
+            String::from(path.to_str().unwrap()),
+            CERT_QUERY
+        ),
+    }
+
+    /* Certificates minted by an authority that's gone are no use to anyone */
+    let leaves = path.join(LEAF_DIRECTORY);
+    if !existing {
+        let _ = std::fs::remove_dir_all(&leaves);
+    }
+    if let Err(e) = std::fs::create_dir_all(&leaves) {
+        error!("{e}");
+        std::process::exit(1);
+    }
+
+    (authority, leaves)
+}
+
+fn verifier() -> Result<Arc<dyn ServerCertVerifier>, String> {
+    #[cfg(debug_assertions)]
+    if setting("X_PROXY_CERT_GOSPEL").is_ok() {
+        return Ok(treat_certificates_as_gospel());
     }
 
-    match std::fs::write(&key_path, key_pair.serialize_pem()) {
-        Ok(_) => {
-            set_read_only(&key_path);
+    upstream_verifier()
+}
+
+fn load_material(authority: CertificateAuthority) -> Result<Material, String> {
+    setup_record_size()?;
+    let server_tls = TlsSettings::from_env(X_PROXY_TLS_MIN_VERSION, X_PROXY_TLS_CIPHERS, None)?;
+    let upstream_tls = TlsSettings::from_env(
+        X_PROXY_UPSTREAM_TLS_MIN_VERSION,
+        X_PROXY_UPSTREAM_TLS_CIPHERS,
+        Some((X_PROXY_TLS_MIN_VERSION, X_PROXY_TLS_CIPHERS)),
+    )?;
+
+    let verifier = verifier()?;
+    let client_config = connector(&upstream_tls, &verifier, None)
+        .map_err(|e| format!("unable to create client https config: {e}"))?;
+
+    Ok(Material {
+        client_identities: client_identities(&upstream_tls, &verifier)?,
+        client_config,
+        authority,
+        server_tls,
+    })
+}
+
+pub(crate) fn setup_certificates() -> CertificateSetup {
+    #[cfg(feature = "keylog")]
+    if let Err(e) = check_key_log() {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    #[cfg(not(feature = "keylog"))]
+    for variable in [SSLKEYLOGFILE, X_PROXY_UPSTREAM_KEY_LOG] {
+        if setting(variable).is_ok() {
+            warn!("'{variable}' is ignored, rproxy was built without the keylog feature");
         }
+    }
+
+    let (authority, leaves) = check_or_create_tls();
+
+    match load_material(authority) {
+        Ok(m) => CertificateSetup::new(m, Some(leaves)),
         Err(e) => {
-            eprintln!("{e}");
+            error!("{e}");
             std::process::exit(1);
         }
     }
+}
 
-    eprintln!(
-        "{PKG_NAME} generated key and self-signed certificate in '{}'. \
-        This certificate can be downloaded from the servers '/{}' path",
-        String::from(path.to_str().unwrap()),
-        CERT_QUERY
+/* The authority, client certificates and extra roots, anything else needs a restart */
+fn watched_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![path.join("ca.pem"), path.join("ca.key")];
+    files.extend(
+        client_cert_files()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, f)| f),
     );
 
-    (cert_path, key_path)
+    if let Ok(paths) = setting(X_PROXY_TLS_ROOTS) {
+        for path in paths.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            match std::fs::read_dir(path) {
+                Ok(entries) => files.extend(entries.filter_map(|e| e.ok().map(|e| e.path()))),
+                Err(_) => files.push(PathBuf::from(path)),
+            }
+        }
+    }
+
+    files.sort();
+    files
 }
 
-pub(crate) fn setup_certificates() -> CertificateSetup {
-    #[cfg(debug_assertions)]
-    let client_config = match std::env::var("X_PROXY_CERT_GOSPEL") {
-        Ok(_) => treat_certificates_as_gospel(),
-        Err(_) => load_system_certificates(),
+fn file_stamps(files: &[PathBuf]) -> Vec<(PathBuf, Option<(SystemTime, u64)>)> {
+    files
+        .iter()
+        .map(|f| {
+            let stamp = std::fs::metadata(f)
+                .ok()
+                .and_then(|m| Some((m.modified().ok()?, m.len())));
+            (f.clone(), stamp)
+        })
+        .collect()
+}
+
+/// Load certificates and keys again when their files change, such as when they're renewed.
+/// Connections already open carry on with the certificates they started with.
+/// When the new files can't be used the old ones are kept until they change again.
+pub(crate) async fn certificate_reload_loop(certificates: Arc<CertificateSetup>) {
+    let path = match tls_path() {
+        Ok(p) => p,
+        Err(_) => return,
     };
 
-    #[cfg(not(debug_assertions))]
-    let client_config = load_system_certificates();
-    let (server_cert_path, server_key_path) = check_or_create_tls();
-    let server_config = load_server_certificates(&server_cert_path, &server_key_path);
+    let mut stamps = file_stamps(&watched_files(&path));
+    loop {
+        sleep(RELOAD_INTERVAL).await;
 
-    CertificateSetup {
-        client_config,
-        server_config,
-        server_cert_path,
+        if file_stamps(&watched_files(&path)) == stamps {
+            continue;
+        }
+
+        /* A certificate and its key are written one after the other, give the second time to land */
+        sleep(Duration::from_secs(1)).await;
+        stamps = file_stamps(&watched_files(&path));
+
+        reload_certificates(&certificates);
+    }
+}
+
+/// Load certificates and keys again, keeping the old ones when the new files can't be used
+pub(crate) fn reload_certificates(certificates: &CertificateSetup) {
+    let path = match tls_path() {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+
+    match load_authority(&path).and_then(load_material) {
+        Ok(material) => {
+            certificates.replace(material);
+            info!("reloaded certificates");
+        }
+        Err(e) => warn!("kept the certificates it had, {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::convert::TryFrom, std::fs::File};
+
+    #[test]
+    fn test_server_config_for() {
+        let key = KeyPair::generate().unwrap();
+        let cert = authority_params().self_signed(&key).unwrap();
+        let (pem, der) = (cert.pem(), cert.der().clone());
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let setup = CertificateSetup::new(
+            Material {
+                client_config: Arc::new(TlsConnector::from(Arc::new(client_config))),
+                client_identities: Vec::new(),
+                authority: CertificateAuthority {
+                    cert,
+                    key,
+                    pem,
+                    der,
+                },
+                server_tls: TlsSettings::default(),
+            },
+            None,
+        );
+
+        let minted = setup.server_config_for("Example.com").unwrap();
+        let reused = setup.server_config_for("example.com").unwrap();
+        assert!(Arc::ptr_eq(&minted, &reused));
+
+        assert!(setup.server_config_for("[::1]").is_some());
+        assert_eq!(setup.minted.lock().unwrap().len(), 2);
+
+        /* Rotated once it's old enough */
+        setup
+            .minted
+            .lock()
+            .unwrap()
+            .get_mut("example.com")
+            .unwrap()
+            .minted -= LEAF_ROTATION_AGE;
+        let rotated = setup.server_config_for("example.com").unwrap();
+        assert!(!Arc::ptr_eq(&minted, &rotated));
+
+        let (pem, content_type) = setup.certificate_download("ca.crt").unwrap();
+        assert!(pem.starts_with(b"-----BEGIN CERTIFICATE-----"));
+        assert_eq!(content_type, "application/x-x509-ca-cert");
+        let (der, _) = setup.certificate_download("ca.der").unwrap();
+        assert_eq!(der, setup.material().authority.cert.der().to_vec());
+        assert_eq!(
+            setup.certificate_download("ca.pem").unwrap().0,
+            setup.material().authority.cert.pem().into_bytes()
+        );
+        assert!(setup.certificate_download("ca.key").is_none());
+    }
+
+    #[test]
+    fn test_saved_leaves() {
+        let leaves = std::env::temp_dir().join(format!("{PKG_NAME}-test-leaves"));
+        let _ = std::fs::remove_dir_all(&leaves);
+        std::fs::create_dir_all(&leaves).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = authority_params().self_signed(&key).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let setup = CertificateSetup::new(
+            Material {
+                client_config: Arc::new(TlsConnector::from(Arc::new(client_config))),
+                client_identities: Vec::new(),
+                authority: CertificateAuthority {
+                    pem: cert.pem(),
+                    der: cert.der().clone(),
+                    cert,
+                    key,
+                },
+                server_tls: TlsSettings::default(),
+            },
+            Some(leaves.clone()),
+        );
+
+        setup.server_config_for("saved.example").unwrap();
+        setup.server_config_for("[fd00::1]").unwrap();
+        assert!(leaves.join("fd00__1.pem").is_file());
+        assert!(setup.leaf_path("../escape").is_none());
+
+        /* Picked up again as if rproxy had restarted */
+        setup.minted.lock().unwrap().clear();
+        let material = setup.material();
+        assert!(setup.load_leaf(&material, "saved.example").is_some());
+
+        let saved = File::options()
+            .write(true)
+            .open(leaves.join("saved.example.pem"))
+            .unwrap();
+        saved
+            .set_modified(SystemTime::now() - LEAF_ROTATION_AGE)
+            .unwrap();
+        assert!(setup.load_leaf(&material, "saved.example").is_none());
+
+        /* The oldest make room on disk */
+        for n in 0..MAX_MINTED_CERTIFICATES {
+            File::create(leaves.join(format!("old-{n}.pem")))
+                .unwrap()
+                .set_modified(SystemTime::now() - LEAF_ROTATION_AGE / 2)
+                .unwrap();
+        }
+        setup.server_config_for("fresh.example").unwrap();
+        assert_eq!(
+            std::fs::read_dir(&leaves).unwrap().count(),
+            MAX_MINTED_CERTIFICATES
+        );
+        assert!(leaves.join("fresh.example.pem").is_file());
+        assert!(!leaves.join("saved.example.pem").exists());
+
+        std::fs::remove_dir_all(&leaves).unwrap();
+    }
+
+    #[test]
+    fn test_replace() {
+        let leaves = std::env::temp_dir().join(format!("{PKG_NAME}-test-replace"));
+        let _ = std::fs::remove_dir_all(&leaves);
+        std::fs::create_dir_all(&leaves).unwrap();
+
+        let material = || {
+            let key = KeyPair::generate().unwrap();
+            let cert = authority_params().self_signed(&key).unwrap();
+            let client_config = ClientConfig::builder()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth();
+            Material {
+                client_config: Arc::new(TlsConnector::from(Arc::new(client_config))),
+                client_identities: Vec::new(),
+                authority: CertificateAuthority {
+                    pem: cert.pem(),
+                    der: cert.der().clone(),
+                    cert,
+                    key,
+                },
+                server_tls: TlsSettings::default(),
+            }
+        };
+
+        let setup = CertificateSetup::new(material(), Some(leaves.clone()));
+        let minted = setup.server_config_for("example.com").unwrap();
+        assert!(leaves.join("example.com.pem").is_file());
+
+        /* New roots or client certificates leave minted certificates alone */
+        let first = setup.material();
+        let same_authority = Material {
+            authority: CertificateAuthority {
+                cert: authority_params()
+                    .self_signed(&first.authority.key)
+                    .unwrap(),
+                key: KeyPair::from_pem(&first.authority.key.serialize_pem()).unwrap(),
+                pem: first.authority.pem.clone(),
+                der: first.authority.der.clone(),
+            },
+            ..material()
+        };
+        setup.replace(same_authority);
+        assert!(!Arc::ptr_eq(&first, &setup.material()));
+        assert!(Arc::ptr_eq(
+            &minted,
+            &setup.server_config_for("example.com").unwrap()
+        ));
+
+        /* A new authority can't vouch for them */
+        setup.replace(material());
+        assert!(setup.minted.lock().unwrap().is_empty());
+        assert!(!leaves.join("example.com.pem").exists());
+        assert!(leaves.is_dir());
+        assert!(!Arc::ptr_eq(
+            &minted,
+            &setup.server_config_for("example.com").unwrap()
+        ));
+        assert_ne!(
+            setup.certificate_download("ca.der").unwrap().0,
+            first.authority.der.to_vec()
+        );
+
+        std::fs::remove_dir_all(&leaves).unwrap();
+    }
+
+    #[test]
+    fn test_parse_pins() {
+        let digest = "AB:".repeat(31) + "AB";
+        let pins = parse_pins(&format!(
+            "Lab.example={digest}, *.lab.example = {}",
+            "cd".repeat(32)
+        ))
+        .unwrap();
+        assert_eq!(
+            pins,
+            vec![
+                ("lab.example".to_string(), [0xab; 32]),
+                ("*.lab.example".to_string(), [0xcd; 32])
+            ]
+        );
+
+        assert_eq!(parse_pins(""), Some(Vec::new()));
+        assert!(parse_pins("lab.example").is_none());
+        assert!(parse_pins("lab.example=abcd").is_none());
+        assert!(parse_pins(&format!("lab.example={}", "zz".repeat(32))).is_none());
+    }
+
+    #[test]
+    fn test_policy_verifier() {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["lab.example".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let der = cert.der().clone();
+        let mut hasher = Sha256::default();
+        hasher.update(&der);
+        let digest = hasher.finish();
+
+        /* Any root will do, the certificate is self signed so it's never trusted by them */
+        let mut root_store = RootCertStore::empty();
+        let authority = authority_params()
+            .self_signed(&KeyPair::generate().unwrap())
+            .unwrap();
+        root_store.add(authority.der().clone()).unwrap();
+
+        let provider = Arc::new(default_provider());
+        let verifier = PolicyVerifier {
+            roots: WebPkiServerVerifier::builder_with_provider(
+                Arc::new(root_store),
+                Arc::clone(&provider),
+            )
+            .build()
+            .unwrap(),
+            policy: UpstreamPolicy {
+                pins: vec![
+                    ("lab.example".to_string(), digest),
+                    ("other.example".to_string(), [0; 32]),
+                ],
+                insecure: vec![
+                    "*.insecure.example".to_string(),
+                    "other.example".to_string(),
+                ],
+            },
+            algorithms: provider.signature_verification_algorithms,
+        };
+
+        let verify = |host: &'static str| {
+            verifier
+                .verify_server_cert(
+                    &der,
+                    &[],
+                    &ServerName::try_from(host).unwrap(),
+                    &[],
+                    UnixTime::now(),
+                )
+                .is_ok()
+        };
+
+        assert!(verify("lab.example"));
+        assert!(verify("box.insecure.example"));
+        /* A pin wins over being listed as insecure */
+        assert!(!verify("other.example"));
+        assert!(!verify("untrusted.example"));
+    }
+
+    #[test]
+    fn test_tls_settings() {
+        let names = |t: &TlsSettings| -> Vec<String> {
+            t.provider
+                .cipher_suites
+                .iter()
+                .map(|s| format!("{:?}", s.suite()))
+                .collect()
+        };
+
+        let all = TlsSettings::parse(None, None).unwrap();
+        assert_eq!(all.versions.len(), 2);
+        assert_eq!(names(&all).len(), default_provider().cipher_suites.len());
+
+        let strict = TlsSettings::parse(Some("TLSv1.3"), None).unwrap();
+        assert_eq!(strict.versions, vec![&TLS13]);
+        assert!(strict
+            .provider
+            .cipher_suites
+            .iter()
+            .all(|s| s.version() == &TLS13));
+
+        /* Kept in the order given */
+        let chosen = TlsSettings::parse(
+            Some("1.2"),
+            Some("tls13_chacha20_poly1305_sha256, TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384"),
+        )
+        .unwrap();
+        assert_eq!(
+            names(&chosen),
+            vec![
+                "TLS13_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384"
+            ]
+        );
+
+        assert!(TlsSettings::parse(Some("1.1"), None).is_err());
+        assert!(TlsSettings::parse(None, Some("TLS_RSA_WITH_RC4_128_MD5")).is_err());
+        assert!(
+            TlsSettings::parse(Some("1.3"), Some("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256")).is_err()
+        );
+    }
+
+    #[cfg(feature = "keylog")]
+    #[test]
+    fn test_upstream_key_log() {
+        let path = std::env::temp_dir().join(format!("{PKG_NAME}-test-upstream-keys.log"));
+        let _ = std::fs::remove_file(&path);
+
+        let log = UpstreamKeyLog(Mutex::new(
+            open_key_log(X_PROXY_UPSTREAM_KEY_LOG, &path).unwrap(),
+        ));
+        log.log("CLIENT_RANDOM", &[0x01, 0xab], &[0xff]);
+        log.log("SERVER_TRAFFIC_SECRET_0", &[0x02], &[0x00, 0x10]);
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "CLIENT_RANDOM 01ab ff\nSERVER_TRAFFIC_SECRET_0 02 0010\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_record_size() {
+        assert_eq!(parse_record_size("4K"), Some(4096));
+        assert_eq!(parse_record_size("16384"), Some(MAX_RECORD_SIZE));
+        assert_eq!(parse_record_size("512"), Some(MIN_RECORD_SIZE));
+        assert_eq!(parse_record_size("511"), None);
+        assert_eq!(parse_record_size("32K"), None);
+        assert_eq!(parse_record_size("big"), None);
+    }
+
+    #[test]
+    fn test_root_source() {
+        assert_eq!(RootSource::from_name(" System"), Some(RootSource::System));
+        assert_eq!(RootSource::from_name("BUNDLED"), Some(RootSource::Bundled));
+        assert_eq!(RootSource::from_name("both"), Some(RootSource::Both));
+        assert_eq!(RootSource::from_name("webpki"), None);
+
+        let mut root_store = RootCertStore::empty();
+        let added = add_bundled_roots(&mut root_store);
+        assert_eq!(added, root_store.len());
+        assert_eq!(added > 0, cfg!(feature = "bundled-roots"));
+    }
+
+    #[test]
+    fn test_parse_client_certs() {
+        assert_eq!(
+            parse_client_certs("Registry.example=/etc/rproxy/registry.pem, *.lab = lab.pem"),
+            Some(vec![
+                (
+                    "registry.example".to_string(),
+                    PathBuf::from("/etc/rproxy/registry.pem")
+                ),
+                ("*.lab".to_string(), PathBuf::from("lab.pem")),
+            ])
+        );
+        assert_eq!(parse_client_certs(""), Some(Vec::new()));
+        assert!(parse_client_certs("registry.example").is_none());
+        assert!(parse_client_certs("registry.example=").is_none());
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["client.example".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let pem = cert.pem() + &key.serialize_pem();
+
+        let (chain, _) = load_client_identity(pem.as_bytes()).unwrap();
+        assert_eq!(chain, vec![cert.der().clone()]);
+        assert!(load_client_identity(cert.pem().as_bytes()).is_err());
+        assert!(load_client_identity(key.serialize_pem().as_bytes()).is_err());
+
+        let authority_key = KeyPair::generate().unwrap();
+        let authority = authority_params().self_signed(&authority_key).unwrap();
+        let mut root_store = RootCertStore::empty();
+        root_store.add(authority.der().clone()).unwrap();
+        let verifier: Arc<dyn ServerCertVerifier> = WebPkiServerVerifier::builder_with_provider(
+            Arc::new(root_store),
+            Arc::new(default_provider()),
+        )
+        .build()
+        .unwrap();
+
+        let identity = connector(
+            &TlsSettings::default(),
+            &verifier,
+            load_client_identity(pem.as_bytes()).ok(),
+        )
+        .unwrap();
+        let setup = CertificateSetup::new(
+            Material {
+                client_config: connector(&TlsSettings::default(), &verifier, None).unwrap(),
+                client_identities: vec![("*.registry.example".to_string(), Arc::clone(&identity))],
+                authority: CertificateAuthority {
+                    pem: authority.pem(),
+                    der: authority.der().clone(),
+                    cert: authority,
+                    key: authority_key,
+                },
+                server_tls: TlsSettings::default(),
+            },
+            None,
+        );
+
+        assert!(Arc::ptr_eq(
+            &setup.connector_for("Private.Registry.example"),
+            &identity
+        ));
+        assert!(Arc::ptr_eq(
+            &setup.connector_for("public.example"),
+            &setup.material().client_config
+        ));
     }
 }
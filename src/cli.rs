@@ -0,0 +1,463 @@
+use {
+    crate::{
+        http::X_PROXY_CACHE_PATH,
+        log::{parse_filter, parse_format, X_PROXY_LOG_FORMAT, X_PROXY_LOG_LEVEL},
+        PKG_NAME, PKG_VERSION, X_PROXY_HTTP_LISTEN_ADDRESS,
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        env::VarError,
+        sync::{OnceLock, RwLock},
+    },
+    tracing::{error, info, warn},
+};
+
+/// What `rproxy --help` prints
+const USAGE: &str = "\
+Usage: rproxy [OPTIONS] [COMMAND]
+
+Options:
+  -l, --listen <ADDRESS>    Address and port to listen on, as X_PROXY_HTTP_LISTEN_ADDRESS,
+                            given more than once to listen on each
+  -d, --cache-dir <PATH>    Where cached files are kept, as X_PROXY_CACHE_PATH
+  -c, --config <FILE>       Read X_PROXY_* settings from a file of NAME=value lines
+      --log-level <LEVEL>   How much is logged, such as 'debug' or 'info,fetch=debug',
+                            as X_PROXY_LOG_LEVEL
+      --log-format <FORMAT> 'pretty' or 'json', as X_PROXY_LOG_FORMAT
+      --features            Print what this build can do
+  -V, --version             Print the version
+  -h, --help                Print this help
+
+Commands:
+  cache ls|du|purge [PATTERN]...            List, measure or remove cached files
+  migrate [flat|sharded]                    Move cached files to another layout
+  selftest <PROXY> [URL] [REDIRECTING-URL]  Check a running proxy works
+
+Options override the environment, which overrides the configuration file.
+Without a command rproxy serves clients until it's stopped.
+";
+
+/// What rproxy was asked to do
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Serve,
+    Help,
+    Version,
+    Features,
+    Cache(Vec<String>),
+    Migrate(Vec<String>),
+    Selftest(Vec<String>),
+}
+
+/// The command line, read by [`parse_arguments`]
+#[derive(Debug, PartialEq)]
+pub(crate) struct Arguments {
+    /// Environment variables given by options such as `--listen`
+    settings: Vec<(&'static str, String)>,
+    /// The file named by `--config`
+    config: Option<String>,
+    pub(crate) command: Command,
+}
+
+/* `--name value` or `--name=value`, the value taken from `rest` in the first case */
+fn option_value<'a>(
+    name: &str,
+    inline: Option<&'a str>,
+    rest: &mut impl Iterator<Item = &'a String>,
+) -> Result<String, String> {
+    match inline.map(str::to_string).or_else(|| rest.next().cloned()) {
+        Some(v) if !v.is_empty() => Ok(v),
+        _ => Err(format!("'{name}' needs a value")),
+    }
+}
+
+/* Options other than `--listen` set one thing, so giving them twice is most likely a mistake */
+fn set_once(
+    settings: &mut Vec<(&'static str, String)>,
+    name: &str,
+    variable: &'static str,
+    value: String,
+) -> Result<(), String> {
+    if settings.iter().any(|(n, _)| *n == variable) {
+        return Err(format!("'{name}' was given more than once"));
+    }
+    settings.push((variable, value));
+    Ok(())
+}
+
+/// Read the options in front of a command, the command taking the arguments after it
+pub(crate) fn parse_arguments(args: &[String]) -> Result<Arguments, String> {
+    let mut arguments = Arguments {
+        settings: Vec::new(),
+        config: None,
+        command: Command::Serve,
+    };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((n, v)) if n.starts_with("--") => (n, Some(v)),
+            _ => (arg.as_str(), None),
+        };
+
+        match name {
+            "-l" | "--listen" => {
+                let value = option_value(name, inline, &mut args)?;
+                /* Given more than once it listens on each */
+                match arguments
+                    .settings
+                    .iter_mut()
+                    .find(|(n, _)| *n == X_PROXY_HTTP_LISTEN_ADDRESS)
+                {
+                    Some((_, listen)) => *listen = format!("{listen},{value}"),
+                    None => arguments
+                        .settings
+                        .push((X_PROXY_HTTP_LISTEN_ADDRESS, value)),
+                }
+            }
+            "-d" | "--cache-dir" => {
+                let value = option_value(name, inline, &mut args)?;
+                set_once(&mut arguments.settings, name, X_PROXY_CACHE_PATH, value)?;
+            }
+            "-c" | "--config" => {
+                let value = option_value(name, inline, &mut args)?;
+                if arguments.config.replace(value).is_some() {
+                    return Err(format!("'{name}' was given more than once"));
+                }
+            }
+            "--log-level" => {
+                let value = option_value(name, inline, &mut args)?;
+                parse_filter(&value).map_err(|e| format!("'--log-level' {e}"))?;
+                set_once(&mut arguments.settings, name, X_PROXY_LOG_LEVEL, value)?;
+            }
+            "--log-format" => {
+                let value = option_value(name, inline, &mut args)?;
+                parse_format(&value)
+                    .map_err(|_| format!("'--log-format' is 'pretty' or 'json', not '{value}'"))?;
+                set_once(&mut arguments.settings, name, X_PROXY_LOG_FORMAT, value)?;
+            }
+            "-h" | "--help" | "-V" | "--version" | "--features" if inline.is_some() => {
+                return Err(format!("'{name}' doesn't take a value"))
+            }
+            "-h" | "--help" => arguments.command = Command::Help,
+            "-V" | "--version" => arguments.command = Command::Version,
+            "--features" => arguments.command = Command::Features,
+            _ if name.starts_with('-') => return Err(format!("unknown option '{arg}'")),
+            command => {
+                let rest: Vec<String> = args.cloned().collect();
+                arguments.command = match command {
+                    "cache" => Command::Cache(rest),
+                    "migrate" => Command::Migrate(rest),
+                    "selftest" => Command::Selftest(rest),
+                    _ => return Err(format!("unknown command '{command}'")),
+                };
+                break;
+            }
+        }
+    }
+    Ok(arguments)
+}
+
+/// The `NAME=value` lines of a configuration file, written like the examples in the README.
+/// Blank lines and lines starting with `#` are skipped and a leading `export` is allowed,
+/// so a file sourced by a shell or read by systemd's `EnvironmentFile` can be used as is.
+pub(crate) fn parse_config(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut settings = Vec::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+
+        let (name, value) = match line.split_once('=') {
+            Some((n, v)) if n.trim().starts_with("X_PROXY_") => (n.trim(), v.trim()),
+            _ => {
+                return Err(format!(
+                    "line {} isn't an X_PROXY_ setting: '{line}'",
+                    number + 1
+                ))
+            }
+        };
+        let value = match value.as_bytes() {
+            [q, .., e] if (*q == b'"' || *q == b'\'') && q == e => &value[1..value.len() - 1],
+            _ => value,
+        };
+        settings.push((name.to_string(), value.to_string()));
+    }
+    Ok(settings)
+}
+
+/// The file named by `--config`, read again when the configuration is reloaded
+static CONFIG: OnceLock<String> = OnceLock::new();
+
+/* Variables the file can't change, given by the environment or an option when rproxy started */
+static FIXED: OnceLock<HashSet<String>> = OnceLock::new();
+
+/* Given by options, which override both the environment and the file */
+static OPTIONS: OnceLock<Vec<(&'static str, String)>> = OnceLock::new();
+
+/* Where the cache is and what's listened on are only read when rproxy starts */
+const RESTART_ONLY: [&str; 2] = [X_PROXY_CACHE_PATH, X_PROXY_HTTP_LISTEN_ADDRESS];
+
+/* What the options and the file set. The environment is never changed once rproxy is running
+ * since other threads read it, a reload replaces this instead */
+fn settings_table() -> &'static RwLock<HashMap<String, String>> {
+    static SETTINGS: OnceLock<RwLock<HashMap<String, String>>> = OnceLock::new();
+    SETTINGS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// The value of the `X_PROXY_*` setting `name` as given by an option, the environment or the
+/// configuration file, in that order. Read like `std::env::var` but changed by a reload.
+pub(crate) fn setting(name: &str) -> Result<String, VarError> {
+    let given = match settings_table().read() {
+        Ok(s) => s.get(name).cloned(),
+        Err(e) => e.into_inner().get(name).cloned(),
+    };
+
+    match given {
+        Some(v) => Ok(v),
+        None => std::env::var(name),
+    }
+}
+
+fn read_config(path: &str) -> Result<Vec<(String, String)>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(c) => parse_config(&c),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/* Replace what the file gave with `settings`, anything it gave before and doesn't anymore is gone */
+fn apply_config(settings: Vec<(String, String)>) {
+    let fixed = FIXED.get_or_init(HashSet::new);
+    let mut table: HashMap<String, String> = settings
+        .into_iter()
+        .filter(|(n, _)| !fixed.contains(n))
+        .collect();
+    for (name, value) in OPTIONS.get().into_iter().flatten() {
+        table.insert(name.to_string(), value.clone());
+    }
+
+    match settings_table().write() {
+        Ok(mut s) => *s = table,
+        Err(e) => *e.into_inner() = table,
+    }
+}
+
+/// Take the settings given by the configuration file and options,
+/// false when the configuration file can't be used
+pub(crate) fn apply_arguments(arguments: &Arguments) -> bool {
+    let mut fixed: HashSet<String> = std::env::vars_os()
+        .filter_map(|(n, _)| n.into_string().ok())
+        .collect();
+    fixed.extend(arguments.settings.iter().map(|(n, _)| n.to_string()));
+    let _ = FIXED.set(fixed);
+    let _ = OPTIONS.set(arguments.settings.clone());
+
+    let settings = match &arguments.config {
+        None => Vec::new(),
+        Some(path) => match read_config(path) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("unable to read configuration file '{path}': {e}");
+                return false;
+            }
+        },
+    };
+    apply_config(settings);
+
+    if let Some(path) = &arguments.config {
+        info!("configuration file: {path}");
+        let _ = CONFIG.set(path.clone());
+    }
+    true
+}
+
+/// Read the configuration file again, when there is one. When it can't be read
+/// the variables it gave before are left as they were.
+pub(crate) fn reload_config() -> Result<(), String> {
+    let path = match CONFIG.get() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let mut settings = read_config(path).map_err(|e| format!("unable to read '{path}': {e}"))?;
+
+    let fixed = FIXED.get_or_init(HashSet::new);
+    for name in RESTART_ONLY {
+        let current = setting(name).ok();
+        let given = settings
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone());
+        if !fixed.contains(name) && given != current {
+            warn!("keeps '{name}' as it was until it's restarted");
+        }
+
+        settings.retain(|(n, _)| n != name);
+        if let Some(c) = current {
+            settings.push((name.to_string(), c));
+        }
+    }
+
+    apply_config(settings);
+    Ok(())
+}
+
+/// Print the help or version, for `--help` and `--version`
+pub(crate) fn print_usage(command: &Command) {
+    match command {
+        Command::Version => println!("{PKG_NAME} {PKG_VERSION}"),
+        _ => print!("{USAGE}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Arguments, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_arguments(&args)
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        let arguments = parse(&[
+            "--listen",
+            "127.0.0.1:8080",
+            "--cache-dir=/var/cache/rproxy",
+            "-c",
+            "/etc/rproxy.conf",
+            "--log-level",
+            "DEBUG",
+            "--log-format=json",
+        ])
+        .unwrap();
+        assert_eq!(
+            arguments.settings,
+            vec![
+                (X_PROXY_HTTP_LISTEN_ADDRESS, "127.0.0.1:8080".to_string()),
+                (X_PROXY_CACHE_PATH, "/var/cache/rproxy".to_string()),
+                (X_PROXY_LOG_LEVEL, "DEBUG".to_string()),
+                (X_PROXY_LOG_FORMAT, "json".to_string()),
+            ]
+        );
+        assert_eq!(arguments.config, Some("/etc/rproxy.conf".to_string()));
+        assert_eq!(arguments.command, Command::Serve);
+
+        assert_eq!(parse(&[]).unwrap().command, Command::Serve);
+        assert_eq!(parse(&["-h"]).unwrap().command, Command::Help);
+        assert_eq!(parse(&["--features"]).unwrap().command, Command::Features);
+        assert!(parse(&["--listen"]).is_err());
+        assert!(parse(&["--log-level", "loud"]).is_err());
+        assert!(parse(&["--log-level", "info,fetch=loud"]).is_err());
+        assert!(parse(&["--log-format", "xml"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["bogus"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_arguments_unknown() {
+        for unknown in [
+            &["-x"][..],
+            &["--Listen", "[::1]:3142"],
+            &["--cache"],
+            &["-"],
+        ] {
+            assert_eq!(
+                parse(unknown),
+                Err(format!("unknown option '{}'", unknown[0]))
+            );
+        }
+        assert_eq!(
+            parse(&["--features=yes"]),
+            Err("'--features' doesn't take a value".to_string())
+        );
+        assert!(parse(&["-l", "[::1]:3142", "serve"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_arguments_twice() {
+        assert_eq!(
+            parse(&["-d", "/tmp/a", "--cache-dir=/tmp/b"]),
+            Err("'--cache-dir' was given more than once".to_string())
+        );
+        assert_eq!(
+            parse(&["-c", "/etc/a.conf", "-c", "/etc/b.conf"]),
+            Err("'-c' was given more than once".to_string())
+        );
+        assert!(parse(&["--log-level", "info", "--log-level", "debug"]).is_err());
+        assert!(parse(&["--log-format", "json", "--log-format=pretty"]).is_err());
+
+        /* Except what can be given more than once */
+        assert!(parse(&["-V", "--version"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_arguments_command() {
+        let arguments = parse(&["-d", "/tmp/rproxy", "cache", "ls", "--listen"]).unwrap();
+        assert_eq!(
+            arguments.command,
+            Command::Cache(vec!["ls".to_string(), "--listen".to_string()])
+        );
+        assert_eq!(arguments.settings.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_arguments_listen() {
+        let arguments = parse(&["-l", "127.0.0.1:3142", "--listen=[::1]:3142"]).unwrap();
+        assert_eq!(
+            arguments.settings,
+            vec![(
+                X_PROXY_HTTP_LISTEN_ADDRESS,
+                "127.0.0.1:3142,[::1]:3142".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let settings = parse_config(
+            "# rproxy\n\
+             X_PROXY_CACHE_PATH=/var/cache/rproxy\n\
+             \n\
+             export X_PROXY_HTTP_LISTEN_ADDRESS=\"[::1]:8080\"\n\
+             X_PROXY_WIRE_LOG_REDACT = 'X-Token'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                (
+                    "X_PROXY_CACHE_PATH".to_string(),
+                    "/var/cache/rproxy".to_string()
+                ),
+                (
+                    "X_PROXY_HTTP_LISTEN_ADDRESS".to_string(),
+                    "[::1]:8080".to_string()
+                ),
+                ("X_PROXY_WIRE_LOG_REDACT".to_string(), "X-Token".to_string()),
+            ]
+        );
+
+        assert!(parse_config("PATH=/bin").is_err());
+        assert!(parse_config("X_PROXY_CACHE_PATH").is_err());
+    }
+
+    #[test]
+    fn test_apply_config() {
+        const NAME: &str = "X_PROXY_TEST_APPLY_CONFIG";
+        assert!(setting(NAME).is_err());
+
+        apply_config(vec![(NAME.to_string(), "1".to_string())]);
+        assert_eq!(setting(NAME), Ok("1".to_string()));
+        assert!(std::env::var(NAME).is_err());
+
+        /* Gone once the file doesn't give it, the environment is left alone */
+        apply_config(Vec::new());
+        assert!(setting(NAME).is_err());
+        assert_eq!(setting("PATH").ok(), std::env::var("PATH").ok());
+    }
+}
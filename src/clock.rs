@@ -0,0 +1,74 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+use std::cell::Cell;
+
+/* How far each test thread has moved its clock ahead of the real one */
+#[cfg(test)]
+thread_local! {
+    static OFFSET: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// The current time of day. Freshness, expiry and anything else that compares against
+/// the time of day asks here rather than `SystemTime::now()` so tests can move it forward.
+#[cfg(not(test))]
+pub(crate) fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(test)]
+pub(crate) fn now() -> SystemTime {
+    SystemTime::now() + OFFSET.with(Cell::get)
+}
+
+/// How long ago `earlier` was, nothing if it's in the future
+pub(crate) fn age(earlier: SystemTime) -> Option<Duration> {
+    now().duration_since(earlier).ok()
+}
+
+/// The UTC year, month and day of a time
+pub(crate) fn civil(time: SystemTime) -> (i32, u8, u8) {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or_default() as i64;
+
+    /* Howard Hinnant's days_from_civil inverse */
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year as i32, month as u8, day as u8)
+}
+
+/// Move both clocks forward by `duration` without waiting for it. The time of day moves
+/// for this thread and, in a test started with `start_paused = true`, so do Tokio's timers,
+/// so sleeps and lifetimes measured with `tokio::time::Instant` expire as if the time had passed.
+#[cfg(test)]
+pub(crate) async fn advance(duration: Duration) {
+    OFFSET.with(|o| o.set(o.get() + duration));
+    tokio::time::advance(duration).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_advance() {
+        let instant = tokio::time::Instant::now();
+        let before = now();
+
+        advance(Duration::from_secs(3600)).await;
+
+        assert!(age(before).unwrap() >= Duration::from_secs(3600));
+        assert_eq!(instant.elapsed(), Duration::from_secs(3600));
+        assert_eq!(age(now() + Duration::from_secs(60)), None);
+    }
+}
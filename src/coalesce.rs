@@ -0,0 +1,156 @@
+use {
+    crate::zerocopy::ZeroCopy,
+    std::{
+        io,
+        pin::Pin,
+        task::{ready, Context, Poll},
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+    },
+};
+
+/// A stream that holds writes until there's a record's worth or it's flushed, so a status line,
+/// headers and the first bytes of a body go out in one TLS record rather than one each.
+/// Whatever writes to it must flush before waiting on anything else.
+pub(crate) struct Coalesce<S> {
+    inner: S,
+    buffer: Vec<u8>,
+    capacity: usize,
+}
+
+impl<S> Coalesce<S> {
+    pub(crate) fn new(inner: S, capacity: usize) -> Self {
+        Coalesce {
+            inner,
+            buffer: Vec::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Coalesce<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buffer.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buffer))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => {
+                    self.buffer.drain(..n);
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Coalesce<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Coalesce<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buffer.len() >= this.capacity {
+            ready!(this.poll_drain(cx))?;
+        }
+
+        /* Nothing held and a record or more to write, so there's nothing to gain from copying it */
+        if this.buffer.is_empty() && buf.len() >= this.capacity {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        let taken = buf.len().min(this.capacity - this.buffer.len());
+        this.buffer.extend_from_slice(&buf[..taken]);
+        if this.buffer.len() >= this.capacity {
+            /* Sent now if it can be, otherwise before anything else is taken */
+            if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(taken))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> ZeroCopy for Coalesce<S> {
+    async fn send_file(&mut self, _: &File, _: u64, _: u64) -> Option<io::Result<u64>> {
+        None /* Anything held would be overtaken by the file */
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, tokio::io::AsyncWriteExt};
+
+    /// Keeps every write it's given apart, the way a TLS stream makes a record of each
+    #[derive(Default)]
+    struct Records(Vec<Vec<u8>>);
+
+    impl AsyncWrite for Records {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let mut stream = Coalesce::new(Records::default(), 8);
+
+        /* Held until flushed */
+        stream.write_all(b"HTTP").await.unwrap();
+        stream.write_all(b"/1").await.unwrap();
+        assert!(stream.inner.0.is_empty());
+        stream.flush().await.unwrap();
+        assert_eq!(stream.inner.0, vec![b"HTTP/1".to_vec()]);
+
+        /* A header topped up with the body fills a record before the rest */
+        stream.inner.0.clear();
+        stream.write_all(b"200\r\n").await.unwrap();
+        stream.write_all(b"0123456789").await.unwrap();
+        stream.flush().await.unwrap();
+        assert_eq!(
+            stream.inner.0,
+            vec![b"200\r\n012".to_vec(), b"3456789".to_vec()]
+        );
+
+        /* Nothing held, so a whole record goes straight through */
+        stream.inner.0.clear();
+        stream.write_all(b"abcdefghij").await.unwrap();
+        assert_eq!(stream.inner.0, vec![b"abcdefghij".to_vec()]);
+    }
+}
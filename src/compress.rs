@@ -0,0 +1,297 @@
+use {
+    crate::{
+        cli::setting,
+        http::{http_chunk, HttpRequestHeader, HttpVersion, BUFFER_SIZE, END_OF_HTTP_HEADER},
+        sniff::looks_like_text,
+    },
+    async_compression::{
+        tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+        Level,
+    },
+    std::{io::SeekFrom, pin::Pin, sync::OnceLock},
+    tokio::{
+        fs::File,
+        io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
+    },
+    tracing::{error, info},
+};
+
+pub const X_PROXY_COMPRESS: &str = "X_PROXY_COMPRESS";
+
+/// Files smaller than this gain too little from compression to be worth it
+const MIN_COMPRESS_LENGTH: u64 = 1024;
+
+/// How hard brotli works, its default is too slow to run on every cache hit
+const BROTLI_QUALITY: i32 = 5;
+
+/// A `Content-Encoding` rproxy can compress cache hits with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" => Some(Encoding::Gzip),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+static ENCODINGS: OnceLock<Vec<Encoding>> = OnceLock::new();
+
+fn compress_encodings() -> &'static [Encoding] {
+    ENCODINGS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Read `X_PROXY_COMPRESS`, the encodings cache hits may be compressed with in order of
+/// preference. Nothing is compressed when it isn't defined. False when an encoding isn't known.
+pub(crate) fn setup_compress() -> bool {
+    let value = match setting(X_PROXY_COMPRESS) {
+        Ok(v) => v,
+        Err(_) => return true,
+    };
+
+    let mut encodings = Vec::new();
+    for name in value.split(',').filter(|n| !n.trim().is_empty()) {
+        match Encoding::from_name(name) {
+            Some(e) if !encodings.contains(&e) => encodings.push(e),
+            Some(_) => {}
+            None => {
+                error!(
+                    "'{X_PROXY_COMPRESS}' may only list 'zstd', 'br' and 'gzip': '{}'",
+                    name.trim()
+                );
+                return false;
+            }
+        }
+    }
+
+    if !encodings.is_empty() {
+        info!(
+            "compressing cache hits with {}",
+            encodings
+                .iter()
+                .map(Encoding::name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    let _ = ENCODINGS.set(encodings);
+    true
+}
+
+/// Whether compressing is switched on, so the start of a file is needed to decide
+pub(crate) fn compressing() -> bool {
+    !compress_encodings().is_empty()
+}
+
+/* How much the client wants `coding` from its `Accept-Encoding`, RFC 9110 section 12.5.3.
+ * A coding it doesn't name is only acceptable through `*`. */
+fn quality(accept: &str, coding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for element in accept.split(',') {
+        let mut parameters = element.split(';');
+        let name = parameters.next().unwrap_or_default().trim();
+        let q = parameters
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+/// The encoding of `offered` the client wants most, the earliest winning a tie
+fn negotiate(accept: &str, offered: &[Encoding]) -> Option<Encoding> {
+    let mut best: Option<(f32, Encoding)> = None;
+    for encoding in offered {
+        let q = quality(accept, encoding.name());
+        if q > 0.0 && best.is_none_or(|(b, _)| q > b) {
+            best = Some((q, *encoding));
+        }
+    }
+    best.map(|(_, e)| e)
+}
+
+/// Whether a cached file of `length` bytes starting with `head` is sent compressed to clients
+/// that ask for it, so its responses vary by `Accept-Encoding`, and the encoding for this client.
+/// Files that are already compressed or binary don't start with text and are sent as they are.
+pub(crate) fn encoding_for(
+    client_request_header: &HttpRequestHeader<'_>,
+    length: u64,
+    head: &[u8],
+) -> (bool, Option<Encoding>) {
+    if !compressing() || length < MIN_COMPRESS_LENGTH || !looks_like_text(head) {
+        return (false, None);
+    }
+
+    /* A chunked body is the only way to send a length that isn't known yet.
+     * Ranges are of the file as it's stored. */
+    let headers = &client_request_header.headers;
+    if client_request_header.version != HttpVersion::HTTP_V11 || headers.contains_key("Range") {
+        return (true, None);
+    }
+
+    let encoding = headers
+        .get_joined("Accept-Encoding")
+        .and_then(|a| negotiate(&a, compress_encodings()));
+    (true, encoding)
+}
+
+/// `tag` made different for each encoding, so a client never mistakes one for another
+pub(crate) fn encoded_tag(tag: String, encoding: Option<Encoding>) -> String {
+    match (encoding, tag.strip_suffix('"')) {
+        (Some(e), Some(t)) => format!("{t}-{}\"", e.name()),
+        _ => tag,
+    }
+}
+
+/// Write all of `file` to `stream` as a chunked body compressed with `encoding`
+pub(crate) async fn send_compressed<T>(stream: &mut T, file: &mut File, encoding: Encoding) -> bool
+where
+    T: AsyncWrite + Unpin,
+{
+    if file.seek(SeekFrom::Start(0)).await.is_err() {
+        return false;
+    }
+
+    let reader = BufReader::new(file);
+    let mut encoder: Pin<Box<dyn AsyncRead + Send + '_>> = match encoding {
+        Encoding::Brotli => Box::pin(BrotliEncoder::with_quality(
+            reader,
+            Level::Precise(BROTLI_QUALITY),
+        )),
+        Encoding::Gzip => Box::pin(GzipEncoder::new(reader)),
+        Encoding::Zstd => Box::pin(ZstdEncoder::new(reader)),
+    };
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        match encoder.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(n) => {
+                if stream.write_all(&http_chunk(&buffer[..n])).await.is_err() {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+    }
+
+    let end_chunk = format!("0{END_OF_HTTP_HEADER}");
+    stream.write_all(end_chunk.as_bytes()).await.is_ok() && stream.flush().await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::PKG_NAME,
+        async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZstdDecoder},
+    };
+
+    #[test]
+    fn test_encoding_from_name() {
+        assert_eq!(Encoding::from_name(" BR"), Some(Encoding::Brotli));
+        assert_eq!(Encoding::from_name("gzip"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::from_name("zstd"), Some(Encoding::Zstd));
+        assert_eq!(Encoding::from_name("deflate"), None);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offered = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+        assert_eq!(negotiate("gzip, deflate", &offered), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip, br, zstd", &offered), Some(Encoding::Zstd));
+        assert_eq!(
+            negotiate("zstd;q=0.5, br;q=0.8, gzip", &offered),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("*", &offered), Some(Encoding::Zstd));
+        assert_eq!(
+            negotiate("*;q=0.5, zstd;q=0, br;q=0", &offered),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate("identity", &offered), None);
+        assert_eq!(negotiate("", &offered), None);
+        assert_eq!(negotiate("gzip", &[Encoding::Brotli]), None);
+    }
+
+    #[test]
+    fn test_encoded_tag() {
+        assert_eq!(
+            encoded_tag("W/\"10-20\"".to_string(), Some(Encoding::Brotli)),
+            "W/\"10-20-br\""
+        );
+        assert_eq!(encoded_tag("W/\"10-20\"".to_string(), None), "W/\"10-20\"");
+    }
+
+    async fn decoded(encoding: Encoding, body: &[u8]) -> Vec<u8> {
+        let path =
+            std::env::temp_dir().join(format!("{PKG_NAME}-test-compress-{}", encoding.name()));
+        tokio::fs::write(&path, body).await.unwrap();
+        let mut file = File::open(&path).await.unwrap();
+        /* Where the file was left doesn't matter, it's always sent from the start */
+        file.seek(SeekFrom::Start(5)).await.unwrap();
+
+        let mut chunked = Vec::new();
+        assert!(send_compressed(&mut chunked, &mut file, encoding).await);
+        let _ = tokio::fs::remove_file(&path).await;
+
+        /* Take the chunks apart again */
+        let mut compressed = Vec::new();
+        let mut rest = &chunked[..];
+        loop {
+            let line_end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size =
+                usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).unwrap(), 16).unwrap();
+            rest = &rest[line_end + 2..];
+            if size == 0 {
+                assert_eq!(rest, b"\r\n");
+                break;
+            }
+            compressed.extend_from_slice(&rest[..size]);
+            rest = &rest[size + 2..];
+        }
+
+        let mut decoded = Vec::new();
+        let reader = BufReader::new(&compressed[..]);
+        match encoding {
+            Encoding::Brotli => BrotliDecoder::new(reader).read_to_end(&mut decoded).await,
+            Encoding::Gzip => GzipDecoder::new(reader).read_to_end(&mut decoded).await,
+            Encoding::Zstd => ZstdDecoder::new(reader).read_to_end(&mut decoded).await,
+        }
+        .unwrap();
+        decoded
+    }
+
+    #[tokio::test]
+    async fn test_send_compressed() {
+        let body: Vec<u8> = (0..40_000)
+            .flat_map(|i| format!("Package: example-{i}\n").into_bytes())
+            .collect();
+        for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Zstd] {
+            assert_eq!(decoded(encoding, &body).await, body);
+        }
+    }
+}
@@ -1,22 +1,36 @@
 use {
     crate::{
+        cancel::Cancellation,
+        cli::setting,
         conn::{FetchRequestError::*, StreamType::*, UriKind::*},
-        debug_print,
+        dns::resolve,
+        egress::egress_connect,
+        idn::to_ascii,
+        policy::{address_permitted, is_internal},
+        quirks::host_quirks,
+        rules::parse_duration,
     },
     std::{
         collections::{HashMap, VecDeque},
-        fmt,
+        fmt, io,
+        net::SocketAddr,
         pin::Pin,
+        sync::{Mutex, OnceLock},
+        time::{Duration, Instant},
     },
     tokio::{
         io::{AsyncRead, AsyncWrite},
         net::TcpStream,
-        sync::RwLock,
+        sync::{broadcast, watch, RwLock},
     },
+    tracing::debug,
 };
 
 #[cfg(feature = "https")]
-use {std::convert::TryFrom, tokio_rustls::client};
+use {std::convert::TryFrom, tokio_rustls::client, tracing::warn};
+
+#[cfg(feature = "http3")]
+use {crate::quic, tokio::io::DuplexStream};
 
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -252,10 +266,7 @@ impl<'a> Uri<'a> {
 
             match value[start..end].find(':') {
                 None => scheme_to_port(value),
-                Some(p) => match value[p + start + 1..end].parse::<u16>() {
-                    Ok(p) => Some(p),
-                    Err(_) => None,
-                },
+                Some(p) => value[p + start + 1..end].parse::<u16>().ok(),
             }
         }
 
@@ -296,6 +307,145 @@ impl<'a> Uri<'a> {
     }
 }
 
+pub const X_PROXY_SCHEMES: &str = "X_PROXY_SCHEMES";
+
+/// Schemes this build knows how to fetch
+#[cfg(feature = "https")]
+const SUPPORTED_SCHEMES: [&str; 2] = ["http", "https"];
+#[cfg(not(feature = "https"))]
+const SUPPORTED_SCHEMES: [&str; 1] = ["http"];
+
+/// The schemes clients may ask for, every supported one unless narrowed by `X_PROXY_SCHEMES`
+pub(crate) fn allowed_schemes() -> &'static Vec<String> {
+    static SCHEMES: OnceLock<Vec<String>> = OnceLock::new();
+    SCHEMES.get_or_init(|| match setting(X_PROXY_SCHEMES) {
+        Err(_) => SUPPORTED_SCHEMES.iter().map(|s| s.to_string()).collect(),
+        Ok(s) => s
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| SUPPORTED_SCHEMES.contains(&s.as_str()))
+            .collect(),
+    })
+}
+
+/// The scheme name at the start of a request target, if it has one.
+/// `host:port` is an authority rather than a scheme so it isn't mistaken for one.
+pub(crate) fn scheme_of(target: &str) -> Option<String> {
+    let (name, rest) = target.split_once(':')?;
+
+    let mut chars = name.chars();
+    if !chars.next()?.is_ascii_alphabetic()
+        || !chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    {
+        return None;
+    }
+
+    let port = rest.split(['/', '?']).next().unwrap_or_default();
+    if !rest.starts_with("//") && !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(name.to_lowercase())
+}
+
+/// Whether a request target may be fetched, targets without a scheme are left to later stages
+pub(crate) fn scheme_allowed(target: &str) -> bool {
+    match scheme_of(target) {
+        None => true,
+        Some(s) => allowed_schemes().contains(&s),
+    }
+}
+
+/* Percent-encoded unreserved characters decoded and any other escape in upper case
+ * (RFC 3986 section 6.2.2.2), so `%7e`, `%7E` and `~` are the same */
+fn normalize_percent_encoding(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match escaped {
+            Some(b) if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') => {
+                normalized.push(b);
+                i += 3;
+            }
+            Some(b) => {
+                normalized.extend_from_slice(format!("%{b:02X}").as_bytes());
+                i += 3;
+            }
+            None => {
+                normalized.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(normalized).unwrap_or_else(|_| path.to_string())
+}
+
+/* `.` and `..` segments resolved the way RFC 3986 section 5.2.4 does, never climbing above the root */
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments = match path.strip_prefix('/') {
+        Some(p) => p.split('/').peekable(),
+        None => return path.to_string(),
+    };
+
+    let mut output = Vec::new();
+    while let Some(segment) = segments.next() {
+        let last = segments.peek().is_none();
+        match segment {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            s => {
+                output.push(s);
+                continue;
+            }
+        }
+        /* A path ending in a dot segment names a directory */
+        if last {
+            output.push("");
+        }
+    }
+    format!("/{}", output.join("/"))
+}
+
+/// The way of writing `uri` that every equivalent way of writing it shares (RFC 3986 section 6.2.2):
+/// scheme and host in lower case, no default port, no dot segments and percent-encoding only where
+/// it's needed, so `http://Host/a/%7Euser` and `http://host:80/a/~user` are the same request.
+/// The query is left alone as an origin server may sign it. Nothing when `uri` is written that way already.
+pub(crate) fn normalize_uri(uri: &Uri<'_>) -> Option<String> {
+    /* The rest is only found once the scheme is one that's known */
+    let scheme = uri.scheme?.to_lowercase();
+    let original = &uri.uri;
+    let uri = Uri::from(format!("{scheme}{}", &original[scheme.len()..]));
+
+    let host = to_ascii(uri.host?)?.to_lowercase();
+    let port = match (scheme.as_str(), uri.port) {
+        ("http://", Some(80)) | ("https://", Some(443)) | (_, None) => String::new(),
+        (_, Some(p)) => format!(":{p}"),
+    };
+    let path = remove_dot_segments(&normalize_percent_encoding(uri.path.unwrap_or("/")));
+    let query = uri.query.map(|q| format!("?{q}")).unwrap_or_default();
+
+    let normalized = format!("{scheme}{host}{port}{path}{query}");
+    match normalized == *original {
+        true => None,
+        false => Some(normalized),
+    }
+}
+
+/// The other end of a connection accepted from a client
+#[derive(Clone)]
+pub(crate) struct Client {
+    pub(crate) address: SocketAddr,
+    /// Cancelled when the connection closes or rproxy shuts down
+    pub(crate) cancel: Cancellation,
+}
+
 pub(crate) trait AsyncReadWriteExt: AsyncRead + AsyncWrite + Send + Unpin {}
 impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncReadWriteExt for T {}
 
@@ -306,11 +456,104 @@ enum StreamType {
     TlsClient(client::TlsStream<TcpStream>),
     //#[cfg(feature = "https")]
     //TlsServer(server::TlsStream<TcpStream>),
+    /// HTTP/1.1 requests and responses passed over an HTTP/3 connection by [`quic::connect`]
+    #[cfg(feature = "http3")]
+    Http3(DuplexStream),
 }
 
 pub(crate) struct FetchRequest<'a> {
     uri: Uri<'a>,
     stream: StreamType,
+    /* How many requests have been sent on the current connection */
+    requests: u32,
+    /* The origin currently connected to */
+    peer: Option<SocketAddr>,
+    /* Cleared once a public origin redirects elsewhere so it can't lead into the local network */
+    allow_internal: bool,
+    /* Connections may be taken from the pool rather than always opened afresh */
+    pooling: bool,
+    /* The current connection was taken from the pool */
+    pooled: bool,
+}
+
+pub const X_PROXY_UPSTREAM_POOL: &str = "X_PROXY_UPSTREAM_POOL";
+pub const X_PROXY_UPSTREAM_IDLE_TIMEOUT: &str = "X_PROXY_UPSTREAM_IDLE_TIMEOUT";
+pub const X_PROXY_UPSTREAM_KEEP_ALIVE_MAX: &str = "X_PROXY_UPSTREAM_KEEP_ALIVE_MAX";
+
+/// How many idle connections are kept to each origin when `X_PROXY_UPSTREAM_POOL` isn't defined
+const DEFAULT_UPSTREAM_POOL: usize = 4;
+
+/// How long a connection may sit idle in the pool when `X_PROXY_UPSTREAM_IDLE_TIMEOUT` isn't defined,
+/// short enough that most origins haven't given up on it yet
+const DEFAULT_UPSTREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many requests a connection to an origin may carry when `X_PROXY_UPSTREAM_KEEP_ALIVE_MAX`
+/// isn't defined, the same as a client's connection may make
+const DEFAULT_UPSTREAM_KEEP_ALIVE_MAX: u32 = 100;
+
+fn upstream_pool_size() -> usize {
+    static SIZE: OnceLock<usize> = OnceLock::new();
+    *SIZE.get_or_init(|| {
+        setting(X_PROXY_UPSTREAM_POOL)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_UPSTREAM_POOL)
+    })
+}
+
+fn upstream_idle_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        setting(X_PROXY_UPSTREAM_IDLE_TIMEOUT)
+            .ok()
+            .and_then(|t| parse_duration(&t))
+            .unwrap_or(DEFAULT_UPSTREAM_IDLE_TIMEOUT)
+    })
+}
+
+fn upstream_keep_alive_max() -> u32 {
+    static MAX: OnceLock<u32> = OnceLock::new();
+    *MAX.get_or_init(|| {
+        setting(X_PROXY_UPSTREAM_KEEP_ALIVE_MAX)
+            .ok()
+            .and_then(|m| m.trim().parse().ok())
+            .filter(|m| *m > 0)
+            .unwrap_or(DEFAULT_UPSTREAM_KEEP_ALIVE_MAX)
+    })
+}
+
+/// A connection to an origin waiting in the pool for its next request
+struct Idle {
+    stream: StreamType,
+    peer: Option<SocketAddr>,
+    requests: u32,
+    since: Instant,
+}
+
+/* Idle connections by scheme, host and port, the most recently used last */
+fn upstream_pool() -> &'static Mutex<HashMap<String, Vec<Idle>>> {
+    static POOL: OnceLock<Mutex<HashMap<String, Vec<Idle>>>> = OnceLock::new();
+    POOL.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn pool_key(uri: &Uri<'_>) -> Option<String> {
+    Some(format!("{}{}", uri.scheme?, uri.host_and_port()?))
+}
+
+/* An idle connection has nothing to read. Anything there, even the end of the stream,
+ * means the origin closed it or sent something no request asked for. */
+fn healthy(stream: &StreamType) -> bool {
+    let tcp = match stream {
+        Disconnected => return false,
+        Unencrypted(s) => s,
+        #[cfg(feature = "https")]
+        TlsClient(s) => s.get_ref().0,
+        #[cfg(feature = "http3")]
+        Http3(_) => return false,
+    };
+
+    let mut byte = [0; 1];
+    matches!(tcp.try_read(&mut byte), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
 }
 
 #[derive(Debug)]
@@ -319,9 +562,16 @@ pub(crate) enum FetchRequestError {
     InvalidUri,
     #[cfg(feature = "https")]
     InvalidDomainName(String),
+    DnsResolutionError(String),
+    DeniedAddress(String),
     TcpConnectionError(String),
+    /// The origin or its name server didn't answer in time
+    UpstreamTimeout(String),
     #[cfg(feature = "https")]
     TlsConnectionError(String),
+    /// The host's certificate didn't satisfy the upstream TLS policy
+    #[cfg(feature = "https")]
+    CertificateRejected(String),
 }
 
 impl fmt::Display for FetchRequestError {
@@ -331,44 +581,227 @@ impl fmt::Display for FetchRequestError {
             InvalidUri => write!(f, "Invalid Uri"),
             #[cfg(feature = "https")]
             InvalidDomainName(name) => write!(f, "Invalid domain name: {}", name),
+            DnsResolutionError(msg) => write!(f, "DNS resolution error: {}", msg),
+            DeniedAddress(host) => write!(f, "Connecting to {} is not permitted", host),
             TcpConnectionError(msg) => write!(f, "TCP connection error: {}", msg),
+            UpstreamTimeout(msg) => write!(f, "Timed out: {}", msg),
             #[cfg(feature = "https")]
             TlsConnectionError(msg) => write!(f, "TLS connection error: {}", msg),
+            #[cfg(feature = "https")]
+            CertificateRejected(msg) => write!(f, "Certificate rejected: {}", msg),
         }
     }
 }
 
+impl FetchRequestError {
+    /// Looking up the origin failed with `error`
+    pub(crate) fn resolving(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut => UpstreamTimeout(error.to_string()),
+            _ => DnsResolutionError(error.to_string()),
+        }
+    }
+
+    /// Connecting to the origin failed with `error`
+    pub(crate) fn connecting(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut => UpstreamTimeout(error.to_string()),
+            _ => TcpConnectionError(error.to_string()),
+        }
+    }
+}
+
+/* `value` with its host as it's looked up, sent in `Host` and named to TLS,
+ * a host outside ASCII is encoded with punycode */
+fn ascii_uri<'a>(value: &Uri<'_>) -> Result<Uri<'a>, FetchRequestError> {
+    let host = match value.host {
+        Some(h) if !h.is_ascii() => h,
+        _ => return Ok(Uri::from(&value.uri)),
+    };
+
+    let ascii = to_ascii(host).ok_or(InvalidUri)?;
+    let start = value.scheme.map(str::len).unwrap_or_default();
+    let rest = value.uri[start..].strip_prefix(host).ok_or(InvalidUri)?;
+    Ok(Uri::from(format!("{}{ascii}{rest}", &value.uri[..start])))
+}
+
 impl FetchRequest<'_> {
     pub(crate) fn from_uri(value: &Uri<'_>) -> Result<Self, FetchRequestError> {
         let stream = Disconnected;
 
-        let uri = Uri::from(&value.uri);
-        Ok(FetchRequest { uri, stream })
+        let uri = ascii_uri(value)?;
+        Ok(FetchRequest {
+            uri,
+            stream,
+            requests: 0,
+            peer: None,
+            allow_internal: true,
+            pooling: false,
+            pooled: false,
+        })
     }
 
     #[allow(dead_code)]
     pub(crate) fn from_string(value: &String) -> Result<Self, FetchRequestError> {
         let stream = Disconnected;
 
-        let uri = Uri::from(value);
-        Ok(FetchRequest { uri, stream })
+        let uri = ascii_uri(&Uri::from(value))?;
+        Ok(FetchRequest {
+            uri,
+            stream,
+            requests: 0,
+            peer: None,
+            allow_internal: true,
+            pooling: false,
+            pooled: false,
+        })
     }
 
-    pub(crate) fn uri(&self) -> &Uri {
+    pub(crate) fn uri(&self) -> &Uri<'_> {
         &self.uri
     }
 
+    /// Let [`connect`](Self::connect) take an idle connection from the pool and
+    /// [`release`](Self::release) put this one back. Only for requests that can be sent again
+    /// when a pooled connection turns out to have been closed by the origin.
+    pub(crate) fn use_pool(&mut self) {
+        self.pooling = true;
+    }
+
+    /* The most recently used idle connection to the same origin that's still fit for a request */
+    fn take_idle(&mut self) -> bool {
+        #[cfg(feature = "http3")]
+        if self.h3_port().is_some() {
+            return false; /* Requests to it go over HTTP/3 instead */
+        }
+
+        let key = match pool_key(&self.uri) {
+            Some(k) => k,
+            None => return false,
+        };
+        let mut pool = match upstream_pool().lock() {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        let idle = match pool.get_mut(&key) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        while let Some(connection) = idle.pop() {
+            if connection.since.elapsed() > upstream_idle_timeout()
+                || !connection
+                    .peer
+                    .is_some_and(|p| address_permitted(p.ip(), self.allow_internal))
+                || !healthy(&connection.stream)
+            {
+                continue;
+            }
+
+            debug!("Reusing a pooled connection to {key}");
+            self.stream = connection.stream;
+            self.peer = connection.peer;
+            self.requests = connection.requests;
+            return true;
+        }
+        false
+    }
+
+    /// Put the connection back in the pool for the next request to the same origin.
+    /// Only call this once a whole response has been read and the origin said it would keep the
+    /// connection open. Without [`use_pool`](Self::use_pool) the connection is closed instead.
+    pub(crate) fn release(&mut self) {
+        let stream = std::mem::replace(&mut self.stream, Disconnected);
+        if !self.pooling || matches!(stream, Disconnected) || upstream_pool_size() == 0 {
+            return;
+        }
+        #[cfg(feature = "http3")]
+        if let Http3(_) = stream {
+            return; /* The HTTP/3 connection under it stays open for the next request */
+        }
+
+        let key = match pool_key(&self.uri) {
+            Some(k) => k,
+            None => return,
+        };
+        if host_quirks(&self.uri.host_and_port().unwrap_or_default()).no_reuse {
+            return;
+        }
+        if self.requests >= upstream_keep_alive_max() {
+            debug!("Closing a connection to {key} that carried its last request");
+            return;
+        }
+
+        if let Ok(mut pool) = upstream_pool().lock() {
+            for idle in pool.values_mut() {
+                idle.retain(|c| c.since.elapsed() <= upstream_idle_timeout());
+            }
+            pool.retain(|_, idle| !idle.is_empty());
+
+            let idle = pool.entry(key).or_default();
+            if idle.len() >= upstream_pool_size() {
+                idle.remove(0);
+            }
+            idle.push(Idle {
+                stream,
+                peer: self.peer,
+                requests: self.requests,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// True when the current connection was taken from the pool,
+    /// in which case the origin may have closed it while it sat idle.
+    /// HTTP/3 counts too, a request it couldn't get an answer to is sent again over TCP.
+    pub(crate) fn pooled(&self) -> bool {
+        self.pooled || self.over_http3()
+    }
+
+    /* The origin's HTTP/3 port when it advertised one, only for requests that can be sent
+     * again over TCP when it doesn't answer */
+    #[cfg(feature = "http3")]
+    fn h3_port(&self) -> Option<(String, u16)> {
+        if !self.pooling || self.uri.scheme != Some("https://") {
+            return None;
+        }
+        let key = self.uri.host_and_port()?;
+        quic::h3_port(&key).map(|p| (key, p))
+    }
+
+    fn over_http3(&self) -> bool {
+        #[cfg(feature = "http3")]
+        if let Http3(_) = self.stream {
+            return true;
+        }
+        false
+    }
+
     pub(crate) async fn connect(
         &mut self,
         #[cfg(feature = "https")] certificates: &crate::cert::CertificateSetup,
     ) -> Result<(), FetchRequestError> {
+        self.pooled = self.pooling && self.take_idle();
+        if self.pooled {
+            return Ok(());
+        }
+
+        self.requests = 0;
         let value = &self.uri;
 
-        let host = match value.host_and_port() {
-            Some(s) => s,
-            None => return Err(InvalidUri),
+        let mut host = match (value.host, value.port) {
+            (Some(h), Some(p)) => match resolve(h, p).await {
+                Ok(a) => a,
+                Err(e) => return Err(FetchRequestError::resolving(e)),
+            },
+            _ => return Err(InvalidUri),
         };
 
+        host.retain(|a| address_permitted(a.ip(), self.allow_internal));
+        if host.is_empty() {
+            return Err(DeniedAddress(value.host.unwrap_or_default().to_string()));
+        }
+
         let scheme = match value.scheme {
             None => return Err(InvalidScheme),
             Some(s) => s,
@@ -376,9 +809,12 @@ impl FetchRequest<'_> {
 
         match scheme {
             "http://" => {
-                let stream = match TcpStream::connect(host).await {
-                    Ok(o) => Unencrypted(o),
-                    Err(e) => return Err(TcpConnectionError(e.to_string())),
+                let stream = match egress_connect(&host).await {
+                    Ok(o) => {
+                        self.peer = o.peer_addr().ok();
+                        Unencrypted(o)
+                    }
+                    Err(e) => return Err(FetchRequestError::connecting(e)),
                 };
 
                 self.stream = stream;
@@ -397,21 +833,48 @@ impl FetchRequest<'_> {
                     Err(e) => return Err(InvalidDomainName(e.to_string())),
                 };
 
-                let stream = match TcpStream::connect(host).await {
-                    Ok(o) => o,
-                    Err(e) => return Err(TcpConnectionError(e.to_string())),
+                #[cfg(feature = "http3")]
+                if let Some((key, port)) = self.h3_port() {
+                    let name = value.host.unwrap_or_default();
+                    match quic::connect(&key, name, &host, port, certificates).await {
+                        Ok((stream, peer)) => {
+                            self.peer = Some(peer);
+                            self.stream = Http3(stream);
+                            return Ok(());
+                        }
+                        Err(e) => debug!("HTTP/3 connect error '{e}'"),
+                    }
+                }
+
+                let stream = match egress_connect(&host).await {
+                    Ok(o) => {
+                        self.peer = o.peer_addr().ok();
+                        o
+                    }
+                    Err(e) => return Err(FetchRequestError::connecting(e)),
                 };
 
-                let stream: StreamType =
-                    match certificates.client_config.connect(domain, stream).await {
-                        Ok(s) => TlsClient(s),
-                        Err(e) => {
-                            return {
-                                debug_print!("HTTPS connect error '{e}'");
-                                Err(TlsConnectionError(e.to_string()))
+                let connector = certificates.connector_for(value.host.unwrap_or_default());
+                let stream: StreamType = match connector.connect(domain, stream).await {
+                    Ok(s) => TlsClient(s),
+                    Err(e) => {
+                        debug!("HTTPS connect error '{e}'");
+                        let rejected = matches!(
+                            e.get_ref().and_then(|e| e.downcast_ref()),
+                            Some(tokio_rustls::rustls::Error::InvalidCertificate(_))
+                        );
+                        return match rejected {
+                            true => {
+                                warn!(
+                                    "rejected the certificate of {}: {e}",
+                                    value.host.unwrap_or_default()
+                                );
+                                Err(CertificateRejected(e.to_string()))
                             }
-                        }
-                    };
+                            false => Err(TlsConnectionError(e.to_string())),
+                        };
+                    }
+                };
 
                 self.stream = stream;
                 Ok(())
@@ -429,7 +892,7 @@ impl FetchRequest<'_> {
 
         match compare.same_host_as(other) {
             true => {
-                debug_print!("{} is the same host as {}", self.uri.uri, other.uri);
+                debug!("{} is the same host as {}", self.uri.uri, other.uri);
                 if let Some(new_path) = other.path_and_query {
                     let new = format!(
                         "{}{}{}",
@@ -438,13 +901,27 @@ impl FetchRequest<'_> {
                         new_path
                     );
                     self.uri = Uri::from(new);
+
+                    if let Disconnected = self.stream {
+                        /* The origin wouldn't keep the last connection open, start a new one */
+                        return self
+                            .connect(
+                                #[cfg(feature = "https")]
+                                certificates,
+                            )
+                            .await;
+                    }
                     return Ok(());
                 }
                 Err(InvalidUri)
             }
             false => {
-                debug_print!("{} is not same as host {}", self.uri.uri, other.uri);
-                self.uri = Uri::from(other);
+                debug!("{} is not same as host {}", self.uri.uri, other.uri);
+                if self.peer.is_some_and(|p| !is_internal(p.ip())) {
+                    self.allow_internal = false;
+                }
+                self.release();
+                self.uri = ascii_uri(other)?;
                 match self
                     .connect(
                         #[cfg(feature = "https")]
@@ -461,7 +938,24 @@ impl FetchRequest<'_> {
         }
     }
 
+    /// Drop the upstream connection so the next redirect has to establish a new one
+    pub(crate) fn disconnect(&mut self) {
+        self.stream = Disconnected;
+    }
+
+    /// True when the next request is the last the current connection may carry,
+    /// so the origin should be asked to close it afterwards
+    pub(crate) fn last_request(&self) -> bool {
+        self.requests + 1 >= upstream_keep_alive_max()
+    }
+
+    /// True when the current connection has already carried a request
+    pub(crate) fn reused(&self) -> bool {
+        self.requests > 0 || self.over_http3()
+    }
+
     pub(crate) fn as_stream(&mut self) -> Option<Pin<Box<dyn AsyncReadWriteExt + '_>>> {
+        self.requests += 1;
         match self.stream {
             Disconnected => None,
             Unencrypted(ref mut stream) => Some(Box::pin(stream)),
@@ -469,36 +963,146 @@ impl FetchRequest<'_> {
             TlsClient(ref mut stream) => Some(Box::pin(stream)),
             //#[cfg(feature = "https")]
             //TlsServer(ref mut stream) => Some(Box::pin(stream)),
+            #[cfg(feature = "http3")]
+            Http3(ref mut stream) => Some(Box::pin(stream)),
         }
     }
 }
 
-#[derive(Clone)]
+/// Where a download is in its life. Followers serving a file that's still being written go by
+/// this rather than the file, which looks the same whether its download is still going or has died.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum FlightState {
+    /// Waiting for the origin to answer
     Fetching,
+    /// The body is being written and will be this long
     Length(u64),
+    /// The body is being written and its length isn't known
     Chunks,
+    /// The whole body is in the cache file
+    Complete,
+    /// The download stopped short or was thrown away, the cache file is not the whole body
+    Failed,
 }
 
+impl FlightState {
+    /// Whether a flight may move from this state to `next`.
+    /// A body that has started can only finish one way or the other and a finished flight never changes.
+    pub(crate) fn can_become(self, next: FlightState) -> bool {
+        match self {
+            FlightState::Fetching => true,
+            FlightState::Length(_) | FlightState::Chunks => {
+                matches!(next, FlightState::Complete | FlightState::Failed)
+            }
+            FlightState::Complete | FlightState::Failed => false,
+        }
+    }
+}
+
+/// A change to any download, for watching them all rather than following one
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum FlightChange {
+    /// The download of a cache file took off or moved to a new state
+    State(String, FlightState),
+    /// The download of a cache file ended in this state and is no longer in flight
+    Landed(String, FlightState),
+}
+
+/* Watchers that fall this far behind miss changes rather than hold up downloads */
+const FLIGHT_CHANGES: usize = 64;
+
 pub(crate) struct Flights {
-    in_flight: RwLock<HashMap<String, FlightState>>,
+    in_flight: RwLock<HashMap<String, watch::Sender<FlightState>>>,
+    cancels: RwLock<HashMap<String, Cancellation>>,
+    changes: broadcast::Sender<FlightChange>,
 }
 
 impl Flights {
     pub fn new() -> Self {
         Flights {
-            in_flight: RwLock::new(HashMap::<String, FlightState>::new()),
+            in_flight: RwLock::new(HashMap::new()),
+            cancels: RwLock::new(HashMap::<String, Cancellation>::new()),
+            changes: broadcast::channel(FLIGHT_CHANGES).0,
+        }
+    }
+
+    fn transition(
+        &self,
+        cache_file_path: &str,
+        sender: &watch::Sender<FlightState>,
+        next: FlightState,
+    ) {
+        let changed = sender.send_if_modified(|state| match state.can_become(next) {
+            true => {
+                *state = next;
+                true
+            }
+            false => false,
+        });
+
+        if changed {
+            let _ = self
+                .changes
+                .send(FlightChange::State(cache_file_path.to_owned(), next));
         }
     }
 
     pub async fn takeoff(&self, cache_file_path: &str, flight_state: FlightState) {
         let mut files = self.in_flight.write().await;
-        files.insert(cache_file_path.to_owned(), flight_state);
+        match files.get(cache_file_path) {
+            Some(sender) => self.transition(cache_file_path, sender, flight_state),
+            None => {
+                files.insert(cache_file_path.to_owned(), watch::channel(flight_state).0);
+                let _ = self.changes.send(FlightChange::State(
+                    cache_file_path.to_owned(),
+                    flight_state,
+                ));
+            }
+        }
+    }
+
+    /// Mark the whole body of `cache_file_path` as written, only then does landing count as a success
+    pub async fn complete(&self, cache_file_path: &str) {
+        if let Some(sender) = self.in_flight.read().await.get(cache_file_path) {
+            self.transition(cache_file_path, sender, FlightState::Complete);
+        }
     }
 
+    /// End a flight, one that wasn't marked complete has failed
     pub async fn land(&self, cache_file_path: &String) {
         let mut files = self.in_flight.write().await;
-        files.remove(cache_file_path);
+        if let Some(sender) = files.remove(cache_file_path) {
+            self.transition(cache_file_path, &sender, FlightState::Failed);
+            let _ = self.changes.send(FlightChange::Landed(
+                cache_file_path.to_owned(),
+                *sender.borrow(),
+            ));
+        }
+        self.cancels.write().await.remove(cache_file_path);
+    }
+
+    /// Every takeoff, change of state and landing from now on, of every download
+    #[cfg(feature = "web-ui")]
+    pub fn changes(&self) -> broadcast::Receiver<FlightChange> {
+        self.changes.subscribe()
+    }
+
+    /// Let the download of `cache_file_path` be stopped with [`Flights::abort`]
+    pub async fn set_cancel(&self, cache_file_path: &str, cancel: &Cancellation) {
+        let mut cancels = self.cancels.write().await;
+        cancels.insert(cache_file_path.to_owned(), cancel.clone());
+    }
+
+    /// Stop a download, its file is removed and clients waiting on it are disconnected
+    #[cfg(feature = "web-ui")]
+    pub async fn abort(&self, cache_file_path: &String) -> bool {
+        match self.cancels.read().await.get(cache_file_path) {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
     }
 
     pub async fn is_in_flight(&self, cache_file_path: &String) -> bool {
@@ -506,9 +1110,24 @@ impl Flights {
         files.contains_key(cache_file_path)
     }
 
-    pub async fn flight_state(&self, cache_file_path: &String) -> Option<FlightState> {
+    /// Watch the state of a download, the last state is kept after it lands
+    pub async fn follow(&self, cache_file_path: &String) -> Option<watch::Receiver<FlightState>> {
         let files = self.in_flight.read().await;
-        files.get(cache_file_path).cloned()
+        files.get(cache_file_path).map(|s| s.subscribe())
+    }
+
+    /// How many downloads are in flight
+    pub async fn count(&self) -> usize {
+        self.in_flight.read().await.len()
+    }
+
+    #[cfg(feature = "web-ui")]
+    pub async fn all(&self) -> Vec<(String, FlightState)> {
+        let files = self.in_flight.read().await;
+        files
+            .iter()
+            .map(|(k, v)| (k.clone(), *v.borrow()))
+            .collect()
     }
 }
 
@@ -516,6 +1135,191 @@ impl Flights {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_fetch_request_error_from_io() {
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "slow");
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+
+        assert!(matches!(
+            FetchRequestError::resolving(timed_out()),
+            UpstreamTimeout(_)
+        ));
+        assert!(matches!(
+            FetchRequestError::resolving(io::Error::new(io::ErrorKind::NotFound, "none")),
+            DnsResolutionError(_)
+        ));
+        assert!(matches!(
+            FetchRequestError::connecting(timed_out()),
+            UpstreamTimeout(_)
+        ));
+        assert!(matches!(
+            FetchRequestError::connecting(refused()),
+            TcpConnectionError(_)
+        ));
+    }
+
+    #[test]
+    fn test_normalize_uri() {
+        let normalize = |u: &str| normalize_uri(&Uri::from(u.to_string()));
+
+        assert_eq!(
+            normalize("HTTP://Host/a/%7Euser"),
+            Some("http://host/a/~user".to_string())
+        );
+        assert_eq!(
+            normalize("http://host:80/a/~user"),
+            Some("http://host/a/~user".to_string())
+        );
+        assert_eq!(normalize("http://host/a/~user"), None);
+        assert_eq!(
+            normalize("https://host:443/"),
+            Some("https://host/".to_string())
+        );
+        assert_eq!(normalize("http://host:8080/"), None);
+        assert_eq!(normalize("http://host"), Some("http://host/".to_string()));
+
+        /* Escapes that have to stay are written one way, the query is left as it came */
+        assert_eq!(
+            normalize("http://host/a%2fb%20c?x=%7e"),
+            Some("http://host/a%2Fb%20c?x=%7e".to_string())
+        );
+        assert_eq!(normalize("http://host/100%"), None);
+
+        /* Dot segments, written plainly or escaped, can't climb above the root */
+        assert_eq!(
+            normalize("http://host/a/./b/../c"),
+            Some("http://host/a/c".to_string())
+        );
+        assert_eq!(
+            normalize("http://host/../../etc/passwd"),
+            Some("http://host/etc/passwd".to_string())
+        );
+        assert_eq!(
+            normalize("http://host/a/%2e%2E/%2E%2e/etc"),
+            Some("http://host/etc".to_string())
+        );
+        assert_eq!(
+            normalize("http://host/a/b/.."),
+            Some("http://host/a/".to_string())
+        );
+        assert_eq!(normalize("/a/../b"), None);
+
+        assert_eq!(
+            normalize("http://Bücher.example/a"),
+            Some("http://xn--bcher-kva.example/a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_fetch_request_idn() {
+        let request =
+            FetchRequest::from_string(&"https://Bücher.example:8443/a?b".to_string()).unwrap();
+        assert_eq!(request.uri().uri, "https://xn--bcher-kva.example:8443/a?b");
+        assert_eq!(request.uri().host, Some("xn--bcher-kva.example"));
+
+        let request = FetchRequest::from_string(&"http://example.com/ü".to_string()).unwrap();
+        assert_eq!(request.uri().uri, "http://example.com/ü");
+    }
+
+    #[tokio::test]
+    async fn test_flight_state() {
+        let flights = Flights::new();
+        let path = "/cache/example.com/file".to_string();
+
+        flights.takeoff(&path, FlightState::Fetching).await;
+        let mut follower = flights.follow(&path).await.unwrap();
+        flights.takeoff(&path, FlightState::Length(10)).await;
+        assert_eq!(*follower.borrow_and_update(), FlightState::Length(10));
+
+        /* A body that has started can't start over */
+        flights.takeoff(&path, FlightState::Chunks).await;
+        assert_eq!(*follower.borrow(), FlightState::Length(10));
+
+        flights.complete(&path).await;
+        flights.land(&path).await;
+        assert!(!flights.is_in_flight(&path).await);
+        assert_eq!(*follower.borrow(), FlightState::Complete);
+        assert!(flights.follow(&path).await.is_none());
+
+        /* Landing without completing is a failure, even after the whole header arrived */
+        flights.takeoff(&path, FlightState::Chunks).await;
+        let follower = flights.follow(&path).await.unwrap();
+        flights.land(&path).await;
+        assert_eq!(*follower.borrow(), FlightState::Failed);
+
+        /* Completing a flight that isn't in the air does nothing */
+        flights.complete(&path).await;
+        assert!(!flights.is_in_flight(&path).await);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_pool() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = format!("http://{}/file", listener.local_addr().unwrap());
+
+        let mut first = FetchRequest::from_string(&origin).unwrap();
+        first.use_pool();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        first.peer = stream.peer_addr().ok();
+        first.stream = Unencrypted(stream);
+        first.requests = 1;
+        let (accepted, _) = listener.accept().await.unwrap();
+        first.release();
+        assert!(matches!(first.stream, Disconnected));
+
+        let mut second = FetchRequest::from_string(&origin).unwrap();
+        second.use_pool();
+        assert!(second.take_idle());
+        assert!(second.reused());
+
+        /* Gone from the pool once taken */
+        let mut third = FetchRequest::from_string(&origin).unwrap();
+        third.use_pool();
+        assert!(!third.take_idle());
+
+        /* A connection the origin closed while it was idle isn't handed out */
+        second.release();
+        drop(accepted);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!third.take_idle());
+
+        /* Requests that can't be sent twice never go into the pool */
+        let mut unpooled = FetchRequest::from_string(&origin).unwrap();
+        unpooled.stream = Unencrypted(
+            TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap(),
+        );
+        unpooled.release();
+        assert!(!third.take_idle());
+    }
+
+    #[test]
+    fn test_scheme_of() {
+        assert_eq!(scheme_of("http://example.com/"), Some("http".to_string()));
+        assert_eq!(scheme_of("HTTPS://example.com/"), Some("https".to_string()));
+        assert_eq!(
+            scheme_of("gopher://example.com/"),
+            Some("gopher".to_string())
+        );
+        assert_eq!(scheme_of("data:text/plain,hi"), Some("data".to_string()));
+        assert_eq!(scheme_of("example.com:443"), None);
+        assert_eq!(scheme_of("localhost:8080/file"), None);
+        assert_eq!(scheme_of("/file?a=b:c"), None);
+        assert_eq!(scheme_of("example.com/file"), None);
+    }
+
+    #[test]
+    fn test_scheme_allowed() {
+        assert!(scheme_allowed("http://example.com/"));
+        assert!(scheme_allowed("/file"));
+        assert!(!scheme_allowed("gopher://example.com/"));
+        assert!(!scheme_allowed("data:text/plain,hi"));
+        assert!(!scheme_allowed("file:///etc/passwd"));
+    }
+
     #[test]
     fn test_uri_absolute_address() {
         let uri = Uri::new("http://example.com/path".to_string());
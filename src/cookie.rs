@@ -0,0 +1,301 @@
+use {
+    crate::{
+        cli::setting,
+        clock::now,
+        conn::Uri,
+        evict::matches_pattern,
+        http::{HttpHeader, HttpResponseHeader},
+    },
+    std::{
+        sync::{OnceLock, RwLock},
+        time::{Duration, SystemTime},
+    },
+};
+
+pub const X_PROXY_COOKIE_HOSTS: &str = "X_PROXY_COOKIE_HOSTS";
+
+/// Upper bound on stored cookies so an origin handing out a new one per request can't grow the jar forever
+const MAX_COOKIES: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// The host that set it, or the domain it was scoped to
+    domain: String,
+    /// Without a `Domain` attribute a cookie is only sent back to the exact host that set it
+    host_only: bool,
+    path: String,
+    secure: bool,
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+
+    fn matches(&self, host: &str, path: &str, secure: bool) -> bool {
+        let domain = match self.host_only {
+            true => host == self.domain,
+            false => domain_matches(host, &self.domain),
+        };
+
+        domain && path_matches(path, &self.path) && (secure || !self.secure)
+    }
+}
+
+/* The host itself or any subdomain of it, RFC 6265 section 5.1.3 */
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/* RFC 6265 section 5.1.4 */
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    match path.strip_prefix(cookie_path) {
+        None => false,
+        Some(rest) => cookie_path.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+    }
+}
+
+fn cookie_hosts() -> &'static Vec<String> {
+    static HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+    HOSTS.get_or_init(|| match setting(X_PROXY_COOKIE_HOSTS) {
+        Err(_) => Vec::new(),
+        Ok(s) => s
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    })
+}
+
+fn jar() -> &'static RwLock<Vec<Cookie>> {
+    static JAR: OnceLock<RwLock<Vec<Cookie>>> = OnceLock::new();
+    JAR.get_or_init(|| RwLock::new(Vec::new()))
+}
+
+/// Whether upstream fetches from `host` keep their own cookies
+fn uses_cookie_jar(host: &str) -> bool {
+    let host = host.to_lowercase();
+    cookie_hosts().iter().any(|p| matches_pattern(p, &host))
+}
+
+/// Read a single `Set-Cookie` value sent by `host` in answer to a request for `path`
+fn parse_set_cookie(value: &str, host: &str, path: &str, now: SystemTime) -> Option<Cookie> {
+    let mut attributes = value.split(';');
+    let (name, value) = attributes.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().to_string(),
+        domain: host.to_string(),
+        host_only: true,
+        /* The default path is the directory of the request, RFC 6265 section 5.1.4 */
+        path: match path.rfind('/') {
+            Some(i) if i > 0 => path[..i].to_string(),
+            _ => "/".to_string(),
+        },
+        secure: false,
+        expires: None,
+    };
+    let mut max_age = None;
+
+    for attribute in attributes {
+        let (key, value) = match attribute.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => (attribute.trim(), ""),
+        };
+
+        match key.to_lowercase().as_str() {
+            "domain" => {
+                let domain = value.trim_start_matches('.').to_lowercase();
+                if domain.is_empty() {
+                    continue;
+                }
+                /* An origin may only set cookies for itself or a domain above it */
+                if !domain_matches(host, &domain) {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "secure" => cookie.secure = true,
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "expires" => {
+                if let Ok(e) = httpdate::parse_http_date(value) {
+                    cookie.expires = Some(e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /* Max-Age wins over Expires when both are given */
+    if let Some(seconds) = max_age {
+        cookie.expires = Some(match seconds {
+            s if s <= 0 => SystemTime::UNIX_EPOCH,
+            s => now + Duration::from_secs(s as u64),
+        });
+    }
+
+    Some(cookie)
+}
+
+fn store(jar: &mut Vec<Cookie>, cookie: Cookie, now: SystemTime) {
+    let replaced =
+        |c: &Cookie| c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path;
+    jar.retain(|c| !replaced(c) && !c.expired(now));
+
+    /* An expiry in the past is how an origin deletes a cookie */
+    if cookie.expired(now) {
+        return;
+    }
+
+    if jar.len() >= MAX_COOKIES {
+        jar.remove(0);
+    }
+    jar.push(cookie);
+}
+
+fn cookie_header(jar: &[Cookie], host: &str, path: &str, secure: bool, now: SystemTime) -> String {
+    jar.iter()
+        .filter(|c| !c.expired(now) && c.matches(host, path, secure))
+        .map(|c| format!("{}={}", c.name, c.value))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// Swap the client's cookies for the jar's when fetching from a host that uses it. Clients
+/// share whatever gets cached, so their own cookies are never sent to such a host.
+pub(crate) fn apply_cookie_jar(uri: &Uri<'_>, headers: &mut HttpHeader) {
+    let host = match uri.host {
+        Some(h) if uses_cookie_jar(h) => h.to_lowercase(),
+        _ => return,
+    };
+
+    headers.remove("Cookie");
+
+    let jar = match jar().read() {
+        Ok(j) => j,
+        Err(_) => return,
+    };
+
+    let secure = uri
+        .scheme
+        .is_some_and(|s| s.eq_ignore_ascii_case("https://"));
+    let cookies = cookie_header(&jar, &host, uri.path.unwrap_or("/"), secure, now());
+    if !cookies.is_empty() {
+        headers.insert("Cookie".to_string(), cookies);
+    }
+}
+
+/// Keep the cookies a host that uses the jar sent with `response` and take them out of it
+/// so they never reach a client
+pub(crate) fn store_cookies(uri: &Uri<'_>, response: &mut HttpResponseHeader) {
+    let host = match uri.host {
+        Some(h) if uses_cookie_jar(h) => h.to_lowercase(),
+        _ => return,
+    };
+
+    let values: Vec<String> = response.headers.get_all("Set-Cookie").cloned().collect();
+    if values.is_empty() {
+        return;
+    }
+    response.headers.remove("Set-Cookie");
+
+    let mut jar = match jar().write() {
+        Ok(j) => j,
+        Err(_) => return,
+    };
+
+    let now = now();
+    for set_cookie in values {
+        if let Some(cookie) = parse_set_cookie(&set_cookie, &host, uri.path.unwrap_or("/"), now) {
+            store(&mut jar, cookie, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_set_cookie() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+
+        let cookie =
+            parse_set_cookie("session=abc; Path=/; Secure", "dl.example.com", "/a/b", now).unwrap();
+        assert_eq!(cookie.name, "session");
+        assert_eq!(cookie.value, "abc");
+        assert!(cookie.host_only && cookie.secure);
+        assert_eq!(cookie.expires, None);
+
+        let cookie = parse_set_cookie(
+            "id=1; Domain=.example.com; Max-Age=60; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+            "dl.example.com",
+            "/a/b",
+            now,
+        )
+        .unwrap();
+        assert_eq!(cookie.domain, "example.com");
+        assert!(!cookie.host_only);
+        assert_eq!(cookie.path, "/a");
+        assert_eq!(cookie.expires, Some(now + Duration::from_secs(60)));
+
+        assert!(parse_set_cookie("id=1; Domain=other.com", "dl.example.com", "/", now).is_none());
+        assert!(parse_set_cookie("=1", "dl.example.com", "/", now).is_none());
+        assert!(parse_set_cookie("novalue", "dl.example.com", "/", now).is_none());
+    }
+
+    #[test]
+    fn test_cookie_header() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mut jar = Vec::new();
+
+        for set_cookie in [
+            "session=abc; Path=/",
+            "scoped=1; Domain=example.com; Path=/downloads",
+            "secure=1; Path=/; Secure",
+        ] {
+            let cookie = parse_set_cookie(set_cookie, "dl.example.com", "/", now).unwrap();
+            store(&mut jar, cookie, now);
+        }
+
+        assert_eq!(
+            cookie_header(&jar, "dl.example.com", "/downloads/x.iso", false, now),
+            "session=abc; scoped=1"
+        );
+        assert_eq!(
+            cookie_header(&jar, "dl.example.com", "/downloadsx", true, now),
+            "session=abc; secure=1"
+        );
+        assert_eq!(
+            cookie_header(&jar, "cdn.example.com", "/downloads/x.iso", false, now),
+            "scoped=1"
+        );
+        assert_eq!(cookie_header(&jar, "example.org", "/", true, now), "");
+
+        /* Replaced, then deleted by an expiry in the past */
+        let cookie = parse_set_cookie("session=def; Path=/", "dl.example.com", "/", now).unwrap();
+        store(&mut jar, cookie, now);
+        assert_eq!(
+            cookie_header(&jar, "dl.example.com", "/", false, now),
+            "session=def"
+        );
+
+        let cookie =
+            parse_set_cookie("session=; Path=/; Max-Age=0", "dl.example.com", "/", now).unwrap();
+        store(&mut jar, cookie, now);
+        assert_eq!(cookie_header(&jar, "dl.example.com", "/", false, now), "");
+    }
+}
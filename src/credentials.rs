@@ -0,0 +1,184 @@
+use {
+    crate::{
+        cli::setting,
+        conn::Uri,
+        evict::matches_pattern,
+        http::{encode_base64, HttpHeader},
+    },
+    std::{collections::HashMap, sync::OnceLock},
+    tracing::{error, info, warn},
+};
+
+pub const X_PROXY_UPSTREAM_NETRC: &str = "X_PROXY_UPSTREAM_NETRC";
+pub const X_PROXY_UPSTREAM_AUTH_CACHE: &str = "X_PROXY_UPSTREAM_AUTH_CACHE";
+
+/// A login rproxy uses for a host on behalf of every client
+#[derive(Clone, Debug, PartialEq)]
+struct Login {
+    login: String,
+    password: String,
+}
+
+static LOGINS: OnceLock<HashMap<String, Login>> = OnceLock::new();
+
+/// Read the `machine`, `login` and `password` entries of a netrc file, keyed by lower cased host.
+/// A `default` entry would hand a login to every host so it's left out, along with anything after it.
+fn parse_netrc(contents: &str) -> (HashMap<String, Login>, Vec<String>) {
+    let mut logins = HashMap::new();
+    let mut skipped = Vec::new();
+    let mut tokens = contents.split_whitespace();
+
+    let mut machine: Option<String> = None;
+    let mut login = Login {
+        login: String::new(),
+        password: String::new(),
+    };
+    let mut finish = |machine: Option<String>, login: &mut Login| {
+        let login = std::mem::replace(
+            login,
+            Login {
+                login: String::new(),
+                password: String::new(),
+            },
+        );
+        match machine {
+            Some(m) if !login.login.is_empty() => {
+                logins.insert(m, login);
+            }
+            Some(m) => skipped.push(m),
+            None => {}
+        }
+    };
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => {
+                finish(machine.take(), &mut login);
+                machine = tokens.next().map(str::to_lowercase);
+            }
+            "default" => break,
+            "login" => login.login = tokens.next().unwrap_or_default().to_string(),
+            "password" => login.password = tokens.next().unwrap_or_default().to_string(),
+            "account" | "macdef" => {
+                tokens.next();
+            }
+            _ => {}
+        }
+    }
+    finish(machine, &mut login);
+    (logins, skipped)
+}
+
+/// Read the netrc file named by `X_PROXY_UPSTREAM_NETRC`, false when it can't be read
+pub(crate) fn setup_upstream_credentials() -> bool {
+    let path = match setting(X_PROXY_UPSTREAM_NETRC) {
+        Ok(p) => p,
+        Err(_) => return true,
+    };
+
+    let contents = match std::fs::read_to_string(path.trim()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("unable to read '{X_PROXY_UPSTREAM_NETRC}' file '{path}': {e}");
+            return false;
+        }
+    };
+
+    let (logins, skipped) = parse_netrc(&contents);
+    for machine in skipped {
+        warn!("skipping '{machine}' in '{path}', it has no login");
+    }
+    info!(
+        "logging in to {} upstream host(s) from {path}",
+        logins.len()
+    );
+    let _ = LOGINS.set(logins);
+    true
+}
+
+fn auth_cache_hosts() -> &'static Vec<String> {
+    static HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+    HOSTS.get_or_init(|| match setting(X_PROXY_UPSTREAM_AUTH_CACHE) {
+        Err(_) => Vec::new(),
+        Ok(s) => s
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    })
+}
+
+fn authorization(logins: &HashMap<String, Login>, host: &str) -> Option<String> {
+    let login = logins.get(&host.to_lowercase())?;
+    Some(format!(
+        "Basic {}",
+        encode_base64(format!("{}:{}", login.login, login.password).as_bytes())
+    ))
+}
+
+/// Log in to the host of `uri` with its login from the netrc file, unless the client sent
+/// its own. True when rproxy's login was added, so the response is only for those allowed it.
+pub(crate) fn apply_upstream_credentials(uri: &Uri<'_>, headers: &mut HttpHeader) -> bool {
+    if headers.contains_key("Authorization") {
+        return false;
+    }
+
+    match LOGINS
+        .get()
+        .zip(uri.host)
+        .and_then(|(logins, host)| authorization(logins, host))
+    {
+        Some(a) => {
+            headers.insert("Authorization".to_string(), a);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether what rproxy fetched from `host` with its own login may be cached and served
+/// to any client, which `X_PROXY_UPSTREAM_AUTH_CACHE` has to allow
+pub(crate) fn caches_authenticated(host: Option<&str>) -> bool {
+    let host = match host {
+        Some(h) => h.to_lowercase(),
+        None => return false,
+    };
+    auth_cache_hosts().iter().any(|p| matches_pattern(p, &host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_netrc() {
+        let (logins, skipped) = parse_netrc(
+            "machine PyPI.internal login ci password s3cret\n\
+             machine artifacts.internal\n  login deploy\n  account ops\n  password hunter2\n\
+             machine nologin.internal password x\n\
+             default login anonymous password guest\n\
+             machine after.default login a password b\n",
+        );
+
+        assert_eq!(logins.len(), 2);
+        assert_eq!(
+            logins["pypi.internal"],
+            Login {
+                login: "ci".to_string(),
+                password: "s3cret".to_string()
+            }
+        );
+        assert_eq!(logins["artifacts.internal"].password, "hunter2");
+        assert_eq!(skipped, vec!["nologin.internal".to_string()]);
+    }
+
+    #[test]
+    fn test_authorization() {
+        let (logins, _) = parse_netrc("machine pypi.internal login ci password s3cret");
+        assert_eq!(
+            authorization(&logins, "PYPI.internal"),
+            Some("Basic Y2k6czNjcmV0".to_string())
+        );
+        assert_eq!(authorization(&logins, "pypi.example"), None);
+    }
+}
@@ -1,7 +1,98 @@
-#[macro_export]
-macro_rules! debug_print {
-    ($($arg:tt)*) => {
-        #[cfg(debug_assertions)]
-        eprintln!("{}:{}\n{}\n", file!(), line!(), format!($($arg)*));
-    };
+use {
+    crate::{cli::setting, evict::matches_pattern},
+    std::sync::OnceLock,
+    tracing::info,
+};
+
+pub const X_PROXY_DEBUG: &str = "X_PROXY_DEBUG";
+pub const X_PROXY_WIRE_LOG: &str = "X_PROXY_WIRE_LOG";
+pub const X_PROXY_WIRE_LOG_REDACT: &str = "X_PROXY_WIRE_LOG_REDACT";
+
+/* Headers that carry credentials are never written out in full */
+const ALWAYS_REDACTED: [&str; 5] = [
+    "Authorization",
+    "Proxy-Authorization",
+    "Cookie",
+    "Set-Cookie",
+    "X-Api-Key",
+];
+
+fn comma_separated(variable: &str) -> Vec<String> {
+    match setting(variable) {
+        Err(_) => Vec::new(),
+        Ok(s) => s
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    }
+}
+
+fn wire_log_patterns() -> &'static Vec<String> {
+    static PATTERNS: OnceLock<Vec<String>> = OnceLock::new();
+    PATTERNS.get_or_init(|| comma_separated(X_PROXY_WIRE_LOG))
+}
+
+fn redacted_headers() -> &'static Vec<String> {
+    static REDACTED: OnceLock<Vec<String>> = OnceLock::new();
+    REDACTED.get_or_init(|| {
+        let mut redacted: Vec<String> = ALWAYS_REDACTED.iter().map(|s| s.to_string()).collect();
+        redacted.extend(comma_separated(X_PROXY_WIRE_LOG_REDACT));
+        redacted
+    })
+}
+
+/// Replace the value of every header line named in `redacted`
+pub(crate) fn redact(header: &str, redacted: &[String]) -> String {
+    header
+        .split("\r\n")
+        .map(|line| match line.split_once(':') {
+            Some((name, _)) if redacted.iter().any(|r| r.eq_ignore_ascii_case(name.trim())) => {
+                format!("{name}: [redacted]")
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+/// Dump a raw HTTP header when `uri` matches one of the `X_PROXY_WIRE_LOG` patterns.
+/// `*` on its own logs every exchange.
+pub(crate) fn wire_log<F>(label: &str, uri: &str, header: F)
+where
+    F: FnOnce() -> String,
+{
+    let patterns = wire_log_patterns();
+    if patterns.is_empty() {
+        return;
+    }
+
+    let target = uri
+        .trim_start_matches("http://")
+        .trim_start_matches("https://");
+
+    if patterns
+        .iter()
+        .any(|p| matches_pattern(p, target) || matches_pattern(p, uri))
+    {
+        info!(
+            "{label} {uri}\n{}",
+            redact(header().trim_end(), redacted_headers())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let header = "GET / HTTP/1.1\r\nHost: example.com\r\nauthorization: Basic Zm9vOmJhcg==";
+        let redacted = redact(header, &["Authorization".to_string()]);
+        assert_eq!(
+            redacted,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nauthorization: [redacted]"
+        );
+    }
 }
@@ -0,0 +1,241 @@
+use {
+    crate::{
+        cli::setting,
+        digest::{remember_digest, BodyDigest},
+        http::X_PROXY_CACHE_PATH,
+        rules::cache_rule,
+    },
+    std::{
+        fs::Metadata,
+        io,
+        path::{Path, PathBuf},
+        sync::OnceLock,
+        time::Duration,
+    },
+    tokio::{
+        fs::{create_dir_all, hard_link, metadata, read_dir, remove_dir, remove_file, rename},
+        time::sleep,
+    },
+    tracing::{debug, error, info},
+};
+
+pub const X_PROXY_DEDUP: &str = "X_PROXY_DEDUP";
+
+/// Where bodies are kept by their SHA-256 in the cache root,
+/// host names can't start with a dot so it's never taken for one
+pub(crate) const BLOB_DIRECTORY_NAME: &str = ".blobs";
+
+/// How often bodies that no cache entry links to any more are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/* A blob is linked under this name next to itself before it's renamed over an entry */
+const LINKING_EXTENSION: &str = "linking";
+
+/// Whether cache entries with identical bodies are stored once, as hard links to a blob
+pub(crate) fn deduplicating() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| cfg!(unix) && setting(X_PROXY_DEDUP).is_ok_and(|s| s.trim() == "1"))
+}
+
+/// False when `X_PROXY_DEDUP` is set on a platform without link counts to know when a blob is unused
+pub(crate) fn setup_dedup() -> bool {
+    if !cfg!(unix) && setting(X_PROXY_DEDUP).is_ok() {
+        error!("'{X_PROXY_DEDUP}' is set but this platform doesn't count hard links");
+        return false;
+    }
+    if deduplicating() {
+        info!("storing identical bodies once");
+    }
+    true
+}
+
+/* How many names the file has, its blob and every cache entry sharing it */
+#[cfg(unix)]
+fn links(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.nlink())
+}
+
+#[cfg(not(unix))]
+fn links(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+fn blob_path(store_path: &Path, digest: &BodyDigest) -> PathBuf {
+    let hex = digest.hex();
+    store_path
+        .join(BLOB_DIRECTORY_NAME)
+        .join(&hex[..2])
+        .join(hex)
+}
+
+/// Make the cache entry at `path` a link to the blob with its body,
+/// or make it the blob when no other entry has the same body yet
+async fn share(store_path: &Path, path: &Path, digest: &BodyDigest) -> io::Result<()> {
+    let blob = blob_path(store_path, digest);
+    match metadata(&blob).await {
+        Ok(m) if m.is_file() && m.len() == digest.length => {
+            /* Renamed over the entry so clients already reading it carry on with their copy */
+            let linking = blob.with_extension(LINKING_EXTENSION);
+            hard_link(&blob, &linking).await?;
+            let renamed = rename(&linking, path).await;
+            /* Left behind when the entry was already the blob */
+            let _ = remove_file(&linking).await;
+            renamed
+        }
+        Ok(_) => {
+            /* Whatever's there isn't the body it's named after */
+            remove_file(&blob).await?;
+            hard_link(path, &blob).await
+        }
+        Err(_) => {
+            if let Some(parent) = blob.parent() {
+                create_dir_all(parent).await?;
+            }
+            hard_link(path, &blob).await
+        }
+    }
+}
+
+/// Store the body of a download that's just been kept only once, however many entries have it.
+/// Entries with a `ttl` rule keep a file of their own since their age is the file's age.
+pub(crate) async fn deduplicate(uri: &str, path: &Path, digest: &BodyDigest) {
+    if !deduplicating() || digest.length == 0 || cache_rule(uri).is_some_and(|r| r.ttl.is_some()) {
+        return;
+    }
+
+    let store_path = match setting(X_PROXY_CACHE_PATH) {
+        Ok(p) => PathBuf::from(p),
+        Err(_) => return,
+    };
+
+    match share(&store_path, path, digest).await {
+        Ok(_) => {
+            /* Linked to a blob that was already there the entry has the blob's modification time */
+            let modified = metadata(path).await.ok().and_then(|m| m.modified().ok());
+            remember_digest(path, *digest, modified);
+        }
+        Err(e) => debug!("Couldn't share the body of {uri}: {e}"),
+    }
+}
+
+/// Remove the cache entry at `path` if its body is shared,
+/// so writing a new one in its place leaves the other entries as they are
+pub(crate) async fn unshare(path: &Path) {
+    let shared = metadata(path)
+        .await
+        .ok()
+        .and_then(|m| links(&m))
+        .is_some_and(|n| n > 1);
+    if shared {
+        let _ = remove_file(path).await;
+    }
+}
+
+/// Remove the blobs no cache entry links to any more, returning how many files and bytes that was
+pub(crate) async fn prune_blobs(store_path: &Path) -> (u64, u64) {
+    let mut files = 0;
+    let mut bytes = 0;
+
+    let mut shards = match read_dir(store_path.join(BLOB_DIRECTORY_NAME)).await {
+        Ok(d) => d,
+        Err(_) => return (files, bytes),
+    };
+
+    while let Ok(Some(shard)) = shards.next_entry().await {
+        let mut blobs = match read_dir(shard.path()).await {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        while let Ok(Some(blob)) = blobs.next_entry().await {
+            let metadata = match blob.metadata().await {
+                Ok(m) if m.is_file() => m,
+                _ => continue,
+            };
+            let abandoned = blob
+                .path()
+                .extension()
+                .is_some_and(|e| e == LINKING_EXTENSION);
+            match links(&metadata) {
+                Some(1) if remove_file(blob.path()).await.is_ok() => {
+                    files += 1;
+                    bytes += metadata.len();
+                }
+                _ if abandoned => {
+                    let _ = remove_file(blob.path()).await;
+                }
+                _ => {}
+            }
+        }
+
+        let _ = remove_dir(shard.path()).await; /* Only succeeds once the shard is empty */
+    }
+
+    (files, bytes)
+}
+
+/// Every `PRUNE_INTERVAL`, remove the blobs of entries that have all been removed or replaced
+pub(crate) async fn dedup_loop(store_path: PathBuf) {
+    loop {
+        let (files, bytes) = prune_blobs(&store_path).await;
+        if files > 0 {
+            debug!("Removed {files} bodies no cache entry has any more ({bytes} bytes)");
+        }
+        sleep(PRUNE_INTERVAL).await;
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use {super::*, crate::digest::Sha256, crate::PKG_NAME, std::os::unix::fs::MetadataExt};
+
+    fn digest_of(body: &[u8]) -> BodyDigest {
+        let mut hasher = Sha256::default();
+        hasher.update(body);
+        BodyDigest {
+            length: body.len() as u64,
+            sha256: hasher.finish(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_share() {
+        let store_path = std::env::temp_dir().join(format!("{PKG_NAME}-test-dedup"));
+        let _ = tokio::fs::remove_dir_all(&store_path).await;
+        for host in ["a.example.com", "b.example.com"] {
+            create_dir_all(store_path.join(host)).await.unwrap();
+        }
+
+        let a = store_path.join("a.example.com").join("package");
+        let b = store_path.join("b.example.com").join("package");
+        let body = b"the same package from two mirrors";
+        tokio::fs::write(&a, body).await.unwrap();
+        tokio::fs::write(&b, body).await.unwrap();
+        let digest = digest_of(body);
+
+        share(&store_path, &a, &digest).await.unwrap();
+        share(&store_path, &b, &digest).await.unwrap();
+        /* Sharing again changes nothing */
+        share(&store_path, &b, &digest).await.unwrap();
+
+        let blob = blob_path(&store_path, &digest);
+        let (a_metadata, b_metadata) = (metadata(&a).await.unwrap(), metadata(&b).await.unwrap());
+        assert_eq!(a_metadata.ino(), b_metadata.ino());
+        assert_eq!(a_metadata.nlink(), 3);
+        assert!(!blob.with_extension(LINKING_EXTENSION).exists());
+
+        /* A new body written to one entry leaves the other alone */
+        unshare(&a).await;
+        assert!(!a.exists());
+        assert_eq!(tokio::fs::read(&b).await.unwrap(), body);
+        assert_eq!(prune_blobs(&store_path).await, (0, 0));
+
+        remove_file(&b).await.unwrap();
+        assert_eq!(prune_blobs(&store_path).await, (1, body.len() as u64));
+        assert!(!blob.exists());
+        assert!(!blob.parent().unwrap().exists());
+
+        let _ = tokio::fs::remove_dir_all(&store_path).await;
+    }
+}
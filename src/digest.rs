@@ -0,0 +1,439 @@
+use {
+    crate::cli::setting,
+    std::{
+        collections::HashMap,
+        io,
+        path::Path,
+        pin::Pin,
+        sync::{OnceLock, RwLock},
+        task::{Context, Poll},
+        time::SystemTime,
+    },
+    tokio::io::{AsyncReadExt, AsyncWrite},
+    tracing::info,
+};
+
+pub const X_PROXY_QUARANTINE_DIGESTS: &str = "X_PROXY_QUARANTINE_DIGESTS";
+
+/// Upper bound on remembered digests, older files are simply hashed again by their next download
+const MAX_DIGESTS: usize = 4096;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// A SHA-256 that's fed a body as it streams past instead of reading it back from disk
+#[derive(Clone)]
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    filled: usize,
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            filled: 0,
+            length: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        while !data.is_empty() {
+            let n = (64 - self.filled).min(data.len());
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+
+            if self.filled == 64 {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// How many bytes have been hashed so far
+    pub(crate) fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub(crate) fn finish(mut self) -> [u8; 32] {
+        let bits = self.length.wrapping_mul(8);
+
+        self.block[self.filled] = 0x80;
+        self.block[self.filled + 1..].fill(0);
+        if self.filled >= 56 {
+            self.compress();
+            self.block.fill(0);
+        }
+        self.block[56..].copy_from_slice(&bits.to_be_bytes());
+        self.compress();
+
+        let mut digest = [0u8; 32];
+        for (bytes, s) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&s.to_be_bytes());
+        }
+        digest
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hash a file that's already on disk, for a download that carries on from part of one
+pub(crate) async fn hash_file(path: &Path) -> Option<Sha256> {
+    let mut file = tokio::fs::File::open(path).await.ok()?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0; crate::http::BUFFER_SIZE];
+
+    loop {
+        match file.read(&mut buffer).await.ok()? {
+            0 => return Some(hasher),
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+}
+
+/// Hashes everything written to the cache file it wraps
+pub(crate) struct Digesting<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> Digesting<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self::resume(inner, Sha256::default())
+    }
+
+    /// Carry on hashing after the bytes `hasher` has already seen
+    pub(crate) fn resume(inner: W, hasher: Sha256) -> Self {
+        Digesting { inner, hasher }
+    }
+
+    pub(crate) fn finish(self) -> BodyDigest {
+        BodyDigest {
+            length: self.hasher.length(),
+            sha256: self.hasher.finish(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Digesting<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.hasher.update(&buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct BodyDigest {
+    pub(crate) length: u64,
+    pub(crate) sha256: [u8; 32],
+}
+
+impl BodyDigest {
+    pub(crate) fn hex(&self) -> String {
+        to_hex(&self.sha256)
+    }
+}
+
+/// A body that has just been written to the cache in full
+pub(crate) struct Download<'a> {
+    pub(crate) uri: &'a str,
+    pub(crate) path: &'a Path,
+    pub(crate) digest: &'a BodyDigest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Keep,
+    /// Take the file out of the cache so it's never served again
+    Quarantine,
+}
+
+/// Middleware told about every completed download along with its digest.
+/// Hooks run on the download's own task so they should decide quickly.
+pub(crate) trait DownloadHook: Send + Sync {
+    fn downloaded(&self, download: &Download) -> Verdict;
+}
+
+fn hooks() -> &'static RwLock<Vec<Box<dyn DownloadHook>>> {
+    static HOOKS: OnceLock<RwLock<Vec<Box<dyn DownloadHook>>>> = OnceLock::new();
+    HOOKS.get_or_init(|| RwLock::new(Vec::new()))
+}
+
+pub(crate) fn register_download_hook(hook: Box<dyn DownloadHook>) {
+    if let Ok(mut hooks) = hooks().write() {
+        hooks.push(hook);
+    }
+}
+
+/// Quarantines downloads whose digest is on a list, the list comes from `X_PROXY_QUARANTINE_DIGESTS`
+struct Blocklist {
+    digests: Vec<String>,
+}
+
+impl DownloadHook for Blocklist {
+    fn downloaded(&self, download: &Download) -> Verdict {
+        let digest = download.digest.hex();
+        match self.digests.contains(&digest) {
+            true => Verdict::Quarantine,
+            false => Verdict::Keep,
+        }
+    }
+}
+
+fn parse_digests(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|d| d.trim().to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+/// Register the built in hooks that have been configured
+pub(crate) fn setup_download_hooks() {
+    if let Ok(value) = setting(X_PROXY_QUARANTINE_DIGESTS) {
+        let digests = parse_digests(&value);
+        if !digests.is_empty() {
+            info!("quarantining {} digest(s)", digests.len());
+            register_download_hook(Box::new(Blocklist { digests }));
+        }
+    }
+}
+
+/// Tell every hook about a download, any one of them can quarantine it.
+/// Kept files have their digest remembered so it can be served with them.
+pub(crate) fn inspect_download(download: &Download, modified: Option<SystemTime>) -> Verdict {
+    let verdict = match hooks().read() {
+        Ok(hooks) => match hooks
+            .iter()
+            .any(|h| h.downloaded(download) == Verdict::Quarantine)
+        {
+            true => Verdict::Quarantine,
+            false => Verdict::Keep,
+        },
+        Err(_) => Verdict::Keep,
+    };
+
+    match verdict {
+        Verdict::Keep => remember_digest(download.path, *download.digest, modified),
+        Verdict::Quarantine => {
+            info!(
+                "quarantined {} with SHA-256 {}",
+                download.uri,
+                download.digest.hex()
+            );
+            forget_digest(download.path);
+        }
+    }
+
+    verdict
+}
+
+struct DigestMetadata {
+    digest: BodyDigest,
+    modified: Option<SystemTime>,
+}
+
+/* Keyed by cache file path, like the head table */
+fn digest_table() -> &'static RwLock<HashMap<String, DigestMetadata>> {
+    static DIGESTS: OnceLock<RwLock<HashMap<String, DigestMetadata>>> = OnceLock::new();
+    DIGESTS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+pub(crate) fn remember_digest(path: &Path, digest: BodyDigest, modified: Option<SystemTime>) {
+    let mut digests = match digest_table().write() {
+        Ok(d) => d,
+        Err(_) => return,
+    };
+
+    let key = path.to_string_lossy().to_string();
+    if digests.len() >= MAX_DIGESTS && !digests.contains_key(&key) {
+        if let Some(any) = digests.keys().next().cloned() {
+            digests.remove(&any);
+        }
+    }
+
+    digests.insert(key, DigestMetadata { digest, modified });
+}
+
+pub(crate) fn forget_digest(path: &Path) {
+    if let Ok(mut digests) = digest_table().write() {
+        digests.remove(path.to_string_lossy().as_ref());
+    }
+}
+
+/// The digest taken when the file at `path` was downloaded,
+/// nothing if it's since changed length or modification time
+pub(crate) fn recall_digest(
+    path: &Path,
+    length: u64,
+    modified: Option<SystemTime>,
+) -> Option<BodyDigest> {
+    let digests = digest_table().read().ok()?;
+    let metadata = digests.get(path.to_string_lossy().as_ref())?;
+
+    match metadata.digest.length == length && metadata.modified == modified {
+        true => Some(metadata.digest),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, tokio::io::AsyncWriteExt};
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::default();
+        hasher.update(data);
+        to_hex(&hasher.finish())
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+
+        /* Fed in uneven pieces it comes out the same */
+        let mut hasher = Sha256::default();
+        for piece in [
+            b"abcdbcdecdefdefgefgh".as_slice(),
+            b"fghighijhijkijkljklmklmnlmnomnopnopq",
+        ] {
+            hasher.update(piece);
+        }
+        assert_eq!(
+            to_hex(&hasher.finish()),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_digesting() {
+        let mut written = Vec::new();
+        let mut body = Digesting::new(&mut written);
+        body.write_all(b"ab").await.unwrap();
+        body.write_all(b"c").await.unwrap();
+        let digest = body.finish();
+
+        assert_eq!(written, b"abc");
+        assert_eq!(digest.length, 3);
+        assert_eq!(
+            digest.hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let digest = BodyDigest {
+            length: 3,
+            sha256: [0xab; 32],
+        };
+        let download = Download {
+            uri: "http://example.com/a",
+            path: Path::new("/tmp/a"),
+            digest: &digest,
+        };
+
+        let blocklist = Blocklist {
+            digests: parse_digests(&format!(" {} ,", "AB".repeat(32))),
+        };
+        assert_eq!(blocklist.downloaded(&download), Verdict::Quarantine);
+
+        let blocklist = Blocklist {
+            digests: parse_digests(&"cd".repeat(32)),
+        };
+        assert_eq!(blocklist.downloaded(&download), Verdict::Keep);
+    }
+}
@@ -0,0 +1,693 @@
+use {
+    crate::{cli::setting, PKG_NAME},
+    std::net::{IpAddr, Ipv4Addr, SocketAddr},
+    tokio::{
+        net::UdpSocket,
+        time::{sleep, Duration},
+    },
+    tracing::{debug, error, info},
+};
+
+pub const X_PROXY_DISCOVERY: &str = "X_PROXY_DISCOVERY";
+pub const X_PROXY_DISCOVERY_SERVICES: &str = "X_PROXY_DISCOVERY_SERVICES";
+pub const X_PROXY_DISCOVERY_TXT: &str = "X_PROXY_DISCOVERY_TXT";
+
+const MDNS_ADDRESS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// What `squid-deb-proxy-client` and `auto-apt-proxy` browse for, announced unless others are asked for
+const SERVICE_TYPE: &str = "_apt_proxy._tcp.local";
+/// Lists every service type on the network for browsers such as `avahi-browse -a`
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+const RECORD_A: u16 = 1;
+const RECORD_PTR: u16 = 12;
+const RECORD_TXT: u16 = 16;
+const RECORD_SRV: u16 = 33;
+const RECORD_ANY: u16 = 255;
+
+/* RFC 6762 section 10, records naming a host are kept short in case its address changes */
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/* Section 6.7, legacy resolvers don't expect to cache answers for long */
+const LEGACY_TTL: u32 = 10;
+
+/// Whether rproxy should announce itself on the local network, off unless `X_PROXY_DISCOVERY` switches it on
+fn discovery_enabled() -> bool {
+    match setting(X_PROXY_DISCOVERY) {
+        Ok(v) => matches!(v.trim(), "1" | "true" | "on"),
+        Err(_) => false,
+    }
+}
+
+/// The records rproxy answers mDNS questions with
+#[derive(Debug)]
+pub(crate) struct Announcement {
+    /// Shown to people browsing for services, the first label of the instance name
+    instance: String,
+    /// The `.local` name of this machine
+    host: String,
+    address: Ipv4Addr,
+    port: u16,
+    /// Service types rproxy is an instance of, each ending in `.local`
+    services: Vec<String>,
+    /// Strings of the TXT record, the same for every service type
+    txt: Vec<String>,
+}
+
+/* RFC 6763 section 7, an underscore, a short name, then the protocol.
+ * The name should only be letters, digits and hyphens but `_apt_proxy` came before anyone checked */
+fn parse_service(service: &str) -> Option<String> {
+    let service = service.trim().trim_end_matches('.');
+    let service = service.strip_suffix(".local").unwrap_or(service);
+    let (name, protocol) = service.split_once('.')?;
+    let name = name.strip_prefix('_')?;
+
+    let valid = !name.is_empty()
+        && name.len() <= 15
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && matches!(protocol.to_ascii_lowercase().as_str(), "_tcp" | "_udp");
+
+    match valid {
+        true => Some(format!("_{name}.{}.local", protocol.to_ascii_lowercase())),
+        false => None,
+    }
+}
+
+/// Service types from `X_PROXY_DISCOVERY_SERVICES`, comma separated, or just `_apt_proxy._tcp`
+fn parse_services(value: &str) -> Option<Vec<String>> {
+    let mut services = Vec::new();
+    for service in value.split(',').filter(|s| !s.trim().is_empty()) {
+        let service = parse_service(service)?;
+        if !services.contains(&service) {
+            services.push(service);
+        }
+    }
+
+    match services.is_empty() {
+        true => None,
+        false => Some(services),
+    }
+}
+
+/// TXT strings from `X_PROXY_DISCOVERY_TXT`, comma separated. `port`, `https` and `ca` are filled in
+/// with what rproxy knows about itself, anything else is given as `key=value` and announced as is
+#[cfg_attr(not(feature = "https"), allow(unused_variables))]
+fn parse_txt(value: &str, address: Ipv4Addr, port: u16) -> Result<Vec<String>, String> {
+    let mut txt = Vec::new();
+    for entry in value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let entry = match entry {
+            "port" => format!("port={port}"),
+            "https" => {
+                #[cfg(feature = "https")]
+                let https = crate::cert::intercepting();
+                #[cfg(not(feature = "https"))]
+                let https = false;
+                format!("https={}", https as u8)
+            }
+            #[cfg(feature = "https")]
+            "ca" => format!("ca=http://{address}:{port}/?ca.crt"),
+            #[cfg(not(feature = "https"))]
+            "ca" => continue,
+            e if e.starts_with('=') || !e.contains('=') => return Err(e.to_string()),
+            e if e.len() > 255 || !e.is_ascii() => return Err(e.to_string()),
+            e => e.to_string(),
+        };
+
+        /* Section 6.4, a key appearing twice means nothing after the first */
+        let key = |t: &str| t.split('=').next().unwrap_or_default().to_ascii_lowercase();
+        if txt.iter().any(|t: &String| key(t) == key(&entry)) {
+            return Err(entry);
+        }
+        txt.push(entry);
+    }
+
+    Ok(txt)
+}
+
+/* Host names become a DNS label so anything else is left out */
+fn host_label(name: &str) -> Option<String> {
+    let label: String = name
+        .trim()
+        .split('.')
+        .next()?
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .take(63)
+        .collect();
+
+    match label.is_empty() {
+        true => None,
+        false => Some(label),
+    }
+}
+
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|p| std::fs::read_to_string(p).ok())
+        .find_map(|n| host_label(&n))
+        .unwrap_or_else(|| PKG_NAME.to_string())
+}
+
+/* Listening on every address doesn't say which one the LAN can reach,
+ * the interface a multicast packet would leave through is the best guess */
+fn lan_address(listen: SocketAddr) -> Option<Ipv4Addr> {
+    match listen.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() && !ip.is_loopback() => return Some(ip),
+        IpAddr::V4(ip) if ip.is_loopback() => return None,
+        IpAddr::V6(ip) if !ip.is_unspecified() => return None,
+        _ => {}
+    }
+
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_ADDRESS, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+/// What to announce, `None` when discovery is off or there's no address the LAN could reach
+pub(crate) fn discovery_announcement(listen: SocketAddr) -> Option<Announcement> {
+    if !discovery_enabled() {
+        return None;
+    }
+
+    let address = match lan_address(listen) {
+        Some(a) => a,
+        None => {
+            error!(
+                "'{X_PROXY_DISCOVERY}' needs rproxy to listen on an IPv4 address the LAN can reach"
+            );
+            return None;
+        }
+    };
+
+    let services = match setting(X_PROXY_DISCOVERY_SERVICES) {
+        Err(_) => vec![SERVICE_TYPE.to_string()],
+        Ok(v) => match parse_services(&v) {
+            Some(s) => s,
+            None => {
+                error!("'{X_PROXY_DISCOVERY_SERVICES}' is not a list of service types: '{v}'");
+                return None;
+            }
+        },
+    };
+
+    let txt = match setting(X_PROXY_DISCOVERY_TXT) {
+        Err(_) => Vec::new(),
+        Ok(v) => match parse_txt(&v, address, listen.port()) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("'{X_PROXY_DISCOVERY_TXT}' can't announce '{e}'");
+                return None;
+            }
+        },
+    };
+
+    let host = hostname();
+    Some(Announcement {
+        instance: format!("{PKG_NAME} on {host}"),
+        host: format!("{host}.local"),
+        address,
+        port: listen.port(),
+        services,
+        txt,
+    })
+}
+
+fn push_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        packet.push(label.len().min(63) as u8);
+        packet.extend_from_slice(&label.as_bytes()[..label.len().min(63)]);
+    }
+    packet.push(0);
+}
+
+fn read_name(packet: &[u8], mut i: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+
+    /* Compression pointers can only go so deep before they must be a loop */
+    for _ in 0..32 {
+        let length = *packet.get(i)? as usize;
+        match length {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(i + 1)));
+            }
+            l if l & 0xC0 == 0xC0 => {
+                let pointer = (l & 0x3F) << 8 | *packet.get(i + 1)? as usize;
+                end.get_or_insert(i + 2);
+                i = pointer;
+            }
+            l => {
+                let label = packet.get(i + 1..i + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                i += l + 1;
+            }
+        }
+    }
+
+    None
+}
+
+/// The ID and questions of an mDNS query, `None` for responses and anything malformed
+fn parse_query(packet: &[u8]) -> Option<(u16, Vec<(String, u16)>)> {
+    let header = packet.get(..12)?;
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let count = u16::from_be_bytes([header[4], header[5]]);
+    let mut questions = Vec::new();
+    let mut i = 12;
+
+    for _ in 0..count {
+        let (name, next) = read_name(packet, i)?;
+        let record = u16::from_be_bytes([*packet.get(next)?, *packet.get(next + 1)?]);
+        questions.push((name, record));
+        i = next + 4;
+    }
+
+    Some((id, questions))
+}
+
+struct Record {
+    name: String,
+    record: u16,
+    /// Records only rproxy can answer for replace any a cache has rather than add to them
+    unique: bool,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+impl Announcement {
+    fn instance_name(&self, service: &str) -> String {
+        format!("{}.{service}", self.instance)
+    }
+
+    fn ptr(&self, service: &str) -> Record {
+        let mut data = Vec::new();
+        push_name(&mut data, &self.instance_name(service));
+        Record {
+            name: service.to_string(),
+            record: RECORD_PTR,
+            unique: false,
+            ttl: SERVICE_TTL,
+            data,
+        }
+    }
+
+    fn service_types(&self, service: &str) -> Record {
+        let mut data = Vec::new();
+        push_name(&mut data, service);
+        Record {
+            name: SERVICE_TYPES.to_string(),
+            record: RECORD_PTR,
+            unique: false,
+            ttl: SERVICE_TTL,
+            data,
+        }
+    }
+
+    fn srv(&self, service: &str) -> Record {
+        /* Priority and weight mean nothing with only one proxy to choose from */
+        let mut data = vec![0, 0, 0, 0];
+        data.extend_from_slice(&self.port.to_be_bytes());
+        push_name(&mut data, &self.host);
+        Record {
+            name: self.instance_name(service),
+            record: RECORD_SRV,
+            unique: true,
+            ttl: HOST_TTL,
+            data,
+        }
+    }
+
+    fn txt(&self, service: &str) -> Record {
+        let mut data = Vec::new();
+        for entry in &self.txt {
+            data.push(entry.len() as u8);
+            data.extend_from_slice(entry.as_bytes());
+        }
+
+        /* DNS-SD wants a TXT record even when there's nothing to say, an empty string */
+        if data.is_empty() {
+            data.push(0);
+        }
+
+        Record {
+            name: self.instance_name(service),
+            record: RECORD_TXT,
+            unique: true,
+            ttl: SERVICE_TTL,
+            data,
+        }
+    }
+
+    fn a(&self) -> Record {
+        Record {
+            name: self.host.clone(),
+            record: RECORD_A,
+            unique: true,
+            ttl: HOST_TTL,
+            data: self.address.octets().to_vec(),
+        }
+    }
+
+    /// Answers to the questions rproxy knows about with the records a client will ask for next
+    fn answers(&self, questions: &[(String, u16)]) -> (Vec<Record>, Vec<Record>) {
+        let mut answers = Vec::new();
+        let mut additional = Vec::new();
+        let wants = |record: u16, wanted: u16| record == wanted || record == RECORD_ANY;
+
+        for (name, record) in questions {
+            if name.eq_ignore_ascii_case(SERVICE_TYPES) && wants(*record, RECORD_PTR) {
+                answers.extend(self.services.iter().map(|s| self.service_types(s)));
+            } else if name.eq_ignore_ascii_case(&self.host) && wants(*record, RECORD_A) {
+                answers.push(self.a());
+            }
+
+            for service in &self.services {
+                if name.eq_ignore_ascii_case(service) && wants(*record, RECORD_PTR) {
+                    answers.push(self.ptr(service));
+                    additional.extend([self.srv(service), self.txt(service), self.a()]);
+                } else if name.eq_ignore_ascii_case(&self.instance_name(service)) {
+                    if wants(*record, RECORD_SRV) {
+                        answers.push(self.srv(service));
+                        additional.push(self.a());
+                    }
+                    if wants(*record, RECORD_TXT) {
+                        answers.push(self.txt(service));
+                    }
+                }
+            }
+        }
+
+        /* Nothing needs saying twice */
+        additional.retain(|a| {
+            !answers
+                .iter()
+                .any(|b| b.name == a.name && b.record == a.record)
+        });
+        let mut seen = Vec::new();
+        additional.retain(|a| match seen.contains(&(a.name.clone(), a.record)) {
+            true => false,
+            false => {
+                seen.push((a.name.clone(), a.record));
+                true
+            }
+        });
+        (answers, additional)
+    }
+
+    /// A response to a query, `legacy` for one sent from a port other than 5353 by a plain
+    /// DNS resolver which expects its ID and questions back and can't cope with cache flushes
+    fn respond(&self, id: u16, questions: &[(String, u16)], legacy: bool) -> Option<Vec<u8>> {
+        let (answers, additional) = self.answers(questions);
+        if answers.is_empty() {
+            return None;
+        }
+
+        let mut packet = Vec::with_capacity(512);
+        packet.extend_from_slice(&if legacy { id } else { 0 }.to_be_bytes());
+        packet.extend_from_slice(&[0x84, 0x00]); /* Authoritative response */
+        let echoed = if legacy { questions.len() } else { 0 };
+        for count in [echoed, answers.len(), 0, additional.len()] {
+            packet.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+
+        if legacy {
+            for (name, record) in questions {
+                push_name(&mut packet, name);
+                packet.extend_from_slice(&record.to_be_bytes());
+                packet.extend_from_slice(&[0, 1]);
+            }
+        }
+
+        for record in answers.iter().chain(additional.iter()) {
+            push_name(&mut packet, &record.name);
+            packet.extend_from_slice(&record.record.to_be_bytes());
+            let class: u16 = match record.unique && !legacy {
+                true => 0x8001,
+                false => 0x0001,
+            };
+            packet.extend_from_slice(&class.to_be_bytes());
+            let ttl = match legacy {
+                true => record.ttl.min(LEGACY_TTL),
+                false => record.ttl,
+            };
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&record.data);
+        }
+
+        Some(packet)
+    }
+}
+
+fn mdns_socket() -> std::io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, MDNS_PORT))?;
+    socket.join_multicast_v4(&MDNS_ADDRESS, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Announce rproxy as each of its service types with mDNS and DNS-SD, then answer anyone looking for one
+pub(crate) async fn discovery_loop(announcement: Announcement) {
+    let socket = match mdns_socket() {
+        Ok(s) => s,
+        Err(e) => {
+            error!(
+                "couldn't listen for mDNS on port {MDNS_PORT}, is another responder such as Avahi using it? {e}"
+            );
+            return;
+        }
+    };
+
+    info!(
+        "announcing '{}' at {}:{} on the LAN as {}",
+        announcement.instance,
+        announcement.address,
+        announcement.port,
+        announcement.services.join(", ")
+    );
+
+    /* Section 8.3, announced twice a second apart so a lost packet doesn't go unnoticed */
+    let unsolicited: Vec<(String, u16)> = announcement
+        .services
+        .iter()
+        .map(|s| (s.clone(), RECORD_PTR))
+        .collect();
+    if let Some(packet) = announcement.respond(0, &unsolicited, false) {
+        for _ in 0..2 {
+            let _ = socket.send_to(&packet, (MDNS_ADDRESS, MDNS_PORT)).await;
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    let mut buffer = [0u8; 9000];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buffer).await {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+
+        let (id, questions) = match parse_query(&buffer[..length]) {
+            Some(q) => q,
+            None => continue,
+        };
+
+        let legacy = from.port() != MDNS_PORT;
+        if let Some(packet) = announcement.respond(id, &questions, legacy) {
+            debug!("Answering mDNS query from {from}");
+            let to = match legacy {
+                true => from,
+                false => SocketAddr::from((MDNS_ADDRESS, MDNS_PORT)),
+            };
+            let _ = socket.send_to(&packet, to).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement() -> Announcement {
+        Announcement {
+            instance: "rproxy on nas".to_string(),
+            host: "nas.local".to_string(),
+            address: Ipv4Addr::new(192, 168, 1, 2),
+            port: 3142,
+            services: vec![SERVICE_TYPE.to_string()],
+            txt: Vec::new(),
+        }
+    }
+
+    fn query(id: u16, questions: &[(&str, u16)]) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0, 0, 0, questions.len() as u8, 0, 0, 0, 0, 0, 0]);
+        for (name, record) in questions {
+            push_name(&mut packet, name);
+            packet.extend_from_slice(&record.to_be_bytes());
+            packet.extend_from_slice(&[0, 1]);
+        }
+        packet
+    }
+
+    #[test]
+    fn test_host_label() {
+        assert_eq!(host_label("nas\n").as_deref(), Some("nas"));
+        assert_eq!(host_label("build-01.lan").as_deref(), Some("build-01"));
+        assert_eq!(host_label("my box").as_deref(), Some("mybox"));
+        assert_eq!(host_label("\n"), None);
+    }
+
+    #[test]
+    fn test_parse_services() {
+        assert_eq!(
+            parse_services("_apt_proxy._tcp, _HTTP._TCP.local.,_apt_proxy._tcp.local"),
+            Some(vec![
+                SERVICE_TYPE.to_string(),
+                "_HTTP._tcp.local".to_string()
+            ])
+        );
+        assert_eq!(parse_services(""), None);
+        assert_eq!(parse_services("apt_proxy._tcp"), None);
+        assert_eq!(parse_services("_apt_proxy._sctp"), None);
+        assert_eq!(parse_services("_a_name_far_too_long._tcp"), None);
+    }
+
+    #[test]
+    fn test_parse_txt() {
+        let address = Ipv4Addr::new(192, 168, 1, 2);
+        let txt = parse_txt("port, site=lab,path=/", address, 3142).unwrap();
+        assert_eq!(txt, vec!["port=3142", "site=lab", "path=/"]);
+
+        #[cfg(feature = "https")]
+        assert_eq!(
+            parse_txt("ca", address, 3142).unwrap(),
+            vec!["ca=http://192.168.1.2:3142/?ca.crt"]
+        );
+
+        assert_eq!(parse_txt("", address, 3142).unwrap(), Vec::<String>::new());
+        assert!(parse_txt("site", address, 3142).is_err());
+        assert!(parse_txt("=lab", address, 3142).is_err());
+        assert!(parse_txt("port,PORT=80", address, 3142).is_err());
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let packet = query(7, &[(SERVICE_TYPE, RECORD_PTR), ("nas.local", RECORD_A)]);
+        assert_eq!(
+            parse_query(&packet),
+            Some((
+                7,
+                vec![
+                    (SERVICE_TYPE.to_string(), RECORD_PTR),
+                    ("nas.local".to_string(), RECORD_A)
+                ]
+            ))
+        );
+
+        /* The second question points back at "local" in the first */
+        let mut packet = query(0, &[("nas.local", RECORD_A)]);
+        packet[5] = 2;
+        packet.extend_from_slice(&[3, b'b', b'o', b'x', 0xC0, 16, 0, 1, 0, 1]);
+        assert_eq!(
+            parse_query(&packet).unwrap().1[1],
+            ("box.local".to_string(), RECORD_A)
+        );
+
+        /* A pointer to itself */
+        let mut packet = query(0, &[]);
+        packet[5] = 1;
+        packet.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        assert_eq!(parse_query(&packet), None);
+
+        let mut response = query(0, &[(SERVICE_TYPE, RECORD_PTR)]);
+        response[2] = 0x84;
+        assert_eq!(parse_query(&response), None);
+    }
+
+    #[test]
+    fn test_answers() {
+        let announcement = announcement();
+
+        let (answers, additional) = announcement.answers(&[(SERVICE_TYPE.to_string(), RECORD_PTR)]);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            read_name(&answers[0].data, 0).unwrap().0,
+            "rproxy on nas._apt_proxy._tcp.local"
+        );
+        let kinds: Vec<u16> = additional.iter().map(|r| r.record).collect();
+        assert_eq!(kinds, vec![RECORD_SRV, RECORD_TXT, RECORD_A]);
+        assert_eq!(&additional[0].data[4..6], &3142u16.to_be_bytes());
+        assert_eq!(additional[2].data, vec![192, 168, 1, 2]);
+
+        let (answers, additional) = announcement.answers(&[(
+            "rproxy on nas._apt_proxy._tcp.local".to_string(),
+            RECORD_ANY,
+        )]);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(additional.len(), 1);
+
+        assert!(announcement
+            .respond(0, &[("_http._tcp.local".to_string(), RECORD_PTR)], false)
+            .is_none());
+    }
+
+    #[test]
+    fn test_answers_services() {
+        let announcement = Announcement {
+            services: vec![SERVICE_TYPE.to_string(), "_http._tcp.local".to_string()],
+            txt: vec!["port=3142".to_string(), "site=lab".to_string()],
+            ..announcement()
+        };
+
+        let (answers, _) = announcement.answers(&[(SERVICE_TYPES.to_string(), RECORD_PTR)]);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(
+            read_name(&answers[1].data, 0).unwrap().0,
+            "_http._tcp.local"
+        );
+
+        /* Both service types asked for at once share one address record */
+        let (answers, additional) = announcement.answers(&[
+            (SERVICE_TYPE.to_string(), RECORD_PTR),
+            ("_http._tcp.local".to_string(), RECORD_PTR),
+        ]);
+        assert_eq!(answers.len(), 2);
+        let kinds: Vec<u16> = additional.iter().map(|r| r.record).collect();
+        assert_eq!(
+            kinds,
+            vec![RECORD_SRV, RECORD_TXT, RECORD_A, RECORD_SRV, RECORD_TXT]
+        );
+        assert_eq!(additional[4].name, "rproxy on nas._http._tcp.local");
+        assert_eq!(additional[4].data, b"\x09port=3142\x08site=lab".to_vec());
+    }
+
+    #[test]
+    fn test_respond_legacy() {
+        let questions = vec![("NAS.local".to_string(), RECORD_A)];
+        let packet = announcement().respond(42, &questions, true).unwrap();
+
+        assert_eq!(&packet[..4], &[0, 42, 0x84, 0]);
+        assert_eq!(&packet[4..8], &[0, 1, 0, 1]);
+        let (name, i) = read_name(&packet, 12).unwrap();
+        assert_eq!(name, "NAS.local");
+        let (name, i) = read_name(&packet, i + 4).unwrap();
+        assert_eq!(name, "nas.local");
+        /* Class without a cache flush and a short TTL */
+        assert_eq!(&packet[i + 2..i + 8], &[0, 1, 0, 0, 0, LEGACY_TTL as u8]);
+        assert_eq!(&packet[packet.len() - 4..], &[192, 168, 1, 2]);
+    }
+}
@@ -0,0 +1,283 @@
+use {
+    crate::{cli::setting, egress::egress_udp, idn::to_ascii},
+    std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+        io::{self, Read},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    },
+    tokio::{
+        net::lookup_host,
+        time::{timeout, Duration},
+    },
+    tracing::debug,
+};
+
+pub const X_PROXY_DNS_TIMEOUT: &str = "X_PROXY_DNS_TIMEOUT";
+pub const X_PROXY_DNS_SERVERS: &str = "X_PROXY_DNS_SERVERS";
+
+const DNS_TIMEOUT_SECONDS: u64 = 5;
+const DNS_PORT: u16 = 53;
+const RECORD_A: u16 = 1;
+const RECORD_AAAA: u16 = 28;
+
+fn dns_timeout() -> Duration {
+    let seconds = setting(X_PROXY_DNS_TIMEOUT)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DNS_TIMEOUT_SECONDS);
+
+    Duration::from_secs(seconds)
+}
+
+/// Fallback resolvers are a comma separated list of addresses, the port defaults to 53
+fn dns_servers() -> Vec<SocketAddr> {
+    match setting(X_PROXY_DNS_SERVERS) {
+        Err(_) => Vec::new(),
+        Ok(s) => s
+            .split(',')
+            .map(|s| s.trim())
+            .filter_map(|s| match s.parse::<SocketAddr>() {
+                Ok(a) => Some(a),
+                Err(_) => s
+                    .parse::<IpAddr>()
+                    .ok()
+                    .map(|ip| SocketAddr::new(ip, DNS_PORT)),
+            })
+            .collect(),
+    }
+}
+
+pub(crate) fn build_query(id: u16, host: &str, record: u16) -> Option<Vec<u8>> {
+    let mut packet = Vec::with_capacity(host.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0x00]); /* Standard query, recursion desired */
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); /* One question */
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&record.to_be_bytes());
+    packet.extend_from_slice(&[0, 1]); /* Internet class */
+    Some(packet)
+}
+
+fn skip_name(packet: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let length = *packet.get(i)? as usize;
+        match length {
+            0 => return Some(i + 1),
+            l if l & 0xC0 == 0xC0 => return Some(i + 2), /* Compressed name pointer */
+            l => i += l + 1,
+        }
+    }
+}
+
+/* A reply can be forged by anyone who guesses the ID, so it comes from the OS's random source */
+fn query_id() -> u16 {
+    let mut bytes = [0u8; 2];
+    match std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)) {
+        Ok(_) => u16::from_be_bytes(bytes),
+        /* Seeded randomly by the standard library on every platform */
+        Err(_) => RandomState::new().build_hasher().finish() as u16,
+    }
+}
+
+/// Whether `packet` is a reply to `query`, with its ID and the same single question
+fn answers(query: &[u8], packet: &[u8]) -> bool {
+    let question = &query[12..];
+    packet.len() >= query.len()
+        && packet[..2] == query[..2]
+        && packet[2] & 0x80 != 0 /* A response rather than a query */
+        && packet[4..6] == [0, 1]
+        && packet[12..query.len()].eq_ignore_ascii_case(question)
+}
+
+pub(crate) fn parse_response(id: u16, packet: &[u8]) -> Option<Vec<IpAddr>> {
+    if packet.len() < 12 || u16::from_be_bytes([packet[0], packet[1]]) != id {
+        return None;
+    }
+
+    if packet[3] & 0x0F != 0 {
+        return None; /* The server reported an error */
+    }
+
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let answers = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut i = 12;
+    for _ in 0..questions {
+        i = skip_name(packet, i)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        i = skip_name(packet, i)?;
+        let record = u16::from_be_bytes([*packet.get(i)?, *packet.get(i + 1)?]);
+        let length = u16::from_be_bytes([*packet.get(i + 8)?, *packet.get(i + 9)?]) as usize;
+        i += 10;
+        let data = packet.get(i..i + length)?;
+
+        match (record, length) {
+            (RECORD_A, 4) => addresses.push(IpAddr::V4(Ipv4Addr::new(
+                data[0], data[1], data[2], data[3],
+            ))),
+            (RECORD_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => {}
+        }
+        i += length;
+    }
+
+    Some(addresses)
+}
+
+async fn query_server(server: SocketAddr, host: &str, record: u16) -> Option<Vec<IpAddr>> {
+    let bind = match server {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+
+    let id = query_id();
+    let query = build_query(id, host, record)?;
+    let socket = egress_udp(bind).await.ok()?;
+    socket.send_to(&query, server).await.ok()?;
+
+    /* Anything else that arrives on the port is ignored rather than taken for the answer */
+    let mut buffer = vec![0u8; 1500];
+    let n = timeout(dns_timeout(), async {
+        loop {
+            let (n, from) = socket.recv_from(&mut buffer).await.ok()?;
+            if from == server && answers(&query, &buffer[..n]) {
+                return Some(n);
+            }
+        }
+    })
+    .await
+    .ok()??;
+
+    parse_response(id, &buffer[..n])
+}
+
+/// Resolve `host` with the system resolver, giving up after `X_PROXY_DNS_TIMEOUT` seconds
+/// and asking each of the `X_PROXY_DNS_SERVERS` in turn instead
+pub(crate) async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let bare_host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = bare_host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let host = match to_ascii(host) {
+        Some(h) => h,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{host}' isn't a name that can be looked up"),
+            ))
+        }
+    };
+    let host = host.as_str();
+
+    let error = match timeout(dns_timeout(), lookup_host((host, port))).await {
+        Ok(Ok(a)) => {
+            let addresses: Vec<SocketAddr> = a.collect();
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses found for '{host}'"),
+            )
+        }
+        Ok(Err(e)) => e,
+        Err(_) => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out resolving '{host}'"),
+        ),
+    };
+
+    for server in dns_servers() {
+        debug!("Resolving {host} with fallback server {server} because {error}");
+        for record in [RECORD_A, RECORD_AAAA] {
+            if let Some(a) = query_server(server, host, record).await {
+                if !a.is_empty() {
+                    return Ok(a.into_iter().map(|ip| SocketAddr::new(ip, port)).collect());
+                }
+            }
+        }
+    }
+
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_query() {
+        let query = build_query(0x1234, "example.com", RECORD_A).unwrap();
+        assert_eq!(&query[..2], &[0x12, 0x34]);
+        assert_eq!(&query[12..25], b"\x07example\x03com\x00");
+        assert_eq!(&query[25..], &[0, 1, 0, 1]);
+        assert!(build_query(1, "bad..name", RECORD_A).is_none());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let query = build_query(0x1234, "example.com", RECORD_A).unwrap();
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2; /* Two answers */
+
+        response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        response.extend_from_slice(&[0xC0, 12, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+        response.extend_from_slice(&[0x26, 0x06, 0x28, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let addresses = parse_response(0x1234, &response).unwrap();
+        assert_eq!(addresses[0], "93.184.216.34".parse::<IpAddr>().unwrap());
+        assert_eq!(addresses[1], "2606:2800::1".parse::<IpAddr>().unwrap());
+
+        assert!(parse_response(0x4321, &response).is_none());
+        assert!(answers(&query, &response));
+        response[3] = 0x83; /* Name error */
+        assert!(parse_response(0x1234, &response).is_none());
+    }
+
+    #[test]
+    fn test_answers() {
+        let query = build_query(0x1234, "example.com", RECORD_A).unwrap();
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        assert!(answers(&query, &response));
+
+        /* The query itself, sent back */
+        assert!(!answers(&query, &query));
+        /* Another ID */
+        assert!(!answers(
+            &build_query(0x4321, "example.com", RECORD_A).unwrap(),
+            &response
+        ));
+        /* Another question */
+        assert!(!answers(
+            &build_query(0x1234, "example.org", RECORD_A).unwrap(),
+            &response
+        ));
+        assert!(!answers(
+            &build_query(0x1234, "example.com", RECORD_AAAA).unwrap(),
+            &response
+        ));
+        assert!(!answers(&query, &response[..20]));
+    }
+}
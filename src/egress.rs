@@ -0,0 +1,246 @@
+use {
+    crate::{cli::setting, PKG_NAME},
+    std::{io, net::SocketAddr, sync::OnceLock},
+    tokio::net::{TcpSocket, TcpStream, UdpSocket},
+    tracing::{error, info},
+};
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+use {
+    std::{
+        fs::File,
+        os::fd::{AsRawFd, FromRawFd},
+        sync::mpsc,
+    },
+    tokio::sync::oneshot,
+};
+
+pub const X_PROXY_UPSTREAM_DEVICE: &str = "X_PROXY_UPSTREAM_DEVICE";
+pub const X_PROXY_UPSTREAM_NETNS: &str = "X_PROXY_UPSTREAM_NETNS";
+
+/// Where `ip netns add` keeps the namespaces it names
+#[cfg(all(target_os = "linux", feature = "netns"))]
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+/* The interface or VRF upstream sockets are bound to */
+static DEVICE: OnceLock<String> = OnceLock::new();
+
+/// A socket made for an upstream connection by the thread living in the upstream namespace
+#[cfg(all(target_os = "linux", feature = "netns"))]
+enum SocketRequest {
+    Tcp(SocketAddr, oneshot::Sender<io::Result<std::net::TcpStream>>),
+    Udp(SocketAddr, oneshot::Sender<io::Result<std::net::UdpSocket>>),
+}
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+static NAMESPACE: OnceLock<mpsc::Sender<SocketRequest>> = OnceLock::new();
+
+/* A name given to `ip netns add` or the path of any network namespace, such as `/proc/<pid>/ns/net` */
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn namespace_path(value: &str) -> std::path::PathBuf {
+    match value.contains('/') {
+        true => std::path::PathBuf::from(value),
+        false => std::path::Path::new(NETNS_RUN_DIR).join(value),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn tcp_in_namespace(address: SocketAddr) -> io::Result<std::net::TcpStream> {
+    let domain = match address {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    match fd < 0 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(unsafe { std::net::TcpStream::from_raw_fd(fd) }),
+    }
+}
+
+/* A socket stays in the namespace it was made in, so one thread moves into the upstream namespace
+ * and makes every upstream socket while the rest of rproxy stays where it is */
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn enter_namespace(value: &str) -> io::Result<mpsc::Sender<SocketRequest>> {
+    let namespace = File::open(namespace_path(value))?;
+    let (sender, requests) = mpsc::channel();
+    let (entered_sender, entered) = mpsc::channel();
+
+    std::thread::Builder::new()
+        .name(format!("{PKG_NAME}-netns"))
+        .spawn(move || {
+            let result = match unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            };
+            let failed = result.is_err();
+            let _ = entered_sender.send(result);
+            if failed {
+                return;
+            }
+
+            while let Ok(request) = requests.recv() {
+                match request {
+                    SocketRequest::Tcp(address, reply) => {
+                        let _ = reply.send(tcp_in_namespace(address));
+                    }
+                    SocketRequest::Udp(bind, reply) => {
+                        let _ = reply.send(std::net::UdpSocket::bind(bind));
+                    }
+                }
+            }
+        })?;
+
+    match entered.recv() {
+        Ok(Ok(_)) => Ok(sender),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::other("the namespace thread stopped")),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn namespace() -> Option<mpsc::Sender<SocketRequest>> {
+    NAMESPACE.get().cloned()
+}
+
+/// Read `X_PROXY_UPSTREAM_DEVICE` and `X_PROXY_UPSTREAM_NETNS`, moving upstream sockets into
+/// the namespace straight away so a missing one is noticed on start. False when either can't be used.
+pub(crate) fn setup_egress() -> bool {
+    if let Ok(device) = setting(X_PROXY_UPSTREAM_DEVICE) {
+        let device = device.trim().to_string();
+        if !cfg!(target_os = "linux") {
+            error!("'{X_PROXY_UPSTREAM_DEVICE}' is only supported on Linux");
+            return false;
+        }
+        if device.is_empty() {
+            error!("'{X_PROXY_UPSTREAM_DEVICE}' is empty");
+            return false;
+        }
+        info!("upstream device: {device}");
+        let _ = DEVICE.set(device);
+    }
+
+    if let Ok(value) = setting(X_PROXY_UPSTREAM_NETNS) {
+        #[cfg(all(target_os = "linux", feature = "netns"))]
+        match enter_namespace(value.trim()) {
+            Ok(sender) => {
+                info!("upstream network namespace: {}", value.trim());
+                let _ = NAMESPACE.set(sender);
+            }
+            Err(e) => {
+                error!("couldn't enter network namespace '{value}': {e}");
+                return false;
+            }
+        }
+        #[cfg(not(all(target_os = "linux", feature = "netns")))]
+        {
+            error!("'{X_PROXY_UPSTREAM_NETNS}' is set to '{value}' but {PKG_NAME} was built without the 'netns' feature");
+            return false;
+        }
+    }
+
+    true
+}
+
+async fn tcp_socket(address: SocketAddr) -> io::Result<TcpSocket> {
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    if let Some(namespace) = namespace() {
+        let (reply, made) = oneshot::channel();
+        namespace
+            .send(SocketRequest::Tcp(address, reply))
+            .map_err(|_| io::Error::other("the namespace thread stopped"))?;
+        let stream = made
+            .await
+            .map_err(|_| io::Error::other("the namespace thread stopped"))??;
+        stream.set_nonblocking(true)?;
+        return Ok(TcpSocket::from_std_stream(stream));
+    }
+
+    match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+}
+
+async fn connect_one(address: SocketAddr) -> io::Result<TcpStream> {
+    let socket = tcp_socket(address).await?;
+    #[cfg(target_os = "linux")]
+    if let Some(device) = DEVICE.get() {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+    socket.connect(address).await
+}
+
+/// Connect to the first of `addresses` that answers from the upstream device and namespace,
+/// the way [`TcpStream::connect`] would when neither is set
+pub(crate) async fn egress_connect(addresses: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    for address in addresses {
+        match connect_one(*address).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// A UDP socket bound to `bind` for talking to upstream servers such as DNS resolvers
+pub(crate) async fn egress_udp(bind: SocketAddr) -> io::Result<UdpSocket> {
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    let socket = match namespace() {
+        Some(namespace) => {
+            let (reply, made) = oneshot::channel();
+            namespace
+                .send(SocketRequest::Udp(bind, reply))
+                .map_err(|_| io::Error::other("the namespace thread stopped"))?;
+            let socket = made
+                .await
+                .map_err(|_| io::Error::other("the namespace thread stopped"))??;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)?
+        }
+        None => UdpSocket::bind(bind).await?,
+    };
+    #[cfg(not(all(target_os = "linux", feature = "netns")))]
+    let socket = UdpSocket::bind(bind).await?;
+
+    #[cfg(target_os = "linux")]
+    if let Some(device) = DEVICE.get() {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_egress_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+
+        /* A closed port is passed over for the next address */
+        let closed = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap()
+        };
+
+        let stream = egress_connect(&[closed, open]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        assert!(egress_connect(&[closed]).await.is_err());
+        assert!(egress_connect(&[]).await.is_err());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "netns"))]
+    #[test]
+    fn test_namespace_path() {
+        assert_eq!(
+            namespace_path("internet"),
+            std::path::PathBuf::from("/var/run/netns/internet")
+        );
+        assert_eq!(
+            namespace_path("/proc/1/ns/net"),
+            std::path::PathBuf::from("/proc/1/ns/net")
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use {
+    std::sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    tokio::sync::broadcast,
+};
+
+#[cfg(feature = "web-ui")]
+use {
+    crate::{
+        cancel::Cancellation,
+        http::{ConnectionReturn, HttpHeader, HttpResponseHeader, HttpResponseStatus, HttpVersion},
+    },
+    tokio::{
+        io::{AsyncWrite, AsyncWriteExt},
+        sync::broadcast::error::RecvError,
+        time::{interval, Duration, MissedTickBehavior},
+    },
+};
+
+/// Where the web interface streams what the proxy is doing as server-sent events
+#[cfg(feature = "web-ui")]
+pub(crate) const EVENTS_PATH: &str = "/events";
+
+/* Slow readers miss events rather than holding up requests */
+const EVENT_BACKLOG: usize = 256;
+
+/* Proxies and browsers drop event streams that stay quiet for too long */
+#[cfg(feature = "web-ui")]
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Something that happened which is sent to everyone watching `/events`
+#[derive(Clone)]
+#[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+pub(crate) enum Event {
+    /// A client sent a request, `id` matches it to its `Finish`
+    Start {
+        id: u64,
+        method: String,
+        uri: String,
+        client: String,
+        /// Who the client authenticated as, when clients have to
+        user: Option<String>,
+    },
+    /// The proxy is done with a request after `ms` milliseconds
+    Finish { id: u64, uri: String, ms: u128 },
+    /// A request was served from the cache
+    Hit { uri: String, bytes: u64 },
+    /// A request had to be fetched from the origin
+    Miss { uri: String, bytes: u64 },
+    /// A cached file was removed to make room
+    Evict { file: String, bytes: u64 },
+    /// Fetching a request from the origin went wrong
+    Error { uri: String, error: String },
+}
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+    BUS.get_or_init(|| broadcast::channel(EVENT_BACKLOG).0)
+}
+
+/// A number for the next request, so its events can be told apart from those of others
+pub(crate) fn next_request_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Send the event made by `event` to everyone watching. Nothing is made when no one is.
+pub(crate) fn publish<F>(event: F)
+where
+    F: FnOnce() -> Event,
+{
+    let bus = bus();
+    if bus.receiver_count() > 0 {
+        let _ = bus.send(event());
+    }
+}
+
+#[cfg(feature = "web-ui")]
+pub(crate) fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(feature = "web-ui")]
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Start { .. } => "start",
+            Event::Finish { .. } => "finish",
+            Event::Hit { .. } => "hit",
+            Event::Miss { .. } => "miss",
+            Event::Evict { .. } => "evict",
+            Event::Error { .. } => "error",
+        }
+    }
+
+    /// The event as it's sent down the stream, its data a single line of JSON
+    fn to_sse(&self) -> String {
+        let data = match self {
+            Event::Start {
+                id,
+                method,
+                uri,
+                client,
+                user,
+            } => format!(
+                "{{\"id\":{id},\"method\":\"{}\",\"uri\":\"{}\",\"client\":\"{}\"{}}}",
+                escape_json(method),
+                escape_json(uri),
+                escape_json(client),
+                match user {
+                    Some(u) => format!(",\"user\":\"{}\"", escape_json(u)),
+                    None => String::new(),
+                }
+            ),
+            Event::Finish { id, uri, ms } => format!(
+                "{{\"id\":{id},\"uri\":\"{}\",\"ms\":{ms}}}",
+                escape_json(uri)
+            ),
+            Event::Hit { uri, bytes } | Event::Miss { uri, bytes } => {
+                format!("{{\"uri\":\"{}\",\"bytes\":{bytes}}}", escape_json(uri))
+            }
+            Event::Evict { file, bytes } => {
+                format!("{{\"file\":\"{}\",\"bytes\":{bytes}}}", escape_json(file))
+            }
+            Event::Error { uri, error } => format!(
+                "{{\"uri\":\"{}\",\"error\":\"{}\"}}",
+                escape_json(uri),
+                escape_json(error)
+            ),
+        };
+        format!("event: {}\ndata: {data}\n\n", self.name())
+    }
+}
+
+/// Start a server-sent event stream that's closed when the client is done with it
+#[cfg(feature = "web-ui")]
+pub(crate) async fn write_event_stream_header<T>(stream: &mut T) -> bool
+where
+    T: AsyncWrite + Unpin,
+{
+    let mut headers = HttpHeader::new();
+    headers.insert(
+        String::from("Content-Type"),
+        "text/event-stream".to_string(),
+    );
+    headers.insert(String::from("Cache-Control"), "no-store".to_string());
+    headers.insert(String::from("Connection"), "close".to_string());
+
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::OK,
+        headers,
+        version: HttpVersion::HTTP_V11,
+    };
+
+    stream.write_all(header.generate().as_bytes()).await.is_ok()
+}
+
+/// Stream every event published from now on until the client goes away.
+/// A `lagged` event says how many were missed when the client couldn't keep up.
+#[cfg(feature = "web-ui")]
+pub(crate) async fn serve_events<T>(stream: &mut T, cancel: &Cancellation) -> ConnectionReturn
+where
+    T: AsyncWrite + Unpin,
+{
+    let mut events = bus().subscribe();
+    if !write_event_stream_header(stream).await {
+        return ConnectionReturn::Close;
+    }
+
+    let mut keep_alive = interval(KEEP_ALIVE_INTERVAL);
+    keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    keep_alive.reset();
+
+    loop {
+        let text = tokio::select! {
+            _ = cancel.clone().cancelled() => return ConnectionReturn::Close,
+            event = events.recv() => match event {
+                Ok(e) => e.to_sse(),
+                Err(RecvError::Lagged(n)) => format!("event: lagged\ndata: {{\"missed\":{n}}}\n\n"),
+                Err(RecvError::Closed) => return ConnectionReturn::Close,
+            },
+            _ = keep_alive.tick() => ":\n\n".to_string(),
+        };
+
+        if stream.write_all(text.as_bytes()).await.is_err() {
+            return ConnectionReturn::Close;
+        }
+        keep_alive.reset();
+    }
+}
+
+#[cfg(all(test, feature = "web-ui"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_json() {
+        assert_eq!(escape_json("a \"b\"\\c\nd"), "a \\\"b\\\"\\\\c\\u000ad");
+    }
+
+    #[test]
+    fn test_event_to_sse() {
+        let start = Event::Start {
+            id: 7,
+            method: "GET".to_string(),
+            uri: "http://deb.debian.org/\"a\"".to_string(),
+            client: "192.168.1.2:50000".to_string(),
+            user: None,
+        };
+        assert_eq!(
+            start.to_sse(),
+            "event: start\ndata: {\"id\":7,\"method\":\"GET\",\
+            \"uri\":\"http://deb.debian.org/\\\"a\\\"\",\"client\":\"192.168.1.2:50000\"}\n\n"
+        );
+        let start = Event::Start {
+            id: 8,
+            method: "GET".to_string(),
+            uri: "http://deb.debian.org/".to_string(),
+            client: "192.168.1.2:50000".to_string(),
+            user: Some("alice".to_string()),
+        };
+        assert_eq!(
+            start.to_sse(),
+            "event: start\ndata: {\"id\":8,\"method\":\"GET\",\
+            \"uri\":\"http://deb.debian.org/\",\"client\":\"192.168.1.2:50000\",\"user\":\"alice\"}\n\n"
+        );
+        assert_eq!(
+            Event::Evict {
+                file: "deb.debian.org/a.deb".to_string(),
+                bytes: 1024
+            }
+            .to_sse(),
+            "event: evict\ndata: {\"file\":\"deb.debian.org/a.deb\",\"bytes\":1024}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_events() {
+        use tokio::io::AsyncReadExt;
+
+        let cancel = Cancellation::new();
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let streaming = async {
+            serve_events(&mut server, &cancel).await;
+            drop(server);
+        };
+
+        let watching = async {
+            /* Wait for the stream to subscribe */
+            while bus().receiver_count() == 0 {
+                tokio::task::yield_now().await;
+            }
+            publish(|| Event::Hit {
+                uri: "http://test.invalid/hit".to_string(),
+                bytes: 10,
+            });
+            publish(|| Event::Error {
+                uri: "http://test.invalid/error".to_string(),
+                error: "refused".to_string(),
+            });
+
+            let mut response = Vec::new();
+            let mut buffer = [0; 1024];
+            while !String::from_utf8_lossy(&response).contains("event: error") {
+                let read = client.read(&mut buffer).await.unwrap();
+                assert_ne!(read, 0);
+                response.extend_from_slice(&buffer[..read]);
+            }
+            cancel.cancel();
+            client.read_to_end(&mut response).await.unwrap();
+            String::from_utf8(response).unwrap()
+        };
+
+        let (_, response) = tokio::join!(streaming, watching);
+
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("Content-Type: text/event-stream"));
+        assert!(response
+            .contains("event: hit\ndata: {\"uri\":\"http://test.invalid/hit\",\"bytes\":10}\n\n"));
+        assert!(response.contains(
+            "event: error\ndata: {\"uri\":\"http://test.invalid/error\",\"error\":\"refused\"}\n\n"
+        ));
+    }
+}
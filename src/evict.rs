@@ -0,0 +1,469 @@
+use {
+    crate::{
+        cli::setting,
+        conn::Flights,
+        dedup::prune_blobs,
+        events::{publish, Event},
+        http::X_PROXY_CACHE_PATH,
+        layout::cache_entries,
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        path::{Path, PathBuf},
+        sync::{Arc, OnceLock, RwLock},
+        time::SystemTime,
+    },
+    tokio::{
+        fs::{metadata, remove_file},
+        time::{sleep, Duration},
+    },
+    tracing::info,
+};
+
+pub const X_PROXY_CACHE_MAX_SIZE: &str = "X_PROXY_CACHE_MAX_SIZE";
+pub const X_PROXY_CACHE_PINS: &str = "X_PROXY_CACHE_PINS";
+pub const X_PROXY_CACHE_POLICY: &str = "X_PROXY_CACHE_POLICY";
+
+const SWEEP_INTERVAL_SECONDS: u64 = 60;
+
+struct CacheEntry {
+    path: PathBuf,
+    key: String,
+    length: u64,
+    last_used: SystemTime,
+    hits: u64,
+}
+
+/// Which entries are removed first when the cache is too big
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum EvictionPolicy {
+    /// Files that haven't been used for the longest time
+    #[default]
+    LeastRecentlyUsed,
+    /// Files served from the cache the fewest times, then the least recently used of those.
+    /// Large files that are only downloaded once can't push out small ones that are wanted often.
+    LeastFrequentlyUsed,
+}
+
+impl EvictionPolicy {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "lru" => Some(EvictionPolicy::LeastRecentlyUsed),
+            "lfu" => Some(EvictionPolicy::LeastFrequentlyUsed),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::LeastRecentlyUsed => "lru",
+            EvictionPolicy::LeastFrequentlyUsed => "lfu",
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct CacheStats {
+    pub(crate) files: u64,
+    pub(crate) bytes: u64,
+    pub(crate) pinned_files: u64,
+    pub(crate) pinned_bytes: u64,
+    pub(crate) evicted_files: u64,
+    pub(crate) evicted_bytes: u64,
+}
+
+/// Parse a size such as `512M` or `2T` into bytes. A value without a suffix is taken as bytes.
+pub(crate) fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1u64 << 10),
+        'M' => (&value[..value.len() - 1], 1u64 << 20),
+        'G' => (&value[..value.len() - 1], 1u64 << 30),
+        'T' => (&value[..value.len() - 1], 1u64 << 40),
+        _ => (value, 1),
+    };
+
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Match `text` against a pattern where `*` stands in for any run of characters.
+pub(crate) fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+
+    let mut rest = match text.strip_prefix(first) {
+        None => return false,
+        Some(r) => r,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.last() {
+        None => return rest.is_empty(),
+        Some(l) => l,
+    };
+
+    for part in &parts[..parts.len() - 1] {
+        match rest.find(part) {
+            None => return false,
+            Some(i) => rest = &rest[i + part.len()..],
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/* Pins added while running, forgotten when rproxy restarts */
+fn runtime_pins() -> &'static RwLock<HashSet<String>> {
+    static PINS: OnceLock<RwLock<HashSet<String>>> = OnceLock::new();
+    PINS.get_or_init(|| RwLock::new(HashSet::new()))
+}
+
+#[cfg(feature = "web-ui")]
+/// Pin a single `host/file` entry on top of the ones in `X_PROXY_CACHE_PINS`
+pub(crate) fn pin_entry(key: &str) {
+    if let Ok(mut pins) = runtime_pins().write() {
+        pins.insert(key.to_string());
+    }
+}
+
+#[cfg(feature = "web-ui")]
+/// Undo [`pin_entry`], entries pinned by `X_PROXY_CACHE_PINS` stay pinned
+pub(crate) fn unpin_entry(key: &str) {
+    if let Ok(mut pins) = runtime_pins().write() {
+        pins.remove(key);
+    }
+}
+
+/// How often a cache entry has been served and the URL it was fetched from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Hits {
+    pub(crate) count: u64,
+    pub(crate) uri: String,
+}
+
+/* Keyed by cache file path, forgotten when rproxy restarts */
+fn hit_table() -> &'static RwLock<HashMap<String, Hits>> {
+    static HITS: OnceLock<RwLock<HashMap<String, Hits>>> = OnceLock::new();
+    HITS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Count a request for `uri` served from the cache file at `path`
+pub(crate) fn record_hit(path: &str, uri: &str) {
+    if let Ok(mut hits) = hit_table().write() {
+        let entry = hits.entry(path.to_string()).or_default();
+        entry.count += 1;
+        if entry.uri != uri {
+            entry.uri = uri.to_string();
+        }
+    }
+}
+
+#[cfg(feature = "web-ui")]
+pub(crate) fn hit_count(path: &str) -> u64 {
+    hits_for(path)
+}
+
+/// The `count` most served cache files, most popular first
+pub(crate) fn hottest(count: usize) -> Vec<(String, Hits)> {
+    let mut hottest: Vec<(String, Hits)> = match hit_table().read() {
+        Ok(hits) => hits.iter().map(|(p, h)| (p.clone(), h.clone())).collect(),
+        Err(_) => return Vec::new(),
+    };
+
+    hottest.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(&b.0)));
+    hottest.truncate(count);
+    hottest
+}
+
+fn hits_for(path: &str) -> u64 {
+    match hit_table().read() {
+        Ok(hits) => hits.get(path).map(|h| h.count).unwrap_or_default(),
+        Err(_) => 0,
+    }
+}
+
+pub(crate) fn forget_hits(path: &str) {
+    if let Ok(mut hits) = hit_table().write() {
+        hits.remove(path);
+    }
+}
+
+/// Cache pins are a comma separated list of `host/file` patterns.
+/// A leading `http://` or `https://` is ignored so full URLs can be pasted in.
+/// Entries pinned with [`pin_entry`] are included.
+pub(crate) fn cache_pins() -> Vec<String> {
+    let mut pins = match setting(X_PROXY_CACHE_PINS) {
+        Err(_) => Vec::new(),
+        Ok(s) => s
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                p.trim_start_matches("http://")
+                    .trim_start_matches("https://")
+                    .to_string()
+            })
+            .collect(),
+    };
+
+    if let Ok(runtime) = runtime_pins().read() {
+        pins.extend(runtime.iter().cloned());
+    }
+
+    pins
+}
+
+pub(crate) fn is_pinned(pins: &[String], key: &str) -> bool {
+    pins.iter().any(|p| matches_pattern(p, key))
+}
+
+async fn collect_entries(cache_path: &Path) -> Vec<CacheEntry> {
+    let mut entries = Vec::new();
+
+    for (host, file, path) in cache_entries(cache_path).await {
+        let metadata = match metadata(&path).await {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+
+        let last_used = metadata
+            .accessed()
+            .or_else(|_| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        entries.push(CacheEntry {
+            hits: hits_for(&path.to_string_lossy()),
+            path,
+            key: format!("{host}/{file}"),
+            length: metadata.len(),
+            last_used,
+        });
+    }
+
+    entries
+}
+
+/* The first entry is the first to go */
+fn eviction_order(entries: &mut [CacheEntry], policy: EvictionPolicy) {
+    match policy {
+        EvictionPolicy::LeastRecentlyUsed => entries.sort_by_key(|e| e.last_used),
+        EvictionPolicy::LeastFrequentlyUsed => entries.sort_by_key(|e| (e.hits, e.last_used)),
+    }
+}
+
+/// Remove entries in the order `policy` gives until the cache fits in `max_size` bytes.
+/// Pinned and in-flight entries are never removed.
+pub(crate) async fn sweep_cache(
+    cache_path: &Path,
+    max_size: u64,
+    policy: EvictionPolicy,
+    pins: &[String],
+    flights: &Flights,
+) -> CacheStats {
+    let mut stats = CacheStats::default();
+    let mut entries = collect_entries(cache_path).await;
+
+    for entry in &entries {
+        stats.files += 1;
+        stats.bytes += entry.length;
+        if is_pinned(pins, &entry.key) {
+            stats.pinned_files += 1;
+            stats.pinned_bytes += entry.length;
+        }
+    }
+
+    if stats.bytes <= max_size {
+        return stats;
+    }
+
+    eviction_order(&mut entries, policy);
+
+    let mut total = stats.bytes;
+
+    for entry in entries {
+        if total <= max_size {
+            break;
+        }
+
+        if is_pinned(pins, &entry.key) {
+            continue;
+        }
+
+        let hash = entry.path.to_string_lossy().to_string();
+        if flights.is_in_flight(&hash).await {
+            continue;
+        }
+
+        if remove_file(&entry.path).await.is_ok() {
+            forget_hits(&hash);
+            total -= entry.length;
+            stats.evicted_files += 1;
+            stats.evicted_bytes += entry.length;
+            publish(|| Event::Evict {
+                file: entry.key.clone(),
+                bytes: entry.length,
+            });
+        }
+    }
+
+    stats
+}
+
+pub(crate) async fn eviction_loop(flights: Arc<Flights>, max_size: u64, policy: EvictionPolicy) {
+    let cache_path = match setting(X_PROXY_CACHE_PATH) {
+        Ok(s) => PathBuf::from(s),
+        Err(_) => return,
+    };
+
+    loop {
+        let pins = cache_pins();
+        let stats = sweep_cache(&cache_path, max_size, policy, &pins, &flights).await;
+        if stats.evicted_files > 0 {
+            prune_blobs(&cache_path).await;
+        }
+
+        info!(
+            "cache: {} files ({} bytes), {} pinned ({} bytes), {} evicted ({} bytes)",
+            stats.files,
+            stats.bytes,
+            stats.pinned_files,
+            stats.pinned_bytes,
+            stats.evicted_files,
+            stats.evicted_bytes
+        );
+
+        sleep(Duration::from_secs(SWEEP_INTERVAL_SECONDS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("2m"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("1T"), Some(1 << 40));
+        assert_eq!(parse_size("lots"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern(
+            "example.com/file.iso",
+            "example.com/file.iso"
+        ));
+        assert!(!matches_pattern(
+            "example.com/file.iso",
+            "example.com/file.iso2"
+        ));
+        assert!(matches_pattern(
+            "example.com/*.iso",
+            "example.com/debian.iso"
+        ));
+        assert!(!matches_pattern(
+            "example.com/*.iso",
+            "example.com/debian.img"
+        ));
+        assert!(matches_pattern("*/*.iso", "cdimage.debian.org/debian.iso"));
+        assert!(matches_pattern("*", "anything/at.all"));
+        assert!(matches_pattern("a*b*c", "aXXbYYc"));
+        assert!(!matches_pattern("a*b*c", "aXXcYYb"));
+    }
+
+    fn entry(key: &str, last_used: u64, hits: u64) -> CacheEntry {
+        CacheEntry {
+            path: PathBuf::from(format!("/cache/{key}")),
+            key: key.to_string(),
+            length: 1,
+            last_used: SystemTime::UNIX_EPOCH + Duration::from_secs(last_used),
+            hits,
+        }
+    }
+
+    #[test]
+    fn test_eviction_policy() {
+        assert_eq!(
+            EvictionPolicy::from_name("LFU"),
+            Some(EvictionPolicy::LeastFrequentlyUsed)
+        );
+        assert_eq!(
+            EvictionPolicy::from_name("lru"),
+            Some(EvictionPolicy::LeastRecentlyUsed)
+        );
+        assert_eq!(EvictionPolicy::from_name("fifo"), None);
+    }
+
+    #[test]
+    fn test_eviction_order() {
+        let order = |policy| {
+            let mut entries = vec![
+                entry("example.com/popular.deb", 1, 50),
+                entry("example.com/once.iso", 3, 0),
+                entry("example.com/twice.deb", 2, 2),
+                entry("example.com/new.deb", 4, 0),
+            ];
+            eviction_order(&mut entries, policy);
+            entries.into_iter().map(|e| e.key).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            order(EvictionPolicy::LeastRecentlyUsed),
+            [
+                "example.com/popular.deb",
+                "example.com/twice.deb",
+                "example.com/once.iso",
+                "example.com/new.deb"
+            ]
+        );
+        assert_eq!(
+            order(EvictionPolicy::LeastFrequentlyUsed),
+            [
+                "example.com/once.iso",
+                "example.com/new.deb",
+                "example.com/twice.deb",
+                "example.com/popular.deb"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_pinned() {
+        let pins = vec!["cdimage.debian.org/*.iso".to_string()];
+        assert!(is_pinned(&pins, "cdimage.debian.org/debian-12.iso"));
+        assert!(!is_pinned(&pins, "deb.debian.org/debian-12.iso"));
+        assert!(!is_pinned(&[], "cdimage.debian.org/debian-12.iso"));
+    }
+
+    #[test]
+    #[cfg(feature = "web-ui")]
+    fn test_runtime_pins() {
+        pin_entry("example.com/pinned.iso");
+        assert!(is_pinned(&cache_pins(), "example.com/pinned.iso"));
+
+        unpin_entry("example.com/pinned.iso");
+        assert!(!is_pinned(&cache_pins(), "example.com/pinned.iso"));
+    }
+
+    #[test]
+    fn test_hit_count() {
+        record_hit("/cache/example.com/hit", "http://example.com/hit");
+        record_hit("/cache/example.com/hit", "http://example.com/hit");
+        record_hit("/cache/example.com/miss", "http://example.com/miss");
+
+        let top = hottest(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, "/cache/example.com/hit");
+        assert_eq!(top[0].1.count, 2);
+        assert_eq!(top[0].1.uri, "http://example.com/hit");
+
+        forget_hits("/cache/example.com/hit");
+        assert!(hottest(2)
+            .iter()
+            .all(|(p, _)| p != "/cache/example.com/hit"));
+    }
+}
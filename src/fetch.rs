@@ -1,31 +1,240 @@
 use {
     crate::{
-        conn::{FetchRequest, FlightState, Flights, Uri},
-        debug_print,
+        cancel::{Cancellable, Cancellation},
+        capture::{capture_body, Capturing},
+        conn::{scheme_allowed, FetchRequest, FetchRequestError, FlightState, Flights, Uri},
+        cookie::{apply_cookie_jar, store_cookies},
+        credentials::{apply_upstream_credentials, caches_authenticated},
+        debug::wire_log,
+        dedup::{deduplicate, unshare},
+        digest::{inspect_download, Digesting, Download, Verdict},
+        events::{publish, Event},
+        forwarded::apply_via,
+        head::{forget_head, head_length, remember_head},
         http::{
-            fetch_and_serve_chunk, fetch_and_serve_known_length, keep_alive_if, respond_with,
+            drain_http_body, fetch_and_serve_chunk, fetch_and_serve_known_length,
+            fetch_and_serve_until_close, keep_alive_if, respond_with, respond_with_reason,
             ConnectionReturn,
             ConnectionReturn::{Close, Redirect},
-            HttpRequestHeader, HttpRequestMethod, HttpResponseHeader, HttpResponseStatus,
-            HttpVersion,
+            HttpHeader, HttpRequestHeader, HttpRequestMethod, HttpResponseHeader,
+            HttpResponseStatus, HttpVersion, WAIT_TIMEOUT_SECONDS,
         },
+        journal::{journal_begin, journal_end, JournalEntry},
+        quirks::{disable_reuse, force_http10, host_quirks},
+        redirect::{redirect_policy, redirect_target},
+        retry::{backoff, retry_after, transient, upstream_retries},
+        rules::{cache_rule, upstream_accept},
+        storage::storage_failed,
+    },
+    std::{
+        collections::VecDeque,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
     },
-    std::{collections::VecDeque, path::PathBuf, sync::Arc, time::Duration},
     tokio::{
         fs::{create_dir_all, remove_file, File},
         io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
-        time::timeout,
+        time::{sleep, timeout},
     },
+    tracing::{debug, error},
 };
 
 #[cfg(feature = "https")]
 use crate::cert::CertificateSetup;
 
+#[cfg(feature = "http3")]
+use crate::quic::remember_alt_svc;
+
+/// How long tidying up after a response, such as closing a connection or dating the cached
+/// file, may hold up the next request before it's left unfinished
+const FINISH_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Tell anyone watching `/events` that fetching `uri` went wrong
+fn upstream_error(uri: &str, error: &dyn std::fmt::Display) {
+    publish(|| Event::Error {
+        uri: uri.to_string(),
+        error: error.to_string(),
+    });
+}
+
+/// `504 Gateway Timeout` when the origin server didn't answer in time and `502 Bad Gateway`
+/// when it couldn't be found or reached or its TLS failed
+fn connect_error_status(error: &FetchRequestError) -> HttpResponseStatus {
+    match error {
+        FetchRequestError::InvalidScheme | FetchRequestError::InvalidUri => {
+            HttpResponseStatus::BAD_REQUEST
+        }
+        #[cfg(feature = "https")]
+        FetchRequestError::InvalidDomainName(_) => HttpResponseStatus::BAD_REQUEST,
+        FetchRequestError::DeniedAddress(_) => HttpResponseStatus::FORBIDDEN,
+        FetchRequestError::UpstreamTimeout(_) => HttpResponseStatus::GATEWAY_TIMEOUT,
+        FetchRequestError::DnsResolutionError(_) | FetchRequestError::TcpConnectionError(_) => {
+            HttpResponseStatus::BAD_GATEWAY
+        }
+        #[cfg(feature = "https")]
+        FetchRequestError::TlsConnectionError(_) | FetchRequestError::CertificateRejected(_) => {
+            HttpResponseStatus::BAD_GATEWAY
+        }
+    }
+}
+
+/// Tell the client the origin server couldn't be reached and why, then close the connection
+pub(crate) async fn respond_connect_error<T>(
+    error: &FetchRequestError,
+    stream: &mut T,
+) -> ConnectionReturn
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    respond_with_reason(
+        Close,
+        connect_error_status(error),
+        &error.to_string(),
+        stream,
+    )
+    .await
+}
+
+/// Whether reading a response header since `started` gave up waiting on the origin server
+fn timed_out(started: Instant) -> bool {
+    started.elapsed() >= Duration::from_secs(WAIT_TIMEOUT_SECONDS)
+}
+
+/// Tell the client the origin server's response header never arrived, `504 Gateway Timeout`
+/// when reading it since `started` gave up waiting and `502 Bad Gateway` when it was cut short or malformed
+pub(crate) async fn respond_header_error<T>(
+    return_type: ConnectionReturn,
+    started: Instant,
+    stream: &mut T,
+) -> ConnectionReturn
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    match timed_out(started) {
+        true => {
+            respond_with_reason(
+                return_type,
+                HttpResponseStatus::GATEWAY_TIMEOUT,
+                "the origin server didn't respond in time",
+                stream,
+            )
+            .await
+        }
+        false => {
+            respond_with_reason(
+                return_type,
+                HttpResponseStatus::BAD_GATEWAY,
+                "the origin server's response couldn't be read",
+                stream,
+            )
+            .await
+        }
+    }
+}
+
+/// What happened to the upstream connection during a fetch
+#[derive(Default)]
+struct UpstreamConnection {
+    /// An earlier request was sent down this connection
+    reused: bool,
+    /// The connection sat idle in the pool before this request, so the origin may have closed it
+    pooled: bool,
+    /// The origin left the connection ready for another request
+    reusable: bool,
+    /// The fetch should be attempted again on a new connection
+    retry: bool,
+    /// How long to wait before the fetch is attempted again, when it failed for a reason that may pass
+    backoff: Option<Duration>,
+    /// Times the fetch was already attempted again after such a failure
+    attempts: usize,
+    /// Redirects already followed to reach this request
+    hops: usize,
+    /// A redirect followed on the way here keeps what's fetched from being cached
+    uncacheable: bool,
+    /// The connection may not carry another request after this one
+    last: bool,
+}
+
+/// Set aside the disk space a download will need without changing the length of the file,
+/// which clients reading along with the download go by. False when the disk is too full to cache it.
+#[cfg(all(target_os = "linux", feature = "sendfile"))]
+fn reserve_space(file: &File, length: u64) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    if length == 0 {
+        return true;
+    }
+
+    let reserved = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            length as libc::off_t,
+        )
+    };
+
+    /* File systems that can't reserve space are written to as they would have been anyway */
+    reserved == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOSPC)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "sendfile")))]
+fn reserve_space(_file: &File, _length: u64) -> bool {
+    true
+}
+
+/// Connect `fetch_request` to its origin, trying again after a while
+/// when it fails for a reason that may pass and `attempts` allows another retry
+/* What a cache miss asks the origin with: the client's own headers, less those meant for one
+ * connection or for rproxy itself, asking for the whole file unencoded */
+fn forwarded_headers(client_request_header: &HttpRequestHeader<'_>, host: String) -> HttpHeader {
+    let mut headers = client_request_header.headers.clone();
+    headers.strip_hop_by_hop();
+    headers.strip_proxy_only();
+    headers.remove("Range"); /* Not cached so need to download from start */
+    /* Cached files are replayed to every client so they must be stored unencoded */
+    headers.insert("Accept-Encoding".to_string(), "identity".to_string());
+    /* Every client shares the cached file so they must all be sent the same representation */
+    if let Some(accept) = upstream_accept(&client_request_header.request.uri) {
+        headers.insert("Accept".to_string(), accept.to_string());
+    }
+    headers.insert("Host".to_string(), host); /* Host field is mandatory on HTTP 1.1 */
+    headers
+}
+
+async fn connect_with_retries(
+    fetch_request: &mut FetchRequest<'_>,
+    attempts: &mut usize,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) -> Result<(), FetchRequestError> {
+    loop {
+        match fetch_request
+            .connect(
+                #[cfg(feature = "https")]
+                certificates,
+            )
+            .await
+        {
+            Err(e) if transient(&e) && *attempts < upstream_retries() => {
+                debug!(
+                    "Connecting to {} failed, retrying: {e}",
+                    fetch_request.uri().uri
+                );
+                sleep(backoff(*attempts)).await;
+                *attempts += 1;
+            }
+            r => return r.map(|_| ()),
+        }
+    }
+}
+
 pub(crate) async fn fetch_and_serve_file<T>(
     cache_file_path: PathBuf,
     mut stream: T,
     flights: &Arc<Flights>,
     client_request_header: HttpRequestHeader<'_>,
+    cancel: &Cancellation,
     #[cfg(feature = "https")] certificates: &CertificateSetup,
 ) -> ConnectionReturn
 where
@@ -43,30 +252,36 @@ where
                 .await
             }
         };
+    fetch_request.use_pool();
 
-    match fetch_request
-        .connect(
-            #[cfg(feature = "https")]
-            certificates,
-        )
-        .await
+    let mut attempts = 0;
+    if let Err(e) = connect_with_retries(
+        &mut fetch_request,
+        &mut attempts,
+        #[cfg(feature = "https")]
+        certificates,
+    )
+    .await
     {
-        Ok(_) => (),
-        Err(_) => {
-            return respond_with(
-                Close,
-                HttpResponseStatus::INTERNAL_SERVER_ERROR,
-                &mut stream,
-            )
-            .await
-        }
-    };
+        upstream_error(&client_request_header.request.uri, &e);
+        return respond_connect_error(&e, &mut stream).await;
+    }
 
     let mut redirects: VecDeque<String> = VecDeque::new();
     redirects.push_back(fetch_request.uri().uri.clone());
+    let mut uncacheable = false;
 
     loop {
-        let uri = redirects.back().unwrap();
+        let current_uri = Uri::from(fetch_request.uri());
+        let mut connection = UpstreamConnection {
+            reused: fetch_request.reused(),
+            pooled: fetch_request.pooled(),
+            hops: redirects.len() - 1,
+            uncacheable,
+            attempts,
+            last: fetch_request.last_request(),
+            ..Default::default()
+        };
 
         let mut fetch_stream = match fetch_request.as_stream() {
             None => {
@@ -77,12 +292,10 @@ where
                 )
                 .await
             }
-            Some(f) => f,
+            Some(f) => Cancellable::new(f, cancel),
         };
 
-        let current_uri = Uri::from(uri);
-
-        debug_print!("Fetching {}", current_uri.uri);
+        debug!("Fetching {}", current_uri.uri);
 
         let fetch_result = fetch(
             &current_uri,
@@ -91,22 +304,44 @@ where
             &client_request_header,
             &mut fetch_stream,
             &mut stream,
+            &mut connection,
         )
         .await;
 
         drop(fetch_stream);
+        uncacheable = connection.uncacheable;
 
-        match fetch_result {
-            Redirect(r) => {
-                if redirects.len() > 5 {
-                    return respond_with(
-                        Close,
-                        HttpResponseStatus::INTERNAL_SERVER_ERROR,
-                        &mut stream,
-                    )
-                    .await;
+        if !connection.reusable {
+            fetch_request.disconnect();
+        }
+
+        if connection.retry {
+            /* A quirk was just learned about this origin or it failed in a way that may pass,
+             * try again on a new connection */
+            if let Some(delay) = connection.backoff {
+                debug!("Retrying {} in {delay:?}", current_uri.uri);
+                fetch_request.disconnect();
+                sleep(delay).await;
+                attempts += 1;
+            }
+            match connect_with_retries(
+                &mut fetch_request,
+                &mut attempts,
+                #[cfg(feature = "https")]
+                certificates,
+            )
+            .await
+            {
+                Ok(_) => continue,
+                Err(e) => {
+                    upstream_error(&current_uri.uri, &e);
+                    return respond_connect_error(&e, &mut stream).await;
                 }
+            }
+        }
 
+        match fetch_result {
+            Redirect(r) => {
                 if redirects.contains(&r) {
                     return respond_with(
                         Close,
@@ -120,6 +355,11 @@ where
 
                 let new_uri = Uri::from(&redirects);
 
+                /* The origin chose this target, hold it to the same rules as the client's */
+                if !scheme_allowed(&new_uri.uri) {
+                    return respond_with(Close, HttpResponseStatus::FORBIDDEN, &mut stream).await;
+                }
+
                 match fetch_request
                     .redirect(
                         &new_uri,
@@ -129,19 +369,18 @@ where
                     .await
                 {
                     Ok(o) => o,
-                    Err(_) => {
-                        return respond_with(
-                            Close,
-                            HttpResponseStatus::INTERNAL_SERVER_ERROR,
-                            &mut stream,
-                        )
-                        .await
+                    Err(e) => {
+                        upstream_error(&new_uri.uri, &e);
+                        return respond_connect_error(&e, &mut stream).await;
                     }
                 };
 
                 continue;
             }
-            x => return x,
+            x => {
+                fetch_request.release();
+                return x;
+            }
         }
     }
 
@@ -152,6 +391,7 @@ where
         client_request_header: &HttpRequestHeader<'_>,
         fetch_stream: &mut R,
         mut stream: &mut S,
+        connection: &mut UpstreamConnection,
     ) -> ConnectionReturn
     where
         R: AsyncRead + AsyncWrite + Unpin,
@@ -181,14 +421,24 @@ where
             Some(s) => s.to_string(),
         };
 
+        let origin = uri.host_and_port().unwrap_or_default();
+        let quirks = host_quirks(&origin);
+        let authenticated;
+
         let fetch_request = HttpRequestHeader {
             method: HttpRequestMethod::Get,
             request: Uri::from(path_and_query),
-            version: HttpVersion::from(client_request_header.version.as_str()),
+            version: match quirks.http10 {
+                true => HttpVersion::HTTP_V10,
+                false => HttpVersion::from(client_request_header.version.as_str()),
+            },
             headers: {
-                let mut headers = client_request_header.headers.clone();
-                headers.remove("Range"); /* Not cached so need to download from start */
-                headers.insert("Host".to_string(), host); /* Host field is mandatory on HTTP 1.1 */
+                let mut headers = forwarded_headers(client_request_header, host);
+                apply_cookie_jar(uri, &mut headers);
+                authenticated = apply_upstream_credentials(uri, &mut headers);
+                if quirks.no_reuse || connection.last {
+                    headers.insert("Connection".to_string(), "close".to_string());
+                }
                 headers
             },
         };
@@ -203,11 +453,12 @@ where
                 .await
             }
             Some(s) => {
-                debug_print!("Writing header\n\n{}", s);
+                wire_log("Upstream request", &uri.uri, || s.clone());
                 if fetch_stream.write_all(s.as_bytes()).await.is_err() {
-                    return respond_with(
+                    return respond_with_reason(
                         keep_alive_if(client_request_header),
-                        HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                        HttpResponseStatus::BAD_GATEWAY,
+                        "the request couldn't be sent to the origin server",
                         stream,
                     )
                     .await;
@@ -217,21 +468,70 @@ where
 
         let mut fetch_buf_reader = BufReader::new(fetch_stream);
 
-        let mut fetch_response_header =
-            match HttpResponseHeader::from_tcp_buffer_async(&mut fetch_buf_reader).await {
-                None => {
-                    eprintln!("Error: unable to extract header");
-                    return respond_with(
-                        keep_alive_if(client_request_header),
-                        HttpResponseStatus::BAD_GATEWAY,
-                        stream,
-                    )
-                    .await;
+        let started = Instant::now();
+        let mut fetch_response_header = match HttpResponseHeader::from_tcp_buffer_async(
+            &mut fetch_buf_reader,
+        )
+        .await
+        {
+            None if connection.reused => {
+                /* The origin closed a connection it said it would keep open,
+                 * which it's allowed to do once the connection has been idle for a while */
+                if !connection.pooled {
+                    disable_reuse(&origin);
                 }
-                Some(s) => s,
-            };
+                connection.retry = true;
+                return Close;
+            }
+            /* The connection was reset or closed before anything was sent to the client,
+             * so the whole fetch can be tried again. An origin too slow to answer isn't waited on twice. */
+            None if !timed_out(started) && connection.attempts < upstream_retries() => {
+                connection.backoff = Some(backoff(connection.attempts));
+                connection.retry = true;
+                return Close;
+            }
+            None => {
+                error!("unable to extract header");
+                upstream_error(&uri.uri, &"unable to extract header");
+                return respond_header_error(keep_alive_if(client_request_header), started, stream)
+                    .await;
+            }
+            Some(s) => s,
+        };
 
-        match fetch_response_header.status.to_code() {
+        wire_log("Upstream response", &uri.uri, || {
+            fetch_response_header.generate()
+        });
+        store_cookies(uri, &mut fetch_response_header);
+        #[cfg(feature = "http3")]
+        remember_alt_svc(uri, &fetch_response_header.headers);
+
+        /* Decided before the origin's connection headers are stripped from what the client sees */
+        let origin_keeps_alive = fetch_response_header.keeps_alive();
+        fetch_response_header.headers.strip_hop_by_hop();
+        apply_via(
+            &mut fetch_response_header.headers,
+            &fetch_response_header.version,
+        );
+
+        /* The origin is overloaded or its own upstream failed but says when to come back */
+        let status = fetch_response_header.status.to_code();
+        if matches!(status, 502 | 503) && connection.attempts < upstream_retries() {
+            let value = fetch_response_header.headers.get("Retry-After");
+            if let Some(delay) = retry_after(value, SystemTime::now()) {
+                connection.backoff = Some(delay.max(backoff(connection.attempts)));
+                connection.retry = true;
+                return Close;
+            }
+        }
+
+        match status {
+            421 | 505 if !quirks.http10 => {
+                /* The origin won't talk HTTP/1.1, fall back to HTTP/1.0 from now on */
+                force_http10(&origin);
+                connection.retry = true;
+                Close
+            }
             200 => {
                 let cache_file_parent = match cache_file_path.parent() {
                     None => {
@@ -247,50 +547,165 @@ where
                 match create_dir_all(cache_file_parent).await {
                     Ok(_) => {}
                     Err(_) => {
+                        storage_failed().await;
                         return respond_with(
                             keep_alive_if(client_request_header),
                             HttpResponseStatus::INTERNAL_SERVER_ERROR,
                             stream,
                         )
-                        .await
+                        .await;
                     }
                 }
+                /* Truncating a shared body would change every entry linked to it */
+                unshare(cache_file_path).await;
                 let mut file = match File::create(&cache_file_path).await {
                     Err(_) => {
+                        storage_failed().await;
                         return respond_with(
                             keep_alive_if(client_request_header),
                             HttpResponseStatus::INTERNAL_SERVER_ERROR,
                             stream,
                         )
-                        .await
+                        .await;
                     }
                     Ok(file) => file,
                 };
 
-                match write_to_client(&mut fetch_response_header, &mut stream).await {
+                /* A body without a length or chunks ends when the origin closes the connection,
+                 * re-framed as chunks so the client's connection can be kept open after it */
+                let until_close = !fetch_response_header
+                    .headers
+                    .contains_key("Transfer-Encoding")
+                    && !fetch_response_header.headers.contains_key("Content-Length");
+                let reframe = until_close && client_request_header.version == HttpVersion::HTTP_V11;
+                if until_close {
+                    let connection = match reframe {
+                        true => {
+                            fetch_response_header
+                                .headers
+                                .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+                            keep_alive_if(client_request_header)
+                        }
+                        false => Close,
+                    };
+                    fetch_response_header.headers.insert(
+                        "Connection".to_string(),
+                        match connection {
+                            Close => "close",
+                            _ => "keep-alive",
+                        }
+                        .to_string(),
+                    );
+                }
+
+                fetch_response_header.close_after(client_request_header);
+                match write_to_client(uri, &mut fetch_response_header, &mut stream).await {
                     Ok(o) => o,
                     Err(_) => return Close, /* Something broke */
                 }
 
-                let (mut write_file, mut write_stream) = fetch_cache_policy(&fetch_response_header);
+                let (mut write_file, mut write_stream) =
+                    match cache_rule(&client_request_header.request.uri) {
+                        Some(r) if r.force_cache => (true, true),
+                        Some(r) if r.bypass => (false, true),
+                        _ => fetch_cache_policy(&fetch_response_header),
+                    };
+
+                if !fetch_response_header.is_identity_encoded() {
+                    /* The origin encoded it anyway, which another client might not be able to decode */
+                    debug!("Not caching {} as it's content encoded", uri.uri);
+                    write_file = false;
+                }
+
+                if connection.uncacheable {
+                    debug!("Not caching {} as a redirect led to it", uri.uri);
+                    write_file = false;
+                }
+
+                if authenticated && !caches_authenticated(uri.host) {
+                    debug!("Not caching {} as it was fetched with a login", uri.uri);
+                    write_file = false;
+                }
+
+                /* Taken from the bytes as they're written so middleware never reads the file back */
+                let digest;
+
+                if until_close {
+                    flights
+                        .takeoff(
+                            cache_file_path.to_string_lossy().as_ref(),
+                            FlightState::Chunks,
+                        )
+                        .await;
+                    if write_file {
+                        journal_begin(journal_entry(
+                            uri,
+                            cache_file_path,
+                            &fetch_response_header,
+                            None,
+                        ))
+                        .await;
+                    }
+                    let mut body = Digesting::new(&mut file);
+                    (write_file, write_stream) = fetch_and_serve_until_close(
+                        cache_file_path,
+                        &mut stream,
+                        Capturing::new(&mut fetch_buf_reader, capture_body("response", &uri.uri)),
+                        &mut body,
+                        reframe,
+                        write_file,
+                        write_stream,
+                    )
+                    .await;
+                    digest = body.finish();
 
-                if let Some(v) = fetch_response_header.headers.get("Transfer-Encoding") {
-                    if v.to_lowercase() == "chunked" {
+                    if write_file {
+                        write_file = file.flush().await.is_ok();
+                    }
+                } else if fetch_response_header
+                    .headers
+                    .contains_key("Transfer-Encoding")
+                {
+                    if fetch_response_header.headers.is_chunked() {
+                        /* Only an earlier HEAD can say how big a chunked download will be */
+                        if write_file {
+                            if let Some(length) = head_length(&cache_file_path.to_string_lossy()) {
+                                write_file = reserve_space(&file, length);
+                            }
+                        }
                         flights
                             .takeoff(
                                 cache_file_path.to_string_lossy().as_ref(),
                                 FlightState::Chunks,
                             )
                             .await;
+                        if write_file {
+                            journal_begin(journal_entry(
+                                uri,
+                                cache_file_path,
+                                &fetch_response_header,
+                                None,
+                            ))
+                            .await;
+                        }
+                        let mut body = Digesting::new(&mut file);
                         (write_file, write_stream) = fetch_and_serve_chunk(
                             cache_file_path,
                             &mut stream,
-                            &mut fetch_buf_reader,
-                            &mut file,
+                            &mut Capturing::new(
+                                &mut fetch_buf_reader,
+                                capture_body("response", &uri.uri),
+                            ),
+                            &mut body,
                             write_file,
                             write_stream,
                         )
-                        .await
+                        .await;
+                        digest = body.finish();
+
+                        if write_file {
+                            write_file = file.flush().await.is_ok();
+                        }
                     } else {
                         return respond_with(
                             keep_alive_if(client_request_header),
@@ -330,50 +745,125 @@ where
                         },
                     };
 
+                    if write_file {
+                        write_file = reserve_space(&file, content_length);
+                    }
+
+                    if write_file {
+                        journal_begin(journal_entry(
+                            uri,
+                            cache_file_path,
+                            &fetch_response_header,
+                            Some(content_length),
+                        ))
+                        .await;
+                    }
+
+                    let mut body = Digesting::new(&mut file);
                     (write_file, write_stream) = fetch_and_serve_known_length(
                         cache_file_path,
                         &mut stream,
                         content_length,
-                        &mut fetch_buf_reader,
-                        &mut file,
+                        Capturing::new(&mut fetch_buf_reader, capture_body("response", &uri.uri)),
+                        &mut body,
                         write_file,
                         write_stream,
                     )
                     .await;
+                    digest = body.finish();
+
+                    /* The origin may have closed the connection before sending everything */
+                    if write_file {
+                        write_file = file.flush().await.is_ok()
+                            && file.metadata().await.map(|m| m.len()).ok() == Some(content_length);
+                    }
+
+                    /* Only a body read to its end leaves the connection ready for the next request */
+                    connection.reusable = write_file
+                        && !quirks.no_reuse
+                        && origin_keeps_alive
+                        && fetch_buf_reader.buffer().is_empty();
                 }
 
-                let _ = timeout(Duration::from_millis(100), fetch_buf_reader.shutdown()).await;
+                if !connection.reusable {
+                    let _ = timeout(FINISH_TIMEOUT, fetch_buf_reader.shutdown()).await;
+                }
 
-                if write_stream {
-                    let _ = timeout(Duration::from_millis(100), stream.shutdown()).await;
+                /* The end of a body without a length is the end of the connection */
+                let client_connection = match until_close && !reframe {
+                    true => Close,
+                    false => keep_alive_if(client_request_header),
+                };
+                if write_stream && client_connection == Close {
+                    let _ = timeout(FINISH_TIMEOUT, stream.shutdown()).await;
                 }
 
                 if write_file {
-                    if let Some(last_modified) = fetch_response_header.headers.get("Last-Modified")
-                    {
-                        if let Ok(last_modified) = httpdate::parse_http_date(last_modified) {
-                            let _ = timeout(
-                                Duration::from_millis(100),
-                                tokio::spawn(async move {
-                                    let _ = file.into_std().await.set_modified(last_modified);
-                                }),
-                            )
-                            .await;
+                    forget_head(&cache_file_path.to_string_lossy());
+                    let last_modified = fetch_response_header
+                        .headers
+                        .get("Last-Modified")
+                        .and_then(|l| httpdate::parse_http_date(l).ok());
+                    if let Some(last_modified) = last_modified {
+                        let _ = timeout(
+                            FINISH_TIMEOUT,
+                            tokio::spawn(async move {
+                                let _ = file.into_std().await.set_modified(last_modified);
+                            }),
+                        )
+                        .await;
+                    }
+
+                    let download = Download {
+                        uri: &uri.uri,
+                        path: cache_file_path,
+                        digest: &digest,
+                    };
+                    let modified = tokio::fs::metadata(cache_file_path)
+                        .await
+                        .ok()
+                        .and_then(|m| m.modified().ok());
+                    match inspect_download(&download, modified) {
+                        Verdict::Keep => {
+                            deduplicate(&uri.uri, cache_file_path, &digest).await;
+                            flights
+                                .complete(cache_file_path.to_string_lossy().as_ref())
+                                .await
+                        }
+                        Verdict::Quarantine => {
+                            let _ = remove_file(cache_file_path).await;
                         }
                     }
                 } else if cache_file_path.is_file() {
                     let _ = remove_file(cache_file_path).await;
+                    journal_end(cache_file_path).await;
                     return Close; /* Something has gone wrong mid-transmission */
                 }
-                return keep_alive_if(client_request_header); /* Next request ready */
+                journal_end(cache_file_path).await;
+                return client_connection; /* Next request ready */
+
+                fn journal_entry(
+                    uri: &Uri<'_>,
+                    cache_file_path: &Path,
+                    response_header: &HttpResponseHeader,
+                    length: Option<u64>,
+                ) -> JournalEntry {
+                    JournalEntry {
+                        path: cache_file_path.to_path_buf(),
+                        uri: uri.uri.clone(),
+                        length,
+                        last_modified: response_header.headers.get("Last-Modified").cloned(),
+                        etag: response_header.headers.get("ETag").cloned(),
+                    }
+                }
 
                 fn fetch_cache_policy(response_header: &HttpResponseHeader) -> (bool, bool) {
-                    match response_header.headers.get("Cache-Control") {
-                        None => (true, true),
-                        Some(v) => match v.to_lowercase().as_str() {
-                            "no-store" | "private" => (false, true),
-                            _ => (true, true),
-                        },
+                    let headers = &response_header.headers;
+                    match headers.has_token("Cache-Control", "no-store")
+                        || headers.has_token("Cache-Control", "private")
+                    {
+                        true => (false, true),
+                        false => (true, true),
                     }
                 }
             }
@@ -387,16 +877,37 @@ where
                         )
                         .await
                     }
-                    Some(s) => s,
+                    Some(s) => s.clone(),
                 };
-                Redirect(String::from(url))
+
+                /* Consume the redirect body so a same host redirect can reuse this connection */
+                connection.reusable = !quirks.no_reuse
+                    && origin_keeps_alive
+                    && drain_http_body(&mut fetch_buf_reader, &fetch_response_header).await;
+
+                let policy = redirect_policy();
+                if !policy.follows(uri, &redirect_target(uri, &url), connection.hops) {
+                    /* The client follows it, the body that came with it was already read */
+                    debug!("Passing the redirect from {} to {url} on", uri.uri);
+                    fetch_response_header.headers.remove("Transfer-Encoding");
+                    fetch_response_header
+                        .headers
+                        .insert("Content-Length".to_string(), "0".to_string());
+                    fetch_response_header.close_after(client_request_header);
+                    return match write_to_client(uri, &mut fetch_response_header, stream).await {
+                        Ok(_) => keep_alive_if(client_request_header),
+                        Err(_) => Close,
+                    };
+                }
+
+                connection.uncacheable |= !policy.caches(status);
+                Redirect(url)
             }
             _x => {
+                fetch_response_header.close_after(client_request_header);
                 let pass_through = fetch_response_header.generate();
-                debug_print!(
-                    "Proxy will pass-through {_x} from server to client\n\
-                 Header as follows:\n\n{pass_through}"
-                );
+                debug!("Proxy will pass-through {_x} from server to client");
+                wire_log("Client response", &uri.uri, || pass_through.clone());
                 match stream.write_all(pass_through.as_bytes()).await {
                     Ok(_) => keep_alive_if(client_request_header),
                     Err(_) => Close,
@@ -406,6 +917,7 @@ where
     }
 
     async fn write_to_client<T>(
+        uri: &Uri<'_>,
         fetch_response_header: &mut HttpResponseHeader,
         stream: &mut T,
     ) -> std::io::Result<()>
@@ -413,9 +925,291 @@ where
         T: AsyncRead + AsyncWrite + Unpin,
     {
         let fetch_response_header_data = fetch_response_header.generate();
+        wire_log("Client response", &uri.uri, || {
+            fetch_response_header_data.clone()
+        });
 
         stream
             .write_all(fetch_response_header_data.as_bytes())
             .await
     }
 }
+
+/// Answer a `HEAD` request by asking the origin, following redirects the way a `GET` would so the
+/// length and validators describe the file that would be cached. When `remember` is set
+/// a successful answer is kept so the next client to ask doesn't have to wait on the origin.
+pub(crate) async fn fetch_head<T>(
+    cache_file_path: &Path,
+    mut stream: T,
+    client_request_header: &HttpRequestHeader<'_>,
+    remember: bool,
+    cancel: &Cancellation,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) -> ConnectionReturn
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut fetch_request = match FetchRequest::from_uri(&client_request_header.request) {
+        Ok(o) => o,
+        Err(_) => {
+            return respond_with(
+                Close,
+                HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                &mut stream,
+            )
+            .await
+        }
+    };
+    fetch_request.use_pool();
+
+    if let Err(e) = fetch_request
+        .connect(
+            #[cfg(feature = "https")]
+            certificates,
+        )
+        .await
+    {
+        upstream_error(&client_request_header.request.uri, &e);
+        return respond_connect_error(&e, &mut stream).await;
+    }
+
+    let mut redirects: VecDeque<String> = VecDeque::new();
+    redirects.push_back(fetch_request.uri().uri.clone());
+
+    loop {
+        let current_uri = Uri::from(fetch_request.uri());
+        let (host, path_and_query) = match (current_uri.host_and_port(), current_uri.path_and_query)
+        {
+            (Some(h), Some(p)) => (h, p.to_string()),
+            _ => {
+                return respond_with(
+                    keep_alive_if(client_request_header),
+                    HttpResponseStatus::BAD_REQUEST,
+                    &mut stream,
+                )
+                .await
+            }
+        };
+
+        let authenticated;
+        let head_request = HttpRequestHeader {
+            method: HttpRequestMethod::Head,
+            request: Uri::from(path_and_query),
+            version: HttpVersion::HTTP_V11,
+            headers: {
+                let mut headers = forwarded_headers(client_request_header, host);
+                apply_cookie_jar(&current_uri, &mut headers);
+                authenticated = apply_upstream_credentials(&current_uri, &mut headers);
+                if fetch_request.last_request() {
+                    headers.insert("Connection".to_string(), "close".to_string());
+                }
+                headers
+            },
+        };
+
+        let request = match head_request.generate() {
+            None => {
+                return respond_with(
+                    keep_alive_if(client_request_header),
+                    HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                    &mut stream,
+                )
+                .await
+            }
+            Some(s) => s,
+        };
+        wire_log("Upstream request", &current_uri.uri, || request.clone());
+
+        let mut fetch_stream = match fetch_request.as_stream() {
+            None => {
+                return respond_with(
+                    Close,
+                    HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                    &mut stream,
+                )
+                .await
+            }
+            Some(f) => Cancellable::new(f, cancel),
+        };
+
+        if fetch_stream.write_all(request.as_bytes()).await.is_err() {
+            return respond_with_reason(
+                Close,
+                HttpResponseStatus::BAD_GATEWAY,
+                "the request couldn't be sent to the origin server",
+                &mut stream,
+            )
+            .await;
+        }
+
+        let started = Instant::now();
+        let fetch_response_header =
+            HttpResponseHeader::from_tcp_buffer_async(&mut BufReader::new(&mut fetch_stream)).await;
+        drop(fetch_stream);
+
+        let mut fetch_response_header = match fetch_response_header {
+            /* It was closed while idle in the pool, the request is safe to send again */
+            None if fetch_request.pooled() => {
+                fetch_request.disconnect();
+                if let Err(e) = fetch_request
+                    .connect(
+                        #[cfg(feature = "https")]
+                        certificates,
+                    )
+                    .await
+                {
+                    upstream_error(&current_uri.uri, &e);
+                    return respond_connect_error(&e, &mut stream).await;
+                }
+                continue;
+            }
+            None => {
+                upstream_error(&current_uri.uri, &"unable to extract header");
+                return respond_header_error(Close, started, &mut stream).await;
+            }
+            Some(h) => h,
+        };
+
+        wire_log("Upstream response", &current_uri.uri, || {
+            fetch_response_header.generate()
+        });
+        store_cookies(&current_uri, &mut fetch_response_header);
+        #[cfg(feature = "http3")]
+        remember_alt_svc(&current_uri, &fetch_response_header.headers);
+
+        /* Decided before the origin's connection headers are stripped from what the client sees */
+        let origin_keeps_alive = fetch_response_header.keeps_alive();
+        fetch_response_header.headers.strip_hop_by_hop();
+        apply_via(
+            &mut fetch_response_header.headers,
+            &fetch_response_header.version,
+        );
+
+        match fetch_response_header.status.to_code() {
+            301..=303 | 307..=308 => {
+                let location = match fetch_response_header.headers.get("Location") {
+                    None => {
+                        return respond_with(
+                            keep_alive_if(client_request_header),
+                            HttpResponseStatus::BAD_GATEWAY,
+                            &mut stream,
+                        )
+                        .await
+                    }
+                    Some(l) => l.clone(),
+                };
+
+                if redirects.len() > 5 || redirects.contains(&location) {
+                    return respond_with(
+                        Close,
+                        HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                        &mut stream,
+                    )
+                    .await;
+                }
+                redirects.push_back(location);
+
+                let new_uri = Uri::from(&redirects);
+                if !scheme_allowed(&new_uri.uri) {
+                    return respond_with(Close, HttpResponseStatus::FORBIDDEN, &mut stream).await;
+                }
+
+                /* A HEAD response has no body so the connection can carry on if the origin allows */
+                if !origin_keeps_alive {
+                    fetch_request.disconnect();
+                }
+
+                if let Err(e) = fetch_request
+                    .redirect(
+                        &new_uri,
+                        #[cfg(feature = "https")]
+                        certificates,
+                    )
+                    .await
+                {
+                    upstream_error(&new_uri.uri, &e);
+                    return respond_connect_error(&e, &mut stream).await;
+                }
+            }
+            code => {
+                if remember
+                    && code == 200
+                    && fetch_response_header.is_identity_encoded()
+                    && (!authenticated || caches_authenticated(current_uri.host))
+                {
+                    remember_head(&cache_file_path.to_string_lossy(), &fetch_response_header);
+                }
+
+                /* A HEAD response has no body so the connection is ready for another request */
+                if origin_keeps_alive {
+                    fetch_request.release();
+                }
+
+                fetch_response_header.close_after(client_request_header);
+                let response = fetch_response_header.generate();
+                wire_log("Client response", &current_uri.uri, || response.clone());
+                return match stream.write_all(response.as_bytes()).await {
+                    Ok(_) => keep_alive_if(client_request_header),
+                    Err(_) => Close,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_error_status() {
+        let status = |e: FetchRequestError| connect_error_status(&e).to_code();
+
+        assert_eq!(status(FetchRequestError::InvalidUri), 400);
+        assert_eq!(
+            status(FetchRequestError::DeniedAddress("".to_string())),
+            403
+        );
+        assert_eq!(
+            status(FetchRequestError::DnsResolutionError("".to_string())),
+            502
+        );
+        assert_eq!(
+            status(FetchRequestError::TcpConnectionError("".to_string())),
+            502
+        );
+        assert_eq!(
+            status(FetchRequestError::UpstreamTimeout("".to_string())),
+            504
+        );
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let mut headers = HttpHeader::new();
+        headers.insert(
+            "Proxy-Authorization".to_string(),
+            "Basic YWxpY2U6c2VjcmV0".to_string(),
+        );
+        headers.insert("Proxy-Connection".to_string(), "keep-alive".to_string());
+        headers.insert("Range".to_string(), "bytes=0-99".to_string());
+        headers.insert("User-Agent".to_string(), "apt".to_string());
+        let client_request_header = HttpRequestHeader {
+            method: HttpRequestMethod::Get,
+            request: Uri::from("http://example.com/file.deb".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers,
+        };
+
+        let forwarded = forwarded_headers(&client_request_header, "example.com".to_string());
+        assert_eq!(forwarded.get("Proxy-Authorization"), None);
+        assert_eq!(forwarded.get("Proxy-Connection"), None);
+        assert_eq!(forwarded.get("Range"), None);
+        assert_eq!(forwarded.get("User-Agent"), Some(&"apt".to_string()));
+        assert_eq!(forwarded.get("Host"), Some(&"example.com".to_string()));
+        assert_eq!(
+            forwarded.get("Accept-Encoding"),
+            Some(&"identity".to_string())
+        );
+    }
+}
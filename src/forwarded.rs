@@ -0,0 +1,305 @@
+use {
+    crate::{
+        cli::setting,
+        conn::UriKind,
+        digest::to_hex,
+        http::{HttpHeader, HttpRequestHeader, HttpVersion},
+        PKG_NAME, PKG_VERSION,
+    },
+    std::{
+        io::Read,
+        net::IpAddr,
+        sync::OnceLock,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tracing::{error, info},
+};
+
+pub const X_PROXY_FORWARDED: &str = "X_PROXY_FORWARDED";
+
+/// What the proxy adds to the messages it passes between clients and origin servers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum ForwardedMode {
+    /// Messages are passed on without any sign they went through the proxy
+    Off,
+    /// A `Via` header names the proxy in requests and responses
+    #[default]
+    Via,
+    /// As `Via`, with the client's address in `X-Forwarded-For` and `Forwarded` of requests
+    Client,
+}
+
+impl ForwardedMode {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "off" => Some(ForwardedMode::Off),
+            "via" => Some(ForwardedMode::Via),
+            "client" => Some(ForwardedMode::Client),
+            _ => None,
+        }
+    }
+}
+
+static MODE: OnceLock<ForwardedMode> = OnceLock::new();
+
+fn forwarded_mode() -> ForwardedMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Read `X_PROXY_FORWARDED`, false when it isn't a mode rproxy knows
+pub(crate) fn setup_forwarded() -> bool {
+    let mode = match setting(X_PROXY_FORWARDED) {
+        Err(_) => ForwardedMode::default(),
+        Ok(s) => match ForwardedMode::from_name(&s) {
+            Some(m) => m,
+            None => {
+                error!("'{X_PROXY_FORWARDED}' must be 'off', 'via' or 'client': '{s}'");
+                return false;
+            }
+        },
+    };
+
+    if mode != ForwardedMode::default() {
+        info!("forwarded: {mode:?}");
+    }
+    let _ = MODE.set(mode);
+    true
+}
+
+/* A list header is kept as one comma separated field however many it arrived in */
+fn append(headers: &mut HttpHeader, name: &str, value: &str) {
+    let value = match headers.get_joined(name) {
+        Some(v) if !v.trim().is_empty() => format!("{v}, {value}"),
+        _ => value.to_string(),
+    };
+    headers.insert(name.to_string(), value);
+}
+
+/// Tells this rproxy apart from any other in a chain of proxies, picked afresh each start
+fn instance_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        let mut bytes = [0u8; 4];
+        if std::fs::File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut bytes))
+            .is_err()
+        {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .subsec_nanos();
+            bytes = (now ^ std::process::id().rotate_left(16)).to_be_bytes();
+        }
+        to_hex(&bytes)
+    })
+}
+
+/* The protocol the message was received with followed by who received it,
+ * as in `1.1 rproxy/1.0.0 (0a1b2c3d)` where the comment is this instance's ID */
+fn via(version: &HttpVersion) -> String {
+    let protocol = match *version {
+        HttpVersion::HTTP_V10 => "1.0",
+        _ => "1.1",
+    };
+    format!("{protocol} {PKG_NAME}/{PKG_VERSION} ({})", instance_id())
+}
+
+/* Whether a `Via` names the proxy with ID `id` among those the message already went through */
+fn via_has(value: &str, id: &str) -> bool {
+    value.split(',').any(|hop| {
+        hop.trim()
+            .split_once('(')
+            .is_some_and(|(_, comment)| comment.trim_end().trim_end_matches(')').trim() == id)
+    })
+}
+
+/// Whether a request already went through this rproxy, such as when it's been set as the
+/// proxy of an origin it fetches from, so it's refused instead of sent around again
+pub(crate) fn is_loop(client_request_header: &HttpRequestHeader<'_>) -> bool {
+    client_request_header
+        .headers
+        .get_all("Via")
+        .any(|v| via_has(v, instance_id()))
+}
+
+/* IPv6 addresses have to be quoted and bracketed so their colons aren't read as a port */
+fn forwarded_for(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(a) => format!("for={a}"),
+        IpAddr::V6(a) => format!("for=\"[{a}]\""),
+    }
+}
+
+fn forwarded(
+    mode: ForwardedMode,
+    client_request_header: &mut HttpRequestHeader<'_>,
+    address: IpAddr,
+) {
+    if mode == ForwardedMode::Off {
+        return;
+    }
+
+    let via = via(&client_request_header.version);
+    append(&mut client_request_header.headers, "Via", &via);
+
+    if mode == ForwardedMode::Client {
+        let address = match address {
+            IpAddr::V6(a) => a.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            a => a,
+        };
+        append(
+            &mut client_request_header.headers,
+            "X-Forwarded-For",
+            &address.to_string(),
+        );
+        append(
+            &mut client_request_header.headers,
+            "Forwarded",
+            &forwarded_for(address),
+        );
+    }
+}
+
+/// Add the proxy to the `Via` of a request that's going to an origin server,
+/// along with the address of the client that sent it when set to
+pub(crate) fn apply_forwarded(client_request_header: &mut HttpRequestHeader<'_>, address: IpAddr) {
+    if client_request_header.request.kind() != UriKind::AbsolutePath {
+        forwarded(forwarded_mode(), client_request_header, address);
+    }
+}
+
+/// Add the proxy to the `Via` of a response from an origin server that was received as `version`
+pub(crate) fn apply_via(headers: &mut HttpHeader, version: &HttpVersion) {
+    if forwarded_mode() != ForwardedMode::Off {
+        append(headers, "Via", &via(version));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::conn::Uri, std::net::Ipv6Addr};
+
+    fn request(via: Option<&str>) -> HttpRequestHeader<'static> {
+        let mut headers = HttpHeader::new();
+        if let Some(v) = via {
+            headers.insert("Via".to_string(), v.to_string());
+        }
+        HttpRequestHeader {
+            method: crate::http::HttpRequestMethod::Get,
+            request: Uri::from("http://example.com/file".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers,
+        }
+    }
+
+    #[test]
+    fn test_forwarded_mode_from_name() {
+        assert_eq!(ForwardedMode::from_name(" Off"), Some(ForwardedMode::Off));
+        assert_eq!(ForwardedMode::from_name("via"), Some(ForwardedMode::Via));
+        assert_eq!(
+            ForwardedMode::from_name("CLIENT"),
+            Some(ForwardedMode::Client)
+        );
+        assert_eq!(ForwardedMode::from_name("yes"), None);
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let address = IpAddr::from([192, 0, 2, 7]);
+        let ours = format!("1.1 {PKG_NAME}/{PKG_VERSION} ({})", instance_id());
+
+        let mut header = request(None);
+        forwarded(ForwardedMode::Off, &mut header, address);
+        assert!(header.headers.is_empty());
+
+        forwarded(ForwardedMode::Via, &mut header, address);
+        assert_eq!(header.headers.get("Via"), Some(&ours));
+        assert_eq!(header.headers.get("X-Forwarded-For"), None);
+        assert_eq!(header.headers.get("Forwarded"), None);
+
+        /* Proxies the request already went through are kept ahead of this one */
+        let mut header = request(Some("1.0 squid"));
+        header
+            .headers
+            .insert("X-Forwarded-For".to_string(), "198.51.100.1".to_string());
+        forwarded(ForwardedMode::Client, &mut header, address);
+        assert_eq!(
+            header.headers.get("Via"),
+            Some(&format!("1.0 squid, {ours}"))
+        );
+        assert_eq!(
+            header.headers.get("X-Forwarded-For"),
+            Some(&"198.51.100.1, 192.0.2.7".to_string())
+        );
+        assert_eq!(
+            header.headers.get("Forwarded"),
+            Some(&"for=192.0.2.7".to_string())
+        );
+
+        let mut header = request(None);
+        header.version = HttpVersion::HTTP_V10;
+        forwarded(
+            ForwardedMode::Client,
+            &mut header,
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        );
+        assert_eq!(
+            header.headers.get("Via"),
+            Some(&format!("1.0 {PKG_NAME}/{PKG_VERSION} ({})", instance_id()))
+        );
+        assert_eq!(
+            header.headers.get("X-Forwarded-For"),
+            Some(&"::1".to_string())
+        );
+        assert_eq!(
+            header.headers.get("Forwarded"),
+            Some(&"for=\"[::1]\"".to_string())
+        );
+
+        /* A client on IPv4 reached through a dual stack listener is shown as IPv4 */
+        let mut header = request(None);
+        let mapped = IpAddr::V6(Ipv6Addr::from([0, 0, 0, 0, 0, 0xffff, 0xc000, 0x0207]));
+        forwarded(ForwardedMode::Client, &mut header, mapped);
+        assert_eq!(
+            header.headers.get("X-Forwarded-For"),
+            Some(&"192.0.2.7".to_string())
+        );
+        assert_eq!(
+            header.headers.get("Forwarded"),
+            Some(&"for=192.0.2.7".to_string())
+        );
+    }
+
+    #[test]
+    fn test_is_loop() {
+        let id = instance_id();
+        assert_eq!(id.len(), 8);
+
+        let mut header = request(None);
+        assert!(!is_loop(&header));
+        forwarded(
+            ForwardedMode::Via,
+            &mut header,
+            IpAddr::from([192, 0, 2, 7]),
+        );
+        assert!(is_loop(&header));
+
+        /* Another rproxy of the same version in the chain isn't this one */
+        let header = request(Some(&format!(
+            "1.1 {PKG_NAME}/{PKG_VERSION} (00000000x), 1.0 squid"
+        )));
+        assert!(!is_loop(&header));
+
+        let header = request(Some(&format!(
+            "1.0 squid (Squid/6.1), 1.1 {PKG_NAME} ( {id} )"
+        )));
+        assert!(is_loop(&header));
+
+        /* However many `Via` fields the hops were recorded in */
+        let mut header = request(Some("1.0 squid"));
+        header
+            .headers
+            .append("via".to_string(), format!("1.1 {PKG_NAME} ({id})"));
+        assert!(is_loop(&header));
+    }
+}
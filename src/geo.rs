@@ -0,0 +1,349 @@
+use {
+    crate::{
+        alias::{mirror_aliases, MirrorAlias},
+        cli::setting,
+        conn::Uri,
+        dns::resolve,
+        evict::matches_pattern,
+        http::HttpRequestHeader,
+    },
+    maxminddb::{path, Reader},
+    std::{
+        collections::HashMap,
+        net::IpAddr,
+        sync::{Mutex, OnceLock},
+        time::{Duration, Instant},
+    },
+    tracing::{debug, error, info},
+};
+
+pub const X_PROXY_GEOIP_DATABASES: &str = "X_PROXY_GEOIP_DATABASES";
+pub const X_PROXY_GEOIP_LOCATION: &str = "X_PROXY_GEOIP_LOCATION";
+
+/// How long a mirror stays the closest before the candidates are looked up again
+const CHOICE_SECONDS: u64 = 3600;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// What the databases know of an address, an ASN database gives the network
+/// and a city database the coordinates
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Place {
+    asn: Option<u32>,
+    coordinates: Option<(f64, f64)>,
+}
+
+struct GeoIp {
+    readers: Vec<Reader<Vec<u8>>>,
+    here: Place,
+}
+
+static GEOIP: OnceLock<GeoIp> = OnceLock::new();
+
+/* Canonical host to the mirror picked for it and when */
+fn choices() -> &'static Mutex<HashMap<String, (String, Instant)>> {
+    static CHOICES: OnceLock<Mutex<HashMap<String, (String, Instant)>>> = OnceLock::new();
+    CHOICES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn lookup(readers: &[Reader<Vec<u8>>], address: IpAddr) -> Place {
+    let mut place = Place::default();
+    for reader in readers {
+        let result = match reader.lookup(address) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+
+        if place.asn.is_none() {
+            place.asn = result
+                .decode_path(&path!["autonomous_system_number"])
+                .ok()
+                .flatten();
+        }
+
+        if place.coordinates.is_none() {
+            let latitude = result.decode_path(&path!["location", "latitude"]);
+            let longitude = result.decode_path(&path!["location", "longitude"]);
+            if let (Ok(Some(latitude)), Ok(Some(longitude))) = (latitude, longitude) {
+                place.coordinates = Some((latitude, longitude));
+            }
+        }
+    }
+    place
+}
+
+/* Written as `latitude,longitude` in degrees */
+fn parse_coordinates(value: &str) -> Option<(f64, f64)> {
+    let (latitude, longitude) = value.split_once(',')?;
+    let latitude: f64 = latitude.trim().parse().ok()?;
+    let longitude: f64 = longitude.trim().parse().ok()?;
+    match (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) {
+        true => Some((latitude, longitude)),
+        false => None,
+    }
+}
+
+/// Open the databases in `X_PROXY_GEOIP_DATABASES` and place rproxy with `X_PROXY_GEOIP_LOCATION`,
+/// either its public address or its coordinates. False when either can't be used.
+pub(crate) fn setup_geoip() -> bool {
+    let paths = match setting(X_PROXY_GEOIP_DATABASES) {
+        Ok(p) => p,
+        Err(_) => return true,
+    };
+
+    let mut readers = Vec::new();
+    for path in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match Reader::open_readfile(path) {
+            Ok(r) => readers.push(r),
+            Err(e) => {
+                error!("couldn't open GeoIP database '{path}': {e}");
+                return false;
+            }
+        }
+    }
+
+    let location = match setting(X_PROXY_GEOIP_LOCATION) {
+        Ok(l) => l,
+        Err(_) => {
+            error!(
+                "'{X_PROXY_GEOIP_LOCATION}' must be set to rproxy's public address \
+                or coordinates to pick the closest mirrors"
+            );
+            return false;
+        }
+    };
+
+    let here = match (
+        location.trim().parse::<IpAddr>(),
+        parse_coordinates(&location),
+    ) {
+        (Ok(address), _) => lookup(&readers, address),
+        (_, Some(coordinates)) => Place {
+            asn: None,
+            coordinates: Some(coordinates),
+        },
+        _ => {
+            error!("'{X_PROXY_GEOIP_LOCATION}' must be an address or 'latitude,longitude': '{location}'");
+            return false;
+        }
+    };
+
+    if here == Place::default() {
+        error!("the GeoIP databases know nothing of '{location}'");
+        return false;
+    }
+
+    info!(
+        "picking mirrors closest to {}{}",
+        here.asn.map(|a| format!("AS{a} ")).unwrap_or_default(),
+        here.coordinates
+            .map(|(la, lo)| format!("{la:.2},{lo:.2}"))
+            .unwrap_or_default()
+    );
+    let _ = GEOIP.set(GeoIp { readers, here });
+    true
+}
+
+/// Great circle distance between two points in degrees
+fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (la1, lo1) = (from.0.to_radians(), from.1.to_radians());
+    let (la2, lo2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((la2 - la1) / 2.0).sin().powi(2)
+        + la1.cos() * la2.cos() * ((lo2 - lo1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/* Lower is closer. A mirror on the same network beats any distance, and a mirror
+ * the databases can't place comes after every one they can */
+fn rank(here: &Place, there: &Place) -> (bool, u64) {
+    let same_network = here.asn.is_some() && here.asn == there.asn;
+    let distance = match (here.coordinates, there.coordinates) {
+        (Some(h), Some(t)) => distance_km(h, t) as u64,
+        _ => u64::MAX,
+    };
+    (!same_network, distance)
+}
+
+/// Hosts that serve the same files as `host`: the canonical host of its alias rule and every
+/// pattern without a wildcard. The requested host comes first so it's kept on a tie.
+fn equivalents(aliases: &[MirrorAlias], host: &str) -> Option<(String, Vec<String>)> {
+    let host = host.to_lowercase();
+    let alias = aliases
+        .iter()
+        .find(|a| a.canonical == host || a.patterns.iter().any(|p| matches_pattern(p, &host)))?;
+
+    let mut hosts = vec![host];
+    for candidate in std::iter::once(&alias.canonical).chain(&alias.patterns) {
+        if !candidate.contains('*') && !hosts.contains(candidate) {
+            hosts.push(candidate.clone());
+        }
+    }
+    Some((alias.canonical.clone(), hosts))
+}
+
+async fn closest_mirror(geoip: &GeoIp, host: &str, port: u16) -> Option<String> {
+    let (canonical, hosts) = equivalents(&mirror_aliases(), host)?;
+    if hosts.len() < 2 {
+        return None;
+    }
+
+    if let Ok(choices) = choices().lock() {
+        if let Some((mirror, when)) = choices.get(&canonical) {
+            if when.elapsed() < Duration::from_secs(CHOICE_SECONDS) {
+                return Some(mirror.clone());
+            }
+        }
+    }
+
+    let mut best: Option<((bool, u64), String)> = None;
+    for candidate in hosts {
+        /* A mirror that can't be found can't be the closest */
+        let address = match resolve(&candidate, port)
+            .await
+            .ok()
+            .and_then(|a| a.first().copied())
+        {
+            Some(a) => a.ip(),
+            None => continue,
+        };
+        let rank = rank(&geoip.here, &lookup(&geoip.readers, address));
+        debug!("Mirror {candidate} for {canonical} ranks {rank:?}");
+        if best.as_ref().is_none_or(|(b, _)| rank < *b) {
+            best = Some((rank, candidate));
+        }
+    }
+
+    let (_, mirror) = best?;
+    if let Ok(mut choices) = choices().lock() {
+        choices.insert(canonical, (mirror.clone(), Instant::now()));
+    }
+    Some(mirror)
+}
+
+/* `uri` with its host swapped for `mirror`, the rest left as it was */
+fn with_host(uri: &Uri<'_>, mirror: &str) -> Option<String> {
+    let scheme = uri.scheme?;
+    let rest = uri.uri.get(scheme.len()..)?;
+    let host = uri.host?;
+    match rest.get(..host.len()) {
+        Some(h) if h.eq_ignore_ascii_case(host) => {
+            Some(format!("{scheme}{mirror}{}", &rest[host.len()..]))
+        }
+        _ => None,
+    }
+}
+
+/// Send a request for a host with equivalent mirrors to the one closest to rproxy instead,
+/// according to the GeoIP databases. The cache entry is shared through the alias rule either way.
+pub(crate) async fn apply_closest_mirror(client_request_header: &mut HttpRequestHeader<'_>) {
+    let geoip = match GEOIP.get() {
+        Some(g) => g,
+        None => return,
+    };
+
+    let (host, port) = match (
+        client_request_header.request.host,
+        client_request_header.request.port,
+    ) {
+        (Some(h), Some(p)) => (h.to_string(), p),
+        _ => return,
+    };
+
+    let mirror = match closest_mirror(geoip, &host, port).await {
+        Some(m) if !m.eq_ignore_ascii_case(&host) => m,
+        _ => return,
+    };
+
+    if let Some(uri) = with_host(&client_request_header.request, &mirror) {
+        debug!(
+            "Fetching {} from closer mirror {mirror}",
+            client_request_header.request.uri
+        );
+        client_request_header.request = Uri::from(uri);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::alias::parse_aliases};
+
+    #[test]
+    fn test_parse_coordinates() {
+        assert_eq!(parse_coordinates("-33.87, 151.21"), Some((-33.87, 151.21)));
+        assert_eq!(parse_coordinates("91,0"), None);
+        assert_eq!(parse_coordinates("0,181"), None);
+        assert_eq!(parse_coordinates("203.0.113.1"), None);
+    }
+
+    #[test]
+    fn test_distance_km() {
+        let sydney = (-33.87, 151.21);
+        let london = (51.51, -0.13);
+        let distance = distance_km(sydney, london);
+        assert!((16_900.0..17_100.0).contains(&distance));
+        assert!(distance_km(london, london) < 1.0);
+    }
+
+    #[test]
+    fn test_rank() {
+        let here = Place {
+            asn: Some(64500),
+            coordinates: Some((-33.87, 151.21)),
+        };
+        let near = Place {
+            asn: Some(64501),
+            coordinates: Some((-37.81, 144.96)),
+        };
+        let far = Place {
+            asn: Some(64502),
+            coordinates: Some((51.51, -0.13)),
+        };
+        let same_network = Place {
+            asn: Some(64500),
+            coordinates: Some((51.51, -0.13)),
+        };
+        let unknown = Place::default();
+
+        assert!(rank(&here, &near) < rank(&here, &far));
+        assert!(rank(&here, &same_network) < rank(&here, &near));
+        assert!(rank(&here, &far) < rank(&here, &unknown));
+    }
+
+    #[test]
+    fn test_equivalents() {
+        let aliases = parse_aliases(
+            "deb.debian.org=ftp.*.debian.org,ftp.au.debian.org,ftp.uk.debian.org;example.com=",
+        );
+
+        let (canonical, hosts) = equivalents(&aliases, "FTP.uk.debian.org").unwrap();
+        assert_eq!(canonical, "deb.debian.org");
+        assert_eq!(
+            hosts,
+            vec!["ftp.uk.debian.org", "deb.debian.org", "ftp.au.debian.org"]
+        );
+
+        let (_, hosts) = equivalents(&aliases, "deb.debian.org").unwrap();
+        assert_eq!(
+            hosts,
+            vec!["deb.debian.org", "ftp.au.debian.org", "ftp.uk.debian.org"]
+        );
+
+        assert_eq!(equivalents(&aliases, "example.com").unwrap().1.len(), 1);
+        assert!(equivalents(&aliases, "example.org").is_none());
+    }
+
+    #[test]
+    fn test_with_host() {
+        let uri = Uri::from("http://deb.debian.org:8080/debian/dists?x=1".to_string());
+        assert_eq!(
+            with_host(&uri, "ftp.au.debian.org"),
+            Some("http://ftp.au.debian.org:8080/debian/dists?x=1".to_string())
+        );
+
+        let uri = Uri::from("https://Deb.Debian.org/debian".to_string());
+        assert_eq!(
+            with_host(&uri, "ftp.au.debian.org"),
+            Some("https://ftp.au.debian.org/debian".to_string())
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use {
+    crate::http::{HttpHeader, HttpResponseHeader},
+    std::{
+        collections::HashMap,
+        sync::{OnceLock, RwLock},
+        time::Duration,
+    },
+    tokio::time::Instant,
+};
+
+/// How long the answer to a `HEAD` request is reused before the origin is asked again
+const HEAD_METADATA_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Upper bound on remembered answers so clients probing many URLs can't grow the table forever
+const MAX_HEAD_METADATA: usize = 4096;
+
+/// Headers that describe the file itself, everything else in a `HEAD` response is left behind
+const METADATA_HEADERS: [&str; 5] = [
+    "Content-Length",
+    "Content-Type",
+    "Last-Modified",
+    "ETag",
+    "Accept-Ranges",
+];
+
+struct HeadMetadata {
+    headers: HttpHeader,
+    fetched: Instant,
+}
+
+/* Keyed by cache file path, like the hit table */
+fn head_table() -> &'static RwLock<HashMap<String, HeadMetadata>> {
+    static HEADS: OnceLock<RwLock<HashMap<String, HeadMetadata>>> = OnceLock::new();
+    HEADS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn metadata_headers(response: &HttpResponseHeader) -> HttpHeader {
+    let mut headers = HttpHeader::new();
+    for name in METADATA_HEADERS {
+        if let Some(value) = response.headers.get(name) {
+            headers.insert(name.to_string(), value.clone());
+        }
+    }
+    headers
+}
+
+/// Keep what a successful upstream `HEAD` said about the file cached at `path`
+pub(crate) fn remember_head(path: &str, response: &HttpResponseHeader) {
+    let mut heads = match head_table().write() {
+        Ok(h) => h,
+        Err(_) => return,
+    };
+
+    if heads.len() >= MAX_HEAD_METADATA && !heads.contains_key(path) {
+        heads.retain(|_, m| m.fetched.elapsed() < HEAD_METADATA_LIFETIME);
+        if heads.len() >= MAX_HEAD_METADATA {
+            if let Some(oldest) = heads
+                .iter()
+                .min_by_key(|(_, m)| m.fetched)
+                .map(|(p, _)| p.clone())
+            {
+                heads.remove(&oldest);
+            }
+        }
+    }
+
+    heads.insert(
+        path.to_string(),
+        HeadMetadata {
+            headers: metadata_headers(response),
+            fetched: Instant::now(),
+        },
+    );
+}
+
+/// The headers of an earlier `HEAD` for the file cached at `path`, if they're recent enough to reuse
+pub(crate) fn recall_head(path: &str) -> Option<HttpHeader> {
+    let heads = head_table().read().ok()?;
+    let metadata = heads.get(path)?;
+
+    match metadata.fetched.elapsed() < HEAD_METADATA_LIFETIME {
+        true => Some(metadata.headers.clone()),
+        false => None,
+    }
+}
+
+/// The length an earlier `HEAD` gave for the file cached at `path`, even if it's no longer reused
+pub(crate) fn head_length(path: &str) -> Option<u64> {
+    let heads = head_table().read().ok()?;
+    heads.get(path)?.headers.get("Content-Length")?.parse().ok()
+}
+
+/// Once a file has been downloaded it describes itself, so what `HEAD` said about it goes
+pub(crate) fn forget_head(path: &str) {
+    if let Ok(mut heads) = head_table().write() {
+        heads.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::http::{HttpResponseStatus, HttpVersion},
+    };
+
+    #[test]
+    fn test_remember_head() {
+        let mut headers = HttpHeader::new();
+        headers.insert("Content-Length".to_string(), "1234".to_string());
+        headers.insert("ETag".to_string(), "\"abc\"".to_string());
+        headers.insert("Set-Cookie".to_string(), "session=1".to_string());
+        let response = HttpResponseHeader {
+            status: HttpResponseStatus::OK,
+            headers,
+            version: HttpVersion::HTTP_V11,
+        };
+
+        remember_head("/cache/example.com/head.iso", &response);
+
+        let recalled = recall_head("/cache/example.com/head.iso").unwrap();
+        assert_eq!(recalled.get("ETag").map(String::as_str), Some("\"abc\""));
+        assert!(recalled.get("Set-Cookie").is_none());
+        assert_eq!(head_length("/cache/example.com/head.iso"), Some(1234));
+
+        forget_head("/cache/example.com/head.iso");
+        assert!(recall_head("/cache/example.com/head.iso").is_none());
+        assert_eq!(head_length("/cache/example.com/head.iso"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_head_lifetime() {
+        let mut headers = HttpHeader::new();
+        headers.insert("Content-Length".to_string(), "1234".to_string());
+        let response = HttpResponseHeader {
+            status: HttpResponseStatus::OK,
+            headers,
+            version: HttpVersion::HTTP_V11,
+        };
+
+        remember_head("/cache/example.com/lifetime.iso", &response);
+
+        crate::clock::advance(HEAD_METADATA_LIFETIME - Duration::from_secs(1)).await;
+        assert!(recall_head("/cache/example.com/lifetime.iso").is_some());
+
+        crate::clock::advance(Duration::from_secs(1)).await;
+        assert!(recall_head("/cache/example.com/lifetime.iso").is_none());
+        /* The length is still good for reserving space */
+        assert_eq!(head_length("/cache/example.com/lifetime.iso"), Some(1234));
+    }
+}
@@ -1,17 +1,30 @@
-use crate::conn::{Uri, UriKind};
+use crate::alias::{canonical_host, mirror_aliases};
+use crate::cli::setting;
+use crate::conn::{scheme_of, Uri, UriKind};
+use crate::evict::parse_size;
 use crate::http::ConnectionReturn::{Close, Keep};
+use crate::layout::CacheLayout;
+use crate::schedule::MissTurn;
 use std::{
-    collections::HashMap,
+    collections::VecDeque,
     fmt::Formatter,
+    future::poll_fn,
     path::{Path, PathBuf},
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
     time::SystemTime,
 };
 use tokio::{
-    fs::{remove_file, File},
-    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    fs::remove_file,
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite,
+        AsyncWriteExt, BufReader, ReadBuf,
+    },
     join,
     time::{self, timeout, Duration, Instant},
 };
+use tracing::error;
 
 pub(crate) const END_OF_HTTP_HEADER: &str = "\r\n\r\n";
 
@@ -21,7 +34,86 @@ pub const X_PROXY_CACHE_PATH: &str = "X_PROXY_CACHE_PATH";
 
 /* 16 KiB will occupy half of l1d on a typical x86_64 core */
 pub const BUFFER_SIZE: usize = 16384;
-const WAIT_TIMEOUT_SECONDS: u64 = 10;
+pub(crate) const WAIT_TIMEOUT_SECONDS: u64 = 10;
+
+pub const X_PROXY_MAX_REQUEST_LINE: &str = "X_PROXY_MAX_REQUEST_LINE";
+pub const X_PROXY_MAX_HEADER_FIELD: &str = "X_PROXY_MAX_HEADER_FIELD";
+pub const X_PROXY_MAX_HEADER_SIZE: &str = "X_PROXY_MAX_HEADER_SIZE";
+pub const X_PROXY_MAX_HEADER_FIELDS: &str = "X_PROXY_MAX_HEADER_FIELDS";
+
+/// How much of a request header a client may send before it's turned away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct HeaderLimits {
+    /// Bytes in the request line, which is mostly the URI
+    pub(crate) request_line: usize,
+    /// Bytes in any one field, name and value together
+    pub(crate) field: usize,
+    /// Bytes in all the fields together
+    pub(crate) total: usize,
+    /// How many fields there may be
+    pub(crate) fields: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            request_line: 8192,
+            field: 8192,
+            total: 65536,
+            fields: 100,
+        }
+    }
+}
+
+/// The limits on request headers, each can be changed by its `X_PROXY_MAX_*` variable
+pub(crate) fn header_limits() -> &'static HeaderLimits {
+    static LIMITS: OnceLock<HeaderLimits> = OnceLock::new();
+    LIMITS.get_or_init(|| {
+        let size = |name: &str| {
+            setting(name)
+                .ok()
+                .and_then(|v| parse_size(&v))
+                .filter(|v| *v > 0)
+                .map(|v| v.min(usize::MAX as u64) as usize)
+        };
+        let default = HeaderLimits::default();
+        HeaderLimits {
+            request_line: size(X_PROXY_MAX_REQUEST_LINE).unwrap_or(default.request_line),
+            field: size(X_PROXY_MAX_HEADER_FIELD).unwrap_or(default.field),
+            total: size(X_PROXY_MAX_HEADER_SIZE).unwrap_or(default.total),
+            fields: setting(X_PROXY_MAX_HEADER_FIELDS)
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(default.fields),
+        }
+    })
+}
+
+/// Why a request header wasn't read
+#[derive(Debug, PartialEq)]
+pub(crate) enum HeaderError {
+    /// The client went quiet, hung up or sent something that isn't an HTTP request
+    Unreadable,
+    /// The request line is over its limit, answered with `414 URI Too Long`
+    RequestLineTooLong,
+    /// A field, all of them together or how many there are is over its limit,
+    /// answered with `431 Request Header Fields Too Large`
+    FieldsTooLarge,
+}
+
+impl HeaderError {
+    /// What the client is told before the connection is closed, nothing when it can't be told
+    pub(crate) fn status(&self) -> Option<HttpResponseStatus> {
+        match self {
+            HeaderError::Unreadable => None,
+            HeaderError::RequestLineTooLong => Some(HttpResponseStatus::URI_TOO_LONG),
+            HeaderError::FieldsTooLarge => {
+                Some(HttpResponseStatus::REQUEST_HEADER_FIELDS_TOO_LARGE)
+            }
+        }
+    }
+}
 
 pub(crate) enum ConnectionReturn {
     Close,
@@ -44,6 +136,7 @@ impl PartialEq for ConnectionReturn {
     }
 }
 
+#[derive(Clone)]
 pub(crate) enum HttpRequestMethod {
     Get,
     Post,
@@ -109,6 +202,7 @@ impl std::fmt::Display for HttpRequestMethod {
     }
 }
 
+#[derive(PartialEq)]
 pub struct HttpVersion(u16);
 
 impl HttpVersion {
@@ -134,87 +228,167 @@ impl HttpVersion {
 }
 
 pub(crate) fn keep_alive_if(header: &HttpRequestHeader) -> ConnectionReturn {
+    /* `Connection` is a list of options such as `keep-alive, Upgrade` in any case */
+    let has = |option: &str| header.headers.has_token("Connection", option);
+
     match header.version {
-        HttpVersion(11) => match header.headers.get("Connection") {
-            None => Keep,
-            Some(v) => {
-                if v == "Close" {
-                    Close
-                } else {
-                    Keep
-                }
-            }
-        },
-        HttpVersion(10) => match header.headers.get("Connection") {
-            None => Close,
-            Some(v) => {
-                if v == "Close" {
-                    Close
-                } else {
-                    Keep
-                }
-            }
-        },
+        HttpVersion(11) if !has("close") => Keep,
+        HttpVersion(10) if has("keep-alive") && !has("close") => Keep,
         _ => Close,
     }
 }
 
-#[derive(Clone)]
+/// Header fields in the order they arrived. A name may appear more than once, such as
+/// `Set-Cookie` or `Via`, and is looked up without regard to case.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct HttpHeader {
-    pub header: HashMap<String, (String, String)>,
-}
-
-impl Default for HttpHeader {
-    fn default() -> Self {
-        Self::new()
-    }
+    fields: Vec<(String, String)>,
 }
 
 impl HttpHeader {
     pub fn new() -> Self {
-        HttpHeader {
-            header: HashMap::new(),
-        }
+        HttpHeader { fields: Vec::new() }
     }
 
-    pub fn contains_key(&mut self, k: &str) -> bool {
-        let key = k.to_uppercase();
-        self.header.contains_key(&key)
+    pub fn len(&self) -> usize {
+        self.fields.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn contains_key(&self, k: &str) -> bool {
+        self.fields
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case(k))
+    }
+
+    /// Set `k` to `v` alone, in place of the first field with that name and instead of any others
     pub fn insert(&mut self, k: String, v: String) {
-        let key = k.to_uppercase();
-        self.header.insert(key, (k, v));
+        match self
+            .fields
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(&k))
+        {
+            Some(i) => {
+                let mut rest = self.fields.split_off(i + 1);
+                rest.retain(|(key, _)| !key.eq_ignore_ascii_case(&k));
+                self.fields.extend(rest);
+                self.fields[i] = (k, v);
+            }
+            None => self.fields.push((k, v)),
+        }
+    }
+
+    /// Add another field named `k` after any already there
+    pub fn append(&mut self, k: String, v: String) {
+        self.fields.push((k, v));
     }
 
+    /// The value of the first field named `k`
     pub fn get(&self, k: &str) -> Option<&String> {
-        let key = k.to_uppercase();
-        match self.header.get(&key) {
-            None => None,
-            Some((_, x)) => Some(x),
-        }
+        self.fields
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(k))
+            .map(|(_, value)| value)
+    }
+
+    /// The value of every field named `k`, in order
+    pub fn get_all<'a>(&'a self, k: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(k))
+            .map(|(_, value)| value)
     }
 
-    pub fn get_all(&self, k: &str) -> Option<&(String, String)> {
-        let key = k.to_uppercase();
-        self.header.get(&key)
+    /// Every field named `k` as one comma separated list, which is how RFC 9110 section 5.3
+    /// says list headers such as `Connection` or `Via` are to be read when they're repeated
+    pub fn get_joined(&self, k: &str) -> Option<String> {
+        let values: Vec<&str> = self.get_all(k).map(String::as_str).collect();
+        match values.is_empty() {
+            true => None,
+            false => Some(values.join(", ")),
+        }
     }
 
     pub fn remove(&mut self, k: &str) {
-        let key = k.to_uppercase();
-        self.header.remove(&key);
+        self.fields.retain(|(key, _)| !key.eq_ignore_ascii_case(k));
+    }
+
+    /// Whether the list header `k` has `token` among its elements in any case, such as `close`
+    /// in `Connection` or `no-store` in `Cache-Control`. Arguments like `max-age=60` are ignored.
+    pub fn has_token(&self, k: &str, token: &str) -> bool {
+        self.get_all(k).flat_map(|v| v.split(',')).any(|element| {
+            element
+                .split(['=', ';'])
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case(token)
+        })
+    }
+
+    /// Whether the last transfer coding is `chunked`, which is what lets the body end on its own
+    pub fn is_chunked(&self) -> bool {
+        self.get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .last()
+            .is_some_and(|c| c.eq_ignore_ascii_case("chunked"))
+    }
+
+    /// Remove the headers that only describe one connection, along with any the `Connection`
+    /// header names, so they aren't passed along to the next hop. The body framing is left alone
+    /// since the body is relayed as it arrived
+    pub(crate) fn strip_hop_by_hop(&mut self) {
+        if let Some(connection) = self.get_joined("Connection") {
+            for name in connection.split(',').map(str::trim) {
+                if !FRAMING_HEADERS.iter().any(|f| f.eq_ignore_ascii_case(name)) {
+                    self.remove(name);
+                }
+            }
+        }
+
+        for name in HOP_BY_HOP_HEADERS {
+            self.remove(name);
+        }
+    }
+
+    /// Remove the headers a client meant for rproxy itself, such as its proxy credentials,
+    /// so nothing forwarded to an origin server gives them away
+    pub(crate) fn strip_proxy_only(&mut self) {
+        for name in PROXY_ONLY_HEADERS {
+            self.remove(name);
+        }
     }
 }
 
+/* Headers that only mean something on the connection they arrived on */
+const HOP_BY_HOP_HEADERS: [&str; 5] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Upgrade",
+];
+
+/* Headers meant for the proxy itself rather than the origin */
+const PROXY_ONLY_HEADERS: [&str; 2] = ["Proxy-Authorization", "Expect"];
+
+/* Headers a `Connection` option can't remove because the body can't be read without them */
+const FRAMING_HEADERS: [&str; 3] = ["Content-Length", "Host", "Transfer-Encoding"];
+
 impl<'a> IntoIterator for &'a HttpHeader {
     type Item = (&'a String, &'a String);
     type IntoIter = std::iter::Map<
-        std::collections::hash_map::Values<'a, String, (String, String)>,
+        std::slice::Iter<'a, (String, String)>,
         fn(&'a (String, String)) -> (&'a String, &'a String),
     >;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.header.values().map(|(key, value)| (key, value))
+        self.fields.iter().map(|(key, value)| (key, value))
     }
 }
 
@@ -235,7 +409,11 @@ fn get_mandatory_http_request_header_line(
 
     let method = HttpRequestMethod::from(elements[0]);
     let path = elements[1].to_string();
-    if method == HttpRequestMethod::Get && !path.starts_with('/') && !path.contains("://") {
+    if method == HttpRequestMethod::Get
+        && !path.starts_with('/')
+        && !path.contains("://")
+        && scheme_of(&path).is_none()
+    {
         return None;
     }
 
@@ -271,11 +449,11 @@ fn assemble_mandatory_http_request_header_line(method: &str, path: &str, version
 }
 
 pub(crate) async fn get_cache_name(url: &HttpRequestHeader<'_>) -> Option<PathBuf> {
-    let store_path = match std::env::var(X_PROXY_CACHE_PATH) {
+    let store_path = match setting(X_PROXY_CACHE_PATH) {
         Ok(s) => s,
         Err(e) => {
             return {
-                eprintln!("{e}");
+                error!("{e}");
                 None
             }
         }
@@ -283,9 +461,14 @@ pub(crate) async fn get_cache_name(url: &HttpRequestHeader<'_>) -> Option<PathBu
 
     let host = match url.request.host {
         None => "Unknown".to_string(),
-        Some(s) => s.to_string(),
+        Some(s) => canonical_host(&mirror_aliases(), s),
     };
 
+    /* The host is a directory of its own, it can't be allowed to name another */
+    if matches!(host.as_str(), "" | "." | "..") || host.contains(['/', '\\']) {
+        return None;
+    }
+
     let file = match url.request.path {
         None => return None,
         Some(s) => {
@@ -297,11 +480,71 @@ pub(crate) async fn get_cache_name(url: &HttpRequestHeader<'_>) -> Option<PathBu
         }
     };
 
-    let path = Path::new(&store_path).join(host).join(file);
+    let path = CacheLayout::configured().entry_path(Path::new(&store_path), &host, &file);
 
     Some(path)
 }
 
+/// Decode standard base64 as used by `Basic` credentials
+pub(crate) fn decode_base64(value: &str) -> Option<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let value = value.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(value.len() * 3 / 4);
+
+    for chunk in value.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+
+        let mut bits: u32 = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            bits |= sextet(*c)? << (18 - 6 * i);
+        }
+
+        decoded.push((bits >> 16) as u8);
+        if chunk.len() > 2 {
+            decoded.push((bits >> 8) as u8);
+        }
+        if chunk.len() > 3 {
+            decoded.push(bits as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Encode bytes as padded standard base64
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |b, (i, c)| b | (*c as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+
+    encoded
+}
+
 #[inline]
 async fn read_header_or_timeout<T>(
     value: &mut BufReader<T>,
@@ -313,11 +556,13 @@ where
     T: AsyncReadExt + Unpin,
 {
     match time::timeout(
-        Duration::from_secs(10),
+        Duration::from_secs(WAIT_TIMEOUT_SECONDS),
         value.read_until(filter[filter.len() - 1], buffer),
     )
     .await
     {
+        /* The stream ended before the header did, reading again would only find the end again */
+        Ok(Ok(0)) => return None,
         Ok(Ok(i)) => {
             *buffer_size += i;
             if *buffer_size >= BUFFER_SIZE {
@@ -329,21 +574,67 @@ where
     Some(())
 }
 
+/* Read one line of up to `limit` bytes besides its line break onto `buffer`,
+ * false when the line goes on for longer */
+async fn read_line_limited<T>(
+    value: &mut BufReader<T>,
+    buffer: &mut Vec<u8>,
+    limit: usize,
+) -> Result<bool, HeaderError>
+where
+    T: AsyncRead + Unpin,
+{
+    let allowed = limit.saturating_add(END_OF_HTTP_HEADER_LINE.len()) as u64;
+    match time::timeout(
+        Duration::from_secs(WAIT_TIMEOUT_SECONDS),
+        (&mut *value).take(allowed).read_until(b'\n', buffer),
+    )
+    .await
+    {
+        Ok(Ok(_)) if buffer.ends_with(b"\n") => Ok(true),
+        Ok(Ok(i)) if i as u64 == allowed => Ok(false),
+        /* The stream ended before the header did, reading again would only find the end again */
+        Ok(Ok(_)) | Ok(Err(_)) | Err(_) => Err(HeaderError::Unreadable),
+    }
+}
+
 impl HttpRequestHeader<'_> {
-    pub(crate) async fn from_tcp_buffer_async<T>(value: &mut BufReader<T>) -> Option<Self>
+    /// Read a request header held to `limits`, so a client can't make rproxy hold on to
+    /// as much header as it cares to send
+    pub(crate) async fn read_limited<T>(
+        value: &mut BufReader<T>,
+        limits: &HeaderLimits,
+    ) -> Result<Self, HeaderError>
     where
-        T: AsyncReadExt + AsyncWriteExt + Unpin,
+        T: AsyncRead + Unpin,
     {
         let mut buffer = Vec::new();
-        let mut buffer_size: usize = 0;
         let begin = Instant::now();
         let filter = END_OF_HTTP_HEADER.as_bytes();
 
-        while !buffer.ends_with(filter) {
-            read_header_or_timeout(value, &mut buffer, &mut buffer_size, filter).await?;
+        if !read_line_limited(value, &mut buffer, limits.request_line).await? {
+            return Err(HeaderError::RequestLineTooLong);
+        }
 
+        let mut fields = 0;
+        let mut size = 0;
+        while !buffer.ends_with(filter) {
             if begin.elapsed() >= Duration::from_secs(60) {
-                return None;
+                return Err(HeaderError::Unreadable);
+            }
+
+            let start = buffer.len();
+            if !read_line_limited(value, &mut buffer, limits.field).await? {
+                return Err(HeaderError::FieldsTooLarge);
+            }
+            if buffer.ends_with(filter) {
+                break;
+            }
+
+            fields += 1;
+            size += buffer.len() - start;
+            if fields > limits.fields || size > limits.total {
+                return Err(HeaderError::FieldsTooLarge);
             }
         }
 
@@ -352,23 +643,20 @@ impl HttpRequestHeader<'_> {
             .split(END_OF_HTTP_HEADER_LINE)
             .map(|s| s.to_string())
             .collect();
-        let mandatory_line = match lines.first() {
-            None => return None,
-            Some(s) => s,
-        };
-        let (method, request, version) =
-            match get_mandatory_http_request_header_line(mandatory_line) {
-                None => return None,
-                Some((a, b, c)) => (a, b, c),
-            };
+        let (method, request, version) = lines
+            .first()
+            .and_then(|l| get_mandatory_http_request_header_line(l))
+            .ok_or(HeaderError::Unreadable)?;
         let headers = get_http_headers(&lines);
-        consume_http_header(value);
 
         let request = Uri::from(request);
 
+        /* Anything with a scheme is kept so it can be refused with a proper response */
         match request.kind() {
-            UriKind::Invalid | UriKind::RelativeAddress => None,
-            _ => Some(HttpRequestHeader {
+            UriKind::Invalid | UriKind::RelativeAddress if scheme_of(&request.uri).is_none() => {
+                Err(HeaderError::Unreadable)
+            }
+            _ => Ok(HttpRequestHeader {
                 method,
                 request,
                 version,
@@ -378,10 +666,7 @@ impl HttpRequestHeader<'_> {
     }
 
     pub(crate) fn generate(&self) -> Option<String> {
-        let path = match self.request.path_and_query {
-            None => return None,
-            Some(p) => p,
-        };
+        let path = self.request.path_and_query?;
 
         let mut str = assemble_mandatory_http_request_header_line(
             self.method.to_string().as_str(),
@@ -542,8 +827,7 @@ impl HttpResponseStatus {
     fn to_header(&self) -> String {
         let code = self.0;
         let str = self.to_description().to_uppercase();
-        let date = httpdate::fmt_http_date(SystemTime::now());
-        format!("HTTP/1.1 {code} {str}{END_OF_HTTP_HEADER_LINE}Date: {date}")
+        format!("HTTP/1.1 {code} {str}")
     }
 
     fn to_empty_response(&self) -> String {
@@ -562,12 +846,22 @@ impl HttpResponseStatus {
 
         format!("HTTP/1.1 {code} {state}{END_OF_HTTP_HEADER_LINE}Date: {date}{END_OF_HTTP_HEADER_LINE}Content-length: {len}{END_OF_HTTP_HEADER}{msg}")
     }
+
+    /* As `to_response` with the reason it was given for after the description */
+    fn to_response_with_reason(&self, reason: &str) -> String {
+        let code = self.0;
+        let msg = format!("{}: {reason}\n", self.to_description());
+        let len = msg.len();
+        let state = self.to_description().to_uppercase();
+        let date = httpdate::fmt_http_date(SystemTime::now());
+
+        format!("HTTP/1.1 {code} {state}{END_OF_HTTP_HEADER_LINE}Date: {date}{END_OF_HTTP_HEADER_LINE}Content-Type: text/plain; charset=utf-8{END_OF_HTTP_HEADER_LINE}Content-length: {len}{END_OF_HTTP_HEADER}{msg}")
+    }
 }
 
 pub struct HttpResponseHeader {
     pub status: HttpResponseStatus,
     pub headers: HttpHeader,
-    #[allow(dead_code)]
     pub version: HttpVersion,
 }
 
@@ -581,24 +875,11 @@ fn get_http_headers(lines: &[String]) -> HttpHeader {
             None => continue,
         };
         let value = header.next().unwrap_or_default().trim().to_string();
-        headers.insert(property, value);
+        headers.append(property, value);
     }
     headers
 }
 
-fn consume_http_header<T>(value: &mut BufReader<T>)
-where
-    T: AsyncReadExt + AsyncWriteExt + Unpin,
-{
-    if let Some(pos) = value
-        .buffer()
-        .windows(END_OF_HTTP_HEADER.len())
-        .position(|window| window == END_OF_HTTP_HEADER.as_bytes())
-    {
-        value.consume(pos + END_OF_HTTP_HEADER.len());
-    }
-}
-
 impl HttpResponseHeader {
     pub(crate) async fn from_tcp_buffer_async<T>(value: &mut BufReader<T>) -> Option<Self>
     where
@@ -632,7 +913,6 @@ impl HttpResponseHeader {
         };
 
         let headers = get_http_headers(&lines);
-        consume_http_header(value);
 
         Some(HttpResponseHeader {
             status,
@@ -641,6 +921,40 @@ impl HttpResponseHeader {
         })
     }
 
+    /// Whether the server intends to keep the connection open after this response
+    pub(crate) fn keeps_alive(&self) -> bool {
+        match self.version {
+            HttpVersion(11) => !self.headers.has_token("Connection", "close"),
+            HttpVersion(10) => self.headers.has_token("Connection", "keep-alive"),
+            _ => false,
+        }
+    }
+
+    /// Whether the body is exactly the resource, with no `Content-Encoding` such as gzip applied
+    pub(crate) fn is_identity_encoded(&self) -> bool {
+        match self.headers.get("Content-Encoding") {
+            None => true,
+            Some(v) => v
+                .split(',')
+                .map(|e| e.trim())
+                .all(|e| e.is_empty() || e.eq_ignore_ascii_case("identity")),
+        }
+    }
+
+    /// Say `Connection: close` when the client's connection won't be kept open after this
+    /// response, such as when `request` asked for that or was the last one it may make
+    pub(crate) fn close_after(&mut self, request: &HttpRequestHeader) {
+        self.close_if(&keep_alive_if(request));
+    }
+
+    /// Say `Connection: close` when the connection is closed after this response
+    pub(crate) fn close_if(&mut self, connection: &ConnectionReturn) {
+        if *connection == Close {
+            self.headers
+                .insert("Connection".to_string(), "close".to_string());
+        }
+    }
+
     pub(crate) fn generate(&mut self) -> String {
         if !self.headers.contains_key("Date") {
             self.headers.insert(
@@ -660,258 +974,732 @@ impl HttpResponseHeader {
     }
 }
 
-pub(crate) async fn fetch_and_serve_known_length<T, R>(
+/// `data` as one chunk of a chunked body, its size line in front and a line break after
+pub(crate) fn http_chunk(data: &[u8]) -> Vec<u8> {
+    let size = format!("{:X}{END_OF_HTTP_HEADER_LINE}", data.len());
+    let mut chunk = Vec::with_capacity(size.len() + data.len() + END_OF_HTTP_HEADER_LINE.len());
+    chunk.extend_from_slice(size.as_bytes());
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(END_OF_HTTP_HEADER_LINE.as_bytes());
+    chunk
+}
+
+/// How far ahead of the client the origin can get before the client is sent the rest
+/// from the cache file, so a slow client doesn't hold up the download
+/// and a slow disk doesn't hold up the client
+const SPLIT_BUFFER_SIZE: usize = 16 * BUFFER_SIZE;
+
+enum SplitStep {
+    /// Bytes read from the origin, zero when only the file or client moved along
+    Progress(usize),
+    /// The client has fallen behind what's buffered and needs the cache file opened to catch up
+    OpenFile,
+    Done,
+}
+
+/* Positions are counted from the start of the body. The buffer holds from `start`
+ * to `fetched`, whatever the file or client hasn't been written yet */
+struct SplitCopy {
+    buffer: VecDeque<u8>,
+    start: u64,
+    fetched: u64,
+    remaining: u64,
+    fetch_done: bool,
+    written: u64,
+    /// Where the file is known to hold everything before, so the client can read it back
+    flushed: u64,
+    write_file: bool,
+    file_failed: bool,
+    sent: u64,
+    unflushed: bool,
+    write_stream: bool,
+    /// Read back from the cache file for a client that fell behind, and how much has been sent
+    behind: Vec<u8>,
+    behind_sent: usize,
+    behind_file: Option<tokio::fs::File>,
+}
+
+impl SplitCopy {
+    /* The part of the buffer from `position` on, up to where it wraps around */
+    fn from(&self, position: u64) -> &[u8] {
+        let offset = (position - self.start) as usize;
+        let (front, back) = self.buffer.as_slices();
+        match offset < front.len() {
+            true => &front[offset..],
+            false => &back[offset - front.len()..],
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        (!self.write_file && !self.write_stream)
+            || (self.fetch_done
+                && (!self.write_file || self.written == self.fetched)
+                && (!self.write_stream || (self.sent == self.fetched && !self.unflushed)))
+    }
+
+    /// Move everything along as far as it will go without waiting. Pending when nothing could
+    fn poll_step<T, R, F>(
+        &mut self,
+        cx: &mut Context<'_>,
+        scratch: &mut [u8],
+        fetch: &mut R,
+        file: &mut F,
+        stream: &mut T,
+    ) -> Poll<std::io::Result<SplitStep>>
+    where
+        T: AsyncWrite + Unpin,
+        R: AsyncRead + Unpin,
+        F: AsyncWrite + Unpin,
+    {
+        let mut progress = false;
+        let mut read = 0;
+        let (written, sent, write_stream) = (self.written, self.sent, self.write_stream);
+
+        if !self.fetch_done && self.buffer.len() + BUFFER_SIZE <= SPLIT_BUFFER_SIZE {
+            let want = scratch.len().min(self.remaining as usize);
+            let mut read_buf = ReadBuf::new(&mut scratch[..want]);
+            match Pin::new(&mut *fetch).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {
+                    read = read_buf.filled().len();
+                    self.buffer.extend(read_buf.filled());
+                    self.fetched += read as u64;
+                    self.remaining -= read as u64;
+                    /* An origin that closes early leaves the caller to find the file short */
+                    self.fetch_done = read == 0 || self.remaining == 0;
+                    progress = true;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
+            }
+        }
+
+        if self.write_file && self.written < self.fetched {
+            match Pin::new(&mut *file).poll_write(cx, self.from(self.written)) {
+                Poll::Ready(Ok(n)) if n > 0 => self.written += n as u64,
+                Poll::Ready(_) => {
+                    self.write_file = false;
+                    self.file_failed = true;
+                }
+                Poll::Pending => {}
+            }
+            progress |= written != self.written || !self.write_file;
+        } else if self.write_file && self.flushed < self.written {
+            match Pin::new(&mut *file).poll_flush(cx) {
+                Poll::Ready(Ok(_)) => self.flushed = self.written,
+                Poll::Ready(Err(_)) => {
+                    self.write_file = false;
+                    self.file_failed = true;
+                }
+                Poll::Pending => {}
+            }
+            progress |= self.flushed == self.written || !self.write_file;
+        }
+
+        if self.write_stream && self.sent < self.start {
+            let file = match self.behind_file.as_mut() {
+                Some(f) => f,
+                None => return Poll::Ready(Ok(SplitStep::OpenFile)),
+            };
+
+            if self.behind_sent == self.behind.len() {
+                /* Only as far as the buffer starts, everything before that is known to be in the file */
+                let want = BUFFER_SIZE.min((self.start - self.sent) as usize);
+                self.behind.resize(want, 0);
+                self.behind_sent = 0;
+                let mut read_buf = ReadBuf::new(&mut self.behind);
+                match Pin::new(file).poll_read(cx, &mut read_buf) {
+                    Poll::Ready(Ok(())) if !read_buf.filled().is_empty() => {
+                        let n = read_buf.filled().len();
+                        self.behind.truncate(n);
+                        progress = true;
+                    }
+                    Poll::Ready(_) => {
+                        self.behind.clear();
+                        self.write_stream = false;
+                        progress = true;
+                    }
+                    Poll::Pending => self.behind.clear(),
+                }
+            }
+
+            if self.write_stream && self.behind_sent < self.behind.len() {
+                let data = &self.behind[self.behind_sent..];
+                match Pin::new(&mut *stream).poll_write(cx, data) {
+                    Poll::Ready(Ok(n)) if n > 0 => {
+                        self.behind_sent += n;
+                        self.sent += n as u64;
+                        self.unflushed = true;
+                    }
+                    Poll::Ready(_) => self.write_stream = false,
+                    Poll::Pending => {}
+                }
+            }
+
+            if self.sent == self.start {
+                self.behind_file = None;
+                self.behind.clear();
+                self.behind_sent = 0;
+            }
+        } else if self.write_stream && self.sent < self.fetched {
+            match Pin::new(&mut *stream).poll_write(cx, self.from(self.sent)) {
+                Poll::Ready(Ok(n)) if n > 0 => {
+                    self.sent += n as u64;
+                    self.unflushed = true;
+                }
+                Poll::Ready(_) => self.write_stream = false,
+                Poll::Pending => {}
+            }
+        } else if self.write_stream && self.unflushed {
+            /* Sent before waiting on the origin again, a TLS stream holds small writes back for a fuller record */
+            match Pin::new(&mut *stream).poll_flush(cx) {
+                Poll::Ready(Ok(_)) => self.unflushed = false,
+                Poll::Ready(Err(_)) => self.write_stream = false,
+                Poll::Pending => {}
+            }
+            progress |= !self.unflushed;
+        }
+        progress |= sent != self.sent || write_stream != self.write_stream;
+
+        /* Nothing is let go until it's written to both, unless the client is holding up the origin
+         * and what it hasn't been sent yet can be read back from the file instead */
+        let mut keep = self.fetched;
+        if self.write_file {
+            keep = keep.min(self.written);
+        }
+        if self.write_stream {
+            let full = self.buffer.len() + BUFFER_SIZE > SPLIT_BUFFER_SIZE;
+            keep = match self.write_file && full {
+                true => keep.min(self.sent.max(self.flushed)),
+                false => keep.min(self.sent.max(self.start)),
+            };
+        }
+        if keep > self.start {
+            self.buffer.drain(..(keep - self.start) as usize);
+            self.start = keep;
+        }
+
+        match (self.is_done(), progress) {
+            (true, _) => Poll::Ready(Ok(SplitStep::Done)),
+            (false, true) => Poll::Ready(Ok(SplitStep::Progress(read))),
+            (false, false) => Poll::Pending,
+        }
+    }
+}
+
+/// Fetch a body of `content_length` bytes, writing it to the file and the client each at their
+/// own pace. The file is written as fast as the origin sends it while the client is sent what's
+/// buffered, or read back from the file once it falls too far behind.
+pub(crate) async fn fetch_and_serve_known_length<T, R, F>(
     cache_file_path: &PathBuf,
     stream: &mut T,
-    mut content_length: u64,
+    content_length: u64,
     mut fetch_buf_reader: R,
-    file: &mut File,
-    mut write_file: bool,
-    mut write_stream: bool,
+    file: &mut F,
+    write_file: bool,
+    write_stream: bool,
 ) -> (bool, bool)
 where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
     R: AsyncBufRead + Unpin,
+    F: AsyncWriteExt + Unpin,
 {
-    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut scratch = vec![0; BUFFER_SIZE];
+    let mut turn = MissTurn::default();
+    let mut copy = SplitCopy {
+        buffer: VecDeque::with_capacity(SPLIT_BUFFER_SIZE),
+        start: 0,
+        fetched: 0,
+        remaining: content_length,
+        fetch_done: content_length == 0,
+        written: 0,
+        flushed: 0,
+        write_file,
+        file_failed: false,
+        sent: 0,
+        unflushed: false,
+        write_stream,
+        behind: Vec::new(),
+        behind_sent: 0,
+        behind_file: None,
+    };
 
     loop {
-        if content_length == 0 {
-            break;
-        }
-
-        let fetch = match timeout(
+        let step = timeout(
             Duration::from_secs(WAIT_TIMEOUT_SECONDS),
-            fetch_buf_reader.read(&mut buffer),
+            poll_fn(|cx| copy.poll_step(cx, &mut scratch, &mut fetch_buf_reader, file, stream)),
         )
-        .await
-        {
-            Ok(f) => f,
-            Err(_) => return (false, false),
-        };
+        .await;
 
-        match fetch {
-            Ok(0) => {
-                break;
+        if copy.file_failed {
+            copy.file_failed = false;
+            if cache_file_path.exists() {
+                /* The file is in an unknown state and should be removed */
+                let _ = remove_file(&cache_file_path).await;
             }
-            Ok(n) => {
-                content_length -= n as u64;
-                let data = &buffer[..n];
-
-                match (write_file, write_stream) {
-                    (true, true) => {
-                        let file_write_future = file.write_all(data);
-                        let client_write_future = stream.write_all(data);
-
-                        match join!(file_write_future, client_write_future) {
-                            (Err(_), _) => {
-                                write_file = false;
-                                if cache_file_path.exists() {
-                                    /* The file is in an unknown state and should be removed */
-                                    let _ = remove_file(&cache_file_path).await;
-                                }
-                            }
-                            (_, Err(_)) => write_stream = false,
-                            _ => {}
-                        }
-                    }
-                    (true, false) => match file.write_all(data).await {
-                        Ok(_) => {}
-                        Err(_) => {
-                            if cache_file_path.exists() {
-                                /* The file is in an unknown state and should be removed */
-                                let _ = remove_file(&cache_file_path).await;
-                            }
-                            return (false, false);
-                        }
-                    },
-                    (false, true) => match stream.write_all(data).await {
-                        Ok(_) => {}
-                        Err(_) => return (false, false),
-                    },
-                    (false, false) => return (false, false),
-                }
+        }
+
+        match step {
+            Ok(Ok(SplitStep::Progress(n))) => turn.passed(n).await,
+            Ok(Ok(SplitStep::OpenFile)) => match open_at(cache_file_path, copy.sent).await {
+                Some(f) => copy.behind_file = Some(f),
+                None => copy.write_stream = false,
+            },
+            Ok(Ok(SplitStep::Done)) => break,
+            /* A client that stopped reading after the whole body is in the file only loses itself */
+            Err(_) if copy.fetch_done && (!copy.write_file || copy.written == copy.fetched) => {
+                copy.write_stream = false;
+                break;
             }
-            Err(_) => return (false, false),
+            Ok(Err(_)) | Err(_) => return (false, false),
         }
     }
 
-    (write_file, write_stream)
+    (copy.write_file, copy.write_stream)
+}
+
+async fn open_at(path: &Path, position: u64) -> Option<tokio::fs::File> {
+    let mut file = tokio::fs::File::open(path).await.ok()?;
+    file.seek(std::io::SeekFrom::Start(position)).await.ok()?;
+    Some(file)
 }
 
-pub(crate) async fn fetch_and_serve_chunk<T, R>(
+/// Fetch a body that ends when the origin closes the connection, writing it to the file as it is
+/// and to the client as chunks when `chunked`, so the client can tell where it ends
+/// without waiting for its own connection to close.
+pub(crate) async fn fetch_and_serve_until_close<T, R, F>(
     cache_file_path: &PathBuf,
     stream: &mut T,
-    fetch_buf_reader: &mut BufReader<R>,
-    file: &mut File,
+    mut fetch_buf_reader: R,
+    file: &mut F,
+    chunked: bool,
     mut write_file: bool,
     mut write_stream: bool,
 ) -> (bool, bool)
 where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
-    R: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: AsyncBufRead + Unpin,
+    F: AsyncWriteExt + Unpin,
 {
-    async fn parse_http_chunk(buffer: &mut [u8]) -> Option<u64> {
-        let size = match String::from_utf8(buffer.to_vec()) {
-            Ok(s) => match u64::from_str_radix(s.trim(), 16) {
-                Ok(value) => value,
-                Err(_) => return None,
-            },
-            Err(_) => return None,
-        };
-
-        Some(size)
-    }
-    async fn get_http_chunk<T>(reader: &mut T, is_start: bool) -> Option<Vec<u8>>
-    where
-        T: AsyncBufRead + Unpin,
-    {
-        let format = END_OF_HTTP_HEADER_LINE.as_bytes();
-        let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
-
-        let mut i: usize = 2;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut turn = MissTurn::default();
 
-        let read = match timeout(
+    loop {
+        let n = match timeout(
             Duration::from_secs(WAIT_TIMEOUT_SECONDS),
-            reader.read(&mut buffer[..i]),
+            fetch_buf_reader.read(&mut buffer),
         )
         .await
         {
-            Ok(r) => r,
-            Err(_) => return None,
+            Ok(Ok(n)) => n,
+            Ok(Err(_)) | Err(_) => return (false, false),
         };
 
-        match read {
-            Ok(d) => {
-                if !is_start && (d != 2 || buffer[0] != format[0] || buffer[1] != format[1]) {
-                    return None;
-                }
+        if n == 0 {
+            if write_stream && chunked {
+                let end_chunk = format!("0{END_OF_HTTP_HEADER}");
+                write_stream = stream.write_all(end_chunk.as_bytes()).await.is_ok();
+            }
+            return (write_file, write_stream);
+        }
 
-                let mut byte = [0u8; 1];
-                while let Ok(d) = reader.read(&mut byte).await {
-                    if d == 0 {
-                        break;
-                    }
-                    buffer[i] = byte[0];
-                    if buffer.len() > 2 && buffer[i - 1] == format[0] && buffer[i] == format[1] {
-                        break;
-                    }
+        let data = &buffer[..n];
+        if write_file && file.write_all(data).await.is_err() {
+            write_file = false;
+            /* The file is in an unknown state and should be removed */
+            let _ = remove_file(&cache_file_path).await;
+        }
 
-                    if i >= BUFFER_SIZE {
-                        break;
-                    } else {
-                        i += 1;
-                    }
-                }
-            }
-            Err(_) => return None,
+        if write_stream {
+            let written = match chunked {
+                /* Written whole so the size line and line break don't end up in records of their own */
+                true => stream.write_all(&http_chunk(data)).await.is_ok(),
+                false => stream.write_all(data).await.is_ok(),
+            };
+            /* Sent before waiting on the origin again, a TLS stream holds small writes back for a fuller record */
+            write_stream = written && stream.flush().await.is_ok();
         }
-        Some(buffer[..i + 1].to_vec())
-    }
+        turn.passed(n).await;
 
-    let filter_line = END_OF_HTTP_HEADER_LINE.as_bytes();
-    let mut buffer = vec![0; BUFFER_SIZE];
+        if !write_file && !write_stream {
+            return (false, false);
+        }
+    }
+}
 
-    let mut content_length = match get_http_chunk(fetch_buf_reader, true).await {
-        Some(mut s) => {
-            match stream.write_all(&s).await {
-                Ok(_) => {}
-                Err(_) => write_stream = false,
-            }
+/* Longest line read in front of a chunk or as a trailer field, chunk extensions included */
+const MAX_CHUNK_LINE: u64 = 4096;
 
-            match parse_http_chunk(s.as_mut_slice()).await {
-                Some(length) => {
-                    if length == 0 {
-                        let _ = stream.write_all(filter_line).await;
-                        return (true, true);
-                    }
+/* Most bytes of trailer fields read after the last chunk */
+const MAX_TRAILERS: usize = BUFFER_SIZE;
 
-                    length
-                }
-                None => return (false, false),
-            }
-        }
-        None => return (false, false),
-    };
+/* A line of a chunked body, false when it's cut short or too long to be one */
+async fn read_chunk_line<R>(reader: &mut R, line: &mut Vec<u8>) -> bool
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    match (&mut *reader)
+        .take(MAX_CHUNK_LINE)
+        .read_until(b'\n', line)
+        .await
+    {
+        Ok(_) => line.ends_with(b"\n"),
+        Err(_) => false,
+    }
+}
+
+/* The hexadecimal size in front of any chunk extensions */
+fn chunk_size(line: &[u8]) -> Option<u64> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    match !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => u64::from_str_radix(size, 16).ok(),
+        false => None,
+    }
+}
+
+/// Read the line in front of a chunk of a chunked body and return the size of the chunk,
+/// the last chunk being 0. The line is left in `line` as it was sent so it can be passed on.
+/// Every chunked body is read through this, [`read_chunk_end`] and [`read_trailers`]
+/// so none of them takes a chunk extension, a trailer or an endless size line differently.
+pub(crate) async fn read_chunk_size<R>(reader: &mut R, line: &mut Vec<u8>) -> Option<u64>
+where
+    R: AsyncBufRead + Unpin,
+{
+    match read_chunk_line(reader, line).await {
+        true => chunk_size(line),
+        false => None,
+    }
+}
 
+/// Read the line break that follows the data of a chunk into `line`, false when it isn't one
+pub(crate) async fn read_chunk_end<R>(reader: &mut R, line: &mut Vec<u8>) -> bool
+where
+    R: AsyncBufRead + Unpin,
+{
+    read_chunk_line(reader, line).await && (line == b"\r\n" || line == b"\n")
+}
+
+/// Read the trailer fields after the last chunk, returning them as they were sent
+/// with the empty line that ends the body
+pub(crate) async fn read_trailers<R>(reader: &mut R) -> Option<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut trailers = Vec::new();
+    let mut line = Vec::new();
     loop {
-        if content_length == 0 {
-            match get_http_chunk(fetch_buf_reader, false).await {
-                Some(mut s) => {
-                    if write_stream {
-                        match stream.write_all(s.as_slice()).await {
-                            Ok(_) => {}
-                            Err(_) => write_stream = false,
-                        }
-                    }
+        if !read_chunk_line(reader, &mut line).await {
+            return None;
+        }
+        trailers.extend_from_slice(&line);
+        if line == b"\r\n" || line == b"\n" {
+            return Some(trailers);
+        }
+        if trailers.len() > MAX_TRAILERS {
+            return None;
+        }
+    }
+}
 
-                    content_length = match parse_http_chunk(s.as_mut_slice()).await {
-                        None => return (false, false),
-                        Some(length) => {
-                            if length == 0 {
-                                let _ = stream.write_all(filter_line).await;
-                                return (true, true);
-                            }
+pub(crate) async fn fetch_and_serve_chunk<T, R, F>(
+    cache_file_path: &PathBuf,
+    stream: &mut T,
+    fetch_buf_reader: &mut R,
+    file: &mut F,
+    mut write_file: bool,
+    mut write_stream: bool,
+) -> (bool, bool)
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: AsyncBufRead + Unpin,
+    F: AsyncWriteExt + Unpin,
+{
+    let wait = Duration::from_secs(WAIT_TIMEOUT_SECONDS);
+    let end_of_line = END_OF_HTTP_HEADER_LINE.as_bytes();
+    let mut line = Vec::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut turn = MissTurn::default();
 
-                            length
-                        }
-                    };
-                }
-                None => return (false, false),
-            }
+    loop {
+        let mut content_length =
+            match timeout(wait, read_chunk_size(fetch_buf_reader, &mut line)).await {
+                Ok(Some(length)) => length,
+                _ => return (false, false),
+            };
 
-            continue;
+        /* Sent without the origin's chunk extensions, which only meant something to it */
+        if write_stream {
+            write_stream = stream
+                .write_all(format!("{content_length:x}{END_OF_HTTP_HEADER_LINE}").as_bytes())
+                .await
+                .is_ok();
+        }
+        if content_length == 0 {
+            break;
         }
 
-        let min = std::cmp::min(content_length as usize, BUFFER_SIZE);
+        while content_length > 0 {
+            let min = std::cmp::min(content_length, BUFFER_SIZE as u64) as usize;
+            let n = match fetch_buf_reader.read_exact(&mut buffer[..min]).await {
+                Ok(n) => n,
+                Err(_) => return (false, false),
+            };
+            content_length -= n as u64;
+            let data = &buffer[..n];
 
-        match fetch_buf_reader.read_exact(&mut buffer[..min]).await {
-            Ok(0) => {
-                break;
-            }
-            Ok(n) => {
-                content_length -= n as u64;
-                let data = &buffer[..n];
-
-                match (write_file, write_stream) {
-                    (true, true) => {
-                        let file_write_future = file.write_all(data);
-                        let client_write_future = stream.write_all(data);
-
-                        match join!(file_write_future, client_write_future) {
-                            (Err(_), _) => {
-                                write_file = false;
-                                if cache_file_path.exists() {
-                                    /* The file is in an unknown state and should be removed */
-                                    let _ = remove_file(&cache_file_path).await;
-                                }
-                            }
-                            (_, Err(_)) => write_stream = false,
-                            _ => {}
-                        }
-                    }
-                    (true, false) => match file.write_all(data).await {
-                        Ok(_) => {}
-                        Err(_) => {
+            match (write_file, write_stream) {
+                (true, true) => {
+                    let file_write_future = file.write_all(data);
+                    let client_write_future = stream.write_all(data);
+
+                    match join!(file_write_future, client_write_future) {
+                        (Err(_), _) => {
+                            write_file = false;
                             if cache_file_path.exists() {
                                 /* The file is in an unknown state and should be removed */
                                 let _ = remove_file(&cache_file_path).await;
                             }
-                            return (false, false);
                         }
-                    },
-                    (false, true) => match stream.write_all(data).await {
-                        Ok(_) => {}
-                        Err(_) => return (false, false),
-                    },
-                    (false, false) => return (false, false),
+                        (_, Err(_)) => write_stream = false,
+                        _ => {}
+                    }
                 }
+                (true, false) => match file.write_all(data).await {
+                    Ok(_) => {}
+                    Err(_) => {
+                        if cache_file_path.exists() {
+                            /* The file is in an unknown state and should be removed */
+                            let _ = remove_file(&cache_file_path).await;
+                        }
+                        return (false, false);
+                    }
+                },
+                (false, true) => match stream.write_all(data).await {
+                    Ok(_) => {}
+                    Err(_) => return (false, false),
+                },
+                (false, false) => return (false, false),
+            }
+
+            /* Sent before waiting on the origin again, a TLS stream holds small writes back for a fuller record */
+            if write_stream && stream.flush().await.is_err() {
+                write_stream = false;
             }
-            Err(_) => return (false, false),
+            turn.passed(n).await;
+        }
+
+        if !matches!(
+            timeout(wait, read_chunk_end(fetch_buf_reader, &mut line)).await,
+            Ok(true)
+        ) {
+            return (false, false);
+        }
+        if write_stream {
+            write_stream = stream.write_all(end_of_line).await.is_ok();
         }
     }
 
-    (false, false)
+    /* Read so they aren't taken for the next response, but not kept with the file */
+    if !matches!(
+        timeout(wait, read_trailers(fetch_buf_reader)).await,
+        Ok(Some(_))
+    ) {
+        return (false, false);
+    }
+    if write_stream {
+        write_stream = stream.write_all(end_of_line).await.is_ok();
+    }
+    (write_file, write_stream)
+}
+
+/* More ranges than this in one request is more likely abuse than a download accelerator */
+pub(crate) const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum RangeRequest {
+    /// No usable range was asked for, send the whole entity
+    Full,
+    /// The first and last byte positions to send, inclusive
+    Satisfiable(u64, u64),
+    /// Several ranges to send as a `multipart/byteranges` body
+    Multiple(Vec<(u64, u64)>),
+    /// None of the requested bytes exist, respond with 416
+    Unsatisfiable,
+}
+
+/// Decide whether a `Range` may be honoured given the `If-Range` precondition as per RFC 7233.
+/// Clients are given the weak tag from [`entity_tag`] for a cached file rather than the origin's,
+/// which is only kept for `HEAD` answers and resuming downloads. A weak tag never satisfies `If-Range`,
+/// so only the date form can match, and then only exactly.
+pub(crate) fn if_range_matches(value: Option<&String>, modified: Option<SystemTime>) -> bool {
+    let value = match value {
+        None => return true,
+        Some(v) => v.trim(),
+    };
+
+    if value.starts_with('"') || value.starts_with("W/") {
+        return false;
+    }
+
+    match (httpdate::parse_http_date(value), modified) {
+        (Ok(date), Some(modified)) => {
+            httpdate::fmt_http_date(date) == httpdate::fmt_http_date(modified)
+        }
+        _ => false,
+    }
+}
+
+/// A weak entity tag for a cached file, it changes whenever the file is replaced
+pub(crate) fn entity_tag(length: u64, modified: Option<SystemTime>) -> String {
+    let seconds = modified
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    format!("W/\"{length:x}-{seconds:x}\"")
+}
+
+/// Evaluate `If-None-Match` or failing that `If-Modified-Since` as per RFC 7232.
+/// Returns true when the client's copy is current and a 304 can be sent instead.
+pub(crate) fn not_modified(
+    header: &HttpRequestHeader<'_>,
+    tag: &str,
+    modified: Option<SystemTime>,
+) -> bool {
+    if let Some(tags) = header.headers.get("If-None-Match") {
+        let opaque = |t: &str| t.trim().trim_start_matches("W/").to_string();
+        return tags
+            .split(',')
+            .any(|t| t.trim() == "*" || opaque(t) == opaque(tag));
+    }
+
+    match (header.headers.get("If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => match httpdate::parse_http_date(since.trim()) {
+            Ok(since) => httpdate::HttpDate::from(modified) <= httpdate::HttpDate::from(since),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Interpret a `Range` header value against an entity `length` bytes long as per RFC 7233.
+/// Anything that can't be parsed is ignored, which means the whole entity should be sent.
+pub(crate) fn parse_range(value: Option<&String>, length: u64) -> RangeRequest {
+    fn parse_range_spec(spec: &str, length: u64) -> Option<Option<(u64, u64)>> {
+        let (first, last) = match spec.split_once('-') {
+            None => return None,
+            Some((f, l)) => (f.trim(), l.trim()),
+        };
+
+        match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Err(_), Ok(suffix)) if first.is_empty() => match suffix {
+                0 => Some(None),
+                _ if length == 0 => Some(None),
+                s => Some(Some((length.saturating_sub(s), length - 1))),
+            },
+            (Ok(first), Err(_)) if last.is_empty() => match first < length {
+                true => Some(Some((first, length - 1))),
+                false => Some(None),
+            },
+            (Ok(first), Ok(last)) if first <= last => match first < length {
+                true => Some(Some((first, std::cmp::min(last, length - 1)))),
+                false => Some(None),
+            },
+            _ => None,
+        }
+    }
+
+    let value = match value {
+        None => return RangeRequest::Full,
+        Some(v) => v.trim(),
+    };
+
+    let specs = match value.strip_prefix("bytes=") {
+        None => return RangeRequest::Full, /* Unknown range units are ignored */
+        Some(s) => s.trim(),
+    };
+
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        match parse_range_spec(spec, length) {
+            None => return RangeRequest::Full, /* Syntactically invalid, ignore the header */
+            Some(None) => {}                   /* Unsatisfiable on its own, skip it */
+            Some(Some(r)) => ranges.push(r),
+        }
+    }
+
+    match ranges.len() {
+        0 => RangeRequest::Unsatisfiable,
+        1 => RangeRequest::Satisfiable(ranges[0].0, ranges[0].1),
+        n if n > MAX_RANGES => RangeRequest::Full,
+        _ => RangeRequest::Multiple(ranges),
+    }
+}
+
+/// Read and throw away the body that follows `header` so the connection can carry another request.
+/// Returns false when the body can't be delimited, in which case the connection must not be reused.
+pub(crate) async fn drain_http_body<R>(reader: &mut R, header: &HttpResponseHeader) -> bool
+where
+    R: AsyncBufRead + Unpin,
+{
+    async fn discard<R>(reader: &mut R, mut length: u64) -> bool
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        while length > 0 {
+            let min = std::cmp::min(length, BUFFER_SIZE as u64) as usize;
+            match timeout(
+                Duration::from_secs(WAIT_TIMEOUT_SECONDS),
+                reader.read(&mut buffer[..min]),
+            )
+            .await
+            {
+                Ok(Ok(0)) | Ok(Err(_)) | Err(_) => return false,
+                Ok(Ok(n)) => length -= n as u64,
+            }
+        }
+        true
+    }
+
+    if header.headers.contains_key("Transfer-Encoding") {
+        if !header.headers.is_chunked() {
+            return false;
+        }
+
+        let wait = Duration::from_secs(WAIT_TIMEOUT_SECONDS);
+        let mut line = Vec::new();
+        loop {
+            let size = match timeout(wait, read_chunk_size(reader, &mut line)).await {
+                Ok(Some(s)) => s,
+                _ => return false,
+            };
+
+            if size == 0 {
+                /* Skip any trailer fields up until the final empty line */
+                return matches!(timeout(wait, read_trailers(reader)).await, Ok(Some(_)));
+            }
+
+            if !discard(reader, size).await
+                || !matches!(
+                    timeout(wait, read_chunk_end(reader, &mut line)).await,
+                    Ok(true)
+                )
+            {
+                return false;
+            }
+        }
+    }
+
+    match header.headers.get("Content-Length") {
+        Some(l) => match l.parse::<u64>() {
+            Ok(l) => discard(reader, l).await,
+            Err(_) => false,
+        },
+        None => false, /* Body runs until the connection closes */
+    }
 }
 
 pub(crate) async fn respond_with<T>(
@@ -927,7 +1715,36 @@ where
         _ => state.to_response(),
     };
 
-    match stream.write_all(r.as_bytes()).await {
+    match stream.write_all(closing(r, &return_type).as_bytes()).await {
+        Ok(_) => return_type,
+        Err(_) => Close,
+    }
+}
+
+/* A response whose connection is closed after it says so below its status line */
+fn closing(response: String, return_type: &ConnectionReturn) -> String {
+    match return_type {
+        Close => response.replacen(
+            END_OF_HTTP_HEADER_LINE,
+            &format!("{END_OF_HTTP_HEADER_LINE}Connection: close{END_OF_HTTP_HEADER_LINE}"),
+            1,
+        ),
+        _ => response,
+    }
+}
+
+/// Answer with `state` and a line saying why, such as what kept the origin server from being reached
+pub(crate) async fn respond_with_reason<T>(
+    return_type: ConnectionReturn,
+    state: HttpResponseStatus,
+    reason: &str,
+    stream: &mut T,
+) -> ConnectionReturn
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let r = state.to_response_with_reason(reason);
+    match stream.write_all(closing(r, &return_type).as_bytes()).await {
         Ok(_) => return_type,
         Err(_) => Close,
     }
@@ -954,20 +1771,107 @@ mod tests {
         assert!(header.contains_key("content-type"));
         assert!(header.contains_key("CONTENT-TYPE"));
 
-        // Test get_all returns originally inserted key
-        match header.get_all("CONTENT-TYPE") {
-            Some((original_key, value)) => {
-                assert_eq!(original_key, "Content-Type");
-                assert_eq!(value, "text/html");
-            }
-            None => panic!("Expected to find header with original key"),
-        }
+        // Test the originally inserted key is kept
+        assert_eq!(
+            (&header).into_iter().next(),
+            Some((&"Content-Type".to_string(), &"text/html".to_string()))
+        );
 
         // Test remove key
         header.remove("content-type");
         assert_eq!(header.get("Content-Type"), None);
     }
 
+    #[test]
+    fn test_header_tokens() {
+        let headers = get_http_headers(&[
+            "GET / HTTP/1.1".to_string(),
+            "range: bytes=0-9".to_string(),
+            "CACHE-CONTROL: max-age=0, No-Store".to_string(),
+            "connection: keep-alive".to_string(),
+            "Connection: Upgrade".to_string(),
+            "transfer-encoding: gzip,".to_string(),
+            "Transfer-Encoding: Chunked".to_string(),
+        ]);
+
+        /* Names are matched however they were sent */
+        assert_eq!(headers.get("Range"), Some(&"bytes=0-9".to_string()));
+
+        assert!(headers.has_token("Cache-Control", "no-store"));
+        assert!(headers.has_token("cache-control", "max-age"));
+        assert!(!headers.has_token("Cache-Control", "private"));
+        assert!(headers.has_token("Connection", "upgrade"));
+        assert!(headers.has_token("Connection", "keep-alive"));
+        assert!(!headers.has_token("Connection", "close"));
+        assert!(headers.is_chunked());
+
+        let mut headers = HttpHeader::new();
+        assert!(!headers.is_chunked());
+        headers.insert("Transfer-Encoding".to_string(), "chunked, gzip".to_string());
+        assert!(!headers.is_chunked());
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut header = HttpHeader::new();
+        header.insert("Connection".to_string(), "keep-alive, Upgrade".to_string());
+        header.insert("Keep-Alive".to_string(), "timeout=5".to_string());
+        header.insert("Proxy-Connection".to_string(), "keep-alive".to_string());
+        header.insert("TE".to_string(), "trailers".to_string());
+        header.insert("Upgrade".to_string(), "websocket".to_string());
+        header.insert("Content-Type".to_string(), "text/html".to_string());
+        header.strip_hop_by_hop();
+        assert_eq!(header.len(), 1);
+        assert!(header.contains_key("Content-Type"));
+
+        /* Headers named in `Connection` go too, in any case and spacing */
+        let mut header = HttpHeader::new();
+        header.insert(
+            "connection".to_string(),
+            "close,X-Trace ,  x-session".to_string(),
+        );
+        header.insert("X-Trace".to_string(), "1".to_string());
+        header.insert("X-Session".to_string(), "abc".to_string());
+        header.insert("X-Kept".to_string(), "yes".to_string());
+        header.strip_hop_by_hop();
+        assert_eq!(header.get("X-Trace"), None);
+        assert_eq!(header.get("X-Session"), None);
+        assert_eq!(header.get("Connection"), None);
+        assert_eq!(header.get("X-Kept"), Some(&"yes".to_string()));
+
+        /* The body can't be found without its framing so those are never removed */
+        let mut header = HttpHeader::new();
+        header.insert(
+            "Connection".to_string(),
+            "Transfer-Encoding, Content-Length, Host".to_string(),
+        );
+        header.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        header.insert("Content-Length".to_string(), "5".to_string());
+        header.insert("Host".to_string(), "example.com".to_string());
+        header.strip_hop_by_hop();
+        assert_eq!(header.len(), 3);
+
+        /* Nothing to do without any hop-by-hop headers */
+        let mut header = HttpHeader::new();
+        header.insert("Accept".to_string(), "*/*".to_string());
+        header.strip_hop_by_hop();
+        assert_eq!(header.get("Accept"), Some(&"*/*".to_string()));
+    }
+
+    #[test]
+    fn test_strip_proxy_only() {
+        let mut header = HttpHeader::new();
+        header.insert(
+            "proxy-authorization".to_string(),
+            "Bearer ci.secret".to_string(),
+        );
+        header.insert("Expect".to_string(), "100-continue".to_string());
+        header.insert("Authorization".to_string(), "Basic b3JpZ2lu".to_string());
+        header.strip_proxy_only();
+        assert_eq!(header.len(), 1);
+        assert!(header.contains_key("Authorization"));
+    }
+
     #[test]
     fn test_http_header_iterator() {
         let mut header = HttpHeader::new();
@@ -993,4 +1897,542 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_drain_http_body() {
+        let mut header = HttpResponseHeader {
+            status: HttpResponseStatus::FOUND,
+            headers: HttpHeader::new(),
+            version: HttpVersion::HTTP_V11,
+        };
+
+        header
+            .headers
+            .insert("Content-Length".to_string(), "5".to_string());
+        let mut reader = BufReader::new(&b"Movednext"[..]);
+        assert!(drain_http_body(&mut reader, &header).await);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "next");
+
+        header.headers.remove("Content-Length");
+        header
+            .headers
+            .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        let mut reader = BufReader::new(&b"5\r\nMoved\r\n0\r\n\r\nnext"[..]);
+        assert!(drain_http_body(&mut reader, &header).await);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "next");
+
+        header.headers.remove("Transfer-Encoding");
+        let mut reader = BufReader::new(&b"Moved"[..]);
+        assert!(!drain_http_body(&mut reader, &header).await);
+    }
+
+    #[tokio::test]
+    async fn test_read_chunks() {
+        let mut line = Vec::new();
+        let mut reader = BufReader::new(
+            &b"4;name=\"a;b\"\r\nWiki\r\n0 ; last\r\nExpires: never\r\n\r\nnext"[..],
+        );
+        assert_eq!(read_chunk_size(&mut reader, &mut line).await, Some(4));
+        assert_eq!(line, b"4;name=\"a;b\"\r\n");
+        let mut data = [0; 4];
+        reader.read_exact(&mut data).await.unwrap();
+        assert!(read_chunk_end(&mut reader, &mut line).await);
+        assert_eq!(read_chunk_size(&mut reader, &mut line).await, Some(0));
+        assert_eq!(
+            read_trailers(&mut reader).await,
+            Some(b"Expires: never\r\n\r\n".to_vec())
+        );
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "next");
+
+        for broken in [
+            &b"+5\r\n"[..],
+            b"\r\n",
+            b"5",
+            b"x\r\n",
+            b"10000000000000000\r\n",
+        ] {
+            let mut reader = BufReader::new(broken);
+            assert_eq!(read_chunk_size(&mut reader, &mut line).await, None);
+        }
+
+        /* A size line or trailer that never ends isn't read forever */
+        let endless = vec![b'0'; MAX_CHUNK_LINE as usize + 1];
+        assert_eq!(read_chunk_size(&mut &endless[..], &mut line).await, None);
+        let endless = [&b"X-Padding: "[..], &vec![b'a'; MAX_TRAILERS]].concat();
+        assert_eq!(read_trailers(&mut &endless[..]).await, None);
+        let endless = b"X-Padding: a\r\n".repeat(MAX_TRAILERS);
+        assert_eq!(read_trailers(&mut &endless[..]).await, None);
+
+        let mut reader = BufReader::new(&b"Wikipedia"[..]);
+        assert!(!read_chunk_end(&mut reader, &mut line).await);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_serve_chunk() {
+        let (mut client, mut server) = tokio::io::duplex(BUFFER_SIZE);
+        let mut origin = BufReader::new(
+            &b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nnext"[..],
+        );
+        let mut file = Vec::new();
+
+        let written = fetch_and_serve_chunk(
+            &PathBuf::from("/nonexistent"),
+            &mut server,
+            &mut origin,
+            &mut file,
+            true,
+            true,
+        )
+        .await;
+        assert_eq!(written, (true, true));
+        assert_eq!(file, b"Wikipedia");
+        drop(server);
+
+        /* The origin's extensions and trailers aren't passed on, nor left for its next response */
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(sent, "4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+        let mut rest = String::new();
+        origin.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "next");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_serve_until_close() {
+        let path = PathBuf::from("/nowhere/until-close");
+        let body = vec![b'a'; BUFFER_SIZE + 10];
+
+        let mut client = Vec::new();
+        let mut file = Vec::new();
+        let result = fetch_and_serve_until_close(
+            &path,
+            &mut std::io::Cursor::new(&mut client),
+            BufReader::new(&body[..]),
+            &mut file,
+            true,
+            true,
+            true,
+        )
+        .await;
+        assert_eq!(result, (true, true));
+        assert_eq!(file, body);
+
+        let mut expected = format!("{BUFFER_SIZE:X}\r\n").into_bytes();
+        expected.extend_from_slice(&body[..BUFFER_SIZE]);
+        expected.extend_from_slice(b"\r\nA\r\n");
+        expected.extend_from_slice(&body[BUFFER_SIZE..]);
+        expected.extend_from_slice(b"\r\n0\r\n\r\n");
+        assert_eq!(client, expected);
+
+        let mut client = Vec::new();
+        let result = fetch_and_serve_until_close(
+            &path,
+            &mut std::io::Cursor::new(&mut client),
+            BufReader::new(&b"as it is"[..]),
+            &mut Vec::new(),
+            false,
+            false,
+            true,
+        )
+        .await;
+        assert_eq!(result, (false, true));
+        assert_eq!(client, b"as it is");
+    }
+
+    #[test]
+    fn test_keep_alive_if() {
+        let request = |version: HttpVersion, connection: Option<&str>| {
+            let mut headers = HttpHeader::new();
+            if let Some(c) = connection {
+                headers.insert("Connection".to_string(), c.to_string());
+            }
+            HttpRequestHeader {
+                method: HttpRequestMethod::Get,
+                request: Uri::from("/".to_string()),
+                version,
+                headers,
+            }
+        };
+
+        let keeps = |version, connection| keep_alive_if(&request(version, connection)) == Keep;
+        assert!(keeps(HttpVersion::HTTP_V11, None));
+        assert!(!keeps(HttpVersion::HTTP_V11, Some("close")));
+        assert!(!keeps(HttpVersion::HTTP_V11, Some("TE, Close")));
+        assert!(keeps(HttpVersion::HTTP_V11, Some("keep-alive")));
+        assert!(!keeps(HttpVersion::HTTP_V10, None));
+        assert!(keeps(HttpVersion::HTTP_V10, Some("Keep-Alive")));
+    }
+
+    #[tokio::test]
+    async fn test_respond_with_close() {
+        let mut client = std::io::Cursor::new(Vec::new());
+        respond_with(Close, HttpResponseStatus::NOT_FOUND, &mut client).await;
+        let response = String::from_utf8(client.into_inner()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\nNot Found"));
+
+        let mut client = std::io::Cursor::new(Vec::new());
+        respond_with(Keep, HttpResponseStatus::NOT_FOUND, &mut client).await;
+        assert!(!String::from_utf8(client.into_inner())
+            .unwrap()
+            .contains("Connection"));
+    }
+
+    #[test]
+    fn test_close_after() {
+        let mut request = HttpRequestHeader {
+            method: HttpRequestMethod::Get,
+            request: Uri::from("/".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers: HttpHeader::new(),
+        };
+        let mut response = HttpResponseHeader {
+            status: HttpResponseStatus::OK,
+            headers: HttpHeader::new(),
+            version: HttpVersion::HTTP_V11,
+        };
+
+        response.close_after(&request);
+        assert_eq!(response.headers.get("Connection"), None);
+
+        /* As the last request a connection may make is read */
+        request
+            .headers
+            .insert("Connection".to_string(), "close".to_string());
+        response.close_after(&request);
+        assert_eq!(
+            response.headers.get("Connection"),
+            Some(&"close".to_string())
+        );
+    }
+
+    #[test]
+    fn test_response_keeps_alive() {
+        let mut header = HttpResponseHeader {
+            status: HttpResponseStatus::OK,
+            headers: HttpHeader::new(),
+            version: HttpVersion::HTTP_V11,
+        };
+        assert!(header.keeps_alive());
+
+        header
+            .headers
+            .insert("Connection".to_string(), "close".to_string());
+        assert!(!header.keeps_alive());
+
+        header.version = HttpVersion::HTTP_V10;
+        assert!(!header.keeps_alive());
+
+        header
+            .headers
+            .insert("Connection".to_string(), "Keep-Alive".to_string());
+        assert!(header.keeps_alive());
+    }
+
+    #[test]
+    fn test_response_is_identity_encoded() {
+        let mut header = HttpResponseHeader {
+            status: HttpResponseStatus::OK,
+            headers: HttpHeader::new(),
+            version: HttpVersion::HTTP_V11,
+        };
+        assert!(header.is_identity_encoded());
+
+        header
+            .headers
+            .insert("Content-Encoding".to_string(), "Identity".to_string());
+        assert!(header.is_identity_encoded());
+
+        header
+            .headers
+            .insert("Content-Encoding".to_string(), "gzip".to_string());
+        assert!(!header.is_identity_encoded());
+
+        header
+            .headers
+            .insert("Content-Encoding".to_string(), "identity, br".to_string());
+        assert!(!header.is_identity_encoded());
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let modified = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").ok();
+        let header = |s: &str| Some(s.to_string());
+
+        assert!(if_range_matches(None, modified));
+        assert!(if_range_matches(
+            header("Wed, 21 Oct 2015 07:28:00 GMT").as_ref(),
+            modified
+        ));
+        assert!(!if_range_matches(
+            header("Wed, 21 Oct 2015 07:28:01 GMT").as_ref(),
+            modified
+        ));
+        assert!(!if_range_matches(header("\"abc\"").as_ref(), modified));
+        assert!(!if_range_matches(header("W/\"abc\"").as_ref(), modified));
+        assert!(!if_range_matches(header("garbage").as_ref(), modified));
+        assert!(!if_range_matches(
+            header("Wed, 21 Oct 2015 07:28:00 GMT").as_ref(),
+            None
+        ));
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let modified = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").ok();
+        let tag = entity_tag(10000, modified);
+        assert_eq!(tag, "W/\"2710-56273e80\"");
+
+        let request = |name: &str, value: &str| {
+            let mut headers = HttpHeader::new();
+            headers.insert(name.to_string(), value.to_string());
+            HttpRequestHeader {
+                method: HttpRequestMethod::Get,
+                request: Uri::from("/".to_string()),
+                version: HttpVersion::HTTP_V11,
+                headers,
+            }
+        };
+
+        assert!(not_modified(
+            &request("If-Modified-Since", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            &tag,
+            modified
+        ));
+        assert!(not_modified(
+            &request("If-Modified-Since", "Thu, 22 Oct 2015 07:28:00 GMT"),
+            &tag,
+            modified
+        ));
+        assert!(!not_modified(
+            &request("If-Modified-Since", "Tue, 20 Oct 2015 07:28:00 GMT"),
+            &tag,
+            modified
+        ));
+        assert!(!not_modified(
+            &request("If-Modified-Since", "yesterday"),
+            &tag,
+            modified
+        ));
+        assert!(not_modified(
+            &request("If-None-Match", "\"other\", \"2710-56273e80\""),
+            &tag,
+            modified
+        ));
+        assert!(not_modified(&request("If-None-Match", "*"), &tag, modified));
+        assert!(!not_modified(
+            &request("If-None-Match", "\"other\""),
+            &tag,
+            modified
+        ));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = |s: &str| parse_range(Some(&s.to_string()), 1000);
+
+        assert_eq!(parse_range(None, 1000), RangeRequest::Full);
+        assert_eq!(range("bytes=0-499"), RangeRequest::Satisfiable(0, 499));
+        assert_eq!(range("bytes=500-500"), RangeRequest::Satisfiable(500, 500));
+        assert_eq!(range("bytes=500-"), RangeRequest::Satisfiable(500, 999));
+        assert_eq!(range("bytes=-500"), RangeRequest::Satisfiable(500, 999));
+        assert_eq!(range("bytes=-5000"), RangeRequest::Satisfiable(0, 999));
+        assert_eq!(range("bytes=900-5000"), RangeRequest::Satisfiable(900, 999));
+        assert_eq!(range("bytes=1000-"), RangeRequest::Unsatisfiable);
+        assert_eq!(range("bytes=1000-1001"), RangeRequest::Unsatisfiable);
+        assert_eq!(range("bytes=-0"), RangeRequest::Unsatisfiable);
+        assert_eq!(range("bytes=500-100"), RangeRequest::Full);
+        assert_eq!(range("bytes=abc"), RangeRequest::Full);
+        assert_eq!(range("items=0-5"), RangeRequest::Full);
+        assert_eq!(
+            range("bytes=0-99,200-299"),
+            RangeRequest::Multiple(vec![(0, 99), (200, 299)])
+        );
+        assert_eq!(
+            range("bytes=0-99, -100"),
+            RangeRequest::Multiple(vec![(0, 99), (900, 999)])
+        );
+        assert_eq!(
+            range("bytes=0-99,5000-6000"),
+            RangeRequest::Satisfiable(0, 99)
+        );
+        assert_eq!(range("bytes=5000-,6000-7000"), RangeRequest::Unsatisfiable);
+        assert_eq!(range("bytes=0-99,garbage"), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("Zm9vOmJhcg==").unwrap(), b"foo:bar");
+        assert_eq!(decode_base64("Zm9vYg==").unwrap(), b"foob");
+        assert_eq!(decode_base64("Zm9vYmE=").unwrap(), b"fooba");
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert!(decode_base64("Zm9v!").is_none());
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(b"foo:bar"), "Zm9vOmJhcg==");
+        assert_eq!(encode_base64(b"foob"), "Zm9vYg==");
+        assert_eq!(encode_base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(encode_base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode_base64(b""), "");
+    }
+
+    #[test]
+    fn test_repeated_headers() {
+        let mut headers = get_http_headers(&[
+            "HTTP/1.1 200 OK".to_string(),
+            "Set-Cookie: session=abc; Path=/".to_string(),
+            "Via: 1.0 squid".to_string(),
+            "set-cookie: id=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Secure".to_string(),
+            "Via: 1.1 varnish".to_string(),
+        ]);
+
+        /* Each field is kept as it came, where a comma can't be told apart from a date */
+        assert_eq!(
+            headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+            vec![
+                "session=abc; Path=/",
+                "id=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Secure"
+            ]
+        );
+        assert_eq!(
+            headers.get("Set-Cookie"),
+            Some(&"session=abc; Path=/".to_string())
+        );
+        assert_eq!(
+            headers.get_joined("VIA"),
+            Some("1.0 squid, 1.1 varnish".to_string())
+        );
+        assert_eq!(headers.get_joined("Age"), None);
+
+        let mut response = HttpResponseHeader {
+            status: HttpResponseStatus::OK,
+            headers: headers.clone(),
+            version: HttpVersion::HTTP_V11,
+        };
+        let generated = response.generate();
+        assert_eq!(generated.matches("Date: ").count(), 1);
+        assert!(generated.contains(
+            "Set-Cookie: session=abc; Path=/\r\nVia: 1.0 squid\r\n\
+            set-cookie: id=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Secure\r\nVia: 1.1 varnish"
+        ));
+
+        /* Setting a header replaces every field of that name where the first one was */
+        headers.insert("VIA".to_string(), "1.1 rproxy".to_string());
+        assert_eq!(headers.get_all("Via").count(), 1);
+        assert_eq!(
+            (&headers).into_iter().nth(1),
+            Some((&"VIA".to_string(), &"1.1 rproxy".to_string()))
+        );
+
+        headers.append("Via".to_string(), "1.1 other".to_string());
+        assert_eq!(headers.len(), 4);
+        headers.remove("via");
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_limited() {
+        let limits = HeaderLimits {
+            request_line: 40,
+            field: 20,
+            total: 50,
+            fields: 3,
+        };
+        let read = |bytes: &'static [u8]| async move {
+            HttpRequestHeader::read_limited(&mut BufReader::new(bytes), &limits)
+                .await
+                .map(|r| r.headers.get("Accept").cloned())
+        };
+
+        assert_eq!(
+            read(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").await,
+            Ok(None)
+        );
+        /* The line break doesn't count towards the limit */
+        assert_eq!(
+            read(b"GET http://example.com/ HTTP/1.1\r\nAccept: text/plainxx\r\n\r\n").await,
+            Ok(Some("text/plainxx".to_string()))
+        );
+        assert_eq!(
+            read(b"GET http://example.com/a-long-path HTTP/1.1\r\n\r\n").await,
+            Err(HeaderError::RequestLineTooLong)
+        );
+        assert_eq!(
+            read(b"GET http://example.com/ HTTP/1.1\r\nAccept: text/plainxxx\r\n\r\n").await,
+            Err(HeaderError::FieldsTooLarge)
+        );
+        assert_eq!(
+            read(b"GET http://example.com/ HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n").await,
+            Err(HeaderError::FieldsTooLarge)
+        );
+        assert_eq!(
+            read(b"GET http://example.com/ HTTP/1.1\r\nA: 0123456789ab\r\nB: 0123456789ab\r\nC: 0123456789ab\r\n\r\n").await,
+            Err(HeaderError::FieldsTooLarge)
+        );
+        /* A header that stops short isn't mistaken for a long one */
+        assert_eq!(
+            read(b"GET http://example.com/ HTTP/1.1\r\nHost: exa").await,
+            Err(HeaderError::Unreadable)
+        );
+        assert_eq!(read(b"").await, Err(HeaderError::Unreadable));
+    }
+
+    async fn split_copy(name: &str, length: usize, client_waits: bool) {
+        let path = std::env::temp_dir().join(format!("{}-test-{name}", crate::PKG_NAME));
+        let body: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+        let mut file = tokio::fs::File::create(&path).await.unwrap();
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+
+        let copy = async {
+            let r = fetch_and_serve_known_length(
+                &path,
+                &mut proxy,
+                length as u64,
+                &body[..],
+                &mut file,
+                true,
+                true,
+            )
+            .await;
+            drop(proxy);
+            r
+        };
+        let receive = async {
+            /* A client that doesn't read anything until the whole body is cached */
+            while client_waits
+                && std::fs::metadata(&path).map(|m| m.len()).ok() != Some(length as u64)
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        };
+
+        let ((write_file, write_stream), received) =
+            timeout(Duration::from_secs(10), async { join!(copy, receive) })
+                .await
+                .unwrap();
+        assert!(write_file && write_stream);
+        assert!(received == body);
+        assert!(tokio::fs::read(&path).await.unwrap() == body);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_serve_known_length() {
+        split_copy("split-copy", 100_000, false).await;
+        split_copy("split-copy-empty", 0, false).await;
+
+        /* Far more than is buffered, so the client is sent most of it back from the file */
+        split_copy("split-copy-slow", SPLIT_BUFFER_SIZE * 4 + 123, true).await;
+    }
 }
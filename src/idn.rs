@@ -0,0 +1,154 @@
+/* Punycode parameters from RFC 3492 section 5 */
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+/// Labels of a domain name may be no longer than this once encoded
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Prefix of a label that was encoded with punycode
+const ACE_PREFIX: &str = "xn--";
+
+fn adapt(mut delta: u32, points: u32, first: bool) -> u32 {
+    delta /= match first {
+        true => DAMP,
+        false => 2,
+    };
+    delta += delta / points;
+
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+/// Encode a label with punycode as RFC 3492 section 6.3 does, nothing if it overflows
+fn punycode(label: &str) -> Option<String> {
+    let input: Vec<u32> = label.chars().map(|c| c as u32).collect();
+    let mut output: String = label.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let (mut n, mut delta, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut handled = basic;
+    while (handled as usize) < input.len() {
+        let m = input.iter().copied().filter(|c| *c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for c in &input {
+            if *c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if *c != n {
+                continue;
+            }
+
+            let mut q = delta;
+            let mut k = BASE;
+            loop {
+                let t = match k {
+                    k if k <= bias => TMIN,
+                    k if k >= bias + TMAX => TMAX,
+                    k => k - bias,
+                };
+                if q < t {
+                    break;
+                }
+                output.push(digit(t + (q - t) % (BASE - t)));
+                q = (q - t) / (BASE - t);
+                k += BASE;
+            }
+            output.push(digit(q));
+
+            bias = adapt(delta, handled + 1, handled == basic);
+            delta = 0;
+            handled += 1;
+        }
+
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+    Some(output)
+}
+
+/// `host` as it's looked up and sent to the origin server, each label with characters outside
+/// ASCII lower cased and encoded with punycode (RFC 5891), so `bücher.example` is `xn--bcher-kva.example`.
+/// A host that's already ASCII is returned as it is. Nothing when a label can't be encoded.
+pub(crate) fn to_ascii(host: &str) -> Option<String> {
+    if host.is_ascii() {
+        return Some(host.to_string());
+    }
+
+    /* Ideographic and full width full stops separate labels too (UTS #46) */
+    let host = host.replace(['\u{3002}', '\u{ff0e}', '\u{ff61}'], ".");
+    let mut labels = Vec::new();
+    for label in host.split('.') {
+        let label = label.to_lowercase();
+        let label = match label.is_ascii() {
+            true => label,
+            false => format!("{ACE_PREFIX}{}", punycode(&label)?),
+        };
+        if label.len() > MAX_LABEL_LENGTH {
+            return None;
+        }
+        labels.push(label);
+    }
+    Some(labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punycode() {
+        assert_eq!(punycode("bücher"), Some("bcher-kva".to_string()));
+        assert_eq!(punycode("münchen"), Some("mnchen-3ya".to_string()));
+        assert_eq!(punycode("пример"), Some("e1afmkfd".to_string()));
+        assert_eq!(punycode("испытание"), Some("80akhbyknj4f".to_string()));
+        assert_eq!(punycode("例え"), Some("r8jz45g".to_string()));
+        assert_eq!(punycode("テスト"), Some("zckzah".to_string()));
+        /* RFC 3492 section 7.1 (L) */
+        assert_eq!(
+            punycode("3年b組金八先生"),
+            Some("3b-ww4c5e180e575a65lsy2b".to_string())
+        );
+    }
+
+    #[test]
+    fn test_to_ascii() {
+        assert_eq!(
+            to_ascii("Bücher.example"),
+            Some("xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(
+            to_ascii("пример.испытание"),
+            Some("xn--e1afmkfd.xn--80akhbyknj4f".to_string())
+        );
+        assert_eq!(
+            to_ascii("例え。テスト"),
+            Some("xn--r8jz45g.xn--zckzah".to_string())
+        );
+        assert_eq!(
+            to_ascii("Deb.Debian.org"),
+            Some("Deb.Debian.org".to_string())
+        );
+        assert_eq!(to_ascii(&"ü".repeat(60)), None);
+    }
+}
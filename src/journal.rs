@@ -0,0 +1,725 @@
+use {
+    crate::{
+        cli::setting,
+        conn::{AsyncReadWriteExt, FetchRequest, FlightState, Flights, Uri},
+        cookie::apply_cookie_jar,
+        credentials::apply_upstream_credentials,
+        dedup::deduplicate,
+        digest::{hash_file, inspect_download, BodyDigest, Digesting, Download, Verdict},
+        http::{
+            read_chunk_end, read_chunk_size, read_trailers, HttpHeader, HttpRequestHeader,
+            HttpRequestMethod, HttpResponseHeader, HttpVersion, X_PROXY_CACHE_PATH,
+        },
+        metadata::{decode_metadata, encode_metadata, MetadataError},
+        rules::upstream_accept,
+    },
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        pin::Pin,
+        sync::{Arc, OnceLock},
+    },
+    tokio::{
+        fs::{remove_file, rename, write, File, OpenOptions},
+        io::{copy, AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+        sync::Mutex,
+    },
+    tracing::{debug, info, warn},
+};
+
+#[cfg(feature = "https")]
+use crate::cert::CertificateSetup;
+
+/// Kept in the cache root where it can't be mistaken for a cache entry
+const JOURNAL_FILE_NAME: &str = "journal";
+
+/// A cache file that was still being written
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct JournalEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) uri: String,
+    pub(crate) length: Option<u64>,
+    pub(crate) last_modified: Option<String>,
+    pub(crate) etag: Option<String>,
+}
+
+impl JournalEntry {
+    /// A validator the origin can use to confirm the rest of the file is from the same version
+    fn strong_validator(&self) -> Option<&String> {
+        match &self.etag {
+            Some(e) if !e.starts_with("W/") => Some(e),
+            _ => self.last_modified.as_ref(),
+        }
+    }
+}
+
+struct Journal {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, JournalEntry>>,
+}
+
+static JOURNAL: OnceLock<Journal> = OnceLock::new();
+
+/// Bumped when a field of a journal record changes meaning
+const JOURNAL_VERSION: u32 = 1;
+
+const JOURNAL_KIND: &str = "journal";
+
+fn parse_record(fields: &[&str]) -> Option<JournalEntry> {
+    let optional = |s: &str| match s {
+        "-" => None,
+        s => Some(s.to_string()),
+    };
+
+    if fields.len() < 5 || fields[3].is_empty() || fields[4].is_empty() {
+        return None;
+    }
+
+    Some(JournalEntry {
+        length: fields[0].parse().ok(),
+        last_modified: optional(fields[1]),
+        etag: optional(fields[2]),
+        uri: fields[3].to_string(),
+        path: PathBuf::from(fields[4]),
+    })
+}
+
+/* One record per entry: length, last modified, entity tag, uri and cache path separated by tabs,
+ * with any fields a newer version added after them. Journals from before the format was
+ * versioned are the same records on their own lines without the header or checksums.
+ * The number of damaged records is returned with the ones that could be read. */
+fn parse_journal(contents: &str) -> Result<(Vec<JournalEntry>, usize), MetadataError> {
+    match decode_metadata(contents, JOURNAL_KIND, JOURNAL_VERSION) {
+        Ok(metadata) => Ok((
+            metadata
+                .records
+                .iter()
+                .filter_map(|r| parse_record(&r.split('\t').collect::<Vec<_>>()))
+                .collect(),
+            metadata.corrupt,
+        )),
+        Err(MetadataError::Unversioned) => Ok((
+            contents
+                .lines()
+                .filter_map(|l| parse_record(&l.splitn(5, '\t').collect::<Vec<_>>()))
+                .collect(),
+            0,
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_journal<'a, I>(entries: I) -> String
+where
+    I: Iterator<Item = &'a JournalEntry>,
+{
+    encode_metadata(
+        JOURNAL_KIND,
+        JOURNAL_VERSION,
+        entries.map(|e| {
+            format!(
+                "{}\t{}\t{}\t{}\t{}",
+                e.length.map(|l| l.to_string()).unwrap_or("-".to_string()),
+                e.last_modified.as_deref().unwrap_or("-"),
+                e.etag.as_deref().unwrap_or("-"),
+                e.uri,
+                e.path.to_string_lossy()
+            )
+        }),
+    )
+}
+
+async fn save(journal: &Journal, entries: &HashMap<PathBuf, JournalEntry>) {
+    let temporary = journal.path.with_extension("tmp");
+    let contents = write_journal(entries.values());
+
+    if let Err(e) = write(&temporary, contents).await {
+        warn!("couldn't write journal: {e}");
+        return;
+    }
+
+    if let Err(e) = rename(&temporary, &journal.path).await {
+        warn!("couldn't replace journal: {e}");
+    }
+}
+
+/// Note that a cache file is about to be written
+pub(crate) async fn journal_begin(entry: JournalEntry) {
+    if let Some(journal) = JOURNAL.get() {
+        let mut entries = journal.entries.lock().await;
+        entries.insert(entry.path.clone(), entry);
+        save(journal, &entries).await;
+    }
+}
+
+/// Note that a cache file is complete or has been removed
+pub(crate) async fn journal_end(path: &Path) {
+    if let Some(journal) = JOURNAL.get() {
+        let mut entries = journal.entries.lock().await;
+        if entries.remove(path).is_some() {
+            save(journal, &entries).await;
+        }
+    }
+}
+
+/// Cache files a running rproxy is still writing, according to the journal in `cache_path`
+pub(crate) async fn journal_paths(cache_path: &Path) -> Vec<PathBuf> {
+    match tokio::fs::read_to_string(cache_path.join(JOURNAL_FILE_NAME)).await {
+        Ok(c) => match parse_journal(&c) {
+            Ok((entries, _)) => entries.into_iter().map(|e| e.path).collect(),
+            Err(_) => Vec::new(),
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Open the journal in the cache root and deal with anything left over from an unclean shutdown.
+/// Partial files that can be resumed are marked in flight and finished in the background,
+/// the rest are removed so they're never served truncated.
+pub(crate) async fn setup_journal(
+    flights: &Arc<Flights>,
+    #[cfg(feature = "https")] certificates: &Arc<CertificateSetup>,
+) {
+    let cache_path = match setting(X_PROXY_CACHE_PATH) {
+        Ok(p) => PathBuf::from(p),
+        Err(_) => return,
+    };
+
+    let path = cache_path.join(JOURNAL_FILE_NAME);
+    let leftovers = match tokio::fs::read_to_string(&path).await {
+        Ok(c) => match parse_journal(&c) {
+            Ok((entries, 0)) => entries,
+            Ok((entries, corrupt)) => {
+                warn!("skipped {corrupt} damaged journal entries");
+                entries
+            }
+            Err(e) => {
+                warn!("couldn't read journal '{}': {e}", path.to_string_lossy());
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    };
+
+    let mut resumable = HashMap::new();
+
+    for entry in leftovers {
+        let size = match tokio::fs::metadata(&entry.path).await {
+            Ok(m) => m.len(),
+            Err(_) => continue, /* Already gone */
+        };
+
+        match entry.length {
+            Some(length) if size == length => continue, /* Finished before the journal caught up */
+            Some(length) if size < length && entry.strong_validator().is_some() => {
+                flights
+                    .takeoff(
+                        entry.path.to_string_lossy().as_ref(),
+                        FlightState::Length(length),
+                    )
+                    .await;
+                resumable.insert(entry.path.clone(), entry);
+            }
+            _ => {
+                info!(
+                    "removing partial download '{}'",
+                    entry.path.to_string_lossy()
+                );
+                let _ = remove_file(&entry.path).await;
+            }
+        }
+    }
+
+    let journal = JOURNAL.get_or_init(|| Journal {
+        path,
+        entries: Mutex::new(HashMap::new()),
+    });
+
+    {
+        let mut entries = journal.entries.lock().await;
+        entries.extend(resumable.clone());
+        save(journal, &entries).await;
+    }
+
+    for entry in resumable.into_values() {
+        let flights = Arc::clone(flights);
+        #[cfg(feature = "https")]
+        let certificates = Arc::clone(certificates);
+
+        tokio::spawn(async move {
+            let key = entry.path.to_string_lossy().to_string();
+
+            info!("resuming partial download '{key}'");
+            let resumed = resume_download(
+                &entry,
+                #[cfg(feature = "https")]
+                &certificates,
+            )
+            .await;
+            match resumed {
+                true => flights.complete(&key).await,
+                false => {
+                    warn!("couldn't resume '{key}', downloading it again");
+                    let _ = remove_file(&entry.path).await;
+                }
+            }
+
+            journal_end(&entry.path).await;
+            flights.land(&key).await;
+
+            if !resumed {
+                refetch(
+                    &entry,
+                    &flights,
+                    #[cfg(feature = "https")]
+                    &certificates,
+                )
+                .await;
+            }
+        });
+    }
+}
+
+/* Asks for the body as it's stored, from `range` onwards when the validator still matches */
+async fn send_request<'a>(
+    fetch_request: &'a mut FetchRequest<'_>,
+    uri: &Uri<'_>,
+    range: Option<(u64, &String)>,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) -> Option<Pin<Box<dyn AsyncReadWriteExt + 'a>>> {
+    fetch_request
+        .connect(
+            #[cfg(feature = "https")]
+            certificates,
+        )
+        .await
+        .ok()?;
+
+    let mut headers = HttpHeader::new();
+    headers.insert("Host".to_string(), uri.host?.to_string());
+    if let Some((offset, validator)) = range {
+        headers.insert("Range".to_string(), format!("bytes={offset}-"));
+        headers.insert("If-Range".to_string(), validator.clone());
+    }
+    headers.insert("Accept-Encoding".to_string(), "identity".to_string());
+    headers.insert("Connection".to_string(), "close".to_string());
+    if let Some(accept) = upstream_accept(&uri.uri) {
+        headers.insert("Accept".to_string(), accept.to_string());
+    }
+    apply_cookie_jar(uri, &mut headers);
+    apply_upstream_credentials(uri, &mut headers);
+
+    let request = HttpRequestHeader {
+        method: HttpRequestMethod::Get,
+        request: Uri::from(uri.path_and_query?.to_string()),
+        version: HttpVersion::HTTP_V11,
+        headers,
+    };
+
+    let request = request.generate()?;
+    let mut stream = fetch_request.as_stream()?;
+    stream.write_all(request.as_bytes()).await.ok()?;
+    Some(stream)
+}
+
+/* The body of `response` into `writer`, decoded when it's chunked, returning how long it was.
+ * A body that only ends when the connection closes can't be told apart from one cut short. */
+async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    response: &HttpResponseHeader,
+) -> Option<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if !response.headers.contains_key("Transfer-Encoding") {
+        let length = response
+            .headers
+            .get("Content-Length")?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        return match copy(&mut reader.take(length), writer).await {
+            Ok(n) if n == length => Some(length),
+            _ => None,
+        };
+    }
+
+    if !response.headers.is_chunked() {
+        return None;
+    }
+
+    let mut length = 0u64;
+    let mut line = Vec::new();
+    loop {
+        let size = read_chunk_size(reader, &mut line).await?;
+        if size == 0 {
+            break;
+        }
+
+        match copy(&mut (&mut *reader).take(size), writer).await {
+            Ok(n) if n == size => length += size,
+            _ => return None,
+        }
+        if !read_chunk_end(reader, &mut line).await {
+            return None;
+        }
+    }
+
+    read_trailers(reader).await.map(|_| length)
+}
+
+/* Give a finished file its origin's modification time and hand it to the download hooks */
+async fn keep_download(
+    file: File,
+    uri: &str,
+    path: &Path,
+    last_modified: Option<&String>,
+    digest: BodyDigest,
+) -> bool {
+    let mut file = file;
+    if file.flush().await.is_err() {
+        return false;
+    }
+
+    if let Some(Ok(last_modified)) = last_modified.map(|l| httpdate::parse_http_date(l)) {
+        let _ = file.into_std().await.set_modified(last_modified);
+    }
+
+    let modified = tokio::fs::metadata(path)
+        .await
+        .ok()
+        .and_then(|m| m.modified().ok());
+    let download = Download {
+        uri,
+        path,
+        digest: &digest,
+    };
+
+    /* A quarantined file is removed by the caller like any other that couldn't be kept */
+    if inspect_download(&download, modified) != Verdict::Keep {
+        return false;
+    }
+    deduplicate(uri, path, &digest).await;
+    true
+}
+
+/// Fetch the rest of a partial file, only if the origin still has the same version of it
+async fn resume_download(
+    entry: &JournalEntry,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) -> bool {
+    let (length, validator) = match (entry.length, entry.strong_validator()) {
+        (Some(l), Some(v)) => (l, v.clone()),
+        _ => return false,
+    };
+
+    let mut file = match OpenOptions::new().append(true).open(&entry.path).await {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+
+    let offset = match file.metadata().await {
+        Ok(m) => m.len(),
+        Err(_) => return false,
+    };
+
+    let uri = Uri::from(&entry.uri);
+    let mut fetch_request = match FetchRequest::from_uri(&uri) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+
+    let mut reader = match send_request(
+        &mut fetch_request,
+        &uri,
+        Some((offset, &validator)),
+        #[cfg(feature = "https")]
+        certificates,
+    )
+    .await
+    {
+        Some(s) => BufReader::new(s),
+        None => return false,
+    };
+    let response = match HttpResponseHeader::from_tcp_buffer_async(&mut reader).await {
+        Some(r) => r,
+        None => return false,
+    };
+
+    /* Anything other than the exact range asked for, as it's stored,
+     * means the file changed or can't be resumed */
+    let remaining = length - offset;
+    let expected_range = format!("bytes {offset}-{}/{length}", length - 1);
+    let framed_length = match response.headers.contains_key("Transfer-Encoding") {
+        true => None,
+        false => response.headers.get("Content-Length").map(|l| l.trim()),
+    };
+    if response.status.to_code() != 206
+        || response.headers.get("Content-Range").map(|r| r.trim()) != Some(&expected_range)
+        || framed_length.is_some_and(|l| l != remaining.to_string())
+        || !response.is_identity_encoded()
+    {
+        debug!("Origin won't resume {} from {offset}", entry.uri);
+        return false;
+    }
+
+    /* What's already on disk is hashed first so the digest covers the whole file */
+    let hasher = match hash_file(&entry.path).await {
+        Some(h) if h.length() == offset => h,
+        _ => return false,
+    };
+
+    let mut body = Digesting::resume(&mut file, hasher);
+    match copy_body(&mut reader, &mut body, &response).await {
+        Some(n) if n == remaining => {}
+        _ => return false,
+    }
+    let digest = body.finish();
+
+    keep_download(
+        file,
+        &entry.uri,
+        &entry.path,
+        entry.last_modified.as_ref(),
+        digest,
+    )
+    .await
+}
+
+/// Download a file that couldn't be resumed from the start, in flight like any other download
+/// so clients asking for it meanwhile wait for it rather than fetching it too
+async fn refetch(
+    entry: &JournalEntry,
+    flights: &Flights,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) {
+    let key = entry.path.to_string_lossy().to_string();
+    /* A client already asked for it again and is downloading it */
+    if flights.is_in_flight(&key).await {
+        return;
+    }
+
+    flights.takeoff(&key, FlightState::Fetching).await;
+    match download_again(
+        entry,
+        flights,
+        #[cfg(feature = "https")]
+        certificates,
+    )
+    .await
+    {
+        true => flights.complete(&key).await,
+        false => {
+            warn!("couldn't download '{key}' again");
+            let _ = remove_file(&entry.path).await;
+        }
+    }
+
+    journal_end(&entry.path).await;
+    flights.land(&key).await;
+}
+
+async fn download_again(
+    entry: &JournalEntry,
+    flights: &Flights,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) -> bool {
+    let uri = Uri::from(&entry.uri);
+    let mut fetch_request = match FetchRequest::from_uri(&uri) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+
+    let mut reader = match send_request(
+        &mut fetch_request,
+        &uri,
+        None,
+        #[cfg(feature = "https")]
+        certificates,
+    )
+    .await
+    {
+        Some(s) => BufReader::new(s),
+        None => return false,
+    };
+    let response = match HttpResponseHeader::from_tcp_buffer_async(&mut reader).await {
+        Some(r) => r,
+        None => return false,
+    };
+
+    let no_store = response.headers.has_token("Cache-Control", "no-store")
+        || response.headers.has_token("Cache-Control", "private");
+    if response.status.to_code() != 200 || no_store || !response.is_identity_encoded() {
+        return false;
+    }
+
+    let length = match response.headers.contains_key("Transfer-Encoding") {
+        true => None,
+        false => match response.headers.get("Content-Length") {
+            Some(l) => l.trim().parse::<u64>().ok(),
+            None => return false,
+        },
+    };
+
+    let mut file = match File::create(&entry.path).await {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+
+    let last_modified = response.headers.get("Last-Modified").cloned();
+    journal_begin(JournalEntry {
+        path: entry.path.clone(),
+        uri: entry.uri.clone(),
+        length,
+        last_modified: last_modified.clone(),
+        etag: response.headers.get("ETag").cloned(),
+    })
+    .await;
+    flights
+        .takeoff(
+            &entry.path.to_string_lossy(),
+            match length {
+                Some(l) => FlightState::Length(l),
+                None => FlightState::Chunks,
+            },
+        )
+        .await;
+
+    let mut body = Digesting::new(&mut file);
+    match copy_body(&mut reader, &mut body, &response).await {
+        Some(n) if length.is_none_or(|l| l == n) => {}
+        _ => return false,
+    }
+    let digest = body.finish();
+
+    keep_download(
+        file,
+        &entry.uri,
+        &entry.path,
+        last_modified.as_ref(),
+        digest,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::http::HttpResponseStatus};
+
+    #[test]
+    fn test_journal_round_trip() {
+        let entries = vec![
+            JournalEntry {
+                path: PathBuf::from("/cache/deb.debian.org/foo.deb"),
+                uri: "http://deb.debian.org/debian/foo.deb".to_string(),
+                length: Some(1234),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+                etag: Some("\"abc\"".to_string()),
+            },
+            JournalEntry {
+                path: PathBuf::from("/cache/example.com/stream"),
+                uri: "http://example.com/stream".to_string(),
+                length: None,
+                last_modified: None,
+                etag: None,
+            },
+        ];
+
+        let contents = write_journal(entries.iter());
+        assert_eq!(parse_journal(&contents), Ok((entries.clone(), 0)));
+        assert_eq!(parse_journal("garbage\n\n"), Ok((vec![], 0)));
+
+        /* Fields a newer version added to the end of a record are ignored */
+        let extended = encode_metadata(
+            JOURNAL_KIND,
+            JOURNAL_VERSION,
+            ["1234\t-\t-\thttp://example.com/file\t/cache/example.com/file\tnew"],
+        );
+        let (parsed, _) = parse_journal(&extended).unwrap();
+        assert_eq!(parsed[0].path, PathBuf::from("/cache/example.com/file"));
+
+        /* A damaged record is counted rather than read wrong */
+        let damaged = contents.replacen("1234", "1235", 1);
+        assert_eq!(parse_journal(&damaged), Ok((entries[1..].to_vec(), 1)));
+
+        let newer = encode_metadata(JOURNAL_KIND, JOURNAL_VERSION + 1, ["x"]);
+        assert_eq!(
+            parse_journal(&newer),
+            Err(MetadataError::Newer(JOURNAL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_unversioned_journal() {
+        let contents = "1234\t-\t\"abc\"\thttp://example.com/file\t/cache/example.com/file\n\
+                        -\t-\t-\thttp://example.com/stream\t/cache/example.com/stream\n";
+        let (entries, corrupt) = parse_journal(contents).unwrap();
+        assert_eq!(corrupt, 0);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].length, Some(1234));
+        assert_eq!(entries[0].etag, Some("\"abc\"".to_string()));
+        assert_eq!(entries[1].path, PathBuf::from("/cache/example.com/stream"));
+    }
+
+    #[test]
+    fn test_strong_validator() {
+        let mut entry = JournalEntry {
+            path: PathBuf::from("/cache/example.com/file"),
+            uri: "http://example.com/file".to_string(),
+            length: Some(1),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            etag: Some("W/\"weak\"".to_string()),
+        };
+        assert_eq!(entry.strong_validator(), entry.last_modified.as_ref());
+
+        entry.etag = Some("\"strong\"".to_string());
+        assert_eq!(entry.strong_validator(), Some(&"\"strong\"".to_string()));
+
+        entry.etag = None;
+        entry.last_modified = None;
+        assert!(entry.strong_validator().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_copy_body() {
+        async fn copied(body: &[u8], response: &HttpResponseHeader) -> (Option<u64>, Vec<u8>) {
+            let mut written = Vec::new();
+            let length = copy_body(&mut BufReader::new(body), &mut written, response).await;
+            (length, written)
+        }
+
+        let mut response = HttpResponseHeader {
+            status: HttpResponseStatus::PARTIAL_CONTENT,
+            headers: HttpHeader::new(),
+            version: HttpVersion::HTTP_V11,
+        };
+        response
+            .headers
+            .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+
+        /* Stored as the bytes it carries, not as it was framed */
+        let chunked = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        assert_eq!(
+            copied(chunked, &response).await,
+            (Some(9), b"Wikipedia".to_vec())
+        );
+        assert_eq!(copied(b"4\r\nWiki\r\n5\r\nped", &response).await.0, None);
+
+        response
+            .headers
+            .insert("Transfer-Encoding".to_string(), "gzip".to_string());
+        assert_eq!(copied(chunked, &response).await.0, None);
+
+        response.headers.remove("Transfer-Encoding");
+        response
+            .headers
+            .insert("Content-Length".to_string(), "4".to_string());
+        assert_eq!(
+            copied(b"WikiNEXT", &response).await,
+            (Some(4), b"Wiki".to_vec())
+        );
+        assert_eq!(copied(b"Wi", &response).await.0, None);
+    }
+}
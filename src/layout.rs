@@ -0,0 +1,332 @@
+use {
+    crate::{
+        cli::setting, dedup::BLOB_DIRECTORY_NAME, http::X_PROXY_CACHE_PATH, journal::journal_paths,
+        PKG_NAME,
+    },
+    std::path::{Path, PathBuf},
+    tokio::fs::{create_dir_all, read_dir, read_to_string, remove_dir, remove_file, rename, write},
+    tracing::{error, info, warn},
+};
+
+pub const X_PROXY_CACHE_LAYOUT: &str = "X_PROXY_CACHE_LAYOUT";
+
+/// Bumped whenever what's stored in the cache changes meaning,
+/// along with a step in [`setup_layout`] that brings older caches up to date
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Records the format version and layout of the cache in its root
+const LAYOUT_MARKER_FILE_NAME: &str = "layout";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CacheLayout {
+    /// `host/file`
+    Flat,
+    /// `host/xx/file` where `xx` is derived from the file name,
+    /// keeping directories small on hosts with many files
+    Sharded,
+}
+
+impl CacheLayout {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "flat" => Some(CacheLayout::Flat),
+            "sharded" => Some(CacheLayout::Sharded),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn configured() -> Self {
+        match setting(X_PROXY_CACHE_LAYOUT) {
+            Ok(s) => CacheLayout::from_name(&s).unwrap_or(CacheLayout::Flat),
+            Err(_) => CacheLayout::Flat,
+        }
+    }
+
+    pub(crate) fn entry_path(&self, store_path: &Path, host: &str, file: &str) -> PathBuf {
+        match self {
+            CacheLayout::Flat => store_path.join(host).join(file),
+            CacheLayout::Sharded => store_path.join(host).join(shard_of(file)).join(file),
+        }
+    }
+}
+
+impl std::fmt::Display for CacheLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheLayout::Flat => write!(f, "flat"),
+            CacheLayout::Sharded => write!(f, "sharded"),
+        }
+    }
+}
+
+/// Two hex digits from an FNV-1a hash of the file name
+pub(crate) fn shard_of(file: &str) -> String {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in file.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    format!("{:02x}", hash & 0xff)
+}
+
+fn is_shard_name(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_uppercase())
+}
+
+/// Every cache entry as `(host, file, path)`, regardless of which layout it was stored with
+pub(crate) async fn cache_entries(store_path: &Path) -> Vec<(String, String, PathBuf)> {
+    let mut entries = Vec::new();
+
+    let mut hosts = match read_dir(store_path).await {
+        Ok(d) => d,
+        Err(_) => return entries,
+    };
+
+    while let Ok(Some(host)) = hosts.next_entry().await {
+        if !host.file_type().await.map(|t| t.is_dir()).unwrap_or(false) {
+            continue; /* Files in the cache root are not cache entries (e.g. certificates) */
+        }
+        let host_name = host.file_name().to_string_lossy().to_string();
+        if host_name == BLOB_DIRECTORY_NAME {
+            continue; /* Entries link to these, they aren't entries themselves */
+        }
+
+        let mut files = match read_dir(host.path()).await {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        while let Ok(Some(file)) = files.next_entry().await {
+            let file_name = file.file_name().to_string_lossy().to_string();
+            match file.file_type().await {
+                Ok(t) if t.is_file() => entries.push((host_name.clone(), file_name, file.path())),
+                Ok(t) if t.is_dir() && is_shard_name(&file_name) => {
+                    let mut shard = match read_dir(file.path()).await {
+                        Ok(d) => d,
+                        Err(_) => continue,
+                    };
+                    while let Ok(Some(file)) = shard.next_entry().await {
+                        if file.file_type().await.map(|t| t.is_file()).unwrap_or(false) {
+                            let file_name = file.file_name().to_string_lossy().to_string();
+                            entries.push((host_name.clone(), file_name, file.path()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    entries
+}
+
+/// Move every entry in the cache to where `layout` expects it.
+/// Files are renamed in place so their modification times are preserved.
+/// Downloads in the journal are left where the journal expects to find them.
+pub(crate) async fn migrate_cache(store_path: &Path, layout: CacheLayout) -> (u64, u64) {
+    let mut moved = 0;
+    let mut failed = 0;
+    let in_progress = journal_paths(store_path).await;
+
+    for (host, file, path) in cache_entries(store_path).await {
+        let target = layout.entry_path(store_path, &host, &file);
+        if target == path || in_progress.contains(&path) {
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            if create_dir_all(parent).await.is_err() {
+                failed += 1;
+                continue;
+            }
+        }
+
+        match rename(&path, &target).await {
+            Ok(_) => {
+                moved += 1;
+                if let Some(parent) = path.parent() {
+                    if parent
+                        .file_name()
+                        .map(|n| is_shard_name(&n.to_string_lossy()))
+                        == Some(true)
+                    {
+                        let _ = remove_dir(parent).await; /* Only succeeds once the shard is empty */
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("couldn't move '{}': {e}", path.to_string_lossy());
+                failed += 1;
+            }
+        }
+    }
+
+    (moved, failed)
+}
+
+#[derive(Debug, PartialEq)]
+struct LayoutMarker {
+    version: u32,
+    layout: CacheLayout,
+}
+
+/* Written as `<version> <layout>` on a single line */
+fn parse_marker(text: &str) -> Option<LayoutMarker> {
+    let (version, layout) = text.trim().split_once(' ')?;
+    Some(LayoutMarker {
+        version: version.parse().ok()?,
+        layout: CacheLayout::from_name(layout)?,
+    })
+}
+
+/// The file recording the format and layout of the cache, there from when rproxy starts
+pub(crate) fn layout_marker(store_path: &Path) -> PathBuf {
+    store_path.join(LAYOUT_MARKER_FILE_NAME)
+}
+
+async fn write_marker(store_path: &Path, layout: CacheLayout) -> bool {
+    let marker = format!("{CACHE_FORMAT_VERSION} {layout}\n");
+    match write(layout_marker(store_path), marker).await {
+        Ok(_) => true,
+        Err(e) => {
+            error!("couldn't record the cache layout: {e}");
+            false
+        }
+    }
+}
+
+/// Check the cache was written in a format this version understands and bring it up to date,
+/// moving entries to the layout in `X_PROXY_CACHE_LAYOUT` if they were stored with another.
+/// False when the cache can't be used, such as when a newer version of rproxy wrote it.
+pub(crate) async fn setup_layout(store_path: &Path) -> bool {
+    let marker_path = layout_marker(store_path);
+    let configured = CacheLayout::configured();
+
+    let marker = match read_to_string(&marker_path).await {
+        Err(_) => None, /* Caches from before the marker existed could be in either layout */
+        Ok(text) => match parse_marker(&text) {
+            Some(m) => Some(m),
+            None => {
+                error!(
+                    "'{}' is not a cache layout this version understands",
+                    marker_path.to_string_lossy()
+                );
+                return false;
+            }
+        },
+    };
+
+    match marker {
+        Some(m) if m.version > CACHE_FORMAT_VERSION => {
+            error!(
+                "the cache is in format {} which needs a newer version of {PKG_NAME}",
+                m.version
+            );
+            return false;
+        }
+        Some(m) if m.version == CACHE_FORMAT_VERSION && m.layout == configured => return true,
+        _ => {}
+    }
+
+    /* Partial downloads would be resumed where the journal says but looked up where they moved to */
+    for path in journal_paths(store_path).await {
+        let _ = remove_file(path).await;
+    }
+
+    let (moved, failed) = migrate_cache(store_path, configured).await;
+    if moved > 0 || failed > 0 {
+        info!("moved {moved} files to the {configured} layout, {failed} failed");
+    }
+
+    write_marker(store_path, configured).await
+}
+
+/// Entry point for `rproxy migrate [flat|sharded]`
+pub(crate) async fn migrate_command(args: &[String]) -> i32 {
+    let store_path = match setting(X_PROXY_CACHE_PATH) {
+        Ok(s) => PathBuf::from(s),
+        Err(_) => {
+            error!("'{X_PROXY_CACHE_PATH}' has not been set");
+            return 1;
+        }
+    };
+
+    let layout = match args.first() {
+        None => CacheLayout::configured(),
+        Some(a) => match CacheLayout::from_name(a) {
+            Some(l) => l,
+            None => {
+                error!("unknown cache layout '{a}', expected 'flat' or 'sharded'");
+                return 1;
+            }
+        },
+    };
+
+    info!(
+        "migrating '{}' to the {layout} layout",
+        store_path.to_string_lossy()
+    );
+
+    let (moved, failed) = migrate_cache(&store_path, layout).await;
+    info!("moved {moved} files, {failed} failed");
+
+    match failed == 0 && write_marker(&store_path, layout).await {
+        true => 0,
+        false => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_of_is_stable() {
+        assert_eq!(shard_of("file.deb"), shard_of("file.deb"));
+        assert_eq!(shard_of("file.deb").len(), 2);
+        assert!(is_shard_name(&shard_of("another-file.rpm")));
+    }
+
+    #[test]
+    fn test_entry_path() {
+        let root = Path::new("/cache");
+        assert_eq!(
+            CacheLayout::Flat.entry_path(root, "example.com", "file.deb"),
+            PathBuf::from("/cache/example.com/file.deb")
+        );
+        assert_eq!(
+            CacheLayout::Sharded.entry_path(root, "example.com", "file.deb"),
+            PathBuf::from(format!(
+                "/cache/example.com/{}/file.deb",
+                shard_of("file.deb")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_marker() {
+        assert_eq!(
+            parse_marker("1 sharded\n"),
+            Some(LayoutMarker {
+                version: 1,
+                layout: CacheLayout::Sharded
+            })
+        );
+        assert_eq!(parse_marker("2 tree"), None);
+        assert_eq!(parse_marker("flat"), None);
+        assert_eq!(parse_marker(""), None);
+    }
+
+    #[test]
+    fn test_layout_from_name() {
+        assert_eq!(CacheLayout::from_name("Flat"), Some(CacheLayout::Flat));
+        assert_eq!(
+            CacheLayout::from_name("sharded"),
+            Some(CacheLayout::Sharded)
+        );
+        assert_eq!(CacheLayout::from_name("tree"), None);
+    }
+}
@@ -0,0 +1,582 @@
+use {
+    crate::{
+        auth::{AuthBackend, Checked},
+        cli::setting,
+        conn::AsyncReadWriteExt,
+        digest::Sha256,
+        rules::parse_duration,
+    },
+    std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Mutex,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    },
+    tracing::{debug, warn},
+};
+
+#[cfg(feature = "https")]
+use {
+    crate::cert::CertificateSetup,
+    std::{convert::TryFrom, sync::Arc},
+    tokio_rustls::rustls::pki_types::ServerName,
+};
+
+pub const X_PROXY_AUTH_LDAP_URL: &str = "X_PROXY_AUTH_LDAP_URL";
+pub const X_PROXY_AUTH_LDAP_BIND_DN: &str = "X_PROXY_AUTH_LDAP_BIND_DN";
+pub const X_PROXY_AUTH_LDAP_CACHE: &str = "X_PROXY_AUTH_LDAP_CACHE";
+
+/// How long a successful login is trusted without asking the directory again
+const DEFAULT_CACHE_TIME: Duration = Duration::from_secs(5 * 60);
+
+/// Logins remembered at once, the oldest are forgotten first
+const MAX_CACHED_LOGINS: usize = 1024;
+
+/// Idle connections kept open to the directory
+const POOL_SIZE: usize = 4;
+
+/// How long the directory has to answer a bind
+const LDAP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest response to a bind that's read, they're normally a few dozen bytes
+const MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+
+const RESULT_SUCCESS: u8 = 0;
+
+#[derive(Debug, PartialEq)]
+struct Server {
+    host: String,
+    port: u16,
+    tls: bool,
+}
+
+fn parse_url(url: &str) -> Option<Server> {
+    let url = url.trim();
+    let (rest, tls, port) = match url.strip_prefix("ldap://") {
+        Some(r) => (r, false, 389),
+        None => (url.strip_prefix("ldaps://")?, true, 636),
+    };
+
+    let authority = rest.split('/').next()?;
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']')? {
+            (h, "") => (h, port),
+            (h, p) => (h, p.strip_prefix(':')?.parse().ok()?),
+        },
+        None => match authority.rsplit_once(':') {
+            Some((h, p)) => (h, p.parse().ok()?),
+            None => (authority, port),
+        },
+    };
+
+    match host.is_empty() {
+        true => None,
+        false => Some(Server {
+            host: host.to_string(),
+            port,
+            tls,
+        }),
+    }
+}
+
+/* A user name can't change which entry is bound to by smuggling in its own attributes */
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/* Basic Encoding Rules, just enough of them for a simple bind */
+fn ber(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    let length = contents.len();
+    match length {
+        0..=0x7f => element.push(length as u8),
+        _ => {
+            let bytes: Vec<u8> = length
+                .to_be_bytes()
+                .iter()
+                .copied()
+                .skip_while(|b| *b == 0)
+                .collect();
+            element.push(0x80 | bytes.len() as u8);
+            element.extend_from_slice(&bytes);
+        }
+    }
+    element.extend_from_slice(contents);
+    element
+}
+
+fn ber_integer(value: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value
+        .to_be_bytes()
+        .iter()
+        .copied()
+        .skip_while(|b| *b == 0)
+        .collect();
+
+    /* The top bit would make it negative */
+    if bytes.first().is_none_or(|b| b & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    ber(0x02, &bytes)
+}
+
+fn bind_request(message_id: u32, dn: &str, password: &str) -> Vec<u8> {
+    let mut bind = ber_integer(3);
+    bind.extend(ber(0x04, dn.as_bytes()));
+    bind.extend(ber(0x80, password.as_bytes()));
+
+    let mut message = ber_integer(message_id);
+    message.extend(ber(0x60, &bind));
+    ber(0x30, &message)
+}
+
+/* The tag, contents and whatever follows the first element of `data` */
+fn split_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+
+    let length = match first {
+        0..=0x7f => first as usize,
+        _ => {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || data.len() < count {
+                return None;
+            }
+            let (bytes, rest) = data.split_at(count);
+            data = rest;
+            bytes.iter().fold(0, |l, b| (l << 8) | *b as usize)
+        }
+    };
+
+    match data.len() < length {
+        true => None,
+        false => Some((tag, &data[..length], &data[length..])),
+    }
+}
+
+/// The result code of a bind response to `message_id`
+fn parse_bind_response(message: &[u8], message_id: u32) -> Option<u8> {
+    let (tag, message, _) = split_element(message)?;
+    if tag != 0x30 {
+        return None;
+    }
+
+    let (tag, id, rest) = split_element(message)?;
+    let id = id.iter().fold(0u64, |i, b| (i << 8) | *b as u64);
+    if tag != 0x02 || id != message_id as u64 {
+        return None;
+    }
+
+    let (tag, response, _) = split_element(rest)?;
+    if tag != 0x61 {
+        return None;
+    }
+
+    match split_element(response)? {
+        (0x0a, &[code], _) => Some(code),
+        _ => None,
+    }
+}
+
+async fn read_message<T>(stream: &mut T) -> Option<Vec<u8>>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut message = vec![0u8; 2];
+    stream.read_exact(&mut message).await.ok()?;
+
+    let length = match message[1] {
+        0..=0x7f => message[1] as usize,
+        first => {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let mut bytes = vec![0u8; count];
+            stream.read_exact(&mut bytes).await.ok()?;
+            message.extend_from_slice(&bytes);
+            bytes.iter().fold(0, |l, b| (l << 8) | *b as usize)
+        }
+    };
+
+    if length > MAX_MESSAGE_LENGTH {
+        return None;
+    }
+
+    let start = message.len();
+    message.resize(start + length, 0);
+    stream.read_exact(&mut message[start..]).await.ok()?;
+    Some(message)
+}
+
+/// Logs users in by binding to an LDAP directory or Active Directory as them
+pub(crate) struct Ldap {
+    server: Server,
+    /// The name to bind as with `{user}` where the user name goes
+    template: String,
+    cache_for: Duration,
+    cache: Mutex<HashMap<String, ([u8; 32], Instant)>>,
+    pool: Mutex<Vec<Box<dyn AsyncReadWriteExt>>>,
+    message_id: AtomicU32,
+    /* Only needed for ldaps:// */
+    #[cfg(feature = "https")]
+    certificates: Option<Arc<CertificateSetup>>,
+}
+
+/* Only a digest of the password is kept in memory */
+fn login_digest(user: &str, password: &str) -> [u8; 32] {
+    let mut hasher = Sha256::default();
+    hasher.update(user.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(password.as_bytes());
+    hasher.finish()
+}
+
+impl Ldap {
+    fn cached(&self, user: &str, digest: &[u8; 32]) -> bool {
+        match self.cache.lock() {
+            Ok(cache) => cache
+                .get(user)
+                .is_some_and(|(d, at)| d == digest && at.elapsed() < self.cache_for),
+            Err(_) => false,
+        }
+    }
+
+    fn remember(&self, user: &str, digest: [u8; 32]) {
+        if self.cache_for.is_zero() {
+            return;
+        }
+
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.retain(|_, (_, at)| at.elapsed() < self.cache_for);
+            if cache.len() >= MAX_CACHED_LOGINS {
+                if let Some(oldest) = cache
+                    .iter()
+                    .min_by_key(|(_, (_, at))| *at)
+                    .map(|(u, _)| u.clone())
+                {
+                    cache.remove(&oldest);
+                }
+            }
+            cache.insert(user.to_string(), (digest, Instant::now()));
+        }
+    }
+
+    fn forget(&self, user: &str) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.remove(user);
+        }
+    }
+
+    async fn connect(&self) -> Option<Box<dyn AsyncReadWriteExt>> {
+        let stream = TcpStream::connect((self.server.host.as_str(), self.server.port))
+            .await
+            .ok()?;
+
+        #[cfg(feature = "https")]
+        if self.server.tls {
+            let domain = ServerName::try_from(self.server.host.clone()).ok()?;
+            let stream = self
+                .certificates
+                .as_ref()?
+                .connector_for(&self.server.host)
+                .connect(domain, stream)
+                .await
+                .ok()?;
+            return Some(Box::new(stream));
+        }
+
+        Some(Box::new(stream))
+    }
+
+    async fn exchange(
+        stream: &mut Box<dyn AsyncReadWriteExt>,
+        request: &[u8],
+        message_id: u32,
+    ) -> Option<u8> {
+        stream.write_all(request).await.ok()?;
+        stream.flush().await.ok()?;
+        parse_bind_response(&read_message(stream).await?, message_id)
+    }
+
+    /// The result code of binding as `dn`, `None` when the directory couldn't be asked
+    async fn bind(&self, dn: &str, password: &str) -> Option<u8> {
+        let pooled = self.pool.lock().ok().and_then(|mut p| p.pop());
+
+        /* A pooled connection may have been closed by the directory while idle */
+        for stream in [pooled, None] {
+            let mut stream = match stream {
+                Some(s) => s,
+                None => match self.connect().await {
+                    Some(s) => s,
+                    None => {
+                        warn!(
+                            "couldn't connect to LDAP server {}:{}",
+                            self.server.host, self.server.port
+                        );
+                        return None;
+                    }
+                },
+            };
+
+            let message_id = self.message_id.fetch_add(1, Ordering::Relaxed) % 0x7fff_ffff + 1;
+            let request = bind_request(message_id, dn, password);
+            if let Some(code) = Self::exchange(&mut stream, &request, message_id).await {
+                if let Ok(mut pool) = self.pool.lock() {
+                    if pool.len() < POOL_SIZE {
+                        pool.push(stream);
+                    }
+                }
+                return Some(code);
+            }
+        }
+
+        None
+    }
+}
+
+impl AuthBackend for Ldap {
+    fn name(&self) -> String {
+        format!(
+            "LDAP server {}:{}{}",
+            self.server.host,
+            self.server.port,
+            match self.server.tls {
+                true => " over TLS",
+                false => "",
+            }
+        )
+    }
+
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> Checked<'a> {
+        Box::pin(async move {
+            /* A bind without a password is anonymous and succeeds whoever is named */
+            if user.is_empty() || password.is_empty() {
+                return false;
+            }
+
+            let digest = login_digest(user, password);
+            if self.cached(user, &digest) {
+                return true;
+            }
+
+            let dn = self.template.replace("{user}", &escape_dn_value(user));
+            match timeout(LDAP_TIMEOUT, self.bind(&dn, password)).await {
+                Ok(Some(RESULT_SUCCESS)) => {
+                    self.remember(user, digest);
+                    true
+                }
+                Ok(Some(code)) => {
+                    debug!("LDAP bind as '{dn}' failed with result {code}");
+                    self.forget(user);
+                    false
+                }
+                Ok(None) => false,
+                Err(_) => {
+                    warn!("LDAP server took too long to answer");
+                    false
+                }
+            }
+        })
+    }
+}
+
+/// The LDAP backend configured by `X_PROXY_AUTH_LDAP_URL`, if there is one
+pub(crate) fn ldap_backend(
+    #[cfg(feature = "https")] certificates: &Arc<CertificateSetup>,
+) -> Result<Option<Ldap>, String> {
+    let url = match setting(X_PROXY_AUTH_LDAP_URL) {
+        Err(_) => return Ok(None),
+        Ok(u) => u,
+    };
+
+    let server = parse_url(&url).ok_or(format!("'{X_PROXY_AUTH_LDAP_URL}' is not valid"))?;
+    if server.tls && !cfg!(feature = "https") {
+        return Err(format!(
+            "'{X_PROXY_AUTH_LDAP_URL}' uses ldaps:// which needs the https feature"
+        ));
+    }
+
+    let template = setting(X_PROXY_AUTH_LDAP_BIND_DN)
+        .ok()
+        .filter(|t| t.contains("{user}"))
+        .ok_or(format!(
+            "'{X_PROXY_AUTH_LDAP_BIND_DN}' must be set and contain {{user}}"
+        ))?;
+
+    let cache_for = match setting(X_PROXY_AUTH_LDAP_CACHE) {
+        Err(_) => DEFAULT_CACHE_TIME,
+        Ok(c) => parse_duration(&c).ok_or(format!("'{X_PROXY_AUTH_LDAP_CACHE}' is not valid"))?,
+    };
+
+    Ok(Some(Ldap {
+        server,
+        template,
+        cache_for,
+        cache: Mutex::new(HashMap::new()),
+        pool: Mutex::new(Vec::new()),
+        message_id: AtomicU32::new(0),
+        #[cfg(feature = "https")]
+        certificates: Some(Arc::clone(certificates)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::sync::{atomic::AtomicUsize, Arc},
+        tokio::net::TcpListener,
+    };
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("ldap://dc1.corp.example"),
+            Some(Server {
+                host: "dc1.corp.example".to_string(),
+                port: 389,
+                tls: false
+            })
+        );
+        assert_eq!(
+            parse_url("ldaps://[fd00::1]:3269/"),
+            Some(Server {
+                host: "fd00::1".to_string(),
+                port: 3269,
+                tls: true
+            })
+        );
+        assert!(parse_url("http://dc1.corp.example").is_none());
+        assert!(parse_url("ldap://").is_none());
+        assert!(parse_url("ldap://dc1:port").is_none());
+    }
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("alice"), "alice");
+        assert_eq!(escape_dn_value("x,ou=admins"), "x\\,ou\\=admins");
+        assert_eq!(escape_dn_value("#a b "), "\\#a b\\ ");
+    }
+
+    #[test]
+    fn test_bind_request() {
+        assert_eq!(
+            bind_request(1, "cn=a", "pw"),
+            [
+                0x30, 0x12, 0x02, 0x01, 0x01, 0x60, 0x0d, 0x02, 0x01, 0x03, 0x04, 0x04, b'c', b'n',
+                b'=', b'a', 0x80, 0x02, b'p', b'w'
+            ]
+        );
+        assert_eq!(ber_integer(128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(ber(0x04, &[0; 200])[..3], [0x04, 0x81, 200]);
+
+        let element = ber(0x04, &[7; 300]);
+        let (tag, contents, rest) = split_element(&element).unwrap();
+        assert_eq!((tag, contents.len(), rest.len()), (0x04, 300, 0));
+    }
+
+    #[test]
+    fn test_parse_bind_response() {
+        /* Success then invalidCredentials, as sent by OpenLDAP */
+        let success = [
+            0x30, 0x0c, 0x02, 0x01, 0x05, 0x61, 0x07, 0x0a, 0x01, 0x00, 0x04, 0x00, 0x04, 0x00,
+        ];
+        assert_eq!(parse_bind_response(&success, 5), Some(0));
+        assert_eq!(parse_bind_response(&success, 6), None);
+
+        let invalid = [
+            0x30, 0x84, 0x00, 0x00, 0x00, 0x10, 0x02, 0x01, 0x05, 0x61, 0x84, 0x00, 0x00, 0x00,
+            0x07, 0x0a, 0x01, 0x31, 0x04, 0x00, 0x04, 0x00,
+        ];
+        assert_eq!(parse_bind_response(&invalid, 5), Some(49));
+        assert_eq!(parse_bind_response(&invalid[..10], 5), None);
+    }
+
+    /* Accepts the password "secret" for anyone, counting connections and binds */
+    async fn directory(connections: Arc<AtomicUsize>, binds: Arc<AtomicUsize>) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                connections.fetch_add(1, Ordering::Relaxed);
+                let binds = Arc::clone(&binds);
+                tokio::spawn(async move {
+                    while let Some(request) = read_message(&mut stream).await {
+                        binds.fetch_add(1, Ordering::Relaxed);
+                        let (_, message, _) = split_element(&request).unwrap();
+                        let (_, id, _) = split_element(message).unwrap();
+                        let code = match request.ends_with(b"\x80\x06secret") {
+                            true => 0,
+                            false => 49,
+                        };
+
+                        let mut response = ber(0x02, id);
+                        response.extend(ber(0x61, &[0x0a, 0x01, code, 0x04, 0x00, 0x04, 0x00]));
+                        if stream.write_all(&ber(0x30, &response)).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        Server {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ldap_bind() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let binds = Arc::new(AtomicUsize::new(0));
+        let ldap = Ldap {
+            server: directory(Arc::clone(&connections), Arc::clone(&binds)).await,
+            template: "uid={user},ou=people,dc=example".to_string(),
+            cache_for: DEFAULT_CACHE_TIME,
+            cache: Mutex::new(HashMap::new()),
+            pool: Mutex::new(Vec::new()),
+            message_id: AtomicU32::new(0),
+            #[cfg(feature = "https")]
+            certificates: None,
+        };
+
+        assert!(!ldap.check("alice", "wrong").await);
+        assert!(ldap.check("alice", "secret").await);
+        assert!(!ldap.check("alice", "").await);
+        assert_eq!(binds.load(Ordering::Relaxed), 2);
+
+        /* Remembered, so the directory isn't asked again */
+        assert!(ldap.check("alice", "secret").await);
+        assert_eq!(binds.load(Ordering::Relaxed), 2);
+
+        assert!(ldap.check("bob", "secret").await);
+        assert_eq!(binds.load(Ordering::Relaxed), 3);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,97 @@
+use {std::io, tokio::net::TcpListener, tracing::info};
+
+/// Where clients connect when `X_PROXY_HTTP_LISTEN_ADDRESS` isn't defined
+const DEFAULT_LISTEN_ADDRESS: &str = "[::]:3142";
+
+/// The first descriptor a service manager such as systemd passes (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The addresses clients connect to, a comma separated list of addresses and ports
+/// such as `127.0.0.1:3142,[::1]:3142` so rproxy can listen on several at once
+pub(crate) fn listen_addresses(value: Option<&str>) -> Vec<String> {
+    let addresses: Vec<String> = value
+        .unwrap_or_default()
+        .split(',')
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect();
+
+    match addresses.is_empty() {
+        true => vec![DEFAULT_LISTEN_ADDRESS.to_string()],
+        false => addresses,
+    }
+}
+
+/* How many sockets `LISTEN_FDS` says were passed, none unless `LISTEN_PID` names this process */
+#[cfg(unix)]
+fn passed_sockets(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> i32 {
+    match listen_pid.and_then(|p| p.trim().parse::<u32>().ok()) {
+        Some(p) if p == pid => listen_fds
+            .and_then(|n| n.trim().parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// Listeners bound by systemd and passed to rproxy when it was socket activated, none when it wasn't.
+/// The service manager keeps them open while rproxy restarts so no connection is turned away.
+#[cfg(unix)]
+pub(crate) fn activated_listeners() -> io::Result<Vec<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    let count = passed_sockets(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    /* Download hooks and other children shouldn't think they were activated too */
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let passed = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        /* The copy is closed when a child is started, the passed descriptor isn't */
+        let listener = passed.try_clone()?;
+        drop(passed);
+
+        let address = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        info!("listening on {address} passed by the service manager");
+        listeners.push(TcpListener::from_std(listener)?);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn activated_listeners() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addresses() {
+        assert_eq!(listen_addresses(None), vec![DEFAULT_LISTEN_ADDRESS]);
+        assert_eq!(listen_addresses(Some(" ")), vec![DEFAULT_LISTEN_ADDRESS]);
+        assert_eq!(
+            listen_addresses(Some("127.0.0.1:3142, 192.168.1.2:3142,[::1]:3142,")),
+            vec!["127.0.0.1:3142", "192.168.1.2:3142", "[::1]:3142"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_passed_sockets() {
+        assert_eq!(passed_sockets(Some("42"), Some("2"), 42), 2);
+        assert_eq!(passed_sockets(Some("41"), Some("2"), 42), 0);
+        assert_eq!(passed_sockets(None, Some("2"), 42), 0);
+        assert_eq!(passed_sockets(Some("42"), None, 42), 0);
+        assert_eq!(passed_sockets(Some("42"), Some("-1"), 42), 0);
+    }
+}
@@ -0,0 +1,506 @@
+use {
+    crate::{cli::setting, clock::civil, debug::X_PROXY_DEBUG, PKG_NAME},
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+        fmt::{self, Write},
+        io::Write as _,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Mutex, RwLock,
+        },
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tracing::{
+        field::{Field, Visit},
+        level_filters::LevelFilter,
+        span::{Attributes, Id, Record},
+        subscriber::{Interest, Subscriber},
+        Event, Level, Metadata,
+    },
+};
+
+pub const X_PROXY_LOG_LEVEL: &str = "X_PROXY_LOG_LEVEL";
+pub const X_PROXY_LOG_FORMAT: &str = "X_PROXY_LOG_FORMAT";
+
+/// How verbose each part of rproxy is, such as `info,fetch=debug`.
+/// Other crates are quiet unless they're named.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    const fn new(default: LevelFilter) -> Self {
+        Filter {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    /* The most specific target named decides, names of rproxy's own modules can leave out `rproxy::` */
+    fn allows(&self, target: &str, level: &Level) -> bool {
+        let within = |target: &str, name: &str| {
+            target
+                .strip_prefix(name)
+                .is_some_and(|r| r.is_empty() || r.starts_with("::"))
+        };
+        let own = target
+            .strip_prefix(PKG_NAME)
+            .and_then(|t| t.strip_prefix("::"));
+        let named = self
+            .targets
+            .iter()
+            .filter(|(name, _)| within(target, name) || own.is_some_and(|t| within(t, name)))
+            .max_by_key(|(name, _)| name.len());
+        match named {
+            Some((_, filter)) => level <= filter,
+            None if within(target, PKG_NAME) => level <= &self.default,
+            None => false,
+        }
+    }
+}
+
+/// Read a filter, a level on its own is for all of rproxy and `name=level` for one part of it
+/// or another crate
+pub(crate) fn parse_filter(value: &str) -> Result<Filter, String> {
+    let level = |l: &str| {
+        l.trim()
+            .to_lowercase()
+            .parse::<LevelFilter>()
+            .map_err(|_| format!("'{}' isn't a log level", l.trim()))
+    };
+
+    let mut filter = Filter::new(LevelFilter::INFO);
+    for directive in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match directive.split_once('=') {
+            None => filter.default = level(directive)?,
+            Some((target, l)) => filter.targets.push((target.trim().to_string(), level(l)?)),
+        }
+    }
+    Ok(filter)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    /// Lines as rproxy has always written them
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+pub(crate) fn parse_format(value: &str) -> Result<Format, String> {
+    match value.trim().to_lowercase().as_str() {
+        "pretty" | "" => Ok(Format::Pretty),
+        "json" => Ok(Format::Json),
+        v => Err(format!(
+            "'{X_PROXY_LOG_FORMAT}' is 'pretty' or 'json', not '{v}'"
+        )),
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter::new(LevelFilter::INFO));
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/* `X_PROXY_DEBUG` still works when there's no `X_PROXY_LOG_LEVEL`,
+ * debug builds print debug messages unless told not to */
+fn configured() -> Result<(Filter, Format), String> {
+    let filter = match setting(X_PROXY_LOG_LEVEL) {
+        Ok(v) => parse_filter(&v).map_err(|e| format!("'{X_PROXY_LOG_LEVEL}' {e}"))?,
+        Err(_) => Filter::new(match setting(X_PROXY_DEBUG) {
+            Ok(v) if matches!(v.trim(), "" | "0" | "false" | "off") => LevelFilter::INFO,
+            Ok(_) => LevelFilter::DEBUG,
+            Err(_) if cfg!(debug_assertions) => LevelFilter::DEBUG,
+            Err(_) => LevelFilter::INFO,
+        }),
+    };
+    let format = parse_format(&setting(X_PROXY_LOG_FORMAT).unwrap_or_default())?;
+    Ok((filter, format))
+}
+
+fn apply(filter: Filter, format: Format) {
+    if let Ok(mut f) = FILTER.write() {
+        *f = filter;
+    }
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+/// Start writing messages to stderr, before anything else has something to say
+pub(crate) fn start_logging() {
+    let _ = tracing::subscriber::set_global_default(Logger::default());
+}
+
+/// Read `X_PROXY_LOG_LEVEL` and `X_PROXY_LOG_FORMAT`, false when either isn't understood
+pub(crate) fn setup_logging() -> bool {
+    match configured() {
+        Ok((filter, format)) => {
+            apply(filter, format);
+            true
+        }
+        Err(e) => {
+            tracing::error!("{e}");
+            false
+        }
+    }
+}
+
+/// Take up the log level and format again, such as after the configuration file was reloaded
+pub(crate) fn reload_logging() {
+    match configured() {
+        Ok((filter, format)) => apply(filter, format),
+        Err(e) => tracing::warn!("kept the log level and format it had, {e}"),
+    }
+}
+
+/// Whether messages at `level` from `target` are written
+pub(crate) fn log_enabled(target: &str, level: &Level) -> bool {
+    FILTER.read().is_ok_and(|f| f.allows(target, level))
+}
+
+/// Fields recorded on a span or event, the message kept apart from the rest
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.fields.push((name, value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            name => self.fields.push((name, format!("{value:?}"))),
+        }
+    }
+}
+
+struct SpanData {
+    parent: Option<Id>,
+    fields: Vec<(&'static str, String)>,
+    references: usize,
+}
+
+thread_local! {
+    /* The spans this thread is inside, innermost last */
+    static ENTERED: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Writes every message that passes the filter to stderr, along with the fields of the spans
+/// it happened inside, such as the client of a connection
+#[derive(Default)]
+struct Logger {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+impl Logger {
+    fn current(&self) -> Option<Id> {
+        ENTERED.with(|e| e.borrow().last().cloned())
+    }
+
+    /* Fields of `span` and every span it's inside, outermost first */
+    fn span_fields(&self, span: Option<Id>) -> Vec<(&'static str, String)> {
+        let spans = match self.spans.lock() {
+            Ok(s) => s,
+            Err(_) => return Vec::new(),
+        };
+        let mut chain = Vec::new();
+        let mut next = span;
+        while let Some(data) = next.and_then(|id| spans.get(&id.into_u64())) {
+            chain.push(data.fields.clone());
+            next = data.parent.clone();
+        }
+        chain.into_iter().rev().flatten().collect()
+    }
+}
+
+impl Subscriber for Logger {
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        /* The filter can change when the configuration is reloaded */
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() || log_enabled(metadata.target(), metadata.level())
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let parent = match (attributes.parent(), attributes.is_contextual()) {
+            (Some(p), _) => Some(p.clone()),
+            (None, true) => self.current(),
+            (None, false) => None,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Ok(mut spans) = self.spans.lock() {
+            /* A span keeps the one it's inside open */
+            if let Some(p) = parent.as_ref().and_then(|p| spans.get_mut(&p.into_u64())) {
+                p.references += 1;
+            }
+            spans.insert(
+                id,
+                SpanData {
+                    parent,
+                    fields: fields.fields,
+                    references: 1,
+                },
+            );
+        }
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(data) = self
+            .spans
+            .lock()
+            .ok()
+            .as_mut()
+            .and_then(|s| s.get_mut(&span.into_u64()))
+        {
+            data.fields.extend(fields.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let span = match (event.parent(), event.is_contextual()) {
+            (Some(p), _) => Some(p.clone()),
+            (None, true) => self.current(),
+            (None, false) => None,
+        };
+        let mut all = self.span_fields(span);
+        all.extend(fields.fields);
+
+        let metadata = event.metadata();
+        let line = Line {
+            level: *metadata.level(),
+            target: metadata.target(),
+            location: (metadata.file(), metadata.line()),
+            message: &fields.message,
+            fields: &all,
+        };
+        let text = match JSON.load(Ordering::Relaxed) {
+            true => line.json(SystemTime::now()),
+            false => line.pretty(),
+        };
+        let _ = writeln!(std::io::stderr().lock(), "{text}");
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|e| e.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|e| {
+            let mut entered = e.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|s| s == span) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self
+            .spans
+            .lock()
+            .ok()
+            .as_mut()
+            .and_then(|s| s.get_mut(&span.into_u64()))
+        {
+            data.references += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let parent = {
+            let mut spans = match self.spans.lock() {
+                Ok(s) => s,
+                Err(_) => return false,
+            };
+            match spans.get_mut(&span.into_u64()) {
+                Some(data) if data.references > 1 => {
+                    data.references -= 1;
+                    return false;
+                }
+                Some(_) => spans.remove(&span.into_u64()).and_then(|d| d.parent),
+                None => return false,
+            }
+        };
+        if let Some(p) = parent {
+            self.try_close(p);
+        }
+        true
+    }
+}
+
+/// One message ready to be written
+struct Line<'a> {
+    level: Level,
+    target: &'a str,
+    /// Source file and line, written with debug messages
+    location: (Option<&'a str>, Option<u32>),
+    message: &'a str,
+    fields: &'a [(&'static str, String)],
+}
+
+impl Line<'_> {
+    /* Errors, messages and debug messages look as they did before rproxy had levels,
+     * with any fields after them */
+    fn pretty(&self) -> String {
+        let mut text = match self.level {
+            Level::ERROR => format!("Error: {}", self.message),
+            Level::WARN | Level::INFO => format!("{PKG_NAME} {}", self.message),
+            _ => format!(
+                "{}:{}\n{}",
+                self.location.0.unwrap_or_default(),
+                self.location.1.unwrap_or_default(),
+                self.message
+            ),
+        };
+        for (name, value) in self.fields {
+            match value.is_empty() || value.contains([' ', '"', '=']) {
+                true => write!(text, " {name}={value:?}"),
+                false => write!(text, " {name}={value}"),
+            }
+            .unwrap_or_default();
+        }
+        if self.level > Level::INFO {
+            text.push('\n');
+        }
+        text
+    }
+
+    fn json(&self, time: SystemTime) -> String {
+        let mut text = format!(
+            "{{\"time\":{},\"level\":{},\"target\":{},\"message\":{}",
+            json_string(&timestamp(time)),
+            json_string(&self.level.as_str().to_lowercase()),
+            json_string(self.target),
+            json_string(self.message)
+        );
+        for (name, value) in self.fields {
+            write!(text, ",{}:{}", json_string(name), json_string(value)).unwrap_or_default();
+        }
+        text.push('}');
+        text
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap_or_default(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/* UTC to the millisecond, such as `2024-05-01T12:30:00.250Z` */
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day) = civil(time);
+    let seconds = since.as_secs() % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::Duration};
+
+    #[test]
+    fn test_parse_filter() {
+        let filter = parse_filter("warn, fetch=debug,quinn=info").unwrap();
+        assert_eq!(filter.default, LevelFilter::WARN);
+        assert_eq!(
+            filter.targets,
+            vec![
+                ("fetch".to_string(), LevelFilter::DEBUG),
+                ("quinn".to_string(), LevelFilter::INFO),
+            ]
+        );
+        assert_eq!(parse_filter("").unwrap(), Filter::new(LevelFilter::INFO));
+        assert!(parse_filter("loud").is_err());
+        assert!(parse_filter("fetch=loud").is_err());
+    }
+
+    #[test]
+    fn test_allows() {
+        let filter = parse_filter("warn,fetch=debug,fetch::inner=off").unwrap();
+        assert!(filter.allows("rproxy::serve", &Level::WARN));
+        assert!(!filter.allows("rproxy::serve", &Level::INFO));
+        assert!(filter.allows("rproxy::fetch", &Level::DEBUG));
+        assert!(!filter.allows("rproxy::fetch::inner", &Level::ERROR));
+        assert!(!filter.allows("rproxy::fetcher", &Level::INFO));
+        assert!(filter.allows("rproxy", &Level::ERROR));
+        assert!(!filter.allows("quinn", &Level::ERROR));
+        assert!(parse_filter("quinn_proto=warn")
+            .unwrap()
+            .allows("quinn_proto::connection", &Level::WARN));
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!(parse_format("JSON"), Ok(Format::Json));
+        assert_eq!(parse_format(""), Ok(Format::Pretty));
+        assert!(parse_format("xml").is_err());
+    }
+
+    #[test]
+    fn test_line() {
+        let fields = vec![
+            ("client", "127.0.0.1:50000".to_string()),
+            ("uri", "http://a b".to_string()),
+        ];
+        let line = Line {
+            level: Level::INFO,
+            target: "rproxy::log",
+            location: (Some("src/log.rs"), Some(1)),
+            message: "Fetching \"x\"",
+            fields: &fields,
+        };
+        assert_eq!(
+            line.pretty(),
+            "rproxy Fetching \"x\" client=127.0.0.1:50000 uri=\"http://a b\""
+        );
+        assert_eq!(
+            line.json(UNIX_EPOCH + Duration::from_millis(1_714_566_600_250)),
+            "{\"time\":\"2024-05-01T12:30:00.250Z\",\"level\":\"info\",\"target\":\"rproxy::log\",\
+             \"message\":\"Fetching \\\"x\\\"\",\"client\":\"127.0.0.1:50000\",\"uri\":\"http://a b\"}"
+        );
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+}
@@ -1,142 +1,641 @@
+mod about;
+mod accounting;
+mod alias;
+mod auth;
+mod cache;
+mod cancel;
+mod capture;
 #[cfg(feature = "https")]
 mod cert;
+mod cli;
+mod clock;
+#[cfg(feature = "https")]
+mod coalesce;
+#[cfg(feature = "compress")]
+mod compress;
 mod conn;
+mod cookie;
+mod credentials;
 mod debug;
+mod dedup;
+mod digest;
+mod discovery;
+mod dns;
+mod egress;
+mod events;
+mod evict;
 mod fetch;
+mod forwarded;
+#[cfg(feature = "geoip")]
+mod geo;
+mod head;
 mod http;
+mod idn;
+mod journal;
+mod layout;
+#[cfg(feature = "ldap")]
+mod ldap;
+mod listen;
+mod log;
+mod maintenance;
+mod metadata;
+mod policy;
+mod privdrop;
+#[cfg(feature = "web-ui")]
+mod progress;
+#[cfg(feature = "http3")]
+mod quic;
+mod quirks;
+mod redirect;
+mod relay;
+mod reload;
+mod retry;
+mod revalidate;
+mod reverse;
+mod rules;
+mod schedule;
+mod selftest;
 mod serve;
+mod shutdown;
+mod slow;
+mod sni;
+mod sniff;
+mod storage;
+mod token;
+mod trace;
+mod transparent;
+mod tunnel;
+#[cfg(feature = "web-ui")]
+mod ui;
+mod watchdog;
+mod zerocopy;
 
 #[cfg(feature = "https")]
 use {
     crate::{
-        cert::{setup_certificates, CertificateSetup},
+        accounting::Metered,
+        cert::{
+            certificate_reload_loop, intercepts, setup_certificates, tls_record_size,
+            CertificateSetup,
+        },
+        coalesce::Coalesce,
         conn::{Uri, UriKind::*},
-        http::{respond_with, ConnectionReturn, ConnectionReturn::Upgrade, HttpResponseStatus},
+        http::{ConnectionReturn, ConnectionReturn::Upgrade},
+        token::{over_quota, record_token_usage, request_token},
     },
-    tokio::net::TcpStream,
+    tokio::{io::AsyncWriteExt, net::TcpStream},
+    tokio_rustls::{rustls::server::Acceptor, LazyConfigAcceptor},
+    tracing::{debug, warn},
 };
 
 use {
     crate::{
-        conn::Flights,
-        http::{ConnectionReturn::Keep, X_PROXY_CACHE_PATH},
+        about::build_report,
+        accounting::setup_accounting,
+        auth::{authorized, respond_auth_required, setup_auth},
+        cache::cache_command,
+        cancel::{Cancellable, Cancellation},
+        capture::setup_body_log,
+        cli::{apply_arguments, parse_arguments, print_usage, setting, Command},
+        conn::{Client, Flights, UriKind},
+        credentials::setup_upstream_credentials,
+        dedup::{dedup_loop, deduplicating, setup_dedup},
+        digest::setup_download_hooks,
+        discovery::{discovery_announcement, discovery_loop},
+        egress::setup_egress,
+        evict::{
+            eviction_loop, parse_size, EvictionPolicy, X_PROXY_CACHE_MAX_SIZE, X_PROXY_CACHE_POLICY,
+        },
+        forwarded::setup_forwarded,
+        http::{
+            keep_alive_if, respond_with,
+            ConnectionReturn::{Close, Keep},
+            HttpResponseStatus, X_PROXY_CACHE_PATH,
+        },
+        journal::setup_journal,
+        layout::{migrate_command, setup_layout},
+        listen::{activated_listeners, listen_addresses},
+        log::{setup_logging, start_logging},
+        privdrop::{drop_privileges, setup_privileges},
+        redirect::setup_redirects,
+        reload::reload_loop,
+        revalidate::{revalidate_schedule, revalidation_loop},
+        reverse::{reverse_proxy, setup_reverse, ReverseProxy},
+        selftest::selftest_command,
         serve::{read_http_request, serve_http_request},
+        shutdown::{drain, draining, stopped, termination, Serving},
+        slow::SlowGuard,
+        storage::{setup_storage, storage_loop},
+        trace::setup_trace,
+        transparent::{
+            bind_transparent, original_destination, setup_transparent, transparent_listen_address,
+            transparent_target,
+        },
+        tunnel::setup_tunnel_rules,
+        watchdog::{refuse_connection, shedding, watchdog_ceilings, watchdog_loop},
     },
     std::{path::PathBuf, sync::Arc},
-    tokio::{fs::create_dir_all, net::TcpListener, sync::Semaphore},
+    tokio::{fs::create_dir_all, io::BufReader, net::TcpListener, sync::Semaphore},
+    tracing::{error, info, info_span, Instrument},
 };
 
 pub(crate) const PKG_NAME: &str = env!("CARGO_PKG_NAME");
-const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub(crate) const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const X_PROXY_HTTP_LISTEN_ADDRESS: &str = "X_PROXY_HTTP_LISTEN_ADDRESS";
 const X_PROXY_MAX_CONNECTIONS: &str = "X_PROXY_MAX_CONNECTIONS";
 
+pub(crate) const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+fn main() {
+    start_logging();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let arguments = match parse_arguments(&args) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("{e}, see '{PKG_NAME} --help'");
+            std::process::exit(1);
+        }
+    };
+    if !apply_arguments(&arguments) || !setup_logging() {
+        std::process::exit(1);
+    }
+    info!("version: {PKG_VERSION}");
+    match &arguments.command {
+        Command::Help | Command::Version => {
+            print_usage(&arguments.command);
+            return;
+        }
+        Command::Features => {
+            print!("{}", build_report());
+            return;
+        }
+        Command::Serve if !setup_privileges() => std::process::exit(1),
+        _ => {}
+    }
+
+    run(arguments.command);
+}
+
+/* Everything that starts a thread happens here, after any sandbox is in place */
 #[tokio::main]
-async fn main() {
-    eprintln!("{PKG_NAME} version: {PKG_VERSION}");
-    match std::env::var(X_PROXY_CACHE_PATH) {
+async fn run(command: Command) {
+    match command {
+        Command::Cache(args) => std::process::exit(cache_command(&args).await),
+        Command::Migrate(args) => std::process::exit(migrate_command(&args).await),
+        Command::Selftest(args) => std::process::exit(selftest_command(&args).await),
+        _ => {}
+    }
+
+    match setting(X_PROXY_CACHE_PATH) {
         Ok(s) => {
             let path = PathBuf::from(&s);
             if !path.exists() {
                 if let Err(e) = create_dir_all(&path).await {
-                    eprintln!("Error: couldn't create directory '{s}': {e}");
+                    error!("couldn't create directory '{s}': {e}");
                     return;
                 }
             }
-            eprintln!("{PKG_NAME} cache path: {s}");
+            info!("cache path: {s}");
+            if !setup_layout(&path).await {
+                return;
+            }
+            if !setup_storage() {
+                return;
+            }
+            if !setup_dedup() {
+                return;
+            }
+            if deduplicating() {
+                tokio::spawn(dedup_loop(path.clone()));
+            }
+            tokio::spawn(storage_loop(path));
         }
         Err(_) => {
-            eprintln!("Error: '{X_PROXY_CACHE_PATH}' has not been set");
+            error!("'{X_PROXY_CACHE_PATH}' has not been set");
             return;
         }
     };
 
     #[cfg(feature = "https")]
     let certificates = Arc::new(setup_certificates());
+    #[cfg(feature = "https")]
+    tokio::spawn(certificate_reload_loop(Arc::clone(&certificates)));
+
+    if !setup_auth(
+        #[cfg(all(feature = "ldap", feature = "https"))]
+        &certificates,
+    ) {
+        return;
+    }
+
+    tokio::spawn(reload_loop(
+        #[cfg(feature = "https")]
+        Arc::clone(&certificates),
+    ));
+
+    setup_tunnel_rules();
+
+    if !setup_egress() {
+        return;
+    }
+
+    if !setup_trace() {
+        return;
+    }
+
+    if !setup_body_log() {
+        return;
+    }
+
+    if !setup_forwarded() {
+        return;
+    }
+
+    if !setup_redirects() {
+        return;
+    }
+
+    if !setup_upstream_credentials() {
+        return;
+    }
+
+    if !setup_reverse() {
+        return;
+    }
+
+    if !setup_transparent() {
+        return;
+    }
+
+    #[cfg(feature = "compress")]
+    if !compress::setup_compress() {
+        return;
+    }
+
+    #[cfg(feature = "geoip")]
+    if !geo::setup_geoip() {
+        return;
+    }
 
     let flight_plan = Arc::new(Flights::new());
 
-    let http_bind = std::env::var(X_PROXY_HTTP_LISTEN_ADDRESS).unwrap_or("[::]:3142".to_string());
+    setup_download_hooks();
 
-    let http_listener = match TcpListener::bind(&http_bind).await {
-        Ok(l) => {
-            let details = l.local_addr().unwrap();
-            let address = match details.ip().is_unspecified() {
-                true => "Any".to_string(),
-                false => details.ip().to_string(),
-            };
+    setup_journal(
+        &flight_plan,
+        #[cfg(feature = "https")]
+        &certificates,
+    )
+    .await;
+
+    setup_accounting().await;
+
+    if let Ok(s) = setting(X_PROXY_CACHE_MAX_SIZE) {
+        match parse_size(&s) {
+            Some(max_size) => {
+                let policy = match setting(X_PROXY_CACHE_POLICY) {
+                    Err(_) => EvictionPolicy::default(),
+                    Ok(p) => match EvictionPolicy::from_name(&p) {
+                        Some(p) => p,
+                        None => {
+                            error!("'{X_PROXY_CACHE_POLICY}' must be 'lru' or 'lfu': '{p}'");
+                            return;
+                        }
+                    },
+                };
+
+                info!(
+                    "cache max size: {max_size} bytes ({} eviction)",
+                    policy.name()
+                );
+                tokio::spawn(eviction_loop(Arc::clone(&flight_plan), max_size, policy));
+            }
+            None => {
+                error!("'{X_PROXY_CACHE_MAX_SIZE}' is not a valid size: '{s}'");
+                return;
+            }
+        }
+    }
+
+    if let Some((interval, count)) = revalidate_schedule() {
+        info!(
+            "revalidating the {count} most popular files every {} seconds",
+            interval.as_secs()
+        );
+        tokio::spawn(revalidation_loop(
+            Arc::clone(&flight_plan),
+            interval,
+            count,
             #[cfg(feature = "https")]
-            {
-                eprintln!("{PKG_NAME} HTTP(S) listen address: {}", address);
-                eprintln!("{PKG_NAME} HTTP(S) listen port: {}", details.port());
+            Arc::clone(&certificates),
+        ));
+    }
+
+    match watchdog_ceilings() {
+        Ok(ceilings) if ceilings.open_files.is_some() || ceilings.memory.is_some() => {
+            if let Some(n) = ceilings.open_files {
+                info!("shedding load at {n} open files");
             }
-            #[cfg(not(feature = "https"))]
-            {
-                eprintln!("{PKG_NAME} HTTP listen address: {}", address);
-                eprintln!("{PKG_NAME} HTTP listen port: {}", details.port());
+            if let Some(n) = ceilings.memory {
+                info!("shedding load at {n} bytes of memory");
             }
-            l
+            tokio::spawn(watchdog_loop(ceilings));
+        }
+        Ok(_) => {}
+        Err(variable) => {
+            error!("'{variable}' is not a valid limit");
+            return;
         }
+    }
+
+    let mut http_listeners = match activated_listeners() {
+        Ok(l) => l,
         Err(e) => {
-            eprintln!("Error: unable to bind '{http_bind}': {e}");
+            error!("unable to use the sockets passed by the service manager: {e}");
             return;
         }
     };
-    drop(http_bind);
+    /* Sockets passed by the service manager take the place of the listen addresses */
+    if http_listeners.is_empty() {
+        let listen = setting(X_PROXY_HTTP_LISTEN_ADDRESS).ok();
+        for http_bind in listen_addresses(listen.as_deref()) {
+            match TcpListener::bind(&http_bind).await {
+                Ok(l) => {
+                    let details = l.local_addr().unwrap();
+                    let address = match details.ip().is_unspecified() {
+                        true => "Any".to_string(),
+                        false => details.ip().to_string(),
+                    };
+                    #[cfg(feature = "https")]
+                    {
+                        info!("HTTP(S) listen address: {}", address);
+                        info!("HTTP(S) listen port: {}", details.port());
+                    }
+                    #[cfg(not(feature = "https"))]
+                    {
+                        info!("HTTP listen address: {}", address);
+                        info!("HTTP listen port: {}", details.port());
+                    }
+                    http_listeners.push(l);
+                }
+                Err(e) => {
+                    error!("unable to bind '{http_bind}': {e}");
+                    return;
+                }
+            };
+        }
+    }
+    /* There's always at least one, the first is served here and the rest alongside it */
+    let http_listener = http_listeners.remove(0);
+
+    if let Some(announcement) = http_listener
+        .local_addr()
+        .ok()
+        .and_then(discovery_announcement)
+    {
+        tokio::spawn(discovery_loop(announcement));
+    }
 
-    let max_connections = std::env::var(X_PROXY_MAX_CONNECTIONS)
+    let max_connections = setting(X_PROXY_MAX_CONNECTIONS)
         .ok()
         .and_then(|s| s.parse().ok())
-        .unwrap_or(16);
+        .unwrap_or(DEFAULT_MAX_CONNECTIONS);
 
     let semaphore = Arc::new(Semaphore::new(max_connections));
+    let shutdown = Cancellation::new();
 
-    loop {
-        listen_for(
-            &http_listener,
+    for listener in http_listeners {
+        spawn_listener(
+            listener,
+            Listening::Proxy,
             &flight_plan,
             &semaphore,
+            &shutdown,
             #[cfg(feature = "https")]
             &certificates,
-        )
-        .await;
+        );
     }
+
+    if let Some(reverse) = reverse_proxy() {
+        let reverse_listener = match TcpListener::bind(&reverse.listen).await {
+            Ok(l) => {
+                if let Ok(details) = l.local_addr() {
+                    info!("reverse proxy listen address: {details}");
+                }
+                l
+            }
+            Err(e) => {
+                error!("unable to bind '{}': {e}", reverse.listen);
+                return;
+            }
+        };
+
+        spawn_listener(
+            reverse_listener,
+            Listening::Reverse(reverse),
+            &flight_plan,
+            &semaphore,
+            &shutdown,
+            #[cfg(feature = "https")]
+            &certificates,
+        );
+    }
+
+    if let Some(transparent) = transparent_listen_address() {
+        let transparent_listener = match bind_transparent(transparent).await {
+            Ok(l) => l,
+            Err(e) => {
+                error!("unable to bind '{transparent}': {e}");
+                return;
+            }
+        };
+
+        spawn_listener(
+            transparent_listener,
+            Listening::Transparent,
+            &flight_plan,
+            &semaphore,
+            &shutdown,
+            #[cfg(feature = "https")]
+            &certificates,
+        );
+    }
+
+    if !drop_privileges() {
+        return;
+    }
+
+    let terminated = termination();
+    tokio::pin!(terminated);
+    loop {
+        tokio::select! {
+            _ = listen_for(
+                &http_listener,
+                Listening::Proxy,
+                &flight_plan,
+                &semaphore,
+                &shutdown,
+                #[cfg(feature = "https")]
+                &certificates,
+            ) => {}
+            _ = &mut terminated => break,
+        }
+    }
+
+    drop(http_listener);
+    drain(&flight_plan, &shutdown).await;
+}
+
+/// Who the requests arriving on a listener are from and what they ask for
+#[derive(Clone, Copy)]
+enum Listening {
+    /// Clients that were told to use rproxy as their proxy, asking for whole URLs
+    Proxy,
+    /// Clients asking for paths on the origin server of a reverse proxy
+    Reverse(&'static ReverseProxy),
+    /// Clients whose connections to origin servers the firewall sent to rproxy,
+    /// asking for paths as they would of the origin server
+    Transparent,
+}
+
+/// Serve the connections of another listener alongside the usual one
+fn spawn_listener(
+    listener: TcpListener,
+    listening: Listening,
+    flights: &Arc<Flights>,
+    semaphore: &Arc<Semaphore>,
+    shutdown: &Cancellation,
+    #[cfg(feature = "https")] certificates: &Arc<CertificateSetup>,
+) {
+    let flights = Arc::clone(flights);
+    let semaphore = Arc::clone(semaphore);
+    let shutdown = shutdown.clone();
+    #[cfg(feature = "https")]
+    let certificates = Arc::clone(certificates);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = listen_for(
+                    &listener,
+                    listening,
+                    &flights,
+                    &semaphore,
+                    &shutdown,
+                    #[cfg(feature = "https")]
+                    &certificates,
+                ) => {}
+                _ = stopped() => break,
+            }
+        }
+    });
 }
 
+/// Accept a connection on `http_listener` and serve its requests
 async fn listen_for(
     http_listener: &TcpListener,
+    listening: Listening,
     flights: &Arc<Flights>,
     semaphore: &Arc<Semaphore>,
+    shutdown: &Cancellation,
     #[cfg(feature = "https")] certificates: &Arc<CertificateSetup>,
 ) {
-    let (mut stream, _) = match http_listener.accept().await {
+    let (stream, address) = match http_listener.accept().await {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Error: Unable to accept new connection: {e}");
+            error!("Unable to accept new connection: {e}");
             return;
         }
     };
 
+    if shedding() {
+        tokio::spawn(refuse_connection(stream));
+        return;
+    }
+
+    let destination = match listening {
+        Listening::Transparent => original_destination(&stream),
+        _ => None,
+    };
+
     let semaphore = Arc::clone(semaphore);
     #[cfg(feature = "https")]
     let certificates = Arc::clone(certificates);
     let flights = Arc::clone(flights);
+    let client = Client {
+        address,
+        cancel: shutdown.child(),
+    };
+    let mut stream = BufReader::new(Cancellable::new(SlowGuard::new(stream), &client.cancel));
 
+    let serving = Serving::new();
     tokio::spawn(async move {
+        let _serving = serving;
         match semaphore.acquire().await {
             Ok(_) => {}
             Err(_) => return,
         };
 
+        let mut served = 0;
         loop {
-            let client_request = match read_http_request(&mut stream).await {
-                None => return,
+            let mut client_request = match read_http_request(&mut stream, &mut served).await {
+                None => break,
                 Some(x) => x,
             };
 
+            /* Clients of reverse and transparent listeners don't know they're using a proxy
+             * so they don't send credentials for it. Requests for rproxy itself don't go anywhere
+             * so they don't need them either. Nor do requests inside a tunnel, its CONNECT was
+             * already authenticated */
+            let target = match listening {
+                Listening::Reverse(reverse) => Some(
+                    reverse
+                        .target(&client_request.request)
+                        .ok_or(HttpResponseStatus::FORBIDDEN),
+                ),
+                Listening::Transparent
+                    if client_request.request.kind() == UriKind::AbsolutePath =>
+                {
+                    Some(transparent_target(&client_request, destination).await)
+                }
+                _ => None,
+            };
+            if let Some(target) = target {
+                /* Credentials that were never asked for aren't checked, so they mean nothing */
+                client_request.headers.remove("Proxy-Authorization");
+                match target {
+                    Ok(t) => client_request.request = t,
+                    Err(status) => {
+                        respond_with(Close, status, &mut stream).await;
+                        break;
+                    }
+                }
+            } else if client_request.request.kind() != UriKind::AbsolutePath
+                && !authorized(&client_request).await
+            {
+                /* A body that was sent anyway would be read as the next request */
+                let has_body = client_request.headers.get("Transfer-Encoding").is_some()
+                    || client_request
+                        .headers
+                        .get("Content-Length")
+                        .is_some_and(|l| l.trim() != "0");
+                let return_type = match has_body {
+                    true => Close,
+                    false => keep_alive_if(&client_request),
+                };
+                match respond_auth_required(return_type, &mut stream).await {
+                    Keep => continue,
+                    _ => break,
+                }
+            }
+
+            #[cfg(feature = "https")]
+            let token = request_token(&client_request);
+
             match serve_http_request(
                 &mut stream,
+                &client,
                 &flights,
                 client_request,
                 #[cfg(feature = "https")]
@@ -145,45 +644,90 @@ async fn listen_for(
             .await
             {
                 #[cfg(feature = "https")]
-                Upgrade(h) => listen_for_https(h, &mut stream, &flights, &certificates).await,
-                Keep => continue,
-                _ => return,
+                Upgrade(h) => {
+                    /* The client waits to hear the tunnel is open so nothing is left buffered */
+                    listen_for_https(
+                        h,
+                        token.as_deref(),
+                        stream.get_mut(),
+                        &client,
+                        &flights,
+                        &certificates,
+                    )
+                    .await
+                }
+                Keep if !draining() => continue,
+                _ => break,
             }
         }
-    });
+
+        /* Stop anything still working on behalf of this connection */
+        client.cancel.cancel();
+    }
+    .instrument(info_span!("connection", client = %address)));
 }
 
 #[cfg(feature = "https")]
+/* Requests inside the tunnel don't carry the token its CONNECT was made with,
+ * so they're charged to that one */
 async fn listen_for_https(
     mut host: String,
-    stream: &mut TcpStream,
+    token: Option<&str>,
+    stream: &mut Cancellable<SlowGuard<TcpStream>>,
+    client: &Client,
     flights: &Arc<Flights>,
     certificates: &Arc<CertificateSetup>,
 ) {
+    host.insert_str(0, "https://");
+    let host = Uri::from(host);
+    if host.kind() != Host {
+        return;
+    }
+
+    let connect_host = host.host.unwrap_or_default();
+    if certificates.server_config_for(connect_host).is_none() {
+        respond_with(Close, HttpResponseStatus::INTERNAL_SERVER_ERROR, stream).await;
+        return;
+    }
+
     if respond_with(Keep, HttpResponseStatus::OK, stream).await == ConnectionReturn::Close {
         return;
     };
 
-    let acceptor = certificates.server_config.clone();
-
-    let mut stream = match acceptor.accept(stream).await {
+    let start = match LazyConfigAcceptor::new(Acceptor::default(), &mut *stream).await {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("{PKG_NAME} couldn't create tls stream: {e}");
+            warn!("couldn't create tls stream: {e}");
             return;
         }
     };
 
-    host.insert_str(0, "https://");
-    debug_print!("Connect request to {} is being established", host);
-
-    let host = Uri::from(host);
-    if host.kind() != Host {
-        return;
+    /* The certificate is for the name the client asks for, clients connecting to an address
+     * don't send one. Any other name has to be intercepted too or the client could have
+     * certificates minted for hosts nobody chose to intercept */
+    let name = match start.client_hello().server_name() {
+        Some(n) if n.eq_ignore_ascii_case(connect_host) || intercepts(n) => n,
+        _ => connect_host,
     }
+    .to_string();
+    let config = match certificates.server_config_for(&name) {
+        Some(c) => c,
+        None => return,
+    };
+
+    let mut stream = match start.into_stream(config).await {
+        Ok(s) => BufReader::new(Metered::new(Coalesce::new(s, tls_record_size()))),
+        Err(e) => {
+            warn!("couldn't create tls stream: {e}");
+            return;
+        }
+    };
 
+    debug!("Connect request to {} is being established", host.uri);
+
+    let mut served = 0;
     loop {
-        let mut client_request = match read_http_request(&mut stream).await {
+        let mut client_request = match read_http_request(&mut stream, &mut served).await {
             None => return,
             Some(x) => x,
         };
@@ -192,8 +736,26 @@ async fn listen_for_https(
             client_request.request = client_request.request.merge_with(&host);
         }
 
-        match serve_http_request(&mut stream, flights, client_request, certificates).await {
-            Keep => continue,
+        /* Closed as the quota won't be back before the client gives up on the connection */
+        if token.is_some_and(over_quota) {
+            respond_with(Close, HttpResponseStatus::TOO_MANY_REQUESTS, &mut stream).await;
+            let _ = stream.flush().await;
+            return;
+        }
+
+        let written = stream.get_ref().written();
+        let r =
+            serve_http_request(&mut stream, client, flights, client_request, certificates).await;
+        if let Some(token) = token {
+            record_token_usage(token, stream.get_ref().written() - written);
+        }
+
+        /* Whatever the response left held back has to go before the next request is waited for */
+        if stream.flush().await.is_err() {
+            return;
+        }
+        match r {
+            Keep if !draining() => continue,
             _ => return,
         }
     }
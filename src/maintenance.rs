@@ -0,0 +1,151 @@
+use {
+    crate::{
+        cli::setting,
+        evict::matches_pattern,
+        http::{ConnectionReturn, HttpResponseHeader, HttpResponseStatus, HttpVersion},
+    },
+    std::sync::{OnceLock, RwLock},
+    tokio::io::AsyncWriteExt,
+};
+
+#[cfg(feature = "web-ui")]
+use tracing::info;
+
+pub const X_PROXY_MAINTENANCE_HOSTS: &str = "X_PROXY_MAINTENANCE_HOSTS";
+
+/// How long clients asking for something that isn't cached are told to wait, in seconds
+const MAINTENANCE_RETRY_AFTER: u64 = 300;
+
+/* Host patterns, starting with those in X_PROXY_MAINTENANCE_HOSTS */
+fn maintenance_table() -> &'static RwLock<Vec<String>> {
+    static HOSTS: OnceLock<RwLock<Vec<String>>> = OnceLock::new();
+    HOSTS.get_or_init(|| {
+        RwLock::new(match setting(X_PROXY_MAINTENANCE_HOSTS) {
+            Err(_) => Vec::new(),
+            Ok(s) => parse_hosts(&s),
+        })
+    })
+}
+
+fn parse_hosts(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Whether `host` is in maintenance, in which case nothing is fetched from it
+/// and whatever is cached from it is served however old it is
+pub(crate) fn in_maintenance(host: &str) -> bool {
+    let host = host.to_lowercase();
+    match maintenance_table().read() {
+        Ok(hosts) => hosts.iter().any(|p| matches_pattern(p, &host)),
+        Err(_) => false,
+    }
+}
+
+#[cfg(feature = "web-ui")]
+/// Every host or host pattern in maintenance
+pub(crate) fn maintenance_hosts() -> Vec<String> {
+    match maintenance_table().read() {
+        Ok(hosts) => hosts.clone(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(feature = "web-ui")]
+pub(crate) fn begin_maintenance(host: &str) {
+    let host = host.trim().to_lowercase();
+    if let Ok(mut hosts) = maintenance_table().write() {
+        if !hosts.contains(&host) {
+            info!("put {host} into maintenance");
+            hosts.push(host);
+        }
+    }
+}
+
+#[cfg(feature = "web-ui")]
+/// Take `host` out of maintenance, false if it wasn't in it
+pub(crate) fn end_maintenance(host: &str) -> bool {
+    let host = host.trim().to_lowercase();
+    match maintenance_table().write() {
+        Ok(mut hosts) => {
+            let before = hosts.len();
+            hosts.retain(|h| *h != host);
+            if hosts.len() < before {
+                info!("took {host} out of maintenance");
+            }
+            hosts.len() < before
+        }
+        Err(_) => false,
+    }
+}
+
+/// Turn away a request that would need a host in maintenance
+pub(crate) async fn respond_in_maintenance<T>(
+    return_type: ConnectionReturn,
+    stream: &mut T,
+) -> ConnectionReturn
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let body = "The origin is down for maintenance and this hasn't been cached\n";
+
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::SERVICE_UNAVAILABLE,
+        headers: Default::default(),
+        version: HttpVersion::HTTP_V11,
+    };
+    header.headers.insert(
+        "Retry-After".to_string(),
+        MAINTENANCE_RETRY_AFTER.to_string(),
+    );
+    header.headers.insert(
+        "Content-Type".to_string(),
+        "text/plain; charset=utf-8".to_string(),
+    );
+    header
+        .headers
+        .insert("Content-Length".to_string(), body.len().to_string());
+    header.close_if(&return_type);
+
+    let response = header.generate() + body;
+    match stream.write_all(response.as_bytes()).await {
+        Ok(_) => return_type,
+        Err(_) => ConnectionReturn::Close,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hosts() {
+        assert_eq!(
+            parse_hosts(" Mirror.example , ,*.lab.example"),
+            vec!["mirror.example", "*.lab.example"]
+        );
+    }
+
+    #[cfg(feature = "web-ui")]
+    #[test]
+    fn test_maintenance() {
+        begin_maintenance("Maintained.example");
+        begin_maintenance("maintained.example");
+        assert!(in_maintenance("MAINTAINED.example"));
+        assert!(!in_maintenance("other.example"));
+        assert_eq!(
+            maintenance_hosts()
+                .iter()
+                .filter(|h| *h == "maintained.example")
+                .count(),
+            1
+        );
+
+        assert!(end_maintenance("maintained.example"));
+        assert!(!end_maintenance("maintained.example"));
+        assert!(!in_maintenance("maintained.example"));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::PKG_NAME;
+
+/* Metadata rproxy keeps beside the cache is stored as lines of text, each one prefixed with
+ * a CRC-32 of the rest of the line and a tab. The first line is a header naming what the
+ * file holds and the version of its format: `<crc>\tRPXM <kind> <version>`.
+ *
+ * A record may gain fields on its end without the version changing so readers ignore
+ * any they don't know. The version is only bumped when an existing field changes meaning,
+ * which older readers refuse instead of guessing at. */
+
+/// Starts the header of every metadata file so it can't be confused with anything else
+const METADATA_MAGIC: &str = "RPXM";
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum MetadataError {
+    /// No header, such as a file written before metadata was versioned, or not metadata at all
+    Unversioned,
+    /// The header was damaged so nothing after it can be trusted
+    Damaged,
+    /// Metadata of another kind
+    Foreign(String),
+    /// Written by a newer version of rproxy in a format this one can't read
+    Newer(u32),
+}
+
+impl std::fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MetadataError::Unversioned => write!(f, "no metadata header"),
+            MetadataError::Damaged => write!(f, "damaged metadata header"),
+            MetadataError::Foreign(kind) => write!(f, "holds {kind} metadata"),
+            MetadataError::Newer(v) => {
+                write!(f, "format {v} needs a newer version of {PKG_NAME}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Metadata<'a> {
+    pub(crate) version: u32,
+    /// Records that passed their checksum, in the order they were written
+    pub(crate) records: Vec<&'a str>,
+    /// Records dropped because they were damaged, such as by a write cut short
+    pub(crate) corrupt: usize,
+}
+
+/// CRC-32 as used by zip and PNG, enough to notice torn writes and flipped bits
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb88320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+fn checked_line(payload: &str) -> String {
+    format!("{:08x}\t{payload}\n", crc32(payload.as_bytes()))
+}
+
+/* The payload of a line when its checksum matches */
+fn verify_line(line: &str) -> Option<&str> {
+    let (crc, payload) = line.split_once('\t')?;
+    match u32::from_str_radix(crc, 16) {
+        Ok(c) if crc.len() == 8 && c == crc32(payload.as_bytes()) => Some(payload),
+        _ => None,
+    }
+}
+
+/// Write `records` as metadata of `kind` in format `version`, none of them may contain a newline
+pub(crate) fn encode_metadata<I, S>(kind: &str, version: u32, records: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut contents = checked_line(&format!("{METADATA_MAGIC} {kind} {version}"));
+    for record in records {
+        contents.push_str(&checked_line(record.as_ref()));
+    }
+    contents
+}
+
+/// Read metadata of `kind` written by [`encode_metadata`] in any version up to `supported`
+pub(crate) fn decode_metadata<'a>(
+    contents: &'a str,
+    kind: &str,
+    supported: u32,
+) -> Result<Metadata<'a>, MetadataError> {
+    let mut lines = contents.lines();
+    let first = lines.next().unwrap_or_default();
+    let header = match verify_line(first) {
+        Some(h) => h,
+        None if first.contains(&format!("\t{METADATA_MAGIC} ")) => {
+            return Err(MetadataError::Damaged)
+        }
+        None => return Err(MetadataError::Unversioned),
+    };
+
+    let mut fields = header.split(' ');
+    if fields.next() != Some(METADATA_MAGIC) {
+        return Err(MetadataError::Unversioned);
+    }
+
+    let (written_kind, version) = match (fields.next(), fields.next().map(str::parse::<u32>)) {
+        (Some(k), Some(Ok(v))) => (k, v),
+        _ => return Err(MetadataError::Unversioned),
+    };
+
+    if written_kind != kind {
+        return Err(MetadataError::Foreign(written_kind.to_string()));
+    }
+
+    if version > supported {
+        return Err(MetadataError::Newer(version));
+    }
+
+    let mut records = Vec::new();
+    let mut corrupt = 0;
+    for line in lines.filter(|l| !l.is_empty()) {
+        match verify_line(line) {
+            Some(r) => records.push(r),
+            None => corrupt += 1,
+        }
+    }
+
+    Ok(Metadata {
+        version,
+        records,
+        corrupt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414fa339
+        );
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let contents = encode_metadata("journal", 1, ["a\tb", "", "c"]);
+        assert!(contents
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with("\tRPXM journal 1"));
+
+        let metadata = decode_metadata(&contents, "journal", 1).unwrap();
+        assert_eq!(metadata.version, 1);
+        assert_eq!(metadata.records, vec!["a\tb", "", "c"]);
+        assert_eq!(metadata.corrupt, 0);
+
+        /* Older formats are still handed over for the caller to read */
+        assert_eq!(decode_metadata(&contents, "journal", 2).unwrap().version, 1);
+
+        let empty = encode_metadata("journal", 1, Vec::<String>::new());
+        assert!(decode_metadata(&empty, "journal", 1)
+            .unwrap()
+            .records
+            .is_empty());
+    }
+
+    #[test]
+    fn test_metadata_damage() {
+        let contents = encode_metadata("journal", 1, ["first", "second", "third"]);
+
+        /* A flipped character drops just that record */
+        let damaged = contents.replacen("second", "secund", 1);
+        let metadata = decode_metadata(&damaged, "journal", 1).unwrap();
+        assert_eq!(metadata.records, vec!["first", "third"]);
+        assert_eq!(metadata.corrupt, 1);
+
+        /* As does a write cut short */
+        let torn = &contents[..contents.len() - 3];
+        let metadata = decode_metadata(torn, "journal", 1).unwrap();
+        assert_eq!(metadata.records, vec!["first", "second"]);
+        assert_eq!(metadata.corrupt, 1);
+
+        /* A damaged header means nothing after it can be trusted */
+        let damaged = contents.replacen("journal", "journam", 1);
+        assert_eq!(
+            decode_metadata(&damaged, "journal", 1),
+            Err(MetadataError::Damaged)
+        );
+    }
+
+    #[test]
+    fn test_metadata_refused() {
+        assert_eq!(
+            decode_metadata("", "journal", 1),
+            Err(MetadataError::Unversioned)
+        );
+        assert_eq!(
+            decode_metadata("1234\t-\t-\thttp://example.com/\t/cache/x\n", "journal", 1),
+            Err(MetadataError::Unversioned)
+        );
+
+        let other = encode_metadata("layout", 1, ["sharded"]);
+        assert_eq!(
+            decode_metadata(&other, "journal", 1),
+            Err(MetadataError::Foreign("layout".to_string()))
+        );
+
+        let newer = encode_metadata("journal", 3, ["x"]);
+        assert_eq!(
+            decode_metadata(&newer, "journal", 2),
+            Err(MetadataError::Newer(3))
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use {
+    crate::cli::setting,
+    std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::{Arc, OnceLock, RwLock},
+    },
+    tracing::warn,
+};
+
+pub const X_PROXY_DENY_NETWORKS: &str = "X_PROXY_DENY_NETWORKS";
+
+/// An address range in CIDR notation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let (address, prefix) = match value.trim().split_once('/') {
+            Some((a, p)) => (a.parse::<IpAddr>().ok()?, Some(p.parse::<u8>().ok()?)),
+            None => (value.trim().parse::<IpAddr>().ok()?, None),
+        };
+
+        let bits = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        match prefix.unwrap_or(bits) {
+            p if p > bits => None,
+            prefix => Some(Network { address, prefix }),
+        }
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+
+        match (self.address, ip) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(n) & mask == u32::from(a) & mask
+            }
+            (IpAddr::V6(n), IpAddr::V6(a)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(n) & mask == u128::from(a) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Loopback, private, link local and other addresses that only make sense inside a network
+pub(crate) fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_internal_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_internal_v4(v4),
+            None => is_internal_v6(v6),
+        },
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || Network::parse("100.64.0.0/10").is_some_and(|n| n.contains(IpAddr::V4(ip)))
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local()
+}
+
+/* Whether internal addresses are denied, and the networks that are */
+type DeniedNetworks = (bool, Vec<Network>);
+
+/// Networks no request may reach, from `X_PROXY_DENY_NETWORKS`.
+/// `internal` stands in for every address [`is_internal`] covers.
+fn configured_networks() -> DeniedNetworks {
+    let mut internal = false;
+    let mut networks = Vec::new();
+
+    if let Ok(s) = setting(X_PROXY_DENY_NETWORKS) {
+        for value in s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            if value.eq_ignore_ascii_case("internal") {
+                internal = true;
+            } else {
+                match Network::parse(value) {
+                    Some(n) => networks.push(n),
+                    None => {
+                        warn!("ignoring invalid network '{value}' in '{X_PROXY_DENY_NETWORKS}'")
+                    }
+                }
+            }
+        }
+    }
+
+    (internal, networks)
+}
+
+fn networks_lock() -> &'static RwLock<Arc<DeniedNetworks>> {
+    static DENIED: OnceLock<RwLock<Arc<DeniedNetworks>>> = OnceLock::new();
+    DENIED.get_or_init(|| RwLock::new(Arc::new(configured_networks())))
+}
+
+fn denied_networks() -> Arc<DeniedNetworks> {
+    match networks_lock().read() {
+        Ok(d) => Arc::clone(&d),
+        Err(_) => Arc::default(),
+    }
+}
+
+/// Read `X_PROXY_DENY_NETWORKS` again, connections already open to an upstream stay open
+pub(crate) fn reload_denied_networks() {
+    let denied = configured_networks();
+    if let Ok(mut d) = networks_lock().write() {
+        *d = Arc::new(denied);
+    }
+}
+
+/// Whether an upstream at `ip` may be contacted.
+/// Internal addresses can be refused on top of the configured networks,
+/// which is how a public origin is stopped from redirecting into the local network.
+pub(crate) fn address_permitted(ip: IpAddr, allow_internal: bool) -> bool {
+    let denied = denied_networks();
+    let (deny_internal, networks) = denied.as_ref();
+
+    if is_internal(ip) && (*deny_internal || !allow_internal) {
+        return false;
+    }
+
+    !networks.iter().any(|n| n.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network() {
+        let network = Network::parse("10.1.0.0/16").unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let host = Network::parse("192.168.1.1").unwrap();
+        assert!(host.contains("192.168.1.1".parse().unwrap()));
+        assert!(!host.contains("192.168.1.2".parse().unwrap()));
+
+        let v6 = Network::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains("2001:db8::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+
+        assert!(Network::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!(Network::parse("10.0.0.0/33").is_none());
+        assert!(Network::parse("example.com").is_none());
+    }
+
+    #[test]
+    fn test_is_internal() {
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in ["8.8.8.8", "151.101.2.132", "2a04:4e42::644"] {
+            assert!(!is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_address_permitted() {
+        assert!(address_permitted("10.0.0.1".parse().unwrap(), true));
+        assert!(!address_permitted("10.0.0.1".parse().unwrap(), false));
+        assert!(address_permitted("8.8.8.8".parse().unwrap(), false));
+    }
+}
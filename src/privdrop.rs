@@ -0,0 +1,447 @@
+use {
+    crate::{cli::setting, PKG_NAME},
+    tracing::error,
+};
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+use {
+    crate::{
+        accounting::X_PROXY_ACCOUNTING_PATH, capture::X_PROXY_BODY_LOG_PATH,
+        http::X_PROXY_CACHE_PATH, token::X_PROXY_AUTH_TOKENS,
+    },
+    std::{path::PathBuf, sync::OnceLock},
+    tracing::info,
+};
+
+#[cfg(all(target_os = "linux", feature = "privdrop", feature = "https"))]
+use crate::cert::X_PROXY_TLS_PATH;
+#[cfg(all(target_os = "linux", feature = "privdrop", feature = "keylog"))]
+use crate::cert::{SSLKEYLOGFILE, X_PROXY_UPSTREAM_KEY_LOG};
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::Path,
+};
+
+pub const X_PROXY_USER: &str = "X_PROXY_USER";
+pub const X_PROXY_GROUP: &str = "X_PROXY_GROUP";
+pub const X_PROXY_SANDBOX: &str = "X_PROXY_SANDBOX";
+
+/* Linux's Landlock interface from `<linux/landlock.h>` */
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+/* Making character devices, directories, regular files, sockets, fifos, block devices and symlinks */
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_ACCESS_FS_MAKE_ALL: u64 = 0b111_1111 << 6;
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_ACCESS_FS_REFER: u64 = 1 << 13;
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+const LANDLOCK_ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Who rproxy becomes once its listeners are bound
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+#[derive(Debug, PartialEq)]
+struct Identity {
+    user: Option<u32>,
+    group: Option<u32>,
+}
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+
+/// Somewhere rproxy is configured to write
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+#[derive(Debug, PartialEq)]
+enum Writable {
+    /// A directory that's made if it's missing, everything beneath it may change
+    Directory(PathBuf),
+    /// A file that's written to or replaced
+    File(PathBuf),
+}
+
+/// Everywhere the settings read through `var` have rproxy write
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+fn writable_paths(var: impl Fn(&str) -> Option<String>) -> Vec<Writable> {
+    let directories = vec![
+        X_PROXY_CACHE_PATH,
+        X_PROXY_ACCOUNTING_PATH,
+        X_PROXY_BODY_LOG_PATH,
+    ];
+    let files = vec![X_PROXY_AUTH_TOKENS];
+    #[cfg(feature = "https")]
+    let directories = [directories, vec![X_PROXY_TLS_PATH]].concat();
+    #[cfg(feature = "keylog")]
+    let files = [files, vec![X_PROXY_UPSTREAM_KEY_LOG, SSLKEYLOGFILE]].concat();
+
+    let path = |name: &str| {
+        var(name)
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+    };
+    let mut writable: Vec<Writable> = directories
+        .into_iter()
+        .filter_map(|n| path(n).map(Writable::Directory))
+        .collect();
+    writable.extend(
+        files
+            .into_iter()
+            .filter_map(|n| path(n).map(Writable::File)),
+    );
+    writable
+}
+
+/* A user or group given by name is looked up, a number is taken as it is.
+ * A user's own group is only known when it's in the user database */
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+fn lookup_user(name: &str) -> Result<(u32, Option<u32>), String> {
+    let c_name = CString::new(name).map_err(|_| format!("'{name}' isn't a user name"))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut found = std::ptr::null_mut();
+    let r = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    match (r, found.is_null()) {
+        (0, false) => Ok((passwd.pw_uid, Some(passwd.pw_gid))),
+        (0, true) => match name.parse() {
+            Ok(uid) => Ok((uid, None)),
+            Err(_) => Err(format!("there's no user named '{name}'")),
+        },
+        (e, _) => Err(format!(
+            "couldn't look up user '{name}': {}",
+            io::Error::from_raw_os_error(e)
+        )),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+fn lookup_group(name: &str) -> Result<u32, String> {
+    let c_name = CString::new(name).map_err(|_| format!("'{name}' isn't a group name"))?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut found = std::ptr::null_mut();
+    let r = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    match (r, found.is_null()) {
+        (0, false) => Ok(group.gr_gid),
+        (0, true) => name
+            .parse()
+            .map_err(|_| format!("there's no group named '{name}'")),
+        (e, _) => Err(format!(
+            "couldn't look up group '{name}': {}",
+            io::Error::from_raw_os_error(e)
+        )),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+fn identity(user: Option<&str>, group: Option<&str>) -> Result<Identity, String> {
+    let (user, primary) = match user {
+        Some(u) => lookup_user(u).map(|(uid, gid)| (Some(uid), gid))?,
+        None => (None, None),
+    };
+    let group = match (group, primary) {
+        (Some(g), _) => Some(lookup_group(g)?),
+        (None, Some(gid)) => Some(gid),
+        (None, None) if user.is_some() => {
+            return Err(format!(
+                "user {} isn't in the user database, '{X_PROXY_GROUP}' has to be set too",
+                user.unwrap_or_default()
+            ))
+        }
+        (None, None) => None,
+    };
+    Ok(Identity { user, group })
+}
+
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+fn landlock_abi() -> io::Result<libc::c_long> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    match abi < 1 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(abi),
+    }
+}
+
+/* Let `access` happen beneath `path`, or to it when it's a file */
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+fn allow(ruleset: &File, path: &Path, access: u64) -> io::Result<()> {
+    let parent = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(path)?;
+    let rule = LandlockPathBeneathAttr {
+        allowed_access: access,
+        parent_fd: parent.as_raw_fd(),
+    };
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule as *const LandlockPathBeneathAttr,
+            0,
+        )
+    };
+    match r {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/* Stop this thread, and every thread it starts from now on, writing anywhere it wasn't allowed to */
+#[cfg(all(target_os = "linux", feature = "privdrop"))]
+fn restrict_writes(writable: &[Writable]) -> io::Result<()> {
+    let abi = landlock_abi()?;
+    let mut file_access = LANDLOCK_ACCESS_FS_WRITE_FILE;
+    let mut handled = LANDLOCK_ACCESS_FS_WRITE_FILE
+        | LANDLOCK_ACCESS_FS_REMOVE_DIR
+        | LANDLOCK_ACCESS_FS_REMOVE_FILE
+        | LANDLOCK_ACCESS_FS_MAKE_ALL;
+    if abi >= 2 {
+        handled |= LANDLOCK_ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= LANDLOCK_ACCESS_FS_TRUNCATE;
+        file_access |= LANDLOCK_ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = LandlockRulesetAttr {
+        handled_access_fs: handled,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const LandlockRulesetAttr,
+            size_of::<LandlockRulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = unsafe { File::from_raw_fd(fd as libc::c_int) };
+
+    for w in writable {
+        match w {
+            Writable::Directory(d) => {
+                std::fs::create_dir_all(d)?;
+                allow(&ruleset, d, handled)?;
+            }
+            /* A file that isn't there yet is made in its directory */
+            Writable::File(f) if f.is_file() => allow(&ruleset, f, file_access)?,
+            Writable::File(f) => match f.parent().filter(|p| !p.as_os_str().is_empty()) {
+                Some(p) => allow(&ruleset, p, handled)?,
+                None => allow(&ruleset, Path::new("."), handled)?,
+            },
+        }
+    }
+
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Read `X_PROXY_USER` and `X_PROXY_GROUP`, then sandbox rproxy when `X_PROXY_SANDBOX` is set.
+/// The sandbox only covers threads started after it, so this has to run before the runtime starts.
+/// False when the user or group doesn't exist or the sandbox can't be made.
+pub(crate) fn setup_privileges() -> bool {
+    let user = setting(X_PROXY_USER).ok();
+    let group = setting(X_PROXY_GROUP).ok();
+    let sandbox = setting(X_PROXY_SANDBOX).is_ok_and(|s| s.trim() == "1");
+
+    #[cfg(all(target_os = "linux", feature = "privdrop"))]
+    {
+        if user.is_some() || group.is_some() {
+            match identity(
+                user.as_deref().map(str::trim),
+                group.as_deref().map(str::trim),
+            ) {
+                Ok(i) => {
+                    let _ = IDENTITY.set(i);
+                }
+                Err(e) => {
+                    error!("{e}");
+                    return false;
+                }
+            }
+        }
+
+        if sandbox {
+            let writable = writable_paths(|n| setting(n).ok());
+            if let Err(e) = restrict_writes(&writable) {
+                error!("'{X_PROXY_SANDBOX}' is set but the sandbox couldn't be made: {e}");
+                return false;
+            }
+            for w in writable {
+                let (Writable::Directory(p) | Writable::File(p)) = w;
+                info!("sandboxed, may write to {}", p.display());
+            }
+        }
+        true
+    }
+    #[cfg(not(all(target_os = "linux", feature = "privdrop")))]
+    {
+        let set = [
+            (X_PROXY_USER, user.is_some()),
+            (X_PROXY_GROUP, group.is_some()),
+            (X_PROXY_SANDBOX, sandbox),
+        ];
+        match set.iter().find(|(_, set)| *set) {
+            Some((name, _)) => {
+                error!("'{name}' is set but {PKG_NAME} was built without the 'privdrop' feature");
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// Become the user and group given in `X_PROXY_USER` and `X_PROXY_GROUP`, once the listeners
+/// that needed root to bind are. False when rproxy couldn't, which it mustn't carry on from.
+pub(crate) fn drop_privileges() -> bool {
+    #[cfg(all(target_os = "linux", feature = "privdrop"))]
+    if let Some(identity) = IDENTITY.get() {
+        /* The group goes first, a user that isn't root any more can't change it */
+        if let Some(gid) = identity.group {
+            let r = match identity.user {
+                Some(_) => unsafe { libc::setgroups(1, &gid) },
+                None => 0,
+            };
+            if r != 0 || unsafe { libc::setgid(gid) } != 0 {
+                error!(
+                    "couldn't change to group {gid}: {}",
+                    io::Error::last_os_error()
+                );
+                return false;
+            }
+        }
+        if let Some(uid) = identity.user {
+            if unsafe { libc::setuid(uid) } != 0 {
+                error!(
+                    "couldn't change to user {uid}: {}",
+                    io::Error::last_os_error()
+                );
+                return false;
+            }
+            /* Root could get back what was given up */
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                error!("{PKG_NAME} could become root again after changing to user {uid}");
+                return false;
+            }
+        }
+
+        let passwd = unsafe { libc::getpwuid(libc::getuid()) };
+        let user = match passwd.is_null() {
+            true => unsafe { libc::getuid() }.to_string(),
+            false => unsafe { CStr::from_ptr((*passwd).pw_name) }
+                .to_string_lossy()
+                .into_owned(),
+        };
+        info!("running as user {user} group {}", unsafe { libc::getgid() });
+    }
+    true
+}
+
+#[cfg(all(test, target_os = "linux", feature = "privdrop"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writable_paths() {
+        let writable = writable_paths(|n| match n {
+            X_PROXY_CACHE_PATH => Some("/var/cache/rproxy".to_string()),
+            X_PROXY_BODY_LOG_PATH => Some(" ".to_string()),
+            X_PROXY_AUTH_TOKENS => Some("/var/lib/rproxy/tokens".to_string()),
+            _ => None,
+        });
+        assert_eq!(
+            writable,
+            vec![
+                Writable::Directory(PathBuf::from("/var/cache/rproxy")),
+                Writable::File(PathBuf::from("/var/lib/rproxy/tokens")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(
+            identity(Some("root"), None),
+            Ok(Identity {
+                user: Some(0),
+                group: Some(0)
+            })
+        );
+        assert_eq!(
+            identity(Some("4242424"), Some("0")),
+            Ok(Identity {
+                user: Some(4242424),
+                group: Some(0)
+            })
+        );
+        assert_eq!(
+            identity(None, Some("root")),
+            Ok(Identity {
+                user: None,
+                group: Some(0)
+            })
+        );
+        assert!(identity(Some("4242424"), None).is_err());
+        assert!(identity(Some("no-such-user-here"), None).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use {
+    crate::{
+        cancel::Cancellation,
+        cli::setting,
+        conn::{FlightChange, FlightState, Flights},
+        events::{escape_json, write_event_stream_header},
+        http::{ConnectionReturn, X_PROXY_CACHE_PATH},
+    },
+    std::{collections::HashMap, path::PathBuf},
+    tokio::{
+        fs::metadata,
+        io::{AsyncWrite, AsyncWriteExt},
+        sync::broadcast::error::RecvError,
+        time::{interval, Duration, MissedTickBehavior},
+    },
+};
+
+/// Where the web interface streams the progress of downloads as server-sent events
+pub(crate) const PROGRESS_PATH: &str = "/cache/progress";
+
+/// How often the bytes received by each download are measured
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/* Proxies and browsers drop event streams that stay quiet for too long */
+const KEEP_ALIVE_TICKS: u32 = 15;
+
+/// A download being watched and how much of it there was at the last tick
+struct Transfer {
+    state: FlightState,
+    received: u64,
+}
+
+fn state_name(state: FlightState) -> &'static str {
+    match state {
+        FlightState::Fetching => "fetching",
+        FlightState::Length(_) => "downloading",
+        FlightState::Chunks => "downloading",
+        FlightState::Complete => "complete",
+        FlightState::Failed => "failed",
+    }
+}
+
+/// One server-sent event about a download. `rate` is in bytes per second,
+/// left out of events that aren't about progress
+fn progress_event(
+    event: &str,
+    file: &str,
+    state: FlightState,
+    received: u64,
+    rate: Option<u64>,
+) -> String {
+    let length = match state {
+        FlightState::Length(l) => l.to_string(),
+        _ => "null".to_string(),
+    };
+    let rate = match rate {
+        Some(r) => format!(",\"rate\":{r}"),
+        None => String::new(),
+    };
+
+    format!(
+        "event: {event}\ndata: {{\"file\":\"{}\",\"state\":\"{}\",\"length\":{length},\"received\":{received}{rate}}}\n\n",
+        escape_json(file),
+        state_name(state)
+    )
+}
+
+fn cache_root() -> Option<PathBuf> {
+    setting(X_PROXY_CACHE_PATH).ok().map(PathBuf::from)
+}
+
+/* Named the way the web interface names them, by host and file */
+fn display_name(root: &Option<PathBuf>, cache_file_path: &str) -> String {
+    let path = PathBuf::from(cache_file_path);
+    match root.as_ref().and_then(|r| path.strip_prefix(r).ok()) {
+        Some(p) => p.to_string_lossy().to_string(),
+        None => cache_file_path.to_string(),
+    }
+}
+
+async fn received(cache_file_path: &str) -> u64 {
+    metadata(cache_file_path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or_default()
+}
+
+/// Stream `takeoff`, `state`, `progress` and `landed` events for every download until the
+/// client goes away. Downloads already in flight are sent as `takeoff` events first.
+pub(crate) async fn serve_progress<T>(
+    stream: &mut T,
+    flights: &Flights,
+    cancel: &Cancellation,
+) -> ConnectionReturn
+where
+    T: AsyncWrite + Unpin,
+{
+    /* Subscribed before looking at what's in flight so nothing lands unseen in between */
+    let mut changes = flights.changes();
+
+    if !write_event_stream_header(stream).await {
+        return ConnectionReturn::Close;
+    }
+
+    let root = cache_root();
+    let mut transfers = HashMap::new();
+    let mut events = String::new();
+    for (path, state) in flights.all().await {
+        let received = received(&path).await;
+        let name = display_name(&root, &path);
+        events.push_str(&progress_event("takeoff", &name, state, received, None));
+        transfers.insert(path, Transfer { state, received });
+    }
+
+    let mut ticker = interval(PROGRESS_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut quiet = 0;
+
+    loop {
+        if !events.is_empty() {
+            if stream.write_all(events.as_bytes()).await.is_err() {
+                return ConnectionReturn::Close;
+            }
+            events.clear();
+            quiet = 0;
+        }
+
+        tokio::select! {
+            _ = cancel.clone().cancelled() => return ConnectionReturn::Close,
+            change = changes.recv() => match change {
+                Ok(FlightChange::State(path, state)) => {
+                    let received = received(&path).await;
+                    let event = match transfers.insert(path.clone(), Transfer { state, received }) {
+                        Some(_) => "state",
+                        None => "takeoff",
+                    };
+                    events.push_str(&progress_event(event, &display_name(&root, &path), state, received, None));
+                }
+                Ok(FlightChange::Landed(path, state)) => {
+                    transfers.remove(&path);
+                    let received = received(&path).await;
+                    events.push_str(&progress_event("landed", &display_name(&root, &path), state, received, None));
+                }
+                /* Too far behind to know what happened, so start again from what's in flight now */
+                Err(RecvError::Lagged(_)) => {
+                    let current: HashMap<String, FlightState> = flights.all().await.into_iter().collect();
+                    transfers.retain(|path, _| current.contains_key(path));
+                    for (path, state) in current {
+                        let transfer = transfers.entry(path).or_insert(Transfer { state, received: 0 });
+                        transfer.state = state;
+                    }
+                }
+                Err(RecvError::Closed) => return ConnectionReturn::Close,
+            },
+            _ = ticker.tick() => {
+                for (path, transfer) in transfers.iter_mut() {
+                    if !matches!(transfer.state, FlightState::Length(_) | FlightState::Chunks) {
+                        continue;
+                    }
+
+                    let received = received(path).await;
+                    let rate = received.saturating_sub(transfer.received) / PROGRESS_INTERVAL.as_secs();
+                    transfer.received = received;
+                    events.push_str(&progress_event(
+                        "progress",
+                        &display_name(&root, path),
+                        transfer.state,
+                        received,
+                        Some(rate),
+                    ));
+                }
+
+                quiet += 1;
+                if events.is_empty() && quiet >= KEEP_ALIVE_TICKS {
+                    events.push_str(":\n\n");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_event() {
+        assert_eq!(
+            progress_event(
+                "progress",
+                "deb.debian.org/pool/a \"b\".deb",
+                FlightState::Length(2048),
+                1024,
+                Some(512)
+            ),
+            "event: progress\ndata: {\"file\":\"deb.debian.org/pool/a \\\"b\\\".deb\",\
+            \"state\":\"downloading\",\"length\":2048,\"received\":1024,\"rate\":512}\n\n"
+        );
+        assert_eq!(
+            progress_event("landed", "a\nb", FlightState::Failed, 0, None),
+            "event: landed\ndata: {\"file\":\"a\\u000ab\",\"state\":\"failed\",\
+            \"length\":null,\"received\":0}\n\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_serve_progress() {
+        let flights = Flights::new();
+        flights
+            .takeoff("/nowhere/early", FlightState::Fetching)
+            .await;
+
+        let cancel = Cancellation::new();
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let streaming = async {
+            serve_progress(&mut server, &flights, &cancel).await;
+            drop(server);
+        };
+
+        /* Time only moves on once every event sent has been written */
+        let watching = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            flights.takeoff("/nowhere/late", FlightState::Chunks).await;
+            flights.land(&"/nowhere/late".to_string()).await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cancel.cancel();
+        };
+
+        let mut response = String::new();
+        let reading = tokio::io::AsyncReadExt::read_to_string(&mut client, &mut response);
+        let (_, _, _) = tokio::join!(streaming, watching, reading);
+
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("Content-Type: text/event-stream"));
+        let events: Vec<&str> = response
+            .lines()
+            .filter_map(|l| l.strip_prefix("event: "))
+            .filter(|e| *e != "progress")
+            .collect();
+        assert_eq!(events, vec!["takeoff", "takeoff", "state", "landed"]);
+        assert!(response.contains("\"file\":\"/nowhere/late\",\"state\":\"failed\""));
+    }
+}
@@ -0,0 +1,489 @@
+use {
+    crate::{
+        cert::CertificateSetup,
+        conn::Uri,
+        egress::egress_udp,
+        http::{
+            header_limits, http_chunk, HttpHeader, HttpRequestHeader, HttpRequestMethod,
+            BUFFER_SIZE,
+        },
+    },
+    bytes::{Buf, Bytes},
+    quinn::crypto::rustls::QuicClientConfig,
+    std::{
+        collections::HashMap,
+        convert::TryFrom,
+        future::poll_fn,
+        io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, OnceLock,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::{
+        io::{duplex, AsyncWriteExt, BufReader, DuplexStream},
+        time::timeout,
+    },
+    tracing::debug,
+};
+
+/// How long an `Alt-Svc` advertisement lasts when it doesn't say, as RFC 7838 has it
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long an origin is left to TCP after HTTP/3 fails to connect or answer
+const BROKEN_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How long a QUIC handshake may take before the origin is reached over TCP instead.
+/// Short, since UDP blocked somewhere along the way looks the same as a slow origin.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/* Request headers that only mean something to an HTTP/1.1 connection, HTTP/3 forbids them */
+const CONNECTION_HEADERS: [&str; 7] = [
+    "Connection",
+    "Host",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// What an origin's `Alt-Svc` header says about HTTP/3
+#[derive(Debug, PartialEq)]
+enum AltSvc {
+    /// Served over HTTP/3 on this UDP port of the same host for this long
+    H3(u16, Duration),
+    /// Every alternative advertised before is withdrawn
+    Clear,
+}
+
+/// An origin that advertised HTTP/3
+struct Advertised {
+    port: u16,
+    expires: Instant,
+    /* Set when HTTP/3 didn't work out, the origin is reached over TCP until then */
+    broken_until: Option<Instant>,
+}
+
+/* Origins that advertised HTTP/3 by host and port */
+fn advertised() -> &'static Mutex<HashMap<String, Advertised>> {
+    static ADVERTISED: OnceLock<Mutex<HashMap<String, Advertised>>> = OnceLock::new();
+    ADVERTISED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/* Only alternatives on the same host are used, so the certificate is checked against the name
+ * the client asked for and a hostile origin can't point the proxy somewhere else */
+fn parse_alt_svc(value: &str) -> Option<AltSvc> {
+    if value.trim().eq_ignore_ascii_case("clear") {
+        return Some(AltSvc::Clear);
+    }
+
+    for alternative in value.split(',') {
+        let mut parameters = alternative.split(';');
+        let (protocol, authority) = match parameters.next()?.split_once('=') {
+            Some(p) => p,
+            None => continue,
+        };
+        if protocol.trim() != "h3" {
+            continue;
+        }
+
+        let port = match authority.trim().trim_matches('"').strip_prefix(':') {
+            Some(p) => match p.parse::<u16>() {
+                Ok(p) if p > 0 => p,
+                _ => continue,
+            },
+            None => continue,
+        };
+
+        let max_age = parameters
+            .filter_map(|p| p.split_once('='))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case("ma"))
+            .and_then(|(_, v)| v.trim().trim_matches('"').parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_MAX_AGE);
+        return Some(AltSvc::H3(port, max_age));
+    }
+    None
+}
+
+/// Remember whether an HTTPS origin answering `uri` offers HTTP/3 from its `Alt-Svc` header
+pub(crate) fn remember_alt_svc(uri: &Uri<'_>, headers: &HttpHeader) {
+    if uri.scheme != Some("https://") {
+        return;
+    }
+    let (key, alt_svc) = match (uri.host_and_port(), headers.get("Alt-Svc")) {
+        (Some(k), Some(v)) => (k, v),
+        _ => return,
+    };
+
+    let mut advertised = match advertised().lock() {
+        Ok(a) => a,
+        Err(_) => return,
+    };
+    match parse_alt_svc(alt_svc) {
+        Some(AltSvc::H3(port, max_age)) => {
+            let broken_until = advertised.get(&key).and_then(|a| a.broken_until);
+            advertised.insert(
+                key,
+                Advertised {
+                    port,
+                    expires: Instant::now() + max_age,
+                    broken_until,
+                },
+            );
+        }
+        Some(AltSvc::Clear) => {
+            advertised.remove(&key);
+        }
+        None => (),
+    }
+}
+
+/// The UDP port the origin at `key` serves HTTP/3 on, when it advertised one that's worth trying
+pub(crate) fn h3_port(key: &str) -> Option<u16> {
+    let mut advertised = advertised().lock().ok()?;
+    let now = Instant::now();
+    advertised.retain(|_, a| a.expires > now);
+    advertised
+        .get(key)
+        .filter(|a| a.broken_until.is_none_or(|b| b <= now))
+        .map(|a| a.port)
+}
+
+/// Reach the origin at `key` over TCP for a while
+fn mark_broken(key: &str) {
+    debug!("HTTP/3 to {key} didn't work, using TCP for a while");
+    if let Ok(mut advertised) = advertised().lock() {
+        if let Some(a) = advertised.get_mut(key) {
+            a.broken_until = Some(Instant::now() + BROKEN_BACKOFF);
+        }
+    }
+}
+
+type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+/// An HTTP/3 connection to an origin, shared by every request to it
+#[derive(Clone)]
+struct Connection {
+    send: SendRequest,
+    peer: SocketAddr,
+    /* Cleared once the connection has closed */
+    open: Arc<AtomicBool>,
+}
+
+/* Open connections by host and port */
+fn connections() -> &'static Mutex<HashMap<String, Connection>> {
+    static CONNECTIONS: OnceLock<Mutex<HashMap<String, Connection>>> = OnceLock::new();
+    CONNECTIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn open_connection(key: &str) -> Option<Connection> {
+    let mut connections = connections().lock().ok()?;
+    connections.retain(|_, c| c.open.load(Ordering::Relaxed));
+    connections.get(key).cloned()
+}
+
+fn forget_connection(key: &str, connection: &Connection) {
+    connection.open.store(false, Ordering::Relaxed);
+    if let Ok(mut connections) = connections().lock() {
+        if connections
+            .get(key)
+            .is_some_and(|c| Arc::ptr_eq(&c.open, &connection.open))
+        {
+            connections.remove(key);
+        }
+    }
+}
+
+/* Each connection gets a socket of its own so it leaves from the upstream device and namespace */
+async fn handshake(
+    address: SocketAddr,
+    host: &str,
+    config: quinn::ClientConfig,
+) -> io::Result<Connection> {
+    let bind = match address {
+        SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0; 16], 0)),
+    };
+    let socket = egress_udp(bind).await?.into_std()?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        None,
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+
+    let quic = endpoint
+        .connect_with(config, address, host)
+        .map_err(io::Error::other)?
+        .await
+        .map_err(io::Error::other)?;
+    let (mut driver, send) = h3::client::new(h3_quinn::Connection::new(quic))
+        .await
+        .map_err(io::Error::other)?;
+
+    let open = Arc::new(AtomicBool::new(true));
+    let closed = Arc::clone(&open);
+    tokio::spawn(async move {
+        let e = poll_fn(|cx| driver.poll_close(cx)).await;
+        debug!("HTTP/3 connection closed: {e}");
+        closed.store(false, Ordering::Relaxed);
+    });
+
+    Ok(Connection {
+        send,
+        peer: address,
+        open,
+    })
+}
+
+async fn new_connection(
+    host: &str,
+    addresses: &[SocketAddr],
+    port: u16,
+    certificates: &CertificateSetup,
+) -> io::Result<Connection> {
+    /* The same certificate checks and client identity as HTTPS over TCP, QUIC only takes TLS 1.3 */
+    let mut tls = (**certificates.connector_for(host).config()).clone();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = QuicClientConfig::try_from(tls).map_err(io::Error::other)?;
+    let config = quinn::ClientConfig::new(Arc::new(crypto));
+
+    let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    for address in addresses {
+        let address = SocketAddr::new(address.ip(), port);
+        match timeout(CONNECT_TIMEOUT, handshake(address, host, config.clone())).await {
+            Ok(Ok(c)) => return Ok(c),
+            Ok(Err(e)) => error = e,
+            Err(_) => error = io::ErrorKind::TimedOut.into(),
+        }
+    }
+    Err(error)
+}
+
+/// Reach the origin at `key` over HTTP/3 on `port` of one of `addresses`. The stream given back
+/// takes HTTP/1.1 requests and answers them with HTTP/1.1 responses so fetching and caching work
+/// the same as over TCP. When HTTP/3 fails before a response, the stream closes without one and
+/// the origin is reached over TCP for a while, the same as a pooled connection that was closed.
+pub(crate) async fn connect(
+    key: &str,
+    host: &str,
+    addresses: &[SocketAddr],
+    port: u16,
+    certificates: &CertificateSetup,
+) -> io::Result<(DuplexStream, SocketAddr)> {
+    let (connection, fresh) = match open_connection(key) {
+        Some(c) => (c, false),
+        None => {
+            let connection = match new_connection(host, addresses, port, certificates).await {
+                Ok(c) => c,
+                Err(e) => {
+                    mark_broken(key);
+                    return Err(e);
+                }
+            };
+            if let Ok(mut connections) = connections().lock() {
+                connections.insert(key.to_string(), connection.clone());
+            }
+            (connection, true)
+        }
+    };
+
+    let peer = connection.peer;
+    let (local, remote) = duplex(BUFFER_SIZE);
+    tokio::spawn(exchange(remote, connection, key.to_string(), fresh));
+    Ok((local, peer))
+}
+
+/// Why a request over HTTP/3 stopped
+enum Failure {
+    /// Nothing was written back, so the request can be sent again over TCP
+    Unanswered(String),
+    /// The response was cut short
+    Broken(String),
+}
+
+/* Pass each request written to the stream on over HTTP/3 and write back its response */
+async fn exchange(stream: DuplexStream, mut connection: Connection, key: String, mut fresh: bool) {
+    let mut reader = BufReader::new(stream);
+    while let Ok(request) = HttpRequestHeader::read_limited(&mut reader, header_limits()).await {
+        match send(&mut connection, &key, &request, reader.get_mut()).await {
+            Ok(_) => fresh = false,
+            Err(Failure::Unanswered(e)) => {
+                debug!("HTTP/3 request to {key} failed: {e}");
+                forget_connection(&key, &connection);
+                /* A connection that worked before may just have timed out, a new one is tried next */
+                if fresh {
+                    mark_broken(&key);
+                }
+                return;
+            }
+            Err(Failure::Broken(e)) => {
+                debug!("HTTP/3 response from {key} was cut short: {e}");
+                return;
+            }
+        }
+    }
+}
+
+fn h3_request(key: &str, request: &HttpRequestHeader<'_>) -> Result<http::Request<()>, String> {
+    let path = request.request.path_and_query.unwrap_or("/");
+
+    let mut builder = http::Request::builder()
+        .method(request.method.to_string().as_str())
+        .uri(format!("https://{key}{path}"));
+    for (name, value) in &request.headers {
+        /* Left out the same way they are when the request is written for HTTP/1.1 */
+        if name.trim().is_empty() || value.trim().is_empty() {
+            continue;
+        }
+        if !CONNECTION_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(name))
+        {
+            builder = builder.header(name.trim(), value.trim());
+        }
+    }
+    builder.body(()).map_err(|e| e.to_string())
+}
+
+async fn send(
+    connection: &mut Connection,
+    key: &str,
+    request: &HttpRequestHeader<'_>,
+    stream: &mut DuplexStream,
+) -> Result<(), Failure> {
+    let unanswered = |e: &dyn std::fmt::Display| Failure::Unanswered(e.to_string());
+    let broken = |e: &dyn std::fmt::Display| Failure::Broken(e.to_string());
+
+    let mut request_stream = connection
+        .send
+        .send_request(h3_request(key, request).map_err(|e| unanswered(&e))?)
+        .await
+        .map_err(|e| unanswered(&e))?;
+    request_stream.finish().await.map_err(|e| unanswered(&e))?;
+    let response = request_stream
+        .recv_response()
+        .await
+        .map_err(|e| unanswered(&e))?;
+
+    let status = response.status();
+    let bodiless = request.method == HttpRequestMethod::Head
+        || status.is_informational()
+        || matches!(status.as_u16(), 204 | 304);
+    let chunked = !bodiless
+        && !response
+            .headers()
+            .contains_key(http::header::CONTENT_LENGTH);
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    for (name, value) in response.headers() {
+        if name != http::header::TRANSFER_ENCODING && name != http::header::CONNECTION {
+            head.push_str(&format!(
+                "{name}: {}\r\n",
+                String::from_utf8_lossy(value.as_bytes())
+            ));
+        }
+    }
+    if chunked {
+        head.push_str("Transfer-Encoding: chunked\r\n");
+    }
+    head.push_str("\r\n");
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| broken(&e))?;
+
+    if !bodiless {
+        while let Some(mut data) = request_stream.recv_data().await.map_err(|e| broken(&e))? {
+            let data = data.copy_to_bytes(data.remaining());
+            let written = match chunked {
+                true => stream.write_all(&http_chunk(&data)).await,
+                false => stream.write_all(&data).await,
+            };
+            written.map_err(|e| broken(&e))?;
+        }
+        if chunked {
+            stream
+                .write_all(b"0\r\n\r\n")
+                .await
+                .map_err(|e| broken(&e))?;
+        }
+    }
+    stream.flush().await.map_err(|e| broken(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_alt_svc() {
+        assert_eq!(
+            parse_alt_svc("h3=\":443\"; ma=86400"),
+            Some(AltSvc::H3(443, Duration::from_secs(86400)))
+        );
+        assert_eq!(
+            parse_alt_svc("h3-29=\":8443\", h3=\":8443\""),
+            Some(AltSvc::H3(8443, DEFAULT_MAX_AGE))
+        );
+        assert_eq!(parse_alt_svc(" Clear "), Some(AltSvc::Clear));
+
+        /* Other hosts, other protocols and nonsense are passed over */
+        assert_eq!(parse_alt_svc("h3=\"cdn.example:443\""), None);
+        assert_eq!(parse_alt_svc("h2=\":443\""), None);
+        assert_eq!(parse_alt_svc("h3=\":0\""), None);
+        assert_eq!(parse_alt_svc("h3"), None);
+    }
+
+    #[test]
+    fn test_remember_alt_svc() {
+        let uri = Uri::from("https://h3.example/file".to_string());
+        let mut headers = HttpHeader::new();
+        headers.insert("Alt-Svc".to_string(), "h3=\":4433\"".to_string());
+
+        /* Plain HTTP origins can't be reached over HTTP/3 */
+        remember_alt_svc(&Uri::from("http://h3.example/".to_string()), &headers);
+        assert_eq!(h3_port("h3.example:80"), None);
+
+        remember_alt_svc(&uri, &headers);
+        assert_eq!(h3_port("h3.example:443"), Some(4433));
+
+        mark_broken("h3.example:443");
+        assert_eq!(h3_port("h3.example:443"), None);
+
+        /* Still broken when advertised again, gone once cleared */
+        remember_alt_svc(&uri, &headers);
+        assert_eq!(h3_port("h3.example:443"), None);
+        headers.insert("Alt-Svc".to_string(), "clear".to_string());
+        remember_alt_svc(&uri, &headers);
+        assert!(!advertised().lock().unwrap().contains_key("h3.example:443"));
+    }
+
+    #[test]
+    fn test_h3_request() {
+        let mut headers = HttpHeader::new();
+        headers.insert("Host".to_string(), "h3.example".to_string());
+        headers.insert("Connection".to_string(), "keep-alive".to_string());
+        headers.insert("Accept".to_string(), "*/*".to_string());
+        let request = HttpRequestHeader {
+            method: HttpRequestMethod::Get,
+            request: Uri::from("/file?v=1".to_string()),
+            version: crate::http::HttpVersion::HTTP_V11,
+            headers,
+        };
+
+        let h3 = h3_request("h3.example:443", &request).unwrap();
+        assert_eq!(h3.uri(), "https://h3.example:443/file?v=1");
+        assert_eq!(h3.headers().get("accept").unwrap(), "*/*");
+        assert!(!h3.headers().contains_key("host"));
+        assert!(!h3.headers().contains_key("connection"));
+    }
+}
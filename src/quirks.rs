@@ -0,0 +1,100 @@
+use {
+    std::{
+        collections::HashMap,
+        sync::{OnceLock, RwLock},
+    },
+    tokio::time::Instant,
+    tracing::debug,
+};
+
+/// Upper bound on hosts with quirks remembered, so clients sending requests to
+/// many misbehaving origins can't grow the table forever
+const MAX_QUIRK_HOSTS: usize = 1024;
+
+/// Workarounds for origins that misbehave when spoken to with HTTP/1.1, learned as they're found
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub(crate) struct Quirks {
+    /// Send requests as HTTP/1.0 and ask the origin to close the connection after each one
+    pub(crate) http10: bool,
+    /// Never send a second request down the same connection
+    pub(crate) no_reuse: bool,
+}
+
+/* Keyed by host and port with when a quirk was last learned, forgotten when rproxy restarts */
+fn quirk_table() -> &'static RwLock<HashMap<String, (Quirks, Instant)>> {
+    static QUIRKS: OnceLock<RwLock<HashMap<String, (Quirks, Instant)>>> = OnceLock::new();
+    QUIRKS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+pub(crate) fn host_quirks(host: &str) -> Quirks {
+    match quirk_table().read() {
+        Ok(table) => table.get(host).map(|(q, _)| *q).unwrap_or_default(),
+        Err(_) => Quirks::default(),
+    }
+}
+
+fn remember_quirk<F>(host: &str, quirk: F)
+where
+    F: FnOnce(&mut Quirks),
+{
+    let mut table = match quirk_table().write() {
+        Ok(t) => t,
+        Err(_) => return,
+    };
+
+    if table.len() >= MAX_QUIRK_HOSTS && !table.contains_key(host) {
+        if let Some(oldest) = table
+            .iter()
+            .min_by_key(|(_, (_, learned))| *learned)
+            .map(|(h, _)| h.clone())
+        {
+            table.remove(&oldest);
+        }
+    }
+
+    let (quirks, learned) = table
+        .entry(host.to_string())
+        .or_insert_with(|| (Quirks::default(), Instant::now()));
+    quirk(quirks);
+    *learned = Instant::now();
+    debug!("Remembering {host} has quirks {quirks:?}");
+}
+
+/// The origin rejected an HTTP/1.1 request outright
+pub(crate) fn force_http10(host: &str) {
+    remember_quirk(host, |q| {
+        q.http10 = true;
+        q.no_reuse = true;
+    });
+}
+
+/// The origin advertised keep-alive then dropped a connection it should have kept
+pub(crate) fn disable_reuse(host: &str) {
+    remember_quirk(host, |q| q.no_reuse = true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quirks() {
+        assert_eq!(host_quirks("old.example:80"), Quirks::default());
+
+        disable_reuse("old.example:80");
+        assert!(host_quirks("old.example:80").no_reuse);
+        assert!(!host_quirks("old.example:80").http10);
+
+        force_http10("old.example:80");
+        assert!(host_quirks("old.example:80").http10);
+        assert_eq!(host_quirks("old.example:8080"), Quirks::default());
+
+        /* The host learned about longest ago makes room */
+        for n in 0..MAX_QUIRK_HOSTS {
+            disable_reuse(&format!("many-{n}.example:80"));
+        }
+        assert_eq!(quirk_table().read().unwrap().len(), MAX_QUIRK_HOSTS);
+        assert_eq!(host_quirks("old.example:80"), Quirks::default());
+        assert!(host_quirks("many-0.example:80").no_reuse);
+    }
+}
@@ -0,0 +1,227 @@
+use {
+    crate::{cli::setting, conn::Uri, conn::UriKind::ResolvedAddress},
+    std::sync::OnceLock,
+    tracing::{error, info},
+};
+
+pub const X_PROXY_REDIRECT_MAX: &str = "X_PROXY_REDIRECT_MAX";
+pub const X_PROXY_REDIRECT_CROSS_HOST: &str = "X_PROXY_REDIRECT_CROSS_HOST";
+pub const X_PROXY_REDIRECT_CROSS_SCHEME: &str = "X_PROXY_REDIRECT_CROSS_SCHEME";
+pub const X_PROXY_REDIRECT_CACHE: &str = "X_PROXY_REDIRECT_CACHE";
+
+/// Which redirects may have what they lead to cached under the URL the client asked for
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum RedirectCache {
+    /// Whatever a followed redirect leads to is cached
+    #[default]
+    All,
+    /// Only what a `301` or `308` leads to is cached, it's not expected to move again
+    Permanent,
+    /// Nothing reached through a redirect is cached
+    None,
+}
+
+impl RedirectCache {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "all" => Some(RedirectCache::All),
+            "permanent" => Some(RedirectCache::Permanent),
+            "none" => Some(RedirectCache::None),
+            _ => None,
+        }
+    }
+}
+
+/// Which redirects from an origin server rproxy follows itself,
+/// any other is passed back to the client to follow
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RedirectPolicy {
+    /// How many redirects are followed for one request
+    max: usize,
+    /// Redirects to another host are followed
+    cross_host: bool,
+    /// Redirects to another scheme, such as from `http` to `https`, are followed
+    cross_scheme: bool,
+    cache: RedirectCache,
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy {
+            max: 5,
+            cross_host: true,
+            cross_scheme: true,
+            cache: RedirectCache::default(),
+        }
+    }
+}
+
+impl RedirectPolicy {
+    /// Whether a redirect from `from` to `to` is followed when `hops` were already followed to reach `from`
+    pub(crate) fn follows(&self, from: &Uri<'_>, to: &Uri<'_>, hops: usize) -> bool {
+        let same_host = match (from.host, to.host) {
+            (Some(f), Some(t)) => f.eq_ignore_ascii_case(t),
+            _ => false,
+        };
+        let same_scheme = match (from.scheme, to.scheme) {
+            (Some(f), Some(t)) => f.eq_ignore_ascii_case(t),
+            _ => false,
+        };
+
+        hops < self.max && (same_host || self.cross_host) && (same_scheme || self.cross_scheme)
+    }
+
+    /// Whether what a redirect with `status` leads to may be cached under the client's URL
+    pub(crate) fn caches(&self, status: u16) -> bool {
+        match self.cache {
+            RedirectCache::All => true,
+            RedirectCache::Permanent => matches!(status, 301 | 308),
+            RedirectCache::None => false,
+        }
+    }
+}
+
+static POLICY: OnceLock<RedirectPolicy> = OnceLock::new();
+
+pub(crate) fn redirect_policy() -> RedirectPolicy {
+    POLICY.get().copied().unwrap_or_default()
+}
+
+fn parse_switch(name: &str, default: bool) -> Result<bool, String> {
+    match setting(name) {
+        Err(_) => Ok(default),
+        Ok(v) => match v.trim().to_lowercase().as_str() {
+            "1" | "true" | "on" => Ok(true),
+            "0" | "false" | "off" => Ok(false),
+            _ => Err(format!("'{name}' must be '1' or '0': '{v}'")),
+        },
+    }
+}
+
+fn read_policy() -> Result<RedirectPolicy, String> {
+    let default = RedirectPolicy::default();
+    let max = match setting(X_PROXY_REDIRECT_MAX) {
+        Err(_) => default.max,
+        Ok(m) => m
+            .trim()
+            .parse()
+            .map_err(|_| format!("'{X_PROXY_REDIRECT_MAX}' must be a number: '{m}'"))?,
+    };
+    let cache = match setting(X_PROXY_REDIRECT_CACHE) {
+        Err(_) => default.cache,
+        Ok(c) => RedirectCache::from_name(&c).ok_or(format!(
+            "'{X_PROXY_REDIRECT_CACHE}' must be 'all', 'permanent' or 'none': '{c}'"
+        ))?,
+    };
+
+    Ok(RedirectPolicy {
+        max,
+        cross_host: parse_switch(X_PROXY_REDIRECT_CROSS_HOST, default.cross_host)?,
+        cross_scheme: parse_switch(X_PROXY_REDIRECT_CROSS_SCHEME, default.cross_scheme)?,
+        cache,
+    })
+}
+
+/// Read the `X_PROXY_REDIRECT_*` variables, false when one of them can't be understood
+pub(crate) fn setup_redirects() -> bool {
+    let policy = match read_policy() {
+        Ok(p) => p,
+        Err(e) => {
+            error!("{e}");
+            return false;
+        }
+    };
+
+    if policy != RedirectPolicy::default() {
+        info!("redirects: {policy:?}");
+    }
+    let _ = POLICY.set(policy);
+    true
+}
+
+/// Where a `Location` sent in answer to a request for `base` points,
+/// a relative one is taken as relative to `base`
+pub(crate) fn redirect_target<'a>(base: &Uri<'_>, location: &str) -> Uri<'a> {
+    let target = Uri::from(location.to_string());
+    match target.kind() {
+        ResolvedAddress => target,
+        _ => target.merge_with(base),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uri(u: &str) -> Uri<'static> {
+        Uri::from(u.to_string())
+    }
+
+    #[test]
+    fn test_redirect_cache_from_name() {
+        assert_eq!(RedirectCache::from_name(" All"), Some(RedirectCache::All));
+        assert_eq!(
+            RedirectCache::from_name("permanent"),
+            Some(RedirectCache::Permanent)
+        );
+        assert_eq!(RedirectCache::from_name("NONE"), Some(RedirectCache::None));
+        assert_eq!(RedirectCache::from_name("some"), None);
+    }
+
+    #[test]
+    fn test_follows() {
+        let from = uri("http://deb.debian.org/debian/README");
+        let same = uri("http://DEB.debian.org/debian/README.txt");
+        let other_host = uri("http://ftp.au.debian.org/debian/README");
+        let other_scheme = uri("https://deb.debian.org/debian/README");
+
+        let policy = RedirectPolicy::default();
+        assert!(policy.follows(&from, &same, 0));
+        assert!(policy.follows(&from, &other_host, 4));
+        assert!(policy.follows(&from, &other_scheme, 0));
+        assert!(!policy.follows(&from, &same, 5));
+
+        let policy = RedirectPolicy {
+            cross_host: false,
+            cross_scheme: false,
+            ..Default::default()
+        };
+        assert!(policy.follows(&from, &same, 0));
+        assert!(!policy.follows(&from, &other_host, 0));
+        assert!(!policy.follows(&from, &other_scheme, 0));
+
+        let policy = RedirectPolicy {
+            max: 0,
+            ..Default::default()
+        };
+        assert!(!policy.follows(&from, &same, 0));
+    }
+
+    #[test]
+    fn test_caches() {
+        let mut policy = RedirectPolicy::default();
+        assert!(policy.caches(302));
+
+        policy.cache = RedirectCache::Permanent;
+        assert!(policy.caches(301));
+        assert!(policy.caches(308));
+        assert!(!policy.caches(302));
+        assert!(!policy.caches(307));
+
+        policy.cache = RedirectCache::None;
+        assert!(!policy.caches(301));
+    }
+
+    #[test]
+    fn test_redirect_target() {
+        let base = uri("http://deb.debian.org/debian/README");
+        assert_eq!(
+            redirect_target(&base, "https://mirror.example/debian/README").uri,
+            "https://mirror.example/debian/README"
+        );
+        assert_eq!(
+            redirect_target(&base, "/other/README").uri,
+            "http://deb.debian.org:80/other/README"
+        );
+    }
+}
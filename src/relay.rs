@@ -0,0 +1,795 @@
+use {
+    crate::{
+        cancel::{Cancellable, Cancellation},
+        capture::{capture_body, Capturing},
+        cli::setting,
+        conn::{FetchRequest, Flights, Uri},
+        credentials::apply_upstream_credentials,
+        debug::wire_log,
+        evict::parse_size,
+        fetch::{respond_connect_error, respond_header_error},
+        forwarded::apply_via,
+        head::forget_head,
+        http::{
+            get_cache_name, keep_alive_if, read_chunk_end, read_chunk_size, read_trailers,
+            respond_with, ConnectionReturn, ConnectionReturn::Close, HttpRequestHeader,
+            HttpRequestMethod, HttpResponseHeader, HttpResponseStatus, HttpVersion,
+        },
+        tunnel::{splice, TUNNEL_IDLE_TIMEOUT},
+    },
+    std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, OnceLock,
+        },
+        time::Instant,
+    },
+    tokio::{
+        fs::remove_file,
+        io::{
+            copy, sink, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        },
+    },
+    tracing::debug,
+};
+
+#[cfg(feature = "https")]
+use crate::cert::CertificateSetup;
+
+pub const X_PROXY_MAX_REQUEST_BODY: &str = "X_PROXY_MAX_REQUEST_BODY";
+
+/// The largest body a client may send with a request, unlimited unless `X_PROXY_MAX_REQUEST_BODY` is defined
+fn max_request_body() -> u64 {
+    request_body_limit().load(Ordering::Relaxed)
+}
+
+fn configured_max_request_body() -> u64 {
+    setting(X_PROXY_MAX_REQUEST_BODY)
+        .ok()
+        .and_then(|m| parse_size(&m))
+        .unwrap_or(u64::MAX)
+}
+
+fn request_body_limit() -> &'static AtomicU64 {
+    static MAX: OnceLock<AtomicU64> = OnceLock::new();
+    MAX.get_or_init(|| AtomicU64::new(configured_max_request_body()))
+}
+
+/// Read `X_PROXY_MAX_REQUEST_BODY` again, uploads already under way carry on
+pub(crate) fn reload_max_request_body() {
+    request_body_limit().store(configured_max_request_body(), Ordering::Relaxed);
+}
+
+/// How the end of a message body is found
+#[derive(Debug, PartialEq)]
+enum BodyLength {
+    /// There is no body
+    Empty,
+    /// Exactly this many bytes follow the header
+    Length(u64),
+    /// `Transfer-Encoding: chunked`
+    Chunked,
+    /// Everything until the connection closes, only possible for responses
+    UntilClose,
+}
+
+/// Why a body couldn't be relayed
+#[derive(Debug, PartialEq)]
+enum BodyError {
+    /// The body was cut short, malformed or couldn't be written
+    Broken,
+    /// The body is larger than it's allowed to be
+    TooLarge,
+}
+
+/* A request without either header has no body, RFC 9112 section 6.3. One with both, or with
+ * lengths that disagree, is refused since the origin could find the end somewhere else */
+fn request_body(header: &HttpRequestHeader<'_>) -> Option<BodyLength> {
+    let mut lengths = header
+        .headers
+        .get_all("Content-Length")
+        .flat_map(|l| l.split(','))
+        .map(|l| l.trim().parse::<u64>().ok());
+
+    if header.headers.contains_key("Transfer-Encoding") {
+        return match lengths.next().is_none() && header.headers.is_chunked() {
+            true => Some(BodyLength::Chunked),
+            false => None,
+        };
+    }
+
+    let length = match lengths.next() {
+        None => return Some(BodyLength::Empty),
+        Some(l) => l?,
+    };
+    match lengths.all(|l| l == Some(length)) {
+        true => Some(BodyLength::Length(length)),
+        false => None,
+    }
+}
+
+fn response_body(method: &HttpRequestMethod, header: &HttpResponseHeader) -> BodyLength {
+    let code = header.status.to_code();
+    if *method == HttpRequestMethod::Head
+        || (100..200).contains(&code)
+        || code == 204
+        || code == 304
+    {
+        return BodyLength::Empty;
+    }
+
+    if header.headers.contains_key("Transfer-Encoding") {
+        return match header.headers.is_chunked() {
+            true => BodyLength::Chunked,
+            false => BodyLength::UntilClose,
+        };
+    }
+
+    match header
+        .headers
+        .get("Content-Length")
+        .and_then(|l| l.trim().parse().ok())
+    {
+        Some(l) => BodyLength::Length(l),
+        None => BodyLength::UntilClose,
+    }
+}
+
+/// Copy a chunked body as is, chunk size lines and trailers included.
+/// The chunks may hold no more than `limit` bytes between them.
+async fn relay_chunks<R, W>(reader: &mut R, writer: &mut W, limit: u64) -> Result<(), BodyError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = Vec::new();
+    let mut total = 0u64;
+
+    loop {
+        let size = read_chunk_size(reader, &mut line)
+            .await
+            .ok_or(BodyError::Broken)?;
+
+        total = total.saturating_add(size);
+        if total > limit {
+            return Err(BodyError::TooLarge);
+        }
+        if writer.write_all(&line).await.is_err() {
+            return Err(BodyError::Broken);
+        }
+
+        if size == 0 {
+            break;
+        }
+
+        match copy(&mut reader.take(size), writer).await {
+            Ok(n) if n == size => {}
+            _ => return Err(BodyError::Broken),
+        }
+        if !read_chunk_end(reader, &mut line).await || writer.write_all(&line).await.is_err() {
+            return Err(BodyError::Broken);
+        }
+    }
+
+    let trailers = read_trailers(reader).await.ok_or(BodyError::Broken)?;
+    match writer.write_all(&trailers).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err(BodyError::Broken),
+    }
+}
+
+async fn relay_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: &BodyLength,
+    limit: u64,
+) -> Result<(), BodyError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let relayed = match length {
+        BodyLength::Empty => true,
+        BodyLength::Length(l) if *l > limit => return Err(BodyError::TooLarge),
+        BodyLength::Length(l) => {
+            matches!(copy(&mut reader.take(*l), writer).await, Ok(n) if n == *l)
+        }
+        BodyLength::Chunked => {
+            relay_chunks(reader, writer, limit).await?;
+            true
+        }
+        BodyLength::UntilClose => copy(reader, writer).await.is_ok(),
+    };
+
+    match relayed && writer.flush().await.is_ok() {
+        true => Ok(()),
+        false => Err(BodyError::Broken),
+    }
+}
+
+/// Read and throw away the body of a request that isn't relayed to the origin, so the next request
+/// on the connection is read from where it starts. The request is left without a body.
+pub(crate) async fn discard_request_body<T>(
+    stream: &mut T,
+    client_request_header: &mut HttpRequestHeader<'_>,
+) -> Result<(), HttpResponseStatus>
+where
+    T: AsyncBufRead + Unpin,
+{
+    let length = request_body(client_request_header).ok_or(HttpResponseStatus::BAD_REQUEST)?;
+    if length == BodyLength::Empty {
+        return Ok(());
+    }
+
+    match relay_body(stream, &mut sink(), &length, max_request_body()).await {
+        Ok(_) => {
+            client_request_header.headers.remove("Content-Length");
+            client_request_header.headers.remove("Transfer-Encoding");
+            Ok(())
+        }
+        Err(BodyError::TooLarge) => Err(HttpResponseStatus::CONTENT_TOO_LARGE),
+        Err(BodyError::Broken) => Err(HttpResponseStatus::BAD_REQUEST),
+    }
+}
+
+/// The protocols a request asks the origin to switch the connection to, such as `websocket`.
+/// An `Upgrade` only counts when `Connection` lists it, as RFC 9110 section 7.8 requires.
+pub(crate) fn requested_upgrade(header: &HttpRequestHeader<'_>) -> Option<String> {
+    if header.version == HttpVersion::HTTP_V10 || !header.headers.has_token("Connection", "upgrade")
+    {
+        return None;
+    }
+
+    header
+        .headers
+        .get("Upgrade")
+        .map(|u| u.trim().to_string())
+        .filter(|u| !u.is_empty())
+}
+
+/// Send a request that can't be cached, such as an upload, to the origin with its body
+/// and relay the response back to the client. Nothing is stored but a cached copy of the URL
+/// is removed when the origin accepts the request, since it has probably changed.
+/// A request to upgrade the connection, such as to a WebSocket, becomes a two way copy
+/// once the origin switches protocols.
+pub(crate) async fn relay_request<T>(
+    mut stream: T,
+    flights: &Arc<Flights>,
+    client_request_header: &HttpRequestHeader<'_>,
+    cancel: &Cancellation,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) -> ConnectionReturn
+where
+    T: AsyncBufRead + AsyncRead + AsyncWrite + Unpin,
+{
+    let request_length = match request_body(client_request_header) {
+        Some(l) => l,
+        None => return respond_with(Close, HttpResponseStatus::BAD_REQUEST, &mut stream).await,
+    };
+
+    /* Turned away before the origin is bothered, the body is never read so the connection closes */
+    if matches!(request_length, BodyLength::Length(l) if l > max_request_body()) {
+        return respond_with(Close, HttpResponseStatus::CONTENT_TOO_LARGE, &mut stream).await;
+    }
+
+    let mut fetch_request = match FetchRequest::from_uri(&client_request_header.request) {
+        Ok(o) => o,
+        Err(_) => {
+            return respond_with(
+                Close,
+                HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                &mut stream,
+            )
+            .await
+        }
+    };
+
+    if let Err(e) = fetch_request
+        .connect(
+            #[cfg(feature = "https")]
+            certificates,
+        )
+        .await
+    {
+        /* The body was never read so the connection can't carry another request */
+        return respond_connect_error(&e, &mut stream).await;
+    }
+
+    let uri = Uri::from(fetch_request.uri());
+    let (host, path_and_query) = match (uri.host_and_port(), uri.path_and_query) {
+        (Some(h), Some(p)) => (h, p.to_string()),
+        _ => return respond_with(Close, HttpResponseStatus::BAD_REQUEST, &mut stream).await,
+    };
+
+    let upgrade = requested_upgrade(client_request_header);
+    let upstream_request = HttpRequestHeader {
+        method: client_request_header.method.clone(),
+        request: Uri::from(path_and_query),
+        version: HttpVersion::HTTP_V11,
+        headers: {
+            let mut headers = client_request_header.headers.clone();
+            headers.strip_hop_by_hop();
+            headers.strip_proxy_only();
+            headers.insert("Host".to_string(), host);
+            /* Repeated lengths that agree are sent on as one */
+            if let BodyLength::Length(l) = request_length {
+                headers.insert("Content-Length".to_string(), l.to_string());
+            }
+            apply_upstream_credentials(&uri, &mut headers);
+            match &upgrade {
+                Some(u) => {
+                    headers.insert("Connection".to_string(), "Upgrade".to_string());
+                    headers.insert("Upgrade".to_string(), u.clone());
+                }
+                /* One request per connection keeps a response without a length from being ambiguous */
+                None => {
+                    headers.insert("Connection".to_string(), "close".to_string());
+                }
+            }
+            headers
+        },
+    };
+
+    let request = match upstream_request.generate() {
+        Some(r) => r,
+        None => {
+            return respond_with(
+                Close,
+                HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                &mut stream,
+            )
+            .await
+        }
+    };
+
+    /* The origin is asked on the client's behalf, so the client can send its body straight away */
+    if client_request_header
+        .headers
+        .get("Expect")
+        .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"))
+        && stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .is_err()
+    {
+        return Close;
+    }
+
+    let mut fetch_stream = match fetch_request.as_stream() {
+        None => {
+            return respond_with(
+                Close,
+                HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                &mut stream,
+            )
+            .await
+        }
+        Some(f) => BufReader::new(Cancellable::new(f, cancel)),
+    };
+
+    debug!("Relaying {} {}", client_request_header.method, uri.uri);
+    wire_log("Upstream request", &uri.uri, || request.clone());
+
+    if fetch_stream.write_all(request.as_bytes()).await.is_err() {
+        return respond_with(Close, HttpResponseStatus::BAD_GATEWAY, &mut stream).await;
+    }
+    match relay_body(
+        &mut Capturing::new(&mut stream, capture_body("request", &uri.uri)),
+        &mut fetch_stream,
+        &request_length,
+        max_request_body(),
+    )
+    .await
+    {
+        Ok(_) => {}
+        /* The origin is left with a body cut short, so it never sees a complete request */
+        Err(BodyError::TooLarge) => {
+            return respond_with(Close, HttpResponseStatus::CONTENT_TOO_LARGE, &mut stream).await
+        }
+        Err(BodyError::Broken) => {
+            return respond_with(Close, HttpResponseStatus::BAD_GATEWAY, &mut stream).await
+        }
+    }
+
+    let mut response = match final_response(
+        &mut fetch_stream,
+        &mut stream,
+        client_request_header,
+        upgrade.is_some(),
+        &uri.uri,
+    )
+    .await
+    {
+        Ok(r) => r,
+        Err(c) => return c,
+    };
+
+    if response.status.to_code() == HttpResponseStatus::SWITCHING_PROTOCOLS.to_code() {
+        return relay_upgrade(&mut stream, &mut fetch_stream, response, &uri.uri).await;
+    }
+
+    let response_length = response_body(&client_request_header.method, &response);
+    let connection = match response_length {
+        BodyLength::UntilClose => Close,
+        _ => keep_alive_if(client_request_header),
+    };
+
+    response.headers.strip_hop_by_hop();
+    apply_via(&mut response.headers, &response.version);
+    response.headers.insert(
+        "Connection".to_string(),
+        match connection {
+            Close => "close",
+            _ => "keep-alive",
+        }
+        .to_string(),
+    );
+
+    let header = response.generate();
+    wire_log("Client response", &uri.uri, || header.clone());
+    if stream.write_all(header.as_bytes()).await.is_err()
+        || relay_body(
+            &mut Capturing::new(&mut fetch_stream, capture_body("response", &uri.uri)),
+            &mut stream,
+            &response_length,
+            u64::MAX,
+        )
+        .await
+        .is_err()
+    {
+        return Close;
+    }
+
+    /* Requests for files that can't be cached are relayed too, they change nothing */
+    if client_request_header.method != HttpRequestMethod::Get
+        && (200..400).contains(&response.status.to_code())
+    {
+        invalidate(client_request_header, flights).await;
+    }
+
+    connection
+}
+
+/* Interim responses such as `103 Early Hints` are passed on as they arrive until the final one.
+ * An HTTP/1.0 client doesn't expect them (RFC 9110 section 15.2) and `100 Continue` was already
+ * sent by the proxy itself, so those are dropped. `101` is only final when an upgrade was asked for. */
+async fn final_response<C, O>(
+    fetch_stream: &mut BufReader<O>,
+    stream: &mut C,
+    client_request_header: &HttpRequestHeader<'_>,
+    upgrade: bool,
+    uri: &str,
+) -> Result<HttpResponseHeader, ConnectionReturn>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    loop {
+        let mut response = match HttpResponseHeader::from_tcp_buffer_async(fetch_stream).await {
+            Some(r) => r,
+            None => return Err(respond_header_error(Close, started, stream).await),
+        };
+
+        let code = response.status.to_code();
+        if !(100..200).contains(&code) {
+            return Ok(response);
+        }
+        if code == HttpResponseStatus::SWITCHING_PROTOCOLS.to_code() {
+            return match upgrade {
+                true => Ok(response),
+                /* Whatever the connection speaks now, it isn't HTTP the proxy can read */
+                false => Err(respond_with(Close, HttpResponseStatus::BAD_GATEWAY, stream).await),
+            };
+        }
+        if code == HttpResponseStatus::CONTINUE.to_code()
+            || client_request_header.version == HttpVersion::HTTP_V10
+        {
+            continue;
+        }
+
+        response.headers.strip_hop_by_hop();
+        apply_via(&mut response.headers, &response.version);
+        let header = response.generate();
+        wire_log("Client response", uri, || header.clone());
+        if stream.write_all(header.as_bytes()).await.is_err() || stream.flush().await.is_err() {
+            return Err(Close);
+        }
+    }
+}
+
+/* The origin agreed to switch protocols, so once the client has been told the connection
+ * carries whatever the two of them speak and is copied both ways untouched */
+async fn relay_upgrade<C, O>(
+    stream: &mut C,
+    fetch_stream: &mut O,
+    mut response: HttpResponseHeader,
+    uri: &str,
+) -> ConnectionReturn
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let protocol = response.headers.get("Upgrade").cloned();
+    response.headers.strip_hop_by_hop();
+    apply_via(&mut response.headers, &response.version);
+    response
+        .headers
+        .insert("Connection".to_string(), "Upgrade".to_string());
+    if let Some(p) = protocol {
+        response.headers.insert("Upgrade".to_string(), p);
+    }
+
+    let header = response.generate();
+    wire_log("Client response", uri, || header.clone());
+    if stream.write_all(header.as_bytes()).await.is_err() || stream.flush().await.is_err() {
+        return Close;
+    }
+
+    debug!("Connection to {uri} upgraded");
+    splice(stream, fetch_stream, TUNNEL_IDLE_TIMEOUT).await;
+    debug!("Upgraded connection to {uri} is closed");
+
+    /* Whatever was spoken after the switch, the connection can't go back to being HTTP */
+    Close
+}
+
+/* A successful change to a URL means the copy in the cache is out of date */
+async fn invalidate(client_request_header: &HttpRequestHeader<'_>, flights: &Flights) {
+    let cache_file_path = match get_cache_name(client_request_header).await {
+        Some(p) => p,
+        None => return,
+    };
+
+    let hash = cache_file_path.to_string_lossy().to_string();
+    forget_head(&hash);
+    if !flights.is_in_flight(&hash).await && remove_file(&cache_file_path).await.is_ok() {
+        debug!("Removed {hash} from the cache after a change to it");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_relay_chunks() {
+        let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nNEXT";
+        let mut reader = BufReader::new(&body[..]);
+        let mut relayed = Vec::new();
+
+        assert_eq!(relay_chunks(&mut reader, &mut relayed, 9).await, Ok(()));
+        assert_eq!(relayed, &body[..body.len() - 4]);
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[tokio::test]
+    async fn test_relay_chunks_truncated() {
+        let body = b"A\r\nshort";
+        let mut relayed = Vec::new();
+        assert_eq!(
+            relay_chunks(&mut BufReader::new(&body[..]), &mut relayed, u64::MAX).await,
+            Err(BodyError::Broken)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relay_chunks_too_large() {
+        let body = b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        let mut relayed = Vec::new();
+        assert_eq!(
+            relay_chunks(&mut BufReader::new(&body[..]), &mut relayed, 8).await,
+            Err(BodyError::TooLarge)
+        );
+        /* Nothing past the limit is passed on */
+        assert_eq!(relayed, b"4\r\nWiki\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_discard_request_body() {
+        let mut request = HttpRequestHeader {
+            method: HttpRequestMethod::Get,
+            request: Uri::from("http://deb.debian.org/".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers: Default::default(),
+        };
+        request
+            .headers
+            .insert("Content-Length".to_string(), "4".to_string());
+        let mut reader = BufReader::new(&b"bodyGET / HTTP/1.1"[..]);
+
+        assert!(discard_request_body(&mut reader, &mut request)
+            .await
+            .is_ok());
+        assert_eq!(request_body(&request), Some(BodyLength::Empty));
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1");
+    }
+
+    fn upload(headers: &[(&str, &str)]) -> HttpRequestHeader<'static> {
+        let mut request = HttpRequestHeader {
+            method: HttpRequestMethod::Post,
+            request: Uri::from("http://example.com/upload".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers: Default::default(),
+        };
+        for (name, value) in headers {
+            request.headers.append(name.to_string(), value.to_string());
+        }
+        request
+    }
+
+    #[test]
+    fn test_request_body_with_both_lengths() {
+        let request = upload(&[("Content-Length", "4"), ("Transfer-Encoding", "chunked")]);
+        assert_eq!(request_body(&request), None);
+
+        let request = upload(&[("Transfer-Encoding", "chunked"), ("Content-Length", "4")]);
+        assert_eq!(request_body(&request), None);
+
+        let request = upload(&[("Transfer-Encoding", "chunked")]);
+        assert_eq!(request_body(&request), Some(BodyLength::Chunked));
+    }
+
+    #[test]
+    fn test_request_body_with_conflicting_lengths() {
+        let request = upload(&[("Content-Length", "4"), ("Content-Length", "5")]);
+        assert_eq!(request_body(&request), None);
+
+        let request = upload(&[("Content-Length", "4, 5")]);
+        assert_eq!(request_body(&request), None);
+
+        let request = upload(&[("Content-Length", "4"), ("Content-Length", "four")]);
+        assert_eq!(request_body(&request), None);
+
+        /* Repeated lengths that agree are the same length */
+        let request = upload(&[("Content-Length", "4"), ("Content-Length", "4, 4")]);
+        assert_eq!(request_body(&request), Some(BodyLength::Length(4)));
+    }
+
+    #[tokio::test]
+    async fn test_final_response() {
+        let responses = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let request = upload(&[]);
+
+        let mut origin = BufReader::new(std::io::Cursor::new(responses.to_vec()));
+        let mut client = std::io::Cursor::new(Vec::new());
+        match final_response(&mut origin, &mut client, &request, false, "").await {
+            Ok(r) => assert_eq!(r.status.to_code(), 200),
+            Err(_) => panic!("the final response wasn't read"),
+        }
+
+        /* Only the early hints reach the client, the body is left to be relayed */
+        let forwarded = String::from_utf8(client.into_inner()).unwrap();
+        assert!(forwarded.starts_with("HTTP/1.1 103 "));
+        assert!(forwarded.contains("Link: </style.css>; rel=preload\r\n"));
+        assert_eq!(forwarded.matches("HTTP/1.1").count(), 1);
+        let mut rest = String::new();
+        origin.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "ok");
+
+        /* An HTTP/1.0 client never sees them */
+        let mut request = upload(&[]);
+        request.version = HttpVersion::HTTP_V10;
+        let mut origin = BufReader::new(std::io::Cursor::new(responses.to_vec()));
+        let mut client = std::io::Cursor::new(Vec::new());
+        match final_response(&mut origin, &mut client, &request, false, "").await {
+            Ok(r) => assert_eq!(r.status.to_code(), 200),
+            Err(_) => panic!("the final response wasn't read"),
+        }
+        assert!(client.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_requested_upgrade() {
+        let mut request = HttpRequestHeader {
+            method: HttpRequestMethod::Get,
+            request: Uri::from("http://example.com/chat".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers: Default::default(),
+        };
+        request
+            .headers
+            .insert("Upgrade".to_string(), " websocket ".to_string());
+        assert_eq!(requested_upgrade(&request), None);
+
+        request
+            .headers
+            .insert("Connection".to_string(), "keep-alive, Upgrade".to_string());
+        assert_eq!(requested_upgrade(&request), Some("websocket".to_string()));
+
+        request.version = HttpVersion::HTTP_V10;
+        assert_eq!(requested_upgrade(&request), None);
+    }
+
+    #[tokio::test]
+    async fn test_relay_upgrade() {
+        let (mut client, mut proxy_client) = tokio::io::duplex(64);
+        let (mut origin, mut proxy_origin) = tokio::io::duplex(64);
+
+        let mut response = HttpResponseHeader {
+            status: HttpResponseStatus::SWITCHING_PROTOCOLS,
+            headers: Default::default(),
+            version: HttpVersion::HTTP_V11,
+        };
+        response
+            .headers
+            .insert("Upgrade".to_string(), "websocket".to_string());
+        response
+            .headers
+            .insert("Connection".to_string(), "Upgrade".to_string());
+
+        let relay = tokio::spawn(async move {
+            relay_upgrade(
+                &mut proxy_client,
+                &mut proxy_origin,
+                response,
+                "http://example.com/chat",
+            )
+            .await
+        });
+
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(client.read_u8().await.unwrap());
+        }
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.starts_with("HTTP/1.1 101 "));
+        assert!(header.contains("Upgrade: websocket\r\n"));
+        assert!(header.contains("Connection: Upgrade\r\n"));
+
+        /* Bytes go both ways as they were sent */
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        origin.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        origin.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+
+        drop(client);
+        drop(origin);
+        assert!(matches!(relay.await.unwrap(), Close));
+    }
+
+    #[test]
+    fn test_response_body() {
+        let mut response = HttpResponseHeader {
+            status: HttpResponseStatus::OK,
+            headers: Default::default(),
+            version: HttpVersion::HTTP_V11,
+        };
+        assert_eq!(
+            response_body(&HttpRequestMethod::Post, &response),
+            BodyLength::UntilClose
+        );
+
+        response
+            .headers
+            .insert("Content-Length".to_string(), "12".to_string());
+        assert_eq!(
+            response_body(&HttpRequestMethod::Post, &response),
+            BodyLength::Length(12)
+        );
+        assert_eq!(
+            response_body(&HttpRequestMethod::Head, &response),
+            BodyLength::Empty
+        );
+
+        response
+            .headers
+            .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        assert_eq!(
+            response_body(&HttpRequestMethod::Put, &response),
+            BodyLength::Chunked
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use {
+    crate::{
+        auth::reload_auth, cli::reload_config, log::reload_logging, policy::reload_denied_networks,
+        relay::reload_max_request_body, rules::reload_cache_rules, serve::reload_keep_alive,
+    },
+    std::sync::OnceLock,
+    tokio::sync::Notify,
+    tracing::{info, warn},
+};
+
+#[cfg(feature = "https")]
+use {
+    crate::cert::{reload_certificates, CertificateSetup},
+    std::sync::Arc,
+};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+fn reload_requested() -> &'static Notify {
+    static REQUESTED: OnceLock<Notify> = OnceLock::new();
+    REQUESTED.get_or_init(Notify::new)
+}
+
+/// Reload the configuration as though `SIGHUP` was sent, such as when an administrator asks
+#[cfg(feature = "web-ui")]
+pub(crate) fn request_reload() {
+    reload_requested().notify_one();
+}
+
+/* Resolves each time SIGHUP arrives, never when it can't be listened for */
+#[cfg(unix)]
+async fn hangup(signal: &mut Option<Signal>) {
+    if let Some(s) = signal {
+        if s.recv().await.is_some() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Read the configuration file again whenever `SIGHUP` arrives or [`request_reload`] is called,
+/// then take up the cache rules, denied networks, client logins and certificates it gives.
+/// Transfers in progress carry on with what they started with.
+pub(crate) async fn reload_loop(#[cfg(feature = "https")] certificates: Arc<CertificateSetup>) {
+    #[cfg(unix)]
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            warn!("can't reload its configuration on SIGHUP: {e}");
+            None
+        }
+    };
+
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = hangup(&mut hangups) => {}
+            _ = reload_requested().notified() => {}
+        }
+        #[cfg(not(unix))]
+        reload_requested().notified().await;
+
+        if let Err(e) = reload_config() {
+            warn!("kept the configuration it had, {e}");
+            continue;
+        }
+
+        reload_logging();
+        reload_cache_rules();
+        reload_denied_networks();
+        reload_keep_alive();
+        reload_max_request_body();
+        reload_auth(
+            #[cfg(all(feature = "ldap", feature = "https"))]
+            &certificates,
+        );
+        #[cfg(feature = "https")]
+        reload_certificates(&certificates);
+
+        info!("reloaded its configuration");
+    }
+}
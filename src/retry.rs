@@ -0,0 +1,120 @@
+use {
+    crate::{cli::setting, conn::FetchRequestError, rules::parse_duration},
+    std::{
+        sync::OnceLock,
+        time::{Duration, SystemTime},
+    },
+};
+
+pub const X_PROXY_UPSTREAM_RETRIES: &str = "X_PROXY_UPSTREAM_RETRIES";
+pub const X_PROXY_UPSTREAM_RETRY_DELAY: &str = "X_PROXY_UPSTREAM_RETRY_DELAY";
+
+/// How many times a failed fetch is tried again when `X_PROXY_UPSTREAM_RETRIES` isn't defined
+const DEFAULT_RETRIES: usize = 2;
+
+/// How long the first retry waits when `X_PROXY_UPSTREAM_RETRY_DELAY` isn't defined
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest a client is kept waiting for one retry, an origin asking
+/// for a longer `Retry-After` has its answer passed on instead
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How many times a fetch that failed for a reason that may pass is tried again, `0` never retries
+pub(crate) fn upstream_retries() -> usize {
+    static RETRIES: OnceLock<usize> = OnceLock::new();
+    *RETRIES.get_or_init(|| {
+        setting(X_PROXY_UPSTREAM_RETRIES)
+            .ok()
+            .and_then(|r| r.trim().parse().ok())
+            .unwrap_or(DEFAULT_RETRIES)
+    })
+}
+
+fn retry_delay() -> Duration {
+    static DELAY: OnceLock<Duration> = OnceLock::new();
+    *DELAY.get_or_init(|| {
+        setting(X_PROXY_UPSTREAM_RETRY_DELAY)
+            .ok()
+            .and_then(|d| parse_duration(&d))
+            .unwrap_or(DEFAULT_RETRY_DELAY)
+    })
+}
+
+/* The delay doubles with each retry made */
+fn backoff_from(delay: Duration, attempts: usize) -> Duration {
+    delay
+        .saturating_mul(1 << attempts.min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// How long to wait before trying again when `attempts` retries were already made
+pub(crate) fn backoff(attempts: usize) -> Duration {
+    backoff_from(retry_delay(), attempts)
+}
+
+/// Whether connecting failed for a reason that may pass by trying again,
+/// the connection being refused, reset or timing out
+pub(crate) fn transient(error: &FetchRequestError) -> bool {
+    matches!(
+        error,
+        FetchRequestError::TcpConnectionError(_) | FetchRequestError::UpstreamTimeout(_)
+    )
+}
+
+/// How long a `Retry-After` of seconds or a date asks to wait from `now` (RFC 9110 section 10.2.3),
+/// nothing when there isn't one or it's longer than a client should be kept waiting
+pub(crate) fn retry_after(value: Option<&String>, now: SystemTime) -> Option<Duration> {
+    let value = value?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(s) => Duration::from_secs(s),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(now)
+            .unwrap_or_default(),
+    };
+    Some(delay).filter(|d| *d <= MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_from() {
+        let delay = Duration::from_secs(1);
+        assert_eq!(backoff_from(delay, 0), Duration::from_secs(1));
+        assert_eq!(backoff_from(delay, 1), Duration::from_secs(2));
+        assert_eq!(backoff_from(delay, 3), Duration::from_secs(8));
+        assert_eq!(backoff_from(delay, 10), MAX_RETRY_DELAY);
+        assert_eq!(backoff_from(delay, usize::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        let after = |v: &str| retry_after(Some(&v.to_string()), now);
+
+        assert_eq!(after("5"), Some(Duration::from_secs(5)));
+        assert_eq!(after("120"), None);
+        assert_eq!(
+            after("Sun, 06 Nov 1994 08:49:47 GMT"),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(after("Sun, 06 Nov 1994 08:49:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(after("soon"), None);
+        assert_eq!(retry_after(None, now), None);
+    }
+
+    #[test]
+    fn test_transient() {
+        assert!(transient(&FetchRequestError::TcpConnectionError(
+            "reset".to_string()
+        )));
+        assert!(transient(&FetchRequestError::UpstreamTimeout(
+            "slow".to_string()
+        )));
+        assert!(!transient(&FetchRequestError::DnsResolutionError(
+            "none".to_string()
+        )));
+    }
+}
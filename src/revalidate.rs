@@ -0,0 +1,280 @@
+use {
+    crate::{
+        cli::setting,
+        conn::{FetchRequest, Flights, Uri},
+        cookie::apply_cookie_jar,
+        credentials::apply_upstream_credentials,
+        dedup::deduplicate,
+        digest::{inspect_download, Digesting, Download, Verdict},
+        evict::{hottest, Hits},
+        http::{
+            HttpHeader, HttpRequestHeader, HttpRequestMethod, HttpResponseHeader, HttpVersion,
+            X_PROXY_CACHE_PATH,
+        },
+        maintenance::in_maintenance,
+        rules::{cache_rule, parse_duration, upstream_accept},
+        watchdog::shedding,
+    },
+    std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, SystemTime},
+    },
+    tokio::{
+        fs::{copy, remove_file, rename, File},
+        io::{AsyncReadExt, AsyncWriteExt, BufReader},
+        time::sleep,
+    },
+    tracing::{debug, error, info},
+};
+
+#[cfg(feature = "https")]
+use crate::cert::CertificateSetup;
+
+pub const X_PROXY_REVALIDATE_INTERVAL: &str = "X_PROXY_REVALIDATE_INTERVAL";
+pub const X_PROXY_REVALIDATE_COUNT: &str = "X_PROXY_REVALIDATE_COUNT";
+
+const DEFAULT_REVALIDATE_COUNT: usize = 10;
+
+/// Kept in the cache root where it can't be mistaken for a cache entry
+const TEMPORARY_FILE_NAME: &str = "revalidating";
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// The origin has the same version as the cache
+    Unchanged,
+    /// A newer version was downloaded and replaced the cached one
+    Replaced,
+}
+
+fn parse_schedule(interval: &str, count: Option<&str>) -> Option<(Duration, usize)> {
+    let interval = parse_duration(interval).filter(|d| !d.is_zero())?;
+    let count = match count {
+        None => DEFAULT_REVALIDATE_COUNT,
+        Some(c) => c.trim().parse().ok()?,
+    };
+
+    Some((interval, count))
+}
+
+/// How often and how many of the most popular entries to revalidate, `None` when switched off
+pub(crate) fn revalidate_schedule() -> Option<(Duration, usize)> {
+    let interval = setting(X_PROXY_REVALIDATE_INTERVAL).ok()?;
+    let count = setting(X_PROXY_REVALIDATE_COUNT).ok();
+
+    let schedule = parse_schedule(&interval, count.as_deref());
+    if schedule.is_none() {
+        error!("'{X_PROXY_REVALIDATE_INTERVAL}' or '{X_PROXY_REVALIDATE_COUNT}' is not valid");
+    }
+    schedule
+}
+
+/// Every `interval`, ask the origin whether the `count` most served cache entries have changed
+/// and download them again if they have. Entries with a `ttl` rule have their age restarted
+/// when the origin confirms they're unchanged so they don't expire while popular.
+pub(crate) async fn revalidation_loop(
+    flights: Arc<Flights>,
+    interval: Duration,
+    count: usize,
+    #[cfg(feature = "https")] certificates: Arc<CertificateSetup>,
+) {
+    let temporary = match setting(X_PROXY_CACHE_PATH) {
+        Ok(p) => PathBuf::from(p).join(TEMPORARY_FILE_NAME),
+        Err(_) => return,
+    };
+
+    loop {
+        sleep(interval).await;
+
+        for (path, hits) in hottest(count) {
+            /* Refreshing can wait until rproxy has resources to spare */
+            if shedding() {
+                break;
+            }
+
+            let cache_file_path = PathBuf::from(&path);
+            if flights.is_in_flight(&path).await || !cache_file_path.is_file() {
+                continue;
+            }
+
+            /* The copy that's cached is kept as it is until the origin is back */
+            if Uri::from(&hits.uri).host.is_some_and(in_maintenance) {
+                continue;
+            }
+
+            match revalidate(
+                &cache_file_path,
+                &hits,
+                &temporary,
+                #[cfg(feature = "https")]
+                &certificates,
+            )
+            .await
+            {
+                Some(Outcome::Replaced) => {
+                    info!("refreshed '{}' from the origin", hits.uri)
+                }
+                Some(Outcome::Unchanged) => debug!("{} is unchanged", hits.uri),
+                None => debug!("Couldn't revalidate {}", hits.uri),
+            }
+        }
+
+        let _ = remove_file(&temporary).await;
+    }
+}
+
+/* Renamed over the cached file so clients already reading it carry on with the old version */
+async fn replace_with(
+    temporary: &Path,
+    cache_file_path: &Path,
+    modified: Option<SystemTime>,
+) -> bool {
+    if let Some(modified) = modified {
+        if let Ok(file) = File::options().write(true).open(temporary).await {
+            let _ = file.into_std().await.set_modified(modified);
+        }
+    }
+
+    rename(temporary, cache_file_path).await.is_ok()
+}
+
+async fn revalidate(
+    cache_file_path: &Path,
+    hits: &Hits,
+    temporary: &Path,
+    #[cfg(feature = "https")] certificates: &CertificateSetup,
+) -> Option<Outcome> {
+    let modified = tokio::fs::metadata(cache_file_path)
+        .await
+        .ok()?
+        .modified()
+        .ok();
+
+    let uri = Uri::from(&hits.uri);
+    let mut fetch_request = FetchRequest::from_uri(&uri).ok()?;
+    fetch_request
+        .connect(
+            #[cfg(feature = "https")]
+            certificates,
+        )
+        .await
+        .ok()?;
+
+    let mut headers = HttpHeader::new();
+    headers.insert("Host".to_string(), uri.host_and_port()?);
+    headers.insert("Accept-Encoding".to_string(), "identity".to_string());
+    headers.insert("Connection".to_string(), "close".to_string());
+    if let Some(accept) = upstream_accept(&hits.uri) {
+        headers.insert("Accept".to_string(), accept.to_string());
+    }
+    if let Some(modified) = modified {
+        headers.insert(
+            "If-Modified-Since".to_string(),
+            httpdate::fmt_http_date(modified),
+        );
+    }
+    apply_cookie_jar(&uri, &mut headers);
+    apply_upstream_credentials(&uri, &mut headers);
+
+    let request = HttpRequestHeader {
+        method: HttpRequestMethod::Get,
+        request: Uri::from(uri.path_and_query?.to_string()),
+        version: HttpVersion::HTTP_V11,
+        headers,
+    };
+
+    let mut fetch_stream = fetch_request.as_stream()?;
+    fetch_stream
+        .write_all(request.generate()?.as_bytes())
+        .await
+        .ok()?;
+
+    let mut reader = BufReader::new(&mut fetch_stream);
+    let response = HttpResponseHeader::from_tcp_buffer_async(&mut reader).await?;
+
+    match response.status.to_code() {
+        304 => {
+            /* Only an age limit makes restarting the clock worth copying the file for */
+            if cache_rule(&hits.uri).is_some_and(|r| r.ttl.is_some()) {
+                copy(cache_file_path, temporary).await.ok()?;
+                if !replace_with(temporary, cache_file_path, modified).await {
+                    return None;
+                }
+            }
+            Some(Outcome::Unchanged)
+        }
+        200 => {
+            let no_store = response.headers.has_token("Cache-Control", "no-store")
+                || response.headers.has_token("Cache-Control", "private");
+            if no_store || !response.is_identity_encoded() {
+                return None;
+            }
+
+            /* Chunked responses are left for the next client to fetch */
+            let length = response
+                .headers
+                .get("Content-Length")?
+                .parse::<u64>()
+                .ok()?;
+
+            let mut file = File::create(temporary).await.ok()?;
+            let mut body = Digesting::new(&mut file);
+            match tokio::io::copy(&mut reader.take(length), &mut body).await {
+                Ok(n) if n == length => {}
+                _ => return None,
+            }
+            let digest = body.finish();
+            file.flush().await.ok()?;
+            drop(file);
+
+            let last_modified = response
+                .headers
+                .get("Last-Modified")
+                .and_then(|l| httpdate::parse_http_date(l).ok());
+            if !replace_with(temporary, cache_file_path, last_modified).await {
+                return None;
+            }
+
+            let modified = tokio::fs::metadata(cache_file_path)
+                .await
+                .ok()
+                .and_then(|m| m.modified().ok());
+            let download = Download {
+                uri: &hits.uri,
+                path: cache_file_path,
+                digest: &digest,
+            };
+            match inspect_download(&download, modified) {
+                Verdict::Keep => {
+                    deduplicate(&hits.uri, cache_file_path, &digest).await;
+                    Some(Outcome::Replaced)
+                }
+                Verdict::Quarantine => {
+                    let _ = remove_file(cache_file_path).await;
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(
+            parse_schedule("6h", None),
+            Some((Duration::from_secs(6 * 60 * 60), DEFAULT_REVALIDATE_COUNT))
+        );
+        assert_eq!(
+            parse_schedule("30m", Some("50")),
+            Some((Duration::from_secs(30 * 60), 50))
+        );
+        assert_eq!(parse_schedule("0", None), None);
+        assert_eq!(parse_schedule("soon", None), None);
+        assert_eq!(parse_schedule("1h", Some("many")), None);
+    }
+}
@@ -0,0 +1,115 @@
+use {
+    crate::{
+        cli::setting,
+        conn::{scheme_allowed, scheme_of, Uri, UriKind},
+    },
+    std::sync::OnceLock,
+    tracing::{error, info},
+};
+
+pub const X_PROXY_REVERSE_LISTEN_ADDRESS: &str = "X_PROXY_REVERSE_LISTEN_ADDRESS";
+pub const X_PROXY_REVERSE_UPSTREAM: &str = "X_PROXY_REVERSE_UPSTREAM";
+
+/// A listener that answers for one origin server, the way the server itself would.
+/// Clients ask it for paths such as `GET /path` rather than whole URLs.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ReverseProxy {
+    /// Address and port the listener is bound to
+    pub(crate) listen: String,
+    /// URL every path asked for is relative to, without a trailing `/`
+    upstream: String,
+}
+
+impl ReverseProxy {
+    fn new(listen: &str, upstream: &str) -> Result<Self, String> {
+        let upstream = upstream.trim().trim_end_matches('/');
+        let http = scheme_of(upstream).is_some_and(|s| s == "http" || s == "https");
+        if !http || Uri::from(upstream.to_string()).host.is_none() || upstream.contains(['?', '#'])
+        {
+            return Err(format!(
+                "'{X_PROXY_REVERSE_UPSTREAM}' must be an http or https URL: '{upstream}'"
+            ));
+        }
+        if !scheme_allowed(upstream) {
+            return Err(format!(
+                "'{X_PROXY_REVERSE_UPSTREAM}' uses a scheme that isn't allowed: '{upstream}'"
+            ));
+        }
+
+        Ok(ReverseProxy {
+            listen: listen.trim().to_string(),
+            upstream: upstream.to_string(),
+        })
+    }
+
+    /// The URL on the origin server `request` is for, nothing unless it's a path.
+    /// A whole URL would let the listener be used as a proxy to anywhere.
+    pub(crate) fn target<'a>(&self, request: &Uri<'_>) -> Option<Uri<'a>> {
+        match request.kind() {
+            UriKind::AbsolutePath => Some(Uri::from(format!("{}{}", self.upstream, request.uri))),
+            _ => None,
+        }
+    }
+}
+
+static REVERSE: OnceLock<Option<ReverseProxy>> = OnceLock::new();
+
+pub(crate) fn reverse_proxy() -> Option<&'static ReverseProxy> {
+    REVERSE.get().and_then(Option::as_ref)
+}
+
+/// Read `X_PROXY_REVERSE_LISTEN_ADDRESS` and `X_PROXY_REVERSE_UPSTREAM`, which are defined together.
+/// False when only one of them is or the upstream isn't a URL rproxy can fetch from.
+pub(crate) fn setup_reverse() -> bool {
+    let reverse = match (
+        setting(X_PROXY_REVERSE_LISTEN_ADDRESS),
+        setting(X_PROXY_REVERSE_UPSTREAM),
+    ) {
+        (Err(_), Err(_)) => None,
+        (Ok(listen), Ok(upstream)) => match ReverseProxy::new(&listen, &upstream) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                error!("{e}");
+                return false;
+            }
+        },
+        _ => {
+            error!("'{X_PROXY_REVERSE_LISTEN_ADDRESS}' and '{X_PROXY_REVERSE_UPSTREAM}' must be defined together");
+            return false;
+        }
+    };
+
+    if let Some(r) = &reverse {
+        info!("reverse proxying for: {}", r.upstream);
+    }
+    let _ = REVERSE.set(reverse);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_proxy_new() {
+        let r = ReverseProxy::new("[::]:8080", "http://artifacts.internal:8081/repo/").unwrap();
+        assert_eq!(r.upstream, "http://artifacts.internal:8081/repo");
+        assert!(ReverseProxy::new("[::]:8080", "http://artifacts.internal").is_ok());
+        assert!(ReverseProxy::new("[::]:8080", "artifacts.internal").is_err());
+        assert!(ReverseProxy::new("[::]:8080", "ftp://artifacts.internal/").is_err());
+        assert!(ReverseProxy::new("[::]:8080", "http://artifacts.internal/?a=b").is_err());
+    }
+
+    #[test]
+    fn test_target() {
+        let r = ReverseProxy::new("[::]:8080", "http://artifacts.internal:8081/repo/").unwrap();
+        let target = |u: &str| r.target(&Uri::from(u.to_string())).map(|t| t.uri);
+
+        assert_eq!(
+            target("/pool/a.deb?arch=amd64"),
+            Some("http://artifacts.internal:8081/repo/pool/a.deb?arch=amd64".to_string())
+        );
+        assert_eq!(target("http://elsewhere.example/a.deb"), None);
+        assert_eq!(target("elsewhere.example:443"), None);
+    }
+}
@@ -0,0 +1,351 @@
+use {
+    crate::{
+        cli::setting,
+        clock::age,
+        conn::{normalize_uri, Uri},
+    },
+    std::{
+        path::Path,
+        sync::{Arc, OnceLock, RwLock},
+        time::Duration,
+    },
+    tracing::warn,
+};
+
+pub const X_PROXY_CACHE_RULES: &str = "X_PROXY_CACHE_RULES";
+
+/// What to do with URLs matching `pattern`, where `*` matches anything
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct CacheRule {
+    pub(crate) pattern: String,
+    /// Store responses even when the origin asks for them not to be
+    pub(crate) force_cache: bool,
+    /// Always fetch from the origin and never store the response
+    pub(crate) bypass: bool,
+    /// Fetch again once a cached copy is older than this
+    pub(crate) ttl: Option<Duration>,
+    /// Fetch this URL instead, `$1` to `$9` are replaced with what each `*` matched
+    pub(crate) rewrite: Option<String>,
+    /// Always send the origin this `Accept` header so every client gets the same representation
+    pub(crate) accept: Option<String>,
+}
+
+impl CacheRule {
+    fn captures<'a>(&self, uri: &'a str) -> Option<Vec<&'a str>> {
+        let target = uri
+            .trim_start_matches("http://")
+            .trim_start_matches("https://");
+
+        glob_captures(&self.pattern, target).or_else(|| glob_captures(&self.pattern, uri))
+    }
+
+    fn caches(&self) -> bool {
+        self.force_cache || self.bypass || self.ttl.is_some()
+    }
+}
+
+/// Parse durations like `90`, `30s`, `15m`, `12h` or `7d`
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        None => (value, ""),
+        Some(i) => value.split_at(i),
+    };
+
+    let multiplier = match unit.to_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return None,
+    };
+
+    number
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+}
+
+/// Rules are separated by `;`, each is a pattern followed by one or more actions
+pub(crate) fn parse_rules(value: &str) -> Vec<CacheRule> {
+    let mut rules = Vec::new();
+
+    'rules: for rule in value.split(';').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+        let mut words = rule.split_whitespace();
+        let mut cache_rule = CacheRule {
+            pattern: words.next().unwrap_or_default().to_string(),
+            ..Default::default()
+        };
+
+        for action in words {
+            match action.split_once('=') {
+                None if action.eq_ignore_ascii_case("cache") => cache_rule.force_cache = true,
+                None if action.eq_ignore_ascii_case("bypass") => cache_rule.bypass = true,
+                Some((name, value)) if name.eq_ignore_ascii_case("ttl") => {
+                    match parse_duration(value) {
+                        Some(d) => cache_rule.ttl = Some(d),
+                        None => {
+                            warn!("ignoring cache rule '{rule}': bad ttl '{value}'");
+                            continue 'rules;
+                        }
+                    }
+                }
+                Some((name, value)) if name.eq_ignore_ascii_case("rewrite") => {
+                    cache_rule.rewrite = Some(value.to_string())
+                }
+                Some((name, value)) if name.eq_ignore_ascii_case("accept") && !value.is_empty() => {
+                    cache_rule.accept = Some(value.to_string())
+                }
+                _ => {
+                    warn!("ignoring cache rule '{rule}': unknown action '{action}'");
+                    continue 'rules;
+                }
+            }
+        }
+
+        if cache_rule.force_cache && cache_rule.bypass {
+            warn!("ignoring cache rule '{rule}': can't both cache and bypass");
+            continue;
+        }
+
+        rules.push(cache_rule);
+    }
+
+    rules
+}
+
+fn configured_rules() -> Vec<CacheRule> {
+    match setting(X_PROXY_CACHE_RULES) {
+        Ok(s) => parse_rules(&s),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn rules_lock() -> &'static RwLock<Arc<Vec<CacheRule>>> {
+    static RULES: OnceLock<RwLock<Arc<Vec<CacheRule>>>> = OnceLock::new();
+    RULES.get_or_init(|| RwLock::new(Arc::new(configured_rules())))
+}
+
+/* Shared so a reload doesn't pull the rules out from under a request looking through them */
+fn cache_rules() -> Arc<Vec<CacheRule>> {
+    match rules_lock().read() {
+        Ok(r) => Arc::clone(&r),
+        Err(_) => Arc::default(),
+    }
+}
+
+/// Read `X_PROXY_CACHE_RULES` again, requests already being served keep the rules they started with
+pub(crate) fn reload_cache_rules() {
+    let rules = configured_rules();
+    if let Ok(mut r) = rules_lock().write() {
+        *r = Arc::new(rules);
+    }
+}
+
+/// What each `*` in `pattern` matched in `text`, or `None` if it doesn't match at all
+pub(crate) fn glob_captures<'a>(pattern: &str, text: &'a str) -> Option<Vec<&'a str>> {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return (pattern == text).then(Vec::new);
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if text.len() < first.len() + last.len() || !text.starts_with(first) || !text.ends_with(last) {
+        return None;
+    }
+
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    let mut captures = Vec::with_capacity(parts.len() - 1);
+
+    for part in &parts[1..parts.len() - 1] {
+        let i = rest.find(part)?;
+        captures.push(&rest[..i]);
+        rest = &rest[i + part.len()..];
+    }
+
+    captures.push(rest);
+    Some(captures)
+}
+
+fn apply_rewrite(rules: &[CacheRule], uri: &str) -> Option<String> {
+    rules.iter().find_map(|rule| {
+        let target = rule.rewrite.as_ref()?;
+        let captures = rule.captures(uri)?;
+
+        let mut rewritten = target.clone();
+        for (i, capture) in captures.iter().enumerate().take(9).rev() {
+            rewritten = rewritten.replace(&format!("${}", i + 1), capture);
+        }
+
+        /* What was put together can have dot segments or escapes the request itself didn't */
+        match normalize_uri(&Uri::from(rewritten.clone())) {
+            Some(normalized) => Some(normalized),
+            None => Some(rewritten),
+        }
+    })
+}
+
+fn find_accept<'a>(rules: &'a [CacheRule], uri: &str) -> Option<&'a str> {
+    rules.iter().find_map(|rule| {
+        let accept = rule.accept.as_deref()?;
+        rule.captures(uri).map(|_| accept)
+    })
+}
+
+fn find_rule<'a>(rules: &'a [CacheRule], uri: &str) -> Option<&'a CacheRule> {
+    rules
+        .iter()
+        .find(|rule| rule.caches() && rule.captures(uri).is_some())
+}
+
+/// The URL to fetch in place of `uri` according to the first matching `rewrite` rule,
+/// normalized the same way as the request it replaces
+pub(crate) fn rewrite_uri(uri: &str) -> Option<String> {
+    apply_rewrite(&cache_rules(), uri)
+}
+
+/// The `Accept` header the first matching `accept` rule sends upstream for `uri` in place of the client's
+pub(crate) fn upstream_accept(uri: &str) -> Option<String> {
+    find_accept(&cache_rules(), uri).map(str::to_string)
+}
+
+/// The first rule deciding how `uri` is cached, rewrites are applied before this is looked up
+pub(crate) fn cache_rule(uri: &str) -> Option<CacheRule> {
+    find_rule(&cache_rules(), uri).cloned()
+}
+
+/// Whether a cached copy at `path` may still be served under `rule`.
+/// Age is measured from when the file was created since its modified time is the origin's.
+pub(crate) async fn is_fresh(path: &Path, rule: Option<&CacheRule>) -> bool {
+    let rule = match rule {
+        None => return true,
+        Some(r) => r,
+    };
+
+    if rule.bypass {
+        return false;
+    }
+
+    let ttl = match rule.ttl {
+        None => return true,
+        Some(t) => t,
+    };
+
+    match tokio::fs::metadata(path).await {
+        Ok(m) => m
+            .created()
+            .or_else(|_| m.modified())
+            .ok()
+            .and_then(age)
+            .is_some_and(|age| age < ttl),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PKG_NAME};
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Some(Duration::from_secs(900)));
+        assert_eq!(parse_duration("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(604800)));
+        assert_eq!(parse_duration("1w"), None);
+        assert_eq!(parse_duration("h"), None);
+    }
+
+    #[test]
+    fn test_glob_captures() {
+        assert_eq!(
+            glob_captures(
+                "deb.debian.org/*/dists/*/Release",
+                "deb.debian.org/debian/dists/stable/Release"
+            ),
+            Some(vec!["debian", "stable"])
+        );
+        assert_eq!(
+            glob_captures("*.iso", "example.com/a.iso"),
+            Some(vec!["example.com/a"])
+        );
+        assert_eq!(glob_captures("exact", "exact"), Some(vec![]));
+        assert_eq!(glob_captures("ab*ba", "aba"), None);
+        assert_eq!(glob_captures("*.iso", "example.com/a.img"), None);
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = parse_rules(
+            "*/Release ttl=1h; *.iso bypass; old.example.com/* rewrite=http://new.example.com/$1; \
+            *.deb cache ttl=30d; *.bad explode; *.both cache bypass; \
+            registry.npmjs.org/* accept=application/json",
+        );
+        assert_eq!(rules.len(), 5);
+
+        let release = find_rule(&rules, "http://deb.debian.org/debian/dists/stable/Release");
+        assert_eq!(release.unwrap().ttl, Some(Duration::from_secs(3600)));
+
+        assert!(
+            find_rule(&rules, "http://example.com/a.iso")
+                .unwrap()
+                .bypass
+        );
+        assert!(
+            find_rule(&rules, "http://example.com/a.deb")
+                .unwrap()
+                .force_cache
+        );
+        assert!(find_rule(&rules, "http://example.com/a.bad").is_none());
+        assert!(find_rule(&rules, "http://old.example.com/a.txt").is_none());
+
+        assert_eq!(
+            apply_rewrite(&rules, "http://old.example.com/pool/a.deb"),
+            Some("http://new.example.com/pool/a.deb".to_string())
+        );
+        assert_eq!(apply_rewrite(&rules, "http://example.com/a.deb"), None);
+
+        let rewrites = parse_rules(
+            "old.example.com/*-* rewrite=http://new.example.com/pool/$1.$2; \
+            escaped.example.com/* rewrite=HTTP://New.Example.com:80/pool%2fmain/%7e$1",
+        );
+        assert_eq!(
+            apply_rewrite(&rewrites, "http://old.example.com/.-/secret"),
+            Some("http://new.example.com/secret".to_string())
+        );
+        assert_eq!(
+            apply_rewrite(&rewrites, "http://escaped.example.com/a.deb"),
+            Some("http://new.example.com/pool%2Fmain/~a.deb".to_string())
+        );
+
+        assert_eq!(
+            find_accept(&rules, "https://registry.npmjs.org/left-pad"),
+            Some("application/json")
+        );
+        assert!(find_rule(&rules, "https://registry.npmjs.org/left-pad").is_none());
+        assert_eq!(find_accept(&rules, "http://example.com/a.deb"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_is_fresh() {
+        let path = std::env::temp_dir().join(format!("{PKG_NAME}-test-is-fresh"));
+        tokio::fs::write(&path, b"Release").await.unwrap();
+
+        let rule = CacheRule {
+            ttl: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        assert!(is_fresh(&path, None).await);
+        assert!(is_fresh(&path, Some(&rule)).await);
+
+        crate::clock::advance(Duration::from_secs(3599)).await;
+        assert!(is_fresh(&path, Some(&rule)).await);
+
+        crate::clock::advance(Duration::from_secs(2)).await;
+        assert!(!is_fresh(&path, Some(&rule)).await);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(!is_fresh(&path, Some(&rule)).await);
+    }
+}
@@ -0,0 +1,69 @@
+use {
+    std::sync::atomic::{AtomicUsize, Ordering},
+    tokio::task::yield_now,
+};
+
+/// How much a fetch from an origin passes along before letting other requests have a turn
+const MISS_SLICE: u64 = 1024 * 1024;
+
+/* Files being served from the cache right now, fetches give way to them after every buffer */
+static HITS: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while a file is served from the cache so fetches from origins know to give way
+pub(crate) struct HitTurn;
+
+impl Drop for HitTurn {
+    fn drop(&mut self) {
+        HITS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a file being served from the cache until the returned turn is dropped
+pub(crate) fn serving_hit() -> HitTurn {
+    HITS.fetch_add(1, Ordering::Relaxed);
+    HitTurn
+}
+
+fn hits_waiting() -> bool {
+    HITS.load(Ordering::Relaxed) > 0
+}
+
+/// Keeps track of how much a fetch has passed along since it last gave way
+#[derive(Default)]
+pub(crate) struct MissTurn {
+    since_yield: u64,
+}
+
+impl MissTurn {
+    /// Called after each buffer a fetch passes along. A fetch from a fast origin may never
+    /// have to wait for its reads or writes, so it yields to the runtime every [`MISS_SLICE`]
+    /// to keep from hogging a worker, and after every buffer while cache hits are being served
+    /// so quick hits aren't held up behind downloads of several gigabytes.
+    pub(crate) async fn passed(&mut self, bytes: usize) {
+        self.since_yield += bytes as u64;
+        if self.since_yield >= MISS_SLICE || hits_waiting() {
+            self.since_yield = 0;
+            yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_miss_turn() {
+        let mut turn = MissTurn::default();
+        turn.passed(MISS_SLICE as usize - 1).await;
+        assert_eq!(turn.since_yield, MISS_SLICE - 1);
+        turn.passed(1).await;
+        assert_eq!(turn.since_yield, 0);
+
+        /* Other tests may be serving hits too, so only check that one gives way */
+        let hit = serving_hit();
+        turn.passed(1).await;
+        assert_eq!(turn.since_yield, 0);
+        drop(hit);
+    }
+}
@@ -0,0 +1,452 @@
+use {
+    crate::{
+        conn::Uri,
+        http::{
+            encode_base64, read_chunk_end, read_chunk_size, read_trailers, HttpResponseHeader,
+            HttpResponseStatus,
+        },
+    },
+    std::{fmt, time::Duration},
+    tokio::{
+        io::{AsyncBufRead, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+        time::timeout,
+    },
+    tracing::{error, info},
+};
+
+/// Fetched when no URL is given, a small file that's always there
+const DEFAULT_URL: &str = "http://deb.debian.org/debian/README";
+
+/// A directory asked for without its trailing slash, which the origin redirects
+const DEFAULT_REDIRECT_URL: &str = "http://deb.debian.org/debian";
+
+/// How long one check may take before it's failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The bytes of the range check, fewer if the file is smaller
+const RANGE_LENGTH: usize = 10;
+
+/// How a capability fared
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass,
+    Fail(String),
+    /// The proxy is set up not to offer it, which is no fault
+    Skip(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(r) => write!(f, "fail\t{r}"),
+            Outcome::Skip(r) => write!(f, "skip\t{r}"),
+        }
+    }
+}
+
+struct Response {
+    header: HttpResponseHeader,
+    body: Vec<u8>,
+}
+
+/* A body is framed the way the proxy's response says, the selftest can't trust anything else */
+async fn read_body<R>(reader: &mut R, header: &HttpResponseHeader) -> Result<Vec<u8>, String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let code = header.status.to_code();
+    let mut body = Vec::new();
+    if (100..200).contains(&code) || code == 204 || code == 304 {
+        return Ok(body);
+    }
+
+    if header.headers.is_chunked() {
+        let mut line = Vec::new();
+        loop {
+            let size = read_chunk_size(reader, &mut line).await.ok_or(format!(
+                "bad chunk size '{}'",
+                String::from_utf8_lossy(&line).trim()
+            ))?;
+            if size == 0 {
+                break;
+            }
+
+            let start = body.len();
+            body.resize(start + size as usize, 0);
+            reader
+                .read_exact(&mut body[start..])
+                .await
+                .map_err(|_| "body cut short".to_string())?;
+            if !read_chunk_end(reader, &mut line).await {
+                return Err("chunk not followed by a line break".to_string());
+            }
+        }
+
+        return match read_trailers(reader).await {
+            Some(_) => Ok(body),
+            None => Err("body cut short".to_string()),
+        };
+    }
+
+    match header
+        .headers
+        .get("Content-Length")
+        .and_then(|l| l.trim().parse::<usize>().ok())
+    {
+        Some(length) => {
+            body.resize(length, 0);
+            reader
+                .read_exact(&mut body)
+                .await
+                .map_err(|_| "body cut short".to_string())?;
+        }
+        None => {
+            reader
+                .read_to_end(&mut body)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(body)
+}
+
+/// Where the proxy listens and the credentials it's given, written `[user:password@]host:port`
+struct Proxy {
+    address: String,
+    authorization: Option<String>,
+}
+
+impl Proxy {
+    fn from_arg(arg: &str) -> Self {
+        match arg.rsplit_once('@') {
+            Some((credentials, address)) => Proxy {
+                address: address.to_string(),
+                authorization: Some(format!("Basic {}", encode_base64(credentials.as_bytes()))),
+            },
+            None => Proxy {
+                address: arg.to_string(),
+                authorization: None,
+            },
+        }
+    }
+
+    fn request(&self, method: &str, target: &str, host: &str, extra: &[(&str, &str)]) -> String {
+        let mut request = format!("{method} {target} HTTP/1.1\r\nHost: {host}\r\n");
+        if let Some(a) = &self.authorization {
+            request.push_str(&format!("Proxy-Authorization: {a}\r\n"));
+        }
+        for (name, value) in extra {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        request
+    }
+
+    async fn send(&self, request: &str, with_body: bool) -> Result<Response, String> {
+        let exchange = async {
+            let stream = TcpStream::connect(&self.address)
+                .await
+                .map_err(|e| format!("couldn't connect to {}: {e}", self.address))?;
+            let mut stream = BufReader::new(stream);
+            stream
+                .write_all(request.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let header = HttpResponseHeader::from_tcp_buffer_async(&mut stream)
+                .await
+                .ok_or("no response from the proxy")?;
+            let body = match with_body {
+                true => read_body(&mut stream, &header).await?,
+                false => Vec::new(),
+            };
+            Ok(Response { header, body })
+        };
+
+        match timeout(CHECK_TIMEOUT, exchange).await {
+            Ok(r) => r,
+            Err(_) => Err(format!("no answer in {} seconds", CHECK_TIMEOUT.as_secs())),
+        }
+    }
+
+    async fn get(&self, uri: &Uri<'_>, extra: &[(&str, &str)]) -> Result<Response, String> {
+        let host = uri.host_and_port().unwrap_or_default();
+        self.send(&self.request("GET", &uri.uri, &host, extra), true)
+            .await
+    }
+}
+
+fn status_of(response: &Response) -> String {
+    response.header.status.to_code().to_string()
+}
+
+fn judge_get(response: &Result<Response, String>) -> Outcome {
+    match response {
+        Err(e) => Outcome::Fail(e.clone()),
+        Ok(r) if r.header.status.to_code() != HttpResponseStatus::OK.to_code() => {
+            Outcome::Fail(format!("answered {}", status_of(r)))
+        }
+        Ok(r) if r.body.is_empty() => Outcome::Fail("the body was empty".to_string()),
+        Ok(_) => Outcome::Pass,
+    }
+}
+
+fn judge_hit(first: &[u8], response: &Result<Response, String>) -> Outcome {
+    let response = match response {
+        Err(e) => return Outcome::Fail(e.clone()),
+        Ok(r) => r,
+    };
+
+    let hit = response
+        .header
+        .headers
+        .get_all("Cache-Status")
+        .any(|s| s.split(';').any(|p| p.trim().eq_ignore_ascii_case("hit")));
+
+    match (hit, response.body == first) {
+        _ if response.header.status.to_code() != HttpResponseStatus::OK.to_code() => {
+            Outcome::Fail(format!("answered {}", status_of(response)))
+        }
+        (false, _) => Outcome::Fail("not served from the cache".to_string()),
+        (true, false) => Outcome::Fail("the cached body differs".to_string()),
+        (true, true) => Outcome::Pass,
+    }
+}
+
+fn judge_range(first: &[u8], response: &Result<Response, String>) -> Outcome {
+    let response = match response {
+        Err(e) => return Outcome::Fail(e.clone()),
+        Ok(r) => r,
+    };
+
+    let expected = &first[..first.len().min(RANGE_LENGTH)];
+    match response.header.status.to_code() {
+        206 if response.body == expected => Outcome::Pass,
+        206 => Outcome::Fail("the range holds the wrong bytes".to_string()),
+        _ => Outcome::Fail(format!("answered {}", status_of(response))),
+    }
+}
+
+/* The proxy follows redirects for its clients so they should never see one */
+fn judge_redirect(response: &Result<Response, String>) -> Outcome {
+    match response {
+        Err(e) => Outcome::Fail(e.clone()),
+        Ok(r) => match r.header.status.to_code() {
+            200 => Outcome::Pass,
+            300..=399 => Outcome::Fail(format!("the {} was passed along", status_of(r))),
+            _ => Outcome::Fail(format!("answered {}", status_of(r))),
+        },
+    }
+}
+
+fn judge_connect(response: &Result<Response, String>) -> Outcome {
+    match response {
+        Err(e) => Outcome::Fail(e.clone()),
+        Ok(r) => match r.header.status.to_code() {
+            200..=299 => Outcome::Pass,
+            403 | 405 => Outcome::Skip(format!("refused with {}", status_of(r))),
+            _ => Outcome::Fail(format!("answered {}", status_of(r))),
+        },
+    }
+}
+
+/// Entry point for `rproxy selftest <proxy-addr> [url] [redirecting-url]`, which puts a running
+/// instance through what clients rely on it for and prints how each capability fared
+pub(crate) async fn selftest_command(args: &[String]) -> i32 {
+    let proxy = match args.first() {
+        Some(p) => Proxy::from_arg(p),
+        None => {
+            error!("expected the proxy's address, such as 'localhost:3142'");
+            return 1;
+        }
+    };
+
+    let url = Uri::from(args.get(1).map_or(DEFAULT_URL, String::as_str).to_string());
+    let redirect = Uri::from(
+        args.get(2)
+            .map_or(DEFAULT_REDIRECT_URL, String::as_str)
+            .to_string(),
+    );
+    let host = match url.host {
+        Some(h) => h.to_string(),
+        None => {
+            error!("'{}' isn't an absolute URL", url.uri);
+            return 1;
+        }
+    };
+
+    info!("testing {} with {}", proxy.address, url.uri);
+    let mut outcomes = Vec::new();
+
+    let first = proxy.get(&url, &[]).await;
+    outcomes.push(("absolute-form GET", judge_get(&first)));
+    let first = first.ok().map(|r| r.body).unwrap_or_default();
+
+    match first.is_empty() {
+        true => {
+            let nothing = || Outcome::Skip("nothing was fetched".to_string());
+            outcomes.push(("cache hit", nothing()));
+            outcomes.push(("range request", nothing()));
+        }
+        false => {
+            let hit = proxy.get(&url, &[]).await;
+            outcomes.push(("cache hit", judge_hit(&first, &hit)));
+
+            let range = format!("bytes=0-{}", first.len().min(RANGE_LENGTH) - 1);
+            let range = proxy.get(&url, &[("Range", &range)]).await;
+            outcomes.push(("range request", judge_range(&first, &range)));
+        }
+    }
+
+    let redirected = proxy.get(&redirect, &[]).await;
+    outcomes.push(("redirect following", judge_redirect(&redirected)));
+
+    let authority = format!("{host}:443");
+    let connect = proxy
+        .send(
+            &proxy.request("CONNECT", &authority, &authority, &[]),
+            false,
+        )
+        .await;
+    outcomes.push(("CONNECT", judge_connect(&connect)));
+
+    let mut failed = 0;
+    for (capability, outcome) in outcomes {
+        if matches!(outcome, Outcome::Fail(_)) {
+            failed += 1;
+        }
+        println!("{capability}\t{outcome}");
+    }
+
+    match failed {
+        0 => 0,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::http::{HttpHeader, HttpVersion},
+    };
+
+    fn header(status: HttpResponseStatus, headers: &[(&str, &str)]) -> HttpResponseHeader {
+        let mut header = HttpResponseHeader {
+            status,
+            headers: HttpHeader::new(),
+            version: HttpVersion::HTTP_V11,
+        };
+        for (name, value) in headers {
+            header.headers.append(name.to_string(), value.to_string());
+        }
+        header
+    }
+
+    fn response(
+        status: HttpResponseStatus,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<Response, String> {
+        Ok(Response {
+            header: header(status, headers),
+            body: body.to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_proxy_from_arg() {
+        let proxy = Proxy::from_arg("localhost:3142");
+        assert_eq!(proxy.address, "localhost:3142");
+        assert_eq!(proxy.authorization, None);
+
+        let proxy = Proxy::from_arg("foo:b@r@10.0.0.1:8080");
+        assert_eq!(proxy.address, "10.0.0.1:8080");
+        assert_eq!(proxy.authorization, Some("Basic Zm9vOmJAcg==".to_string()));
+        assert!(proxy
+            .request("GET", "http://example.com/", "example.com:80", &[])
+            .contains("\r\nProxy-Authorization: Basic Zm9vOmJAcg==\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let chunked = header(HttpResponseStatus::OK, &[("Transfer-Encoding", "chunked")]);
+        let mut body = BufReader::new(&b"4;x=1\r\nWiki\r\n5\r\npedia\r\n0\r\nA: b\r\n\r\n"[..]);
+        assert_eq!(read_body(&mut body, &chunked).await.unwrap(), b"Wikipedia");
+
+        let length = header(HttpResponseStatus::OK, &[("Content-Length", "4")]);
+        let mut body = BufReader::new(&b"bodyextra"[..]);
+        assert_eq!(read_body(&mut body, &length).await.unwrap(), b"body");
+
+        let mut body = BufReader::new(&b"bo"[..]);
+        assert!(read_body(&mut body, &length).await.is_err());
+
+        let until_close = header(HttpResponseStatus::OK, &[]);
+        let mut body = BufReader::new(&b"until closed"[..]);
+        assert_eq!(
+            read_body(&mut body, &until_close).await.unwrap(),
+            b"until closed"
+        );
+
+        let not_modified = header(HttpResponseStatus::NOT_MODIFIED, &[]);
+        let mut body = BufReader::new(&b"next response"[..]);
+        assert!(read_body(&mut body, &not_modified)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_judge() {
+        let ok = || response(HttpResponseStatus::OK, &[], b"0123456789abc");
+        assert_eq!(judge_get(&ok()), Outcome::Pass);
+        assert_eq!(
+            judge_get(&response(HttpResponseStatus::OK, &[], b"")),
+            Outcome::Fail("the body was empty".to_string())
+        );
+        assert_eq!(
+            judge_get(&Err("refused".to_string())),
+            Outcome::Fail("refused".to_string())
+        );
+
+        let first = b"0123456789abc";
+        let hit = |body: &[u8]| {
+            response(
+                HttpResponseStatus::OK,
+                &[
+                    ("Cache-Status", "other; fwd=miss"),
+                    ("Cache-Status", "rproxy; hit"),
+                ],
+                body,
+            )
+        };
+        assert_eq!(judge_hit(first, &hit(first)), Outcome::Pass);
+        assert_eq!(
+            judge_hit(first, &hit(b"changed")),
+            Outcome::Fail("the cached body differs".to_string())
+        );
+        assert_eq!(
+            judge_hit(first, &ok()),
+            Outcome::Fail("not served from the cache".to_string())
+        );
+
+        let range = |body: &[u8]| response(HttpResponseStatus::PARTIAL_CONTENT, &[], body);
+        assert_eq!(judge_range(first, &range(b"0123456789")), Outcome::Pass);
+        assert_eq!(judge_range(b"012", &range(b"012")), Outcome::Pass);
+        assert!(matches!(judge_range(first, &ok()), Outcome::Fail(_)));
+
+        assert_eq!(judge_redirect(&ok()), Outcome::Pass);
+        assert_eq!(
+            judge_redirect(&response(HttpResponseStatus::MOVED_PERMANENTLY, &[], b"")),
+            Outcome::Fail("the 301 was passed along".to_string())
+        );
+
+        assert_eq!(judge_connect(&ok()), Outcome::Pass);
+        assert_eq!(
+            judge_connect(&response(HttpResponseStatus::FORBIDDEN, &[], b"")),
+            Outcome::Skip("refused with 403".to_string())
+        );
+    }
+}
@@ -1,109 +1,400 @@
 use {
     crate::{
+        about::{build_report, VERSION_PATH},
+        accounting::{accounting_enabled, record_usage, request_identity, Metered},
+        auth::authenticated_user,
+        cli::setting,
         conn,
-        conn::{FlightState, Flights},
-        fetch::fetch_and_serve_file,
+        conn::{normalize_uri, scheme_allowed, Client, FlightState, Flights},
+        debug::wire_log,
+        digest::recall_digest,
+        events::{next_request_id, publish, Event},
+        evict::record_hit,
+        fetch::{fetch_and_serve_file, fetch_head},
+        forwarded::{apply_forwarded, is_loop},
+        head::recall_head,
         http::{
-            get_cache_name, keep_alive_if, respond_with, ConnectionReturn, ConnectionReturn::Close,
-            HttpHeader, HttpRequestHeader, HttpRequestMethod, HttpResponseHeader,
-            HttpResponseStatus, HttpVersion, BUFFER_SIZE,
+            encode_base64, entity_tag, get_cache_name, header_limits, if_range_matches,
+            keep_alive_if, not_modified, parse_range, respond_with, ConnectionReturn,
+            ConnectionReturn::Close, HttpHeader, HttpRequestHeader, HttpRequestMethod,
+            HttpResponseHeader, HttpResponseStatus, HttpVersion, RangeRequest, BUFFER_SIZE,
         },
+        maintenance::{in_maintenance, respond_in_maintenance},
+        relay::{discard_request_body, relay_request, requested_upgrade},
+        rules::{cache_rule, is_fresh, parse_duration, rewrite_uri},
+        schedule::serving_hit,
+        shutdown::{draining, stopped},
+        slow::header_timeout,
+        sniff::{sniff_content_type, sniff_enabled, SNIFF_LENGTH},
+        storage::{cache_writable, unwritable_response, UnwritableResponse},
+        token::{over_quota, record_token_usage, request_token},
+        trace::apply_traceparent,
+        tunnel::open_tunnel,
+        zerocopy::ZeroCopy,
+        PKG_NAME,
     },
     std::{
         io::SeekFrom,
         path::{Path, PathBuf},
-        sync::Arc,
-        time::Duration,
+        sync::{Arc, OnceLock, RwLock},
+        time::{Duration, Instant},
     },
     tokio::{
         fs::File,
-        io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
+        io::{
+            AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite,
+            AsyncWriteExt, BufReader,
+        },
+        sync::watch,
         time::timeout,
     },
+    tracing::{debug, error},
+};
+
+#[cfg(feature = "compress")]
+use crate::compress::{compressing, encoded_tag, encoding_for, send_compressed, Encoding};
+
+#[cfg(feature = "geoip")]
+use crate::geo::apply_closest_mirror;
+
+#[cfg(feature = "web-ui")]
+use crate::{
+    auth::{administrator, administrators_configured, authorized_locally, respond_admin_required},
+    events::{serve_events, EVENTS_PATH},
+    progress::{serve_progress, PROGRESS_PATH},
+    token::issue_token,
+    ui::{apply_action, cache_page, web_ui_enabled, CacheAction, Permission, UI_PATH},
 };
 
 #[cfg(feature = "https")]
 use {
-    crate::cert::{CertificateSetup, CERT_QUERY},
+    crate::{
+        cert::{intercepts, CertificateSetup},
+        conn::allowed_schemes,
+    },
     ConnectionReturn::Upgrade,
 };
 
-pub(crate) async fn read_http_request<T>(mut stream: T) -> Option<HttpRequestHeader<'static>>
+pub const X_PROXY_KEEP_ALIVE_TIMEOUT: &str = "X_PROXY_KEEP_ALIVE_TIMEOUT";
+pub const X_PROXY_KEEP_ALIVE_MAX: &str = "X_PROXY_KEEP_ALIVE_MAX";
+
+/// How long a connection may wait for its next request when `X_PROXY_KEEP_ALIVE_TIMEOUT` isn't defined
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many requests a connection may make when `X_PROXY_KEEP_ALIVE_MAX` isn't defined
+const DEFAULT_KEEP_ALIVE_MAX: usize = 100;
+
+/* How long and for how many requests connections are kept open, in that order */
+fn configured_keep_alive() -> (Duration, usize) {
+    let timeout = setting(X_PROXY_KEEP_ALIVE_TIMEOUT)
+        .ok()
+        .and_then(|t| parse_duration(&t))
+        .filter(|t| !t.is_zero())
+        .unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT);
+    let max = setting(X_PROXY_KEEP_ALIVE_MAX)
+        .ok()
+        .and_then(|m| m.trim().parse().ok())
+        .filter(|m| *m > 0)
+        .unwrap_or(DEFAULT_KEEP_ALIVE_MAX);
+    (timeout, max)
+}
+
+fn keep_alive_lock() -> &'static RwLock<(Duration, usize)> {
+    static KEEP_ALIVE: OnceLock<RwLock<(Duration, usize)>> = OnceLock::new();
+    KEEP_ALIVE.get_or_init(|| RwLock::new(configured_keep_alive()))
+}
+
+fn keep_alive() -> (Duration, usize) {
+    match keep_alive_lock().read() {
+        Ok(k) => *k,
+        Err(e) => *e.into_inner(),
+    }
+}
+
+fn keep_alive_timeout() -> Duration {
+    keep_alive().0
+}
+
+fn keep_alive_max() -> usize {
+    keep_alive().1
+}
+
+/// Read `X_PROXY_KEEP_ALIVE_TIMEOUT` and `X_PROXY_KEEP_ALIVE_MAX` again,
+/// connections already waiting keep the timeout they started with
+pub(crate) fn reload_keep_alive() {
+    let keep_alive = configured_keep_alive();
+    if let Ok(mut k) = keep_alive_lock().write() {
+        *k = keep_alive;
+    }
+}
+
+/// Read the next request on a connection. The reader lives as long as the connection
+/// so bytes read past the header, such as the start of a request body, aren't lost.
+/// `served` counts the requests already made on the connection, the last one it may make
+/// is read as though it asked for the connection to close, as is any request read while rproxy
+/// is shutting down.
+pub(crate) async fn read_http_request<T>(
+    stream: &mut BufReader<T>,
+    served: &mut usize,
+) -> Option<HttpRequestHeader<'static>>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut client_buf_reader = BufReader::new(&mut stream);
-
-    timeout(
-        Duration::from_secs(5),
-        HttpRequestHeader::from_tcp_buffer_async(&mut client_buf_reader),
-    )
-    .await
-    .unwrap_or_default()
+    /* A connection may sit idle for the keep-alive timeout,
+     * a request that's started has to finish arriving within the header timeout.
+     * An idle connection is closed as soon as rproxy starts shutting down,
+     * one that was just accepted still gets its first request answered */
+    let waited = tokio::select! {
+        w = timeout(keep_alive_timeout(), stream.fill_buf()) => w,
+        _ = stopped(), if *served > 0 => return None,
+    };
+    match waited {
+        Ok(Ok(b)) if !b.is_empty() => {}
+        _ => return None,
+    }
+
+    let read = HttpRequestHeader::read_limited(stream, header_limits());
+    let mut request = match timeout(header_timeout(), read).await {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => {
+            if let Some(status) = e.status() {
+                debug!("Refusing a request header: {e:?}");
+                respond_with(Close, status, stream).await;
+            }
+            return None;
+        }
+        Err(_) => {
+            debug!("A request header took longer than {:?}", header_timeout());
+            respond_with(Close, HttpResponseStatus::REQUEST_TIMEOUT, stream).await;
+            return None;
+        }
+    };
+
+    *served += 1;
+    if *served >= keep_alive_max() || draining() {
+        request
+            .headers
+            .insert("Connection".to_string(), "close".to_string());
+    }
+    Some(request)
 }
 
 pub(crate) async fn serve_http_request<T>(
+    stream: T,
+    client: &Client,
+    flights: &Arc<Flights>,
+    mut client_request_header: HttpRequestHeader<'_>,
+    #[cfg(feature = "https")] cert: &CertificateSetup,
+) -> ConnectionReturn
+where
+    T: AsyncBufRead + AsyncRead + AsyncWrite + ZeroCopy + Unpin,
+{
+    let id = next_request_id();
+    apply_traceparent(&mut client_request_header, id);
+    let looped = is_loop(&client_request_header);
+    apply_forwarded(&mut client_request_header, client.address.ip());
+    let uri = client_request_header.request.uri.to_string();
+    let started = Instant::now();
+    publish(|| Event::Start {
+        id,
+        method: client_request_header.method.to_string(),
+        uri: uri.clone(),
+        client: client.address.to_string(),
+        user: authenticated_user(&client_request_header),
+    });
+
+    /* Everything sent back is charged to the token, whether it came from the cache,
+     * an origin server or through a tunnel */
+    let token = request_token(&client_request_header);
+    let mut stream = Metered::new(stream);
+
+    let r = match looped {
+        true => {
+            error!("{uri} was refused as it already came through this proxy");
+            respond_with(Close, HttpResponseStatus::LOOP_DETECTED, &mut stream).await
+        }
+        false => {
+            serve_request(
+                &mut stream,
+                client,
+                flights,
+                client_request_header,
+                #[cfg(feature = "https")]
+                cert,
+            )
+            .await
+        }
+    };
+
+    if let Some(token) = token {
+        record_token_usage(&token, stream.written());
+    }
+
+    publish(|| Event::Finish {
+        id,
+        uri,
+        ms: started.elapsed().as_millis(),
+    });
+    r
+}
+
+async fn serve_request<T>(
     mut stream: T,
+    client: &Client,
     flights: &Arc<Flights>,
-    client_request_header: HttpRequestHeader<'_>,
+    mut client_request_header: HttpRequestHeader<'_>,
     #[cfg(feature = "https")] cert: &CertificateSetup,
 ) -> ConnectionReturn
 where
-    T: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncBufRead + AsyncRead + AsyncWrite + ZeroCopy + Unpin,
 {
+    wire_log("Client request", &client_request_header.request.uri, || {
+        client_request_header.generate().unwrap_or_default()
+    });
+
+    /* Only uploads and upgrades to origin servers are relayed with their body,
+     * any other has it read and dropped */
+    let upgrade = requested_upgrade(&client_request_header).is_some()
+        && client_request_header.method == HttpRequestMethod::Get;
+    let relayed = (upgrade
+        || matches!(
+            client_request_header.method,
+            HttpRequestMethod::Post
+                | HttpRequestMethod::Put
+                | HttpRequestMethod::Delete
+                | HttpRequestMethod::Patch
+                | HttpRequestMethod::Options
+        ))
+        && client_request_header.request.kind() != conn::UriKind::AbsolutePath;
+    if !relayed {
+        if let Err(status) = discard_request_body(&mut stream, &mut client_request_header).await {
+            return respond_with(Close, status, &mut stream).await;
+        }
+    }
+
+    if request_token(&client_request_header)
+        .as_deref()
+        .is_some_and(over_quota)
+    {
+        return respond_with(
+            match relayed {
+                true => Close,
+                false => keep_alive_if(&client_request_header),
+            },
+            HttpResponseStatus::TOO_MANY_REQUESTS,
+            &mut stream,
+        )
+        .await;
+    }
+
+    if !scheme_allowed(&client_request_header.request.uri) {
+        return respond_with(
+            match relayed {
+                true => Close,
+                false => keep_alive_if(&client_request_header),
+            },
+            HttpResponseStatus::FORBIDDEN,
+            &mut stream,
+        )
+        .await;
+    }
+
+    /* Nothing from a connection that switches protocols can be cached */
+    if upgrade && relayed {
+        if client_request_header
+            .request
+            .host
+            .is_some_and(in_maintenance)
+        {
+            return respond_in_maintenance(Close, &mut stream).await;
+        }
+
+        return relay_request(
+            &mut stream,
+            flights,
+            &client_request_header,
+            &client.cancel,
+            #[cfg(feature = "https")]
+            cert,
+        )
+        .await;
+    }
+
     match client_request_header.method {
         HttpRequestMethod::Get => match client_request_header.request.kind() {
             conn::UriKind::AbsolutePath => {
-                match client_request_header.request.query {
-                    #[cfg(feature = "https")]
-                    Some(q) => {
-                        if q == CERT_QUERY {
-                            if cert.server_cert_path.is_file() {
-                                serve_existing_file(
-                                    &cert.server_cert_path,
-                                    &mut stream,
-                                    flights,
-                                    &client_request_header,
-                                )
-                                .await;
-                            } else {
-                                return respond_with(
-                                    keep_alive_if(&client_request_header),
-                                    HttpResponseStatus::NOT_FOUND,
-                                    &mut stream,
-                                )
-                                .await;
-                            }
-                        }
-                    }
-                    #[cfg(feature = "https")]
-                    _ => {
-                        return respond_with(
-                            keep_alive_if(&client_request_header),
-                            HttpResponseStatus::NO_CONTENT,
-                            &mut stream,
-                        )
-                        .await
+                if client_request_header.request.path == Some(VERSION_PATH) {
+                    return serve_generated(
+                        build_report(),
+                        "text/plain; charset=utf-8",
+                        &mut stream,
+                        &client_request_header,
+                    )
+                    .await;
+                }
+
+                /* The streams tell who fetched what, so they're only sent to clients that may use the proxy */
+                #[cfg(feature = "web-ui")]
+                if client_request_header.request.path == Some(EVENTS_PATH) && web_ui_enabled() {
+                    if !authorized_locally(&client_request_header).await {
+                        return respond_admin_required(&mut stream).await;
                     }
-                    #[cfg(not(feature = "https"))]
-                    _ => {
-                        respond_with(
-                            keep_alive_if(&client_request_header),
-                            HttpResponseStatus::NO_CONTENT,
-                            &mut stream,
-                        )
-                        .await
+                    return serve_events(&mut stream, &client.cancel).await;
+                }
+
+                #[cfg(feature = "web-ui")]
+                if client_request_header.request.path == Some(PROGRESS_PATH) && web_ui_enabled() {
+                    if !authorized_locally(&client_request_header).await {
+                        return respond_admin_required(&mut stream).await;
                     }
+                    return serve_progress(&mut stream, flights, &client.cancel).await;
                 }
+
+                #[cfg(feature = "web-ui")]
+                if client_request_header.request.path == Some(UI_PATH) && web_ui_enabled() {
+                    return serve_generated(
+                        cache_page(flights).await,
+                        "text/html; charset=utf-8",
+                        &mut stream,
+                        &client_request_header,
+                    )
+                    .await;
+                }
+
                 #[cfg(feature = "https")]
+                if let Some((body, content_type)) = client_request_header
+                    .request
+                    .query
+                    .and_then(|q| cert.certificate_download(q))
+                {
+                    return serve_generated(
+                        body,
+                        content_type,
+                        &mut stream,
+                        &client_request_header,
+                    )
+                    .await;
+                }
+
                 respond_with(
                     keep_alive_if(&client_request_header),
-                    HttpResponseStatus::INTERNAL_SERVER_ERROR,
+                    HttpResponseStatus::NO_CONTENT,
                     &mut stream,
                 )
                 .await
             }
             _ => {
+                if !apply_rewrite(&mut client_request_header) {
+                    return respond_with(
+                        keep_alive_if(&client_request_header),
+                        HttpResponseStatus::FORBIDDEN,
+                        &mut stream,
+                    )
+                    .await;
+                }
+                #[cfg(feature = "geoip")]
+                apply_closest_mirror(&mut client_request_header).await;
+
                 let (cache_file_path, hash) = match get_cache_name(&client_request_header).await {
                     None => {
                         return respond_with(
@@ -119,43 +410,316 @@ where
                     }
                 };
 
-                if cache_file_path.exists() || flights.is_in_flight(&hash).await {
-                    serve_existing_file(&cache_file_path, stream, flights, &client_request_header)
-                        .await
+                let identity = match accounting_enabled() {
+                    true => Some(request_identity(client, &client_request_header)),
+                    false => None,
+                };
+                let mut stream = Metered::new(stream);
+
+                let rule = cache_rule(&client_request_header.request.uri);
+                let maintenance = client_request_header
+                    .request
+                    .host
+                    .is_some_and(in_maintenance);
+                let from_cache = match flights.is_in_flight(&hash).await {
+                    true => true,
+                    false if !cache_file_path.exists() => false,
+                    /* However old it is, it's all there is until the origin is back */
+                    false if maintenance => true,
+                    false if is_fresh(&cache_file_path, rule.as_ref()).await => true,
+                    false => {
+                        /* Start the replacement afresh so its age is counted from now */
+                        let _ = tokio::fs::remove_file(&cache_file_path).await;
+                        false
+                    }
+                };
+                if maintenance && !from_cache {
+                    return respond_in_maintenance(
+                        keep_alive_if(&client_request_header),
+                        &mut stream,
+                    )
+                    .await;
+                }
+                if !from_cache && !cache_writable() {
+                    let r = match unwritable_response() {
+                        UnwritableResponse::Relay => {
+                            relay_request(
+                                &mut stream,
+                                flights,
+                                &client_request_header,
+                                &client.cancel,
+                                #[cfg(feature = "https")]
+                                cert,
+                            )
+                            .await
+                        }
+                        UnwritableResponse::Unavailable => {
+                            respond_with(
+                                keep_alive_if(&client_request_header),
+                                HttpResponseStatus::SERVICE_UNAVAILABLE,
+                                &mut stream,
+                            )
+                            .await
+                        }
+                    };
+
+                    if let Some(identity) = identity {
+                        record_usage(&identity, stream.written(), false);
+                    }
+                    return r;
+                }
+                let r = if from_cache {
+                    let _turn = serving_hit();
+                    record_hit(&hash, &client_request_header.request.uri);
+                    let r = serve_existing_file(
+                        &cache_file_path,
+                        &mut stream,
+                        flights,
+                        &client_request_header,
+                    )
+                    .await;
+
+                    publish(|| Event::Hit {
+                        uri: client_request_header.request.uri.to_string(),
+                        bytes: stream.written(),
+                    });
+                    r
                 } else {
+                    let uri = client_request_header.request.uri.to_string();
+                    let cancel = client.cancel.child();
                     flights.takeoff(&hash, FlightState::Fetching).await;
+                    flights.set_cancel(&hash, &cancel).await;
 
                     let r = fetch_and_serve_file(
                         cache_file_path,
-                        stream,
+                        &mut stream,
                         flights,
                         client_request_header,
+                        &cancel,
                         #[cfg(feature = "https")]
                         cert,
                     )
                     .await;
 
                     flights.land(&hash).await;
+                    publish(|| Event::Miss {
+                        uri,
+                        bytes: stream.written(),
+                    });
                     r
+                };
+
+                if let Some(identity) = identity {
+                    record_usage(&identity, stream.written(), from_cache);
                 }
+                r
             }
         },
-        #[cfg(feature = "https")]
-        HttpRequestMethod::Connect => {
-            match (
-                client_request_header.request.host,
-                client_request_header.request.port,
-            ) {
-                (Some(_), Some(_)) => Upgrade(client_request_header.request.uri),
-                _ => {
-                    respond_with(
-                        Close,
+        HttpRequestMethod::Head
+            if client_request_header.request.kind() != conn::UriKind::AbsolutePath =>
+        {
+            if !apply_rewrite(&mut client_request_header) {
+                return respond_with(
+                    keep_alive_if(&client_request_header),
+                    HttpResponseStatus::FORBIDDEN,
+                    &mut stream,
+                )
+                .await;
+            }
+
+            let cache_file_path = match get_cache_name(&client_request_header).await {
+                None => {
+                    return respond_with(
+                        keep_alive_if(&client_request_header),
                         HttpResponseStatus::INTERNAL_SERVER_ERROR,
                         &mut stream,
                     )
                     .await
                 }
+                Some(p) => p,
+            };
+            let hash = cache_file_path.to_string_lossy().to_string();
+
+            /* A cached file answers for itself, the way it would be served to a GET */
+            let rule = cache_rule(&client_request_header.request.uri);
+            let maintenance = client_request_header
+                .request
+                .host
+                .is_some_and(in_maintenance);
+            if !flights.is_in_flight(&hash).await
+                && cache_file_path.is_file()
+                && (maintenance || is_fresh(&cache_file_path, rule.as_ref()).await)
+            {
+                return serve_existing_file(
+                    &cache_file_path,
+                    &mut stream,
+                    flights,
+                    &client_request_header,
+                )
+                .await;
             }
+
+            let remember = !rule.is_some_and(|r| r.bypass);
+            if remember {
+                if let Some(headers) = recall_head(&hash) {
+                    debug!(
+                        "Answering HEAD {} from an earlier response",
+                        client_request_header.request.uri
+                    );
+                    let mut header = HttpResponseHeader {
+                        status: HttpResponseStatus::OK,
+                        headers,
+                        version: HttpVersion::HTTP_V11,
+                    };
+                    header.close_after(&client_request_header);
+                    return match stream.write_all(header.generate().as_bytes()).await {
+                        Ok(_) => keep_alive_if(&client_request_header),
+                        Err(_) => Close,
+                    };
+                }
+            }
+
+            if maintenance {
+                return respond_in_maintenance(keep_alive_if(&client_request_header), &mut stream)
+                    .await;
+            }
+
+            fetch_head(
+                &cache_file_path,
+                &mut stream,
+                &client_request_header,
+                remember,
+                &client.cancel,
+                #[cfg(feature = "https")]
+                cert,
+            )
+            .await
+        }
+        #[cfg(feature = "https")]
+        HttpRequestMethod::Connect
+            if client_request_header.request.host.is_some_and(intercepts) =>
+        {
+            match client_request_header.request.port {
+                Some(_) if !allowed_schemes().iter().any(|s| s == "https") => {
+                    respond_with(Close, HttpResponseStatus::FORBIDDEN, &mut stream).await
+                }
+                Some(_) => Upgrade(client_request_header.request.uri),
+                None => respond_with(Close, HttpResponseStatus::BAD_REQUEST, &mut stream).await,
+            }
+        }
+        HttpRequestMethod::Connect => {
+            open_tunnel(&mut stream, &client_request_header, &client.cancel).await
+        }
+        #[cfg(feature = "web-ui")]
+        HttpRequestMethod::Post
+            if client_request_header.request.kind() == conn::UriKind::AbsolutePath
+                && client_request_header.request.path == Some(UI_PATH)
+                && web_ui_enabled() =>
+        {
+            /* Buttons don't send a body, anything that came along has already been dropped */
+            let action = match client_request_header
+                .request
+                .query
+                .and_then(CacheAction::from_query)
+            {
+                Some(a) => a,
+                None => {
+                    return respond_with(Close, HttpResponseStatus::BAD_REQUEST, &mut stream).await
+                }
+            };
+
+            /* Requests for rproxy itself skip the proxy's own login, so it's asked for here */
+            let permitted = match action.permission(administrators_configured()) {
+                Permission::Administrator => administrator(&client_request_header).await,
+                Permission::Client => match authorized_locally(&client_request_header).await {
+                    true => Ok(()),
+                    false => Err(HttpResponseStatus::UNAUTHORIZED),
+                },
+            };
+            match permitted {
+                Ok(_) => {}
+                Err(s) if s.to_code() == HttpResponseStatus::UNAUTHORIZED.to_code() => {
+                    return respond_admin_required(&mut stream).await
+                }
+                Err(s) => return respond_with(Close, s, &mut stream).await,
+            }
+
+            /* The only time the token is seen, so it's the body rather than a redirect */
+            if let CacheAction::Issue { id, quota } = &action {
+                let token = match issue_token(id, *quota) {
+                    Some(t) => t,
+                    None => {
+                        return respond_with(Close, HttpResponseStatus::BAD_REQUEST, &mut stream)
+                            .await
+                    }
+                };
+
+                let mut headers = HttpHeader::new();
+                headers.insert(
+                    String::from("Content-Type"),
+                    "text/plain; charset=utf-8".to_string(),
+                );
+                headers.insert(
+                    String::from("Content-Length"),
+                    (token.len() + 1).to_string(),
+                );
+                headers.insert(String::from("Cache-Control"), "no-store".to_string());
+                headers.insert(String::from("Connection"), "close".to_string());
+
+                let mut header = HttpResponseHeader {
+                    status: HttpResponseStatus::CREATED,
+                    headers,
+                    version: HttpVersion::HTTP_V11,
+                };
+                let response = format!("{}{token}\n", header.generate());
+                let _ = stream.write_all(response.as_bytes()).await;
+                return Close;
+            }
+
+            let status = apply_action(action, flights).await;
+
+            if status.to_code() != HttpResponseStatus::SEE_OTHER.to_code() {
+                return respond_with(Close, status, &mut stream).await;
+            }
+
+            let mut headers = HttpHeader::new();
+            headers.insert(String::from("Location"), UI_PATH.to_string());
+            headers.insert(String::from("Content-Length"), "0".to_string());
+            headers.insert(String::from("Connection"), "close".to_string());
+
+            let mut header = HttpResponseHeader {
+                status,
+                headers,
+                version: HttpVersion::HTTP_V11,
+            };
+            let _ = stream.write_all(header.generate().as_bytes()).await;
+            Close
+        }
+        HttpRequestMethod::Post
+        | HttpRequestMethod::Put
+        | HttpRequestMethod::Delete
+        | HttpRequestMethod::Patch
+        | HttpRequestMethod::Options
+            if client_request_header.request.kind() != conn::UriKind::AbsolutePath =>
+        {
+            if client_request_header
+                .request
+                .host
+                .is_some_and(in_maintenance)
+            {
+                /* The body is never read so the connection can't carry another request */
+                return respond_in_maintenance(Close, &mut stream).await;
+            }
+
+            relay_request(
+                &mut stream,
+                flights,
+                &client_request_header,
+                &client.cancel,
+                #[cfg(feature = "https")]
+                cert,
+            )
+            .await
         }
         _ => {
             respond_with(
@@ -168,17 +732,70 @@ where
     }
 }
 
+/// Point the request at the target of any matching rewrite rule, false when its scheme isn't allowed
+fn apply_rewrite(client_request_header: &mut HttpRequestHeader<'_>) -> bool {
+    /* However the URI is written, the same resource has the same cache entry */
+    if let Some(normalized) = normalize_uri(&client_request_header.request) {
+        debug!(
+            "Normalizing {} to {normalized}",
+            client_request_header.request.uri
+        );
+        client_request_header.request = conn::Uri::from(normalized);
+    }
+
+    if let Some(rewritten) = rewrite_uri(&client_request_header.request.uri) {
+        debug!(
+            "Rewriting {} to {rewritten}",
+            client_request_header.request.uri
+        );
+        if !scheme_allowed(&rewritten) {
+            return false;
+        }
+        client_request_header.request = conn::Uri::from(rewritten);
+    }
+    true
+}
+
+/// Answer with a short body generated by the proxy itself
+async fn serve_generated<T, B>(
+    body: B,
+    content_type: &str,
+    mut stream: T,
+    client_request_header: &HttpRequestHeader<'_>,
+) -> ConnectionReturn
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    B: AsRef<[u8]>,
+{
+    let body = body.as_ref();
+    let mut headers = HttpHeader::new();
+    headers.insert(String::from("Content-Type"), content_type.to_string());
+    headers.insert(String::from("Content-Length"), body.len().to_string());
+    headers.insert(String::from("Cache-Control"), "no-store".to_string());
+
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::OK,
+        headers,
+        version: HttpVersion::HTTP_V11,
+    };
+    header.close_after(client_request_header);
+
+    match stream.write_all(header.generate().as_bytes()).await {
+        Ok(_) if stream.write_all(body).await.is_ok() => keep_alive_if(client_request_header),
+        _ => Close,
+    }
+}
+
 async fn serve_in_flight_file_chunks<T>(
     mut cache_file: File,
-    cache_file_path: &Path,
     mut stream: T,
-    flights: &Arc<Flights>,
+    flight: watch::Receiver<FlightState>,
     client_request_header: &HttpRequestHeader<'_>,
 ) -> ConnectionReturn
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    use crate::http::{END_OF_HTTP_HEADER, END_OF_HTTP_HEADER_LINE};
+    use crate::http::{http_chunk, END_OF_HTTP_HEADER};
 
     let status = HttpResponseStatus::OK;
     let mut headers = HttpHeader::new();
@@ -189,6 +806,7 @@ where
         headers,
         version: HttpVersion::HTTP_V11,
     };
+    header.close_after(client_request_header);
 
     let header = header.generate();
     if stream.write_all(header.as_bytes()).await.is_err() {
@@ -198,30 +816,27 @@ where
     let mut buffer = vec![0; BUFFER_SIZE];
 
     loop {
+        /* Looked at before reading so a complete flight means the read saw everything written */
+        let state = *flight.borrow();
+
         match cache_file.read(&mut buffer).await {
-            Ok(0) => {
-                /* No new data available, at the moment */
-                if !flights
-                    .is_in_flight(&cache_file_path.to_string_lossy().to_string())
-                    .await
-                {
-                    /* The flight is gone, assume it has finished */
+            Ok(0) => match state {
+                FlightState::Complete => {
                     let end_chunk = format!("0{END_OF_HTTP_HEADER}");
                     return match stream.write_all(end_chunk.as_bytes()).await {
                         Ok(_) => keep_alive_if(client_request_header),
                         Err(_) => Close,
                     };
                 }
-
-                /* Wait a while before retrying */
-                tokio::time::sleep(Duration::from_millis(100)).await;
-            }
+                /* Never end the body, so the client knows what it has is truncated */
+                FlightState::Failed => return Close,
+                /* No new data available, at the moment */
+                _ => tokio::time::sleep(Duration::from_millis(100)).await,
+            },
             Ok(n) => {
-                let chunk = format!("{:X}{END_OF_HTTP_HEADER_LINE}", n);
-                if stream.write_all(chunk.as_bytes()).await.is_err() {
-                    return Close;
-                }
-                if stream.write_all(&buffer[..n]).await.is_err() {
+                if stream.write_all(&http_chunk(&buffer[..n])).await.is_err()
+                    || stream.flush().await.is_err()
+                {
                     return Close;
                 }
                 if n < BUFFER_SIZE {
@@ -236,9 +851,8 @@ where
 
 async fn serve_in_flight_file_length<T>(
     mut cache_file: File,
-    cache_file_path: &Path,
     mut stream: T,
-    flights: &Arc<Flights>,
+    flight: watch::Receiver<FlightState>,
     client_request_header: &HttpRequestHeader<'_>,
     total_length: u64,
 ) -> ConnectionReturn
@@ -254,6 +868,7 @@ where
         headers,
         version: HttpVersion::HTTP_V11,
     };
+    header.close_after(client_request_header);
 
     let header = header.generate();
     if stream.write_all(header.as_bytes()).await.is_err() {
@@ -268,21 +883,17 @@ where
             break; /* The transfer is finished */
         }
 
+        let state = *flight.borrow();
+
         match cache_file.read(&mut buffer).await {
-            Ok(0) => {
+            Ok(0) => match state {
+                /* Landed short of its length, no other choice but to abort */
+                FlightState::Complete | FlightState::Failed => return Close,
                 /* No new data available, at the moment */
-                if !flights
-                    .is_in_flight(&cache_file_path.to_string_lossy().to_string())
-                    .await
-                {
-                    return Close; /* The flight is gone, no other choice but to abort */
-                }
-
-                /* Wait a while before retrying */
-                tokio::time::sleep(Duration::from_millis(100)).await;
-            }
+                _ => tokio::time::sleep(Duration::from_millis(100)).await,
+            },
             Ok(n) => {
-                if stream.write_all(&buffer[..n]).await.is_err() {
+                if stream.write_all(&buffer[..n]).await.is_err() || stream.flush().await.is_err() {
                     return Close;
                 }
                 current_position += n as u64;
@@ -309,42 +920,80 @@ async fn serve_in_flight_file<T>(
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    loop {
-        match flights
-            .flight_state(&cache_file_path.to_string_lossy().to_string())
-            .await
-        {
-            None => {
-                tokio::time::sleep(Duration::from_millis(100)).await;
-                continue;
-            }
-            Some(f) => match f {
-                FlightState::Fetching => {
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                    continue;
-                }
-                FlightState::Length(l) => {
-                    return serve_in_flight_file_length(
+    let mut stream = stream;
+    let mut flight = match flights
+        .follow(&cache_file_path.to_string_lossy().to_string())
+        .await
+    {
+        Some(f) => f,
+        None => {
+            /* Landed since it was looked up, a complete file is kept and a failed one removed */
+            return match cache_file.metadata().await {
+                Ok(m) if cache_file_path.is_file() => {
+                    let length = m.len();
+                    serve_in_flight_file_length(
                         cache_file,
-                        cache_file_path,
                         stream,
-                        flights,
+                        watch::channel(FlightState::Complete).1,
                         client_request_header,
-                        l,
+                        length,
                     )
                     .await
                 }
-                FlightState::Chunks => {
-                    return serve_in_flight_file_chunks(
-                        cache_file,
-                        cache_file_path,
-                        stream,
-                        flights,
-                        client_request_header,
-                    )
-                    .await
+                _ => respond_with(Close, HttpResponseStatus::BAD_GATEWAY, &mut stream).await,
+            };
+        }
+    };
+
+    loop {
+        let state = *flight.borrow_and_update();
+        match state {
+            FlightState::Fetching => {
+                if flight.changed().await.is_err() && *flight.borrow() == FlightState::Fetching {
+                    /* Dropped without landing, treat it like any other failure */
+                    return respond_with(Close, HttpResponseStatus::BAD_GATEWAY, &mut stream).await;
                 }
-            },
+            }
+            FlightState::Length(l) => {
+                return serve_in_flight_file_length(
+                    cache_file,
+                    stream,
+                    flight,
+                    client_request_header,
+                    l,
+                )
+                .await
+            }
+            FlightState::Chunks => {
+                return serve_in_flight_file_chunks(
+                    cache_file,
+                    stream,
+                    flight,
+                    client_request_header,
+                )
+                .await
+            }
+            FlightState::Complete => {
+                return match cache_file.metadata().await {
+                    Ok(m) => {
+                        let length = m.len();
+                        serve_in_flight_file_length(
+                            cache_file,
+                            stream,
+                            flight,
+                            client_request_header,
+                            length,
+                        )
+                        .await
+                    }
+                    Err(_) => {
+                        respond_with(Close, HttpResponseStatus::BAD_GATEWAY, &mut stream).await
+                    }
+                };
+            }
+            FlightState::Failed => {
+                return respond_with(Close, HttpResponseStatus::BAD_GATEWAY, &mut stream).await
+            }
         }
     }
 }
@@ -356,7 +1005,7 @@ async fn serve_existing_file<T>(
     client_request_header: &HttpRequestHeader<'_>,
 ) -> ConnectionReturn
 where
-    T: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + ZeroCopy + Unpin,
 {
     let mut file = match File::open(cache_file_path).await {
         Ok(f) => f,
@@ -406,55 +1055,200 @@ where
         .await;
     }
 
-    let mut start_position: u64 = 0;
-    let mut end_position: u64 = length - 1;
+    let modified = metadata.modified().ok();
+
+    #[cfg(feature = "compress")]
+    let wants_head = sniff_enabled() || compressing();
+    #[cfg(not(feature = "compress"))]
+    let wants_head = sniff_enabled();
+
+    /* Nothing is known about the file besides its contents so guess the type from those */
+    let mut head = Vec::with_capacity(SNIFF_LENGTH);
+    if wants_head
+        && (&mut file)
+            .take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut head)
+            .await
+            .is_err()
+    {
+        return respond_with(
+            keep_alive_if(client_request_header),
+            HttpResponseStatus::INTERNAL_SERVER_ERROR,
+            &mut stream,
+        )
+        .await;
+    }
+    let content_type = sniff_enabled().then(|| sniff_content_type(&head));
+
+    #[cfg(feature = "compress")]
+    let (vary, encoding) = encoding_for(client_request_header, length, &head);
+
+    let tag = entity_tag(length, modified);
+    #[cfg(feature = "compress")]
+    let tag = encoded_tag(tag, encoding);
 
-    let mut status = HttpResponseStatus::OK;
     let mut headers = HttpHeader::new();
-    headers.insert(String::from("Content-Length"), metadata.len().to_string());
-
-    match client_request_header.headers.get("Range") {
-        None => {}
-        Some(range) => {
-            let range = range.trim();
-            if let Some(bytes) = range.strip_prefix("bytes=") {
-                let mut iter = bytes.split('-');
-                if let (Some(start), Some(end)) = (iter.next(), iter.next()) {
-                    start_position = start.parse::<u64>().unwrap_or(0);
-                    end_position = end.parse::<u64>().unwrap_or(length - 1);
-                    if end_position > start_position {
-                        headers.insert(
-                            String::from("Content-Range"),
-                            format!("bytes={start_position}-{end_position}/{length}"),
-                        );
-                        status = HttpResponseStatus::PARTIAL_CONTENT;
-                    }
-                }
-            }
+    headers.insert(String::from("Cache-Status"), format!("{PKG_NAME}; hit"));
+    headers.insert(String::from("ETag"), tag.clone());
+    if let Some(modified) = modified {
+        headers.insert(
+            String::from("Last-Modified"),
+            httpdate::fmt_http_date(modified),
+        );
+    }
+    #[cfg(feature = "compress")]
+    if vary {
+        headers.insert(String::from("Vary"), "Accept-Encoding".to_string());
+    }
+
+    if not_modified(client_request_header, &tag, modified) {
+        let mut header = HttpResponseHeader {
+            status: HttpResponseStatus::NOT_MODIFIED,
+            headers,
+            version: HttpVersion::HTTP_V11,
+        };
+        header.close_after(client_request_header);
+
+        return match stream.write_all(header.generate().as_bytes()).await {
+            Ok(_) => keep_alive_if(client_request_header),
+            Err(_) => Close,
+        };
+    }
+
+    let head_only = client_request_header.method == HttpRequestMethod::Head;
+
+    #[cfg(feature = "compress")]
+    if let Some(encoding) = encoding {
+        return serve_compressed(
+            file,
+            stream,
+            client_request_header,
+            headers,
+            content_type,
+            encoding,
+        )
+        .await;
+    }
+
+    headers.insert(String::from("Accept-Ranges"), "bytes".to_string());
+    if let Some(digest) = recall_digest(cache_file_path, length, modified) {
+        headers.insert(
+            String::from("Digest"),
+            format!("sha-256={}", encode_base64(&digest.sha256)),
+        );
+    }
+
+    /* A stale If-Range means the client's partial copy is of something else, send it everything */
+    let range = match if_range_matches(client_request_header.headers.get("If-Range"), modified) {
+        true if !head_only => client_request_header.headers.get("Range"),
+        _ => None,
+    };
+
+    let ranges = match parse_range(range, length) {
+        RangeRequest::Full => {
+            headers.insert(String::from("Content-Length"), length.to_string());
+            vec![(0, length - 1)]
+        }
+        RangeRequest::Satisfiable(start, end) => {
+            headers.insert(
+                String::from("Content-Length"),
+                (end - start + 1).to_string(),
+            );
+            headers.insert(
+                String::from("Content-Range"),
+                format!("bytes {start}-{end}/{length}"),
+            );
+            vec![(start, end)]
+        }
+        RangeRequest::Multiple(ranges) => {
+            return serve_multiple_ranges(
+                file,
+                stream,
+                client_request_header,
+                headers,
+                content_type,
+                length,
+                ranges,
+            )
+            .await
+        }
+        RangeRequest::Unsatisfiable => {
+            headers.insert(String::from("Content-Length"), "0".to_string());
+            headers.insert(String::from("Content-Range"), format!("bytes */{length}"));
+
+            let mut header = HttpResponseHeader {
+                status: HttpResponseStatus::RANGE_NOT_SATISFIABLE,
+                headers,
+                version: HttpVersion::HTTP_V11,
+            };
+            header.close_after(client_request_header);
+
+            return match stream.write_all(header.generate().as_bytes()).await {
+                Ok(_) => keep_alive_if(client_request_header),
+                Err(_) => Close,
+            };
         }
+    };
+
+    if let Some(content_type) = content_type {
+        headers.insert(String::from("Content-Type"), content_type.to_string());
+        headers.insert(
+            String::from("X-Content-Type-Options"),
+            "nosniff".to_string(),
+        );
     }
 
+    let status = match headers.contains_key("Content-Range") {
+        true => HttpResponseStatus::PARTIAL_CONTENT,
+        false => HttpResponseStatus::OK,
+    };
+
     let mut header = HttpResponseHeader {
         status,
         headers,
         version: HttpVersion::HTTP_V11,
     };
+    header.close_after(client_request_header);
 
     let header = header.generate();
-    let _ = stream.write_all(header.as_ref()).await;
-    let mut buffer = vec![0; BUFFER_SIZE];
-    let _ = file.seek(SeekFrom::Start(start_position)).await;
+    wire_log(
+        "Client response",
+        &client_request_header.request.uri,
+        || header.clone(),
+    );
+    if stream.write_all(header.as_ref()).await.is_err() {
+        return Close;
+    }
 
-    if end_position <= start_position {
-        return respond_with(
-            keep_alive_if(client_request_header),
-            HttpResponseStatus::BAD_REQUEST,
-            &mut stream,
-        )
-        .await;
+    if head_only {
+        return keep_alive_if(client_request_header);
     }
 
-    let mut bytes: u64 = end_position - start_position + 1;
+    let (start_position, end_position) = ranges[0];
+    match send_file_range(&mut stream, &mut file, start_position, end_position).await {
+        true => keep_alive_if(client_request_header), /* Existing file transfer finished */
+        false => Close,                               /* Something went wrong mid-transmission */
+    }
+}
+
+/// Write bytes `start` to `end` inclusive of `file` to `stream`
+async fn send_file_range<T>(stream: &mut T, file: &mut File, start: u64, end: u64) -> bool
+where
+    T: AsyncWrite + ZeroCopy + Unpin,
+{
+    let mut bytes: u64 = end - start + 1;
+
+    match stream.send_file(file, start, bytes).await {
+        None => {} /* Zero-copy isn't possible on this stream, copy through the buffer */
+        Some(Ok(_)) => return true,
+        Some(Err(_)) => return false,
+    }
+
+    if file.seek(SeekFrom::Start(start)).await.is_err() {
+        return false;
+    }
+
+    let mut buffer = vec![0; BUFFER_SIZE];
 
     while bytes > 0 {
         let bytes_to_read = std::cmp::min(BUFFER_SIZE as u64, bytes) as usize;
@@ -462,12 +1256,242 @@ where
             Ok(0) => break,
             Ok(n) => {
                 if stream.write_all(&buffer[..n]).await.is_err() {
-                    return Close; /* Something went wrong mid-transmission */
+                    return false;
                 }
                 bytes -= n as u64;
             }
             Err(_) => break,
         }
     }
-    keep_alive_if(client_request_header) /* Existing file transfer finished */
+
+    true
+}
+
+/* The length of the compressed file isn't known until it's been sent, so it's sent in chunks */
+#[cfg(feature = "compress")]
+async fn serve_compressed<T>(
+    mut file: File,
+    mut stream: T,
+    client_request_header: &HttpRequestHeader<'_>,
+    mut headers: HttpHeader,
+    content_type: Option<&str>,
+    encoding: Encoding,
+) -> ConnectionReturn
+where
+    T: AsyncWrite + Unpin,
+{
+    headers.insert(
+        String::from("Content-Encoding"),
+        encoding.name().to_string(),
+    );
+    headers.insert(String::from("Transfer-Encoding"), "chunked".to_string());
+    if let Some(content_type) = content_type {
+        headers.insert(String::from("Content-Type"), content_type.to_string());
+        headers.insert(
+            String::from("X-Content-Type-Options"),
+            "nosniff".to_string(),
+        );
+    }
+
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::OK,
+        headers,
+        version: HttpVersion::HTTP_V11,
+    };
+    header.close_after(client_request_header);
+
+    let header = header.generate();
+    wire_log(
+        "Client response",
+        &client_request_header.request.uri,
+        || header.clone(),
+    );
+    if stream.write_all(header.as_bytes()).await.is_err() {
+        return Close;
+    }
+
+    if client_request_header.method == HttpRequestMethod::Head {
+        return keep_alive_if(client_request_header);
+    }
+
+    match send_compressed(&mut stream, &mut file, encoding).await {
+        true => keep_alive_if(client_request_header),
+        false => Close,
+    }
+}
+
+async fn serve_multiple_ranges<T>(
+    mut file: File,
+    mut stream: T,
+    client_request_header: &HttpRequestHeader<'_>,
+    mut headers: HttpHeader,
+    content_type: Option<&str>,
+    length: u64,
+    ranges: Vec<(u64, u64)>,
+) -> ConnectionReturn
+where
+    T: AsyncRead + AsyncWrite + ZeroCopy + Unpin,
+{
+    use crate::http::{END_OF_HTTP_HEADER, END_OF_HTTP_HEADER_LINE};
+
+    let boundary = format!(
+        "{}{:016x}",
+        crate::PKG_NAME,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+    );
+
+    let content_type = content_type.unwrap_or("application/octet-stream");
+    let part_headers: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| {
+            format!(
+                "{END_OF_HTTP_HEADER_LINE}--{boundary}{END_OF_HTTP_HEADER_LINE}\
+                Content-Type: {content_type}{END_OF_HTTP_HEADER_LINE}\
+                Content-Range: bytes {start}-{end}/{length}{END_OF_HTTP_HEADER}"
+            )
+        })
+        .collect();
+
+    let closing = format!("{END_OF_HTTP_HEADER_LINE}--{boundary}--{END_OF_HTTP_HEADER_LINE}");
+
+    let content_length = part_headers.iter().map(|p| p.len() as u64).sum::<u64>()
+        + ranges.iter().map(|(s, e)| e - s + 1).sum::<u64>()
+        + closing.len() as u64;
+
+    headers.insert(String::from("Content-Length"), content_length.to_string());
+    headers.insert(
+        String::from("Content-Type"),
+        format!("multipart/byteranges; boundary={boundary}"),
+    );
+
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::PARTIAL_CONTENT,
+        headers,
+        version: HttpVersion::HTTP_V11,
+    };
+    header.close_after(client_request_header);
+
+    if stream
+        .write_all(header.generate().as_bytes())
+        .await
+        .is_err()
+    {
+        return Close;
+    }
+
+    for (part_header, (start, end)) in part_headers.iter().zip(ranges) {
+        if stream.write_all(part_header.as_bytes()).await.is_err() {
+            return Close;
+        }
+
+        if !send_file_range(&mut stream, &mut file, start, end).await {
+            return Close;
+        }
+    }
+
+    match stream.write_all(closing.as_bytes()).await {
+        Ok(_) => keep_alive_if(client_request_header),
+        Err(_) => Close,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{http::ConnectionReturn::Keep, PKG_NAME},
+    };
+
+    /* What a follower sends for a file holding `hello` once its flight ends one way or another */
+    async fn follow(state: FlightState, complete: bool) -> (ConnectionReturn, String) {
+        let path = std::env::temp_dir().join(format!("{PKG_NAME}-test-follow-{complete}"));
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let key = path.to_string_lossy().to_string();
+
+        let flights = Arc::new(Flights::new());
+        flights.takeoff(&key, FlightState::Fetching).await;
+        flights.takeoff(&key, state).await;
+        let flight = flights.follow(&key).await.unwrap();
+        if complete {
+            flights.complete(&key).await;
+        }
+        flights.land(&key).await;
+
+        let header = HttpRequestHeader {
+            method: HttpRequestMethod::Get,
+            request: conn::Uri::from("http://example.com/file".to_string()),
+            version: HttpVersion::HTTP_V11,
+            headers: HttpHeader::new(),
+        };
+
+        let (client, mut server) = tokio::io::duplex(BUFFER_SIZE);
+        let file = File::open(&path).await.unwrap();
+        let r = match state {
+            FlightState::Length(l) => {
+                serve_in_flight_file_length(file, &mut server, flight, &header, l).await
+            }
+            _ => serve_in_flight_file_chunks(file, &mut server, flight, &header).await,
+        };
+        drop(server);
+
+        let mut sent = String::new();
+        let _ = BufReader::new(client).read_to_string(&mut sent).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        (r, sent)
+    }
+
+    #[tokio::test]
+    async fn test_follow_flight() {
+        let (r, sent) = follow(FlightState::Chunks, true).await;
+        assert!(r == Keep);
+        assert!(sent.ends_with("5\r\nhello\r\n0\r\n\r\n"));
+
+        /* A download that died must never look finished to a client reading along */
+        let (r, sent) = follow(FlightState::Chunks, false).await;
+        assert!(r == Close);
+        assert!(sent.ends_with("5\r\nhello\r\n"));
+
+        let (r, sent) = follow(FlightState::Length(5), true).await;
+        assert!(r == Keep);
+        assert!(sent.ends_with("\r\n\r\nhello"));
+
+        let (r, _) = follow(FlightState::Length(10), false).await;
+        assert!(r == Close);
+    }
+
+    #[tokio::test]
+    async fn test_read_pipelined_requests() {
+        let (mut client, server) = tokio::io::duplex(BUFFER_SIZE);
+        let mut stream = BufReader::new(server);
+
+        /* Both arrive at once, the second is kept for the next read */
+        client
+            .write_all(
+                b"GET http://example.com/a HTTP/1.1\r\nHost: example.com\r\n\r\n\
+                GET http://example.com/b HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut served = 0;
+        let first = read_http_request(&mut stream, &mut served).await.unwrap();
+        assert_eq!(first.request.path, Some("/a"));
+        assert!(keep_alive_if(&first) == Keep);
+
+        let second = read_http_request(&mut stream, &mut served).await.unwrap();
+        assert_eq!(second.request.path, Some("/b"));
+        assert_eq!(served, 2);
+
+        /* The last request a connection may make closes it */
+        served = keep_alive_max() - 1;
+        client
+            .write_all(b"GET http://example.com/c HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let last = read_http_request(&mut stream, &mut served).await.unwrap();
+        assert!(keep_alive_if(&last) == Close);
+    }
 }
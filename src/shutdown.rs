@@ -0,0 +1,141 @@
+use {
+    crate::{cancel::Cancellation, cli::setting, conn::Flights, rules::parse_duration},
+    std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            OnceLock,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::time::sleep,
+    tracing::{info, warn},
+};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+pub const X_PROXY_SHUTDOWN_TIMEOUT: &str = "X_PROXY_SHUTDOWN_TIMEOUT";
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often draining looks for connections and downloads that are still going
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long cancelled downloads get to remove their partial files before rproxy exits
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn shutdown_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        setting(X_PROXY_SHUTDOWN_TIMEOUT)
+            .ok()
+            .and_then(|s| parse_duration(&s))
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+    })
+}
+
+fn stopping() -> &'static Cancellation {
+    static STOPPING: OnceLock<Cancellation> = OnceLock::new();
+    STOPPING.get_or_init(Cancellation::new)
+}
+
+/// Whether rproxy has been told to stop, connections finish the request they're on then close
+pub(crate) fn draining() -> bool {
+    stopping().is_cancelled()
+}
+
+/// Resolves once rproxy has been told to stop, listeners stop accepting when it does
+pub(crate) async fn stopped() {
+    stopping().clone().cancelled().await
+}
+
+/// A client connection being served, counted until it's dropped
+pub(crate) struct Serving;
+
+impl Serving {
+    pub(crate) fn new() -> Self {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Serving
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn connections() -> usize {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Resolves when `SIGTERM` or `SIGINT` arrives
+pub(crate) async fn termination() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("can't shut down gracefully on SIGTERM: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Stop accepting connections and let the ones being served finish, along with every download,
+/// for up to `X_PROXY_SHUTDOWN_TIMEOUT`. Whatever's left after that is cancelled,
+/// which removes the partial files of downloads that didn't finish.
+pub(crate) async fn drain(flights: &Flights, shutdown: &Cancellation) {
+    stopping().cancel();
+    let deadline = Instant::now() + shutdown_timeout();
+    info!(
+        "shutting down, waiting up to {}s for transfers to finish",
+        shutdown_timeout().as_secs()
+    );
+
+    while connections() > 0 || flights.count().await > 0 {
+        if Instant::now() >= deadline {
+            warn!(
+                "cancelling {} connection(s) and {} download(s) that didn't finish",
+                connections(),
+                flights.count().await
+            );
+            break;
+        }
+        sleep(DRAIN_INTERVAL).await;
+    }
+
+    shutdown.cancel();
+    let cleanup = Instant::now() + CLEANUP_TIMEOUT;
+    while flights.count().await > 0 && Instant::now() < cleanup {
+        sleep(DRAIN_INTERVAL).await;
+    }
+    info!("shut down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serving() {
+        let before = connections();
+        let a = Serving::new();
+        let b = Serving::new();
+        assert_eq!(connections(), before + 2);
+        drop(a);
+        assert_eq!(connections(), before + 1);
+        drop(b);
+        assert_eq!(connections(), before);
+    }
+}
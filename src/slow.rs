@@ -0,0 +1,271 @@
+use {
+    crate::{cli::setting, evict::parse_size, rules::parse_duration, zerocopy::ZeroCopy},
+    std::{
+        future::Future,
+        io,
+        pin::Pin,
+        sync::OnceLock,
+        task::{Context, Poll},
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        time::{sleep, timeout, Duration, Instant, Sleep},
+    },
+};
+
+pub const X_PROXY_HEADER_TIMEOUT: &str = "X_PROXY_HEADER_TIMEOUT";
+pub const X_PROXY_MIN_SEND_RATE: &str = "X_PROXY_MIN_SEND_RATE";
+
+/// How long a request header may take to arrive once it's started when `X_PROXY_HEADER_TIMEOUT` isn't defined
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Bytes a second a client must take when `X_PROXY_MIN_SEND_RATE` isn't defined
+const DEFAULT_MIN_SEND_RATE: u64 = 1024;
+
+/// How long a client is given to catch up before it's judged too slow
+const SEND_WINDOW: Duration = Duration::from_secs(30);
+
+/// How long the rest of a request header may take once its first byte arrives
+pub(crate) fn header_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        setting(X_PROXY_HEADER_TIMEOUT)
+            .ok()
+            .and_then(|t| parse_duration(&t))
+            .filter(|t| !t.is_zero())
+            .unwrap_or(DEFAULT_HEADER_TIMEOUT)
+    })
+}
+
+/// The slowest a client may take a response in bytes a second, `0` lets it take as long as it likes
+fn min_send_rate() -> u64 {
+    static RATE: OnceLock<u64> = OnceLock::new();
+    *RATE.get_or_init(|| {
+        setting(X_PROXY_MIN_SEND_RATE)
+            .ok()
+            .and_then(|r| parse_size(&r))
+            .unwrap_or(DEFAULT_MIN_SEND_RATE)
+    })
+}
+
+fn too_slow() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "client is taking the response too slowly",
+    )
+}
+
+/// A client connection that fails its writes once the client has kept them waiting for
+/// [`SEND_WINDOW`] while taking less than the minimum rate, so a client reading a byte at a
+/// time can't hold a response open for ever. Only time spent waiting on the client counts,
+/// a response that's slow because its origin server is doesn't.
+pub(crate) struct SlowGuard<S> {
+    inner: S,
+    rate: u64,
+    /// Bytes the client took since the window started
+    sent: u64,
+    /// How long writes waited on the client since the window started, not counting the current wait
+    blocked: Duration,
+    /// When the current wait on the client started
+    waiting: Option<Instant>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> SlowGuard<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self::with_rate(inner, min_send_rate())
+    }
+
+    fn with_rate(inner: S, rate: u64) -> Self {
+        SlowGuard {
+            inner,
+            rate,
+            sent: 0,
+            blocked: Duration::ZERO,
+            waiting: None,
+            deadline: None,
+        }
+    }
+
+    fn window_bytes(&self) -> u64 {
+        self.rate.saturating_mul(SEND_WINDOW.as_secs())
+    }
+
+    /* The window is over once writes waited for all of it, the client kept up if it took enough */
+    fn end_window(&mut self) -> io::Result<()> {
+        if self.sent < self.window_bytes() {
+            return Err(too_slow());
+        }
+        self.sent = 0;
+        self.blocked = Duration::ZERO;
+        self.waiting = None;
+        self.deadline = None;
+        Ok(())
+    }
+
+    fn poll_waiting(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let now = Instant::now();
+        let started = *self.waiting.get_or_insert(now);
+        let blocked = self.blocked;
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(sleep(SEND_WINDOW.saturating_sub(blocked))));
+        if deadline.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        self.blocked += now - started;
+        self.end_window()?;
+        self.poll_waiting(cx)
+    }
+
+    fn written(&mut self, count: usize) {
+        self.sent += count as u64;
+        if let Some(started) = self.waiting.take() {
+            self.blocked += started.elapsed();
+            self.deadline = None;
+        }
+        if self.blocked >= SEND_WINDOW {
+            let _ = self.end_window();
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SlowGuard<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SlowGuard<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) if self.rate > 0 => {
+                self.written(n);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Pending if self.rate > 0 => match self.poll_waiting(cx) {
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                _ => Poll::Pending,
+            },
+            r => r,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/* Files skip the writes, so they're sent a window's worth at a time and each has to go in time */
+impl<S: ZeroCopy> ZeroCopy for SlowGuard<S> {
+    async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        length: u64,
+    ) -> Option<io::Result<u64>> {
+        if self.rate == 0 {
+            return self.inner.send_file(file, offset, length).await;
+        }
+
+        let piece = self.window_bytes();
+        let mut sent = 0;
+        while sent < length {
+            let count = (length - sent).min(piece);
+            match timeout(
+                SEND_WINDOW,
+                self.inner.send_file(file, offset + sent, count),
+            )
+            .await
+            {
+                Ok(Some(Ok(n))) => {
+                    sent += n;
+                    /* The file is shorter than expected */
+                    if n < count {
+                        break;
+                    }
+                }
+                Ok(r) => return r,
+                Err(_) => return Some(Err(too_slow())),
+            }
+        }
+        Some(Ok(sent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::io::{duplex, AsyncReadExt, AsyncWriteExt},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_client() {
+        let (client, proxy) = duplex(16);
+        let mut guarded = SlowGuard::with_rate(proxy, 1);
+
+        /* A client that takes a byte every few seconds is too slow for one a second */
+        let reader = tokio::spawn(async move {
+            let mut client = client;
+            let mut byte = [0u8; 1];
+            while client.read(&mut byte).await.is_ok_and(|n| n > 0) {
+                sleep(Duration::from_secs(5)).await;
+            }
+        });
+        let result = guarded.write_all(&[0u8; 1024]).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        drop(guarded);
+        reader.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_steady_client() {
+        let (client, proxy) = duplex(16);
+        let mut guarded = SlowGuard::with_rate(proxy, 1);
+
+        let reader = tokio::spawn(async move {
+            let mut client = client;
+            let mut bytes = [0u8; 4];
+            let mut total = 0;
+            loop {
+                match client.read(&mut bytes).await {
+                    Ok(0) | Err(_) => return total,
+                    Ok(n) => total += n,
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+        assert!(guarded.write_all(&[0u8; 400]).await.is_ok());
+        drop(guarded);
+        assert_eq!(reader.await.unwrap(), 400);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_origin() {
+        let (mut client, proxy) = duplex(16);
+        let mut guarded = SlowGuard::with_rate(proxy, 1);
+
+        /* Time the proxy spends waiting on anything else isn't held against the client */
+        for _ in 0..3 {
+            guarded.write_all(&[0u8; 16]).await.unwrap();
+            sleep(SEND_WINDOW * 2).await;
+            let mut bytes = [0u8; 16];
+            client.read_exact(&mut bytes).await.unwrap();
+        }
+    }
+}
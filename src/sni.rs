@@ -0,0 +1,164 @@
+/// The largest TLS record, a ClientHello any longer is spread over several and isn't read
+pub(crate) const MAX_RECORD_LENGTH: usize = 5 + 16384;
+
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// How long the TLS record starting `head` is, `None` when it isn't a handshake record
+/// and nothing more should be waited for
+pub(crate) fn record_length(head: &[u8]) -> Option<usize> {
+    match head.first() {
+        Some(&CONTENT_HANDSHAKE) | None => {}
+        Some(_) => return None,
+    }
+
+    match head.get(3..5) {
+        Some(l) => Some(5 + u16::from_be_bytes([l[0], l[1]]) as usize),
+        None => Some(5),
+    }
+}
+
+/// Reads lengths and fields off the front of a ClientHello, stopping at the first that runs off the end
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let taken = self.bytes.get(..length)?;
+        self.bytes = &self.bytes[length..];
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let b = self.take(3)?;
+        Some((b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    fn vector8(&mut self) -> Option<&'a [u8]> {
+        let length = self.u8()? as usize;
+        self.take(length)
+    }
+
+    fn vector16(&mut self) -> Option<&'a [u8]> {
+        let length = self.u16()? as usize;
+        self.take(length)
+    }
+}
+
+/// The server name a client asked for in the ClientHello that starts `record`, lowercase.
+/// `None` when it isn't a ClientHello, has no server name or is cut short.
+pub(crate) fn server_name(record: &[u8]) -> Option<String> {
+    let mut reader = Reader { bytes: record };
+    if reader.u8()? != CONTENT_HANDSHAKE {
+        return None;
+    }
+    reader.take(2)?; /* Record version */
+    let mut record = Reader {
+        bytes: reader.vector16()?,
+    };
+
+    if record.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let length = record.u24()?;
+    let mut hello = Reader {
+        bytes: record.take(length)?,
+    };
+
+    hello.take(2 + 32)?; /* Version and random */
+    hello.vector8()?; /* Session ID */
+    hello.vector16()?; /* Cipher suites */
+    hello.vector8()?; /* Compression methods */
+
+    let mut extensions = Reader {
+        bytes: hello.vector16()?,
+    };
+    while !extensions.bytes.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vector16()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader { bytes: data };
+        let mut names = Reader {
+            bytes: names.vector16()?,
+        };
+        while !names.bytes.is_empty() {
+            let kind = names.u8()?;
+            let name = names.vector16()?;
+            if kind == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return match name.is_empty() {
+                    true => None,
+                    false => Some(name.trim_end_matches('.').to_lowercase()),
+                };
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ClientHello with `name` as its only server name, after an extension that isn't one
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut server_name = vec![0, 0];
+        let list_length = (name.len() + 3) as u16;
+        server_name.extend_from_slice(&(list_length + 2).to_be_bytes());
+        server_name.extend_from_slice(&list_length.to_be_bytes());
+        server_name.push(NAME_TYPE_HOST_NAME);
+        server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(name.as_bytes());
+
+        let mut extensions = vec![0, 10, 0, 2, 0, 0];
+        extensions.extend_from_slice(&server_name);
+
+        let mut hello = vec![3, 3];
+        hello.extend_from_slice(&[7; 32]);
+        hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_HANDSHAKE, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_server_name() {
+        let hello = client_hello("Deb.Debian.org.");
+        assert_eq!(server_name(&hello).as_deref(), Some("deb.debian.org"));
+        assert_eq!(record_length(&hello), Some(hello.len()));
+
+        /* Cut short anywhere */
+        for end in 0..hello.len() {
+            assert_eq!(server_name(&hello[..end]), None);
+        }
+
+        assert_eq!(server_name(&client_hello("")), None);
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(record_length(b"GET / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(record_length(&[CONTENT_HANDSHAKE, 3]), Some(5));
+    }
+}
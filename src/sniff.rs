@@ -0,0 +1,126 @@
+use {crate::cli::setting, std::sync::OnceLock};
+
+pub const X_PROXY_CONTENT_SNIFF: &str = "X_PROXY_CONTENT_SNIFF";
+
+/// How many bytes from the start of a file are needed to recognise every known type
+pub(crate) const SNIFF_LENGTH: usize = 512;
+
+const FALLBACK_CONTENT_TYPE: &str = "application/octet-stream";
+
+/* Deliberately missing anything a browser might render as active content such as HTML or SVG */
+const SIGNATURES: [(&[u8], &str); 19] = [
+    (b"!<arch>\ndebian", "application/vnd.debian.binary-package"),
+    (b"!<arch>\n", "application/x-archive"),
+    (b"\x1F\x8B", "application/gzip"),
+    (b"\xFD7zXZ\x00", "application/x-xz"),
+    (b"\x28\xB5\x2F\xFD", "application/zstd"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\x5D\x00\x00", "application/x-lzma"),
+    (b"7z\xBC\xAF\x27\x1C", "application/x-7z-compressed"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\xED\xAB\xEE\xDB", "application/x-rpm"),
+    (b"\x7FELF", "application/x-executable"),
+    (b"MZ", "application/vnd.microsoft.portable-executable"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x89PNG\r\n\x1A\n", "image/png"),
+    (b"\xFF\xD8\xFF", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (
+        b"-----BEGIN PGP SIGNATURE-----",
+        "application/pgp-signature",
+    ),
+    (b"-----BEGIN PGP", "application/pgp-keys"),
+];
+
+/// Sniffing is on unless `X_PROXY_CONTENT_SNIFF` switches it off
+pub fn sniff_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| match setting(X_PROXY_CONTENT_SNIFF) {
+        Ok(v) => !matches!(v.trim(), "0" | "false" | "off"),
+        Err(_) => true,
+    })
+}
+
+/// Guess a content type from the first bytes of a file.
+/// Anything unrecognised is served as `application/octet-stream`.
+pub(crate) fn sniff_content_type(head: &[u8]) -> &'static str {
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(m, _)| head.starts_with(m)) {
+        return content_type;
+    }
+
+    if head.len() > 262 && &head[257..262] == b"ustar" {
+        return "application/x-tar";
+    }
+
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    /* Markup is left untyped rather than risk it being rendered */
+    if looks_like_text(head) && !head.trim_ascii_start().starts_with(b"<") {
+        return "text/plain; charset=utf-8";
+    }
+
+    FALLBACK_CONTENT_TYPE
+}
+
+/// Whether the first bytes of a file are UTF-8 without control characters, markup included
+pub(crate) fn looks_like_text(head: &[u8]) -> bool {
+    if head.is_empty() {
+        return false;
+    }
+
+    let text = match std::str::from_utf8(head) {
+        Ok(t) => t,
+        /* The sample may have cut a multibyte character in half */
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+
+    text.chars()
+        .all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t' | '\x0C'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_content_type() {
+        assert_eq!(
+            sniff_content_type(b"!<arch>\ndebian-binary   "),
+            "application/vnd.debian.binary-package"
+        );
+        assert_eq!(sniff_content_type(b"\x1F\x8B\x08\x00"), "application/gzip");
+        assert_eq!(sniff_content_type(b"\xFD7zXZ\x00\x00"), "application/x-xz");
+        assert_eq!(sniff_content_type(b"\x89PNG\r\n\x1A\n\0"), "image/png");
+        assert_eq!(
+            sniff_content_type(b"Origin: Debian\nSuite: stable\n"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            sniff_content_type(b"<html><script>alert(1)</script>"),
+            FALLBACK_CONTENT_TYPE
+        );
+        assert_eq!(
+            sniff_content_type(b"\x00\x01\x02\x03"),
+            FALLBACK_CONTENT_TYPE
+        );
+        assert_eq!(sniff_content_type(b""), FALLBACK_CONTENT_TYPE);
+
+        let mut tar = vec![0u8; SNIFF_LENGTH];
+        tar[..8].copy_from_slice(b"file.txt");
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff_content_type(&tar), "application/x-tar");
+    }
+
+    #[test]
+    fn test_sniff_truncated_utf8() {
+        let mut head = "naïve text ".as_bytes().to_vec();
+        head.extend_from_slice(&"é".as_bytes()[..1]);
+        assert_eq!(sniff_content_type(&head), "text/plain; charset=utf-8");
+    }
+}
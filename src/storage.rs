@@ -0,0 +1,166 @@
+use {
+    crate::{cli::setting, http::X_PROXY_CACHE_PATH, layout::layout_marker, PKG_NAME},
+    std::{
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            OnceLock,
+        },
+    },
+    tokio::{
+        fs::{remove_file, write},
+        time::{sleep, Duration},
+    },
+    tracing::{error, info},
+};
+
+pub const X_PROXY_CACHE_UNWRITABLE: &str = "X_PROXY_CACHE_UNWRITABLE";
+
+/// How often the cache path is checked to still be there and writable
+const STORAGE_INTERVAL: Duration = Duration::from_secs(5);
+
+/* Files in the cache root are not cache entries so the probe is never mistaken for one */
+const PROBE_FILE_NAME: &str = ".probe";
+
+/// What clients are given for files that aren't cached while the cache path can't be written to
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum UnwritableResponse {
+    /// Fetch the file from the origin for the client without keeping a copy
+    #[default]
+    Relay,
+    /// `503 Service Unavailable`, for clients that should go elsewhere rather than
+    /// pull through a proxy that isn't caching
+    Unavailable,
+}
+
+impl UnwritableResponse {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "relay" => Some(UnwritableResponse::Relay),
+            "unavailable" => Some(UnwritableResponse::Unavailable),
+            _ => None,
+        }
+    }
+}
+
+static UNWRITABLE: AtomicBool = AtomicBool::new(false);
+
+static RESPONSE: OnceLock<UnwritableResponse> = OnceLock::new();
+
+/// Whether new files can be written to the cache path. While they can't,
+/// nothing is fetched into the cache and clients are given the [`unwritable_response`].
+pub(crate) fn cache_writable() -> bool {
+    !UNWRITABLE.load(Ordering::Relaxed)
+}
+
+pub(crate) fn unwritable_response() -> UnwritableResponse {
+    RESPONSE.get().copied().unwrap_or_default()
+}
+
+/// Read `X_PROXY_CACHE_UNWRITABLE`, false when it isn't a response rproxy knows
+pub(crate) fn setup_storage() -> bool {
+    let response = match setting(X_PROXY_CACHE_UNWRITABLE) {
+        Err(_) => UnwritableResponse::default(),
+        Ok(s) => match UnwritableResponse::from_name(&s) {
+            Some(r) => r,
+            None => {
+                error!("'{X_PROXY_CACHE_UNWRITABLE}' must be 'relay' or 'unavailable': '{s}'");
+                return false;
+            }
+        },
+    };
+
+    let _ = RESPONSE.set(response);
+    true
+}
+
+/* A volume that's been unmounted leaves behind an empty directory that may well be writable,
+ * so the layout marker written at startup going missing counts as the cache being gone */
+async fn probe(store_path: &Path) -> Result<(), String> {
+    if !layout_marker(store_path).is_file() {
+        return Err("the cache layout marker is missing".to_string());
+    }
+
+    let probe_path = store_path.join(PROBE_FILE_NAME);
+    write(&probe_path, PKG_NAME)
+        .await
+        .map_err(|e| e.to_string())?;
+    remove_file(&probe_path).await.map_err(|e| e.to_string())
+}
+
+async fn update(store_path: &Path) {
+    let probed = probe(store_path).await;
+    match (probed, cache_writable()) {
+        (Err(e), true) => {
+            UNWRITABLE.store(true, Ordering::Relaxed);
+            let response = match unwritable_response() {
+                UnwritableResponse::Relay => "relaying requests to origin servers uncached",
+                UnwritableResponse::Unavailable => "answering requests with 503",
+            };
+            error!(
+                "cache path '{}' can't be written to ({e}), {response} until it can",
+                store_path.to_string_lossy()
+            );
+        }
+        (Ok(_), false) => {
+            UNWRITABLE.store(false, Ordering::Relaxed);
+            info!(
+                "cache path '{}' can be written to again, caching resumed",
+                store_path.to_string_lossy()
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Check the cache path straight away after a cache file couldn't be created,
+/// rather than waiting for the next check to notice the volume has gone
+pub(crate) async fn storage_failed() {
+    if let Ok(s) = setting(X_PROXY_CACHE_PATH) {
+        update(&PathBuf::from(s)).await;
+    }
+}
+
+/// Watch the cache path, switching to the [`unwritable_response`] while it can't be written to
+/// and back to caching once it can
+pub(crate) async fn storage_loop(store_path: PathBuf) {
+    loop {
+        sleep(STORAGE_INTERVAL).await;
+        update(&store_path).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unwritable_response_from_name() {
+        assert_eq!(
+            UnwritableResponse::from_name(" Relay"),
+            Some(UnwritableResponse::Relay)
+        );
+        assert_eq!(
+            UnwritableResponse::from_name("unavailable"),
+            Some(UnwritableResponse::Unavailable)
+        );
+        assert_eq!(UnwritableResponse::from_name("500"), None);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let store_path = std::env::temp_dir().join(format!("{PKG_NAME}-test-probe"));
+        let _ = tokio::fs::remove_dir_all(&store_path).await;
+        tokio::fs::create_dir_all(&store_path).await.unwrap();
+
+        /* An empty directory left where a volume was mounted */
+        assert!(probe(&store_path).await.is_err());
+
+        write(layout_marker(&store_path), "1 flat\n").await.unwrap();
+        assert!(probe(&store_path).await.is_ok());
+        assert!(!store_path.join(PROBE_FILE_NAME).exists());
+
+        tokio::fs::remove_dir_all(&store_path).await.unwrap();
+        assert!(probe(&store_path).await.is_err());
+    }
+}
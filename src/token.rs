@@ -0,0 +1,442 @@
+use {
+    crate::{
+        accounting::civil_date,
+        auth::{basic_credentials, AuthBackend, Checked},
+        cli::setting,
+        clock::now,
+        digest::Sha256,
+        http::HttpRequestHeader,
+    },
+    std::{
+        collections::HashMap,
+        convert::TryFrom,
+        path::PathBuf,
+        sync::{Arc, Mutex, OnceLock, RwLock},
+    },
+};
+
+#[cfg(feature = "web-ui")]
+use {
+    crate::digest::to_hex,
+    std::io::Read,
+    tracing::{info, warn},
+};
+
+pub const X_PROXY_AUTH_TOKENS: &str = "X_PROXY_AUTH_TOKENS";
+
+/// Longest token ID accepted, IDs end up in reports and log lines
+const MAX_TOKEN_ID_LENGTH: usize = 64;
+
+/// Random bytes in each token, only their digest is saved
+#[cfg(feature = "web-ui")]
+const TOKEN_SECRET_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Token {
+    /// SHA-256 of the secret part of the token
+    digest: [u8; 32],
+    /// Bytes a day the token may be served, unlimited when `None`
+    pub(crate) quota: Option<u64>,
+}
+
+struct TokenStore {
+    #[cfg(feature = "web-ui")]
+    path: PathBuf,
+    tokens: RwLock<HashMap<String, Token>>,
+}
+
+/* Replaced when the configuration is reloaded, `None` when tokens aren't in use */
+static TOKENS: RwLock<Option<Arc<TokenStore>>> = RwLock::new(None);
+
+fn token_store() -> Option<Arc<TokenStore>> {
+    match TOKENS.read() {
+        Ok(t) => t.clone(),
+        Err(e) => e.into_inner().clone(),
+    }
+}
+
+/* Keyed by token ID, with the day it was counted on. Kept apart from the tokens
+ * so reloading them doesn't hand out fresh quotas */
+fn usage_table() -> &'static Mutex<HashMap<String, (String, u64)>> {
+    static USAGE: OnceLock<Mutex<HashMap<String, (String, u64)>>> = OnceLock::new();
+    USAGE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_TOKEN_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn secret_digest(secret: &str) -> [u8; 32] {
+    let mut hasher = Sha256::default();
+    hasher.update(secret.as_bytes());
+    hasher.finish()
+}
+
+/* One `id,digest,quota` line per token, the quota is empty when unlimited */
+fn parse_tokens(contents: &str) -> HashMap<String, Token> {
+    let mut tokens = HashMap::new();
+
+    for line in contents.lines().map(|l| l.trim()) {
+        let mut fields = line.split(',');
+        let (id, digest, quota) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(i), Some(d), Some(q)) if valid_id(i) => (i, d, q),
+            _ => continue,
+        };
+
+        let digest = match (0..digest.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(digest.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .and_then(|d| <[u8; 32]>::try_from(d).ok())
+        {
+            Some(d) => d,
+            None => continue,
+        };
+
+        let quota = match quota {
+            "" => None,
+            q => match q.parse() {
+                Ok(q) => Some(q),
+                Err(_) => continue,
+            },
+        };
+
+        tokens.insert(id.to_string(), Token { digest, quota });
+    }
+
+    tokens
+}
+
+#[cfg(feature = "web-ui")]
+fn write_tokens(tokens: &HashMap<String, Token>) -> String {
+    let mut ids: Vec<&String> = tokens.keys().collect();
+    ids.sort();
+
+    let mut contents = String::new();
+    for id in ids {
+        let token = &tokens[id];
+        contents.push_str(&format!(
+            "{id},{},{}\n",
+            to_hex(&token.digest),
+            token.quota.map(|q| q.to_string()).unwrap_or_default()
+        ));
+    }
+    contents
+}
+
+/// Accepts service account tokens given as the password of `Basic` credentials
+/// with the token's ID as the user name, for clients that can't send `Bearer`
+struct ServiceAccounts;
+
+impl AuthBackend for ServiceAccounts {
+    fn name(&self) -> String {
+        let count = token_store()
+            .and_then(|t| t.tokens.read().ok().map(|t| t.len()))
+            .unwrap_or_default();
+        format!("{count} service account token(s)")
+    }
+
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> Checked<'a> {
+        let accepted = token_valid(password).is_some_and(|id| id == user);
+        Box::pin(async move { accepted })
+    }
+}
+
+fn read_tokens(path: PathBuf) -> Result<TokenStore, String> {
+    let tokens = match std::fs::read_to_string(&path) {
+        Ok(c) => parse_tokens(&c),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(format!("couldn't read '{}': {e}", path.to_string_lossy())),
+    };
+
+    Ok(TokenStore {
+        #[cfg(feature = "web-ui")]
+        path,
+        tokens: RwLock::new(tokens),
+    })
+}
+
+/// Load the tokens in `X_PROXY_AUTH_TOKENS`, which is created when the first token is issued.
+/// Each reload reads the file again, so tokens edited in it by hand take effect.
+/// Returns the backend checking them, `None` when tokens aren't in use.
+pub(crate) fn setup_tokens() -> Result<Option<Arc<dyn AuthBackend>>, String> {
+    let store = match setting(X_PROXY_AUTH_TOKENS) {
+        Err(_) => None,
+        Ok(p) => Some(Arc::new(read_tokens(PathBuf::from(p))?)),
+    };
+    let in_use = store.is_some();
+
+    match TOKENS.write() {
+        Ok(mut t) => *t = store,
+        Err(e) => *e.into_inner() = store,
+    }
+
+    match in_use {
+        true => Ok(Some(Arc::new(ServiceAccounts))),
+        false => Ok(None),
+    }
+}
+
+/// The ID of `token` when it's one that was issued and hasn't been revoked
+pub(crate) fn token_valid(token: &str) -> Option<String> {
+    let (id, secret) = token.trim().rsplit_once('.')?;
+    let expected = token_store()?.tokens.read().ok()?.get(id)?.digest;
+
+    let digest = secret_digest(secret);
+    match expected.iter().zip(digest).fold(0, |d, (a, b)| d | (a ^ b)) {
+        0 => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// The token sent as `Bearer` proxy credentials
+pub(crate) fn bearer_token(header: &HttpRequestHeader) -> Option<String> {
+    let credentials = header.headers.get("Proxy-Authorization")?;
+    Some(
+        credentials
+            .trim()
+            .strip_prefix("Bearer ")?
+            .trim()
+            .to_string(),
+    )
+}
+
+/// The ID of the service account token a request authenticated with, if it did
+pub(crate) fn request_token(header: &HttpRequestHeader) -> Option<String> {
+    token_store()?;
+    match bearer_token(header) {
+        Some(token) => token_valid(&token),
+        None => {
+            let (user, password) = basic_credentials(header)?;
+            token_valid(&password).filter(|id| *id == user)
+        }
+    }
+}
+
+/// Whether the token has been served as much as its quota allows today
+pub(crate) fn over_quota(id: &str) -> bool {
+    let store = match token_store() {
+        Some(s) => s,
+        None => return false,
+    };
+
+    let quota = match store.tokens.read() {
+        Ok(tokens) => match tokens.get(id).and_then(|t| t.quota) {
+            Some(q) => q,
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+
+    let today = civil_date(now());
+    match usage_table().lock() {
+        Ok(usage) => usage
+            .get(id)
+            .is_some_and(|(day, bytes)| *day == today && *bytes >= quota),
+        Err(_) => false,
+    }
+}
+
+/// Count bytes served to a token towards today's quota
+pub(crate) fn record_token_usage(id: &str, bytes: u64) {
+    if token_store().is_none() {
+        return;
+    }
+
+    let today = civil_date(now());
+    if let Ok(mut usage) = usage_table().lock() {
+        let (day, used) = usage.entry(id.to_string()).or_default();
+        if *day != today {
+            *day = today;
+            *used = 0;
+        }
+        *used += bytes;
+    }
+}
+
+#[cfg(feature = "web-ui")]
+/// Every token with its quota and what it has been served today
+pub(crate) fn token_list() -> Vec<(String, Option<u64>, u64)> {
+    let store = match token_store() {
+        Some(s) => s,
+        None => return Vec::new(),
+    };
+
+    let today = civil_date(now());
+    let usage = match usage_table().lock() {
+        Ok(u) => u.clone(),
+        Err(_) => HashMap::new(),
+    };
+
+    let mut list: Vec<(String, Option<u64>, u64)> = match store.tokens.read() {
+        Ok(tokens) => tokens
+            .iter()
+            .map(|(id, t)| {
+                let used = usage
+                    .get(id)
+                    .filter(|(day, _)| *day == today)
+                    .map(|(_, b)| *b)
+                    .unwrap_or_default();
+                (id.clone(), t.quota, used)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    list.sort();
+    list
+}
+
+#[cfg(feature = "web-ui")]
+fn random_secret() -> Option<String> {
+    let mut bytes = [0u8; TOKEN_SECRET_LENGTH];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .ok()?;
+    Some(to_hex(&bytes))
+}
+
+#[cfg(feature = "web-ui")]
+fn save_tokens(store: &TokenStore, tokens: &HashMap<String, Token>) -> bool {
+    match std::fs::write(&store.path, write_tokens(tokens)) {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("couldn't save '{}': {e}", store.path.to_string_lossy());
+            false
+        }
+    }
+}
+
+#[cfg(feature = "web-ui")]
+/// Issue a token for a new service account, replacing any it had before.
+/// This is the only time the token can be seen, only its digest is kept.
+pub(crate) fn issue_token(id: &str, quota: Option<u64>) -> Option<String> {
+    if !valid_id(id) {
+        return None;
+    }
+
+    let store = token_store()?;
+    let secret = random_secret()?;
+    let mut tokens = store.tokens.write().ok()?;
+    tokens.insert(
+        id.to_string(),
+        Token {
+            digest: secret_digest(&secret),
+            quota,
+        },
+    );
+
+    match save_tokens(&store, &tokens) {
+        true => {
+            info!("issued a token for service account '{id}'");
+            Some(format!("{id}.{secret}"))
+        }
+        false => {
+            tokens.remove(id);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "web-ui")]
+/// Revoke a service account's token, false if it didn't have one
+pub(crate) fn revoke_token(id: &str) -> bool {
+    let store = match token_store() {
+        Some(s) => s,
+        None => return false,
+    };
+
+    let mut tokens = match store.tokens.write() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+
+    match tokens.remove(id) {
+        Some(_) => {
+            info!("revoked the token of service account '{id}'");
+            save_tokens(&store, &tokens)
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::digest::to_hex};
+
+    #[test]
+    fn test_parse_tokens() {
+        let digest = secret_digest("s3cret");
+        let tokens = parse_tokens(&format!(
+            "ci-1,{},1073741824\nci-2,{},\nbad id,{},\nci-3,abcd,\n",
+            to_hex(&digest),
+            to_hex(&digest),
+            to_hex(&digest)
+        ));
+
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(
+            tokens["ci-1"],
+            Token {
+                digest,
+                quota: Some(1 << 30)
+            }
+        );
+        assert_eq!(tokens["ci-2"].quota, None);
+
+        #[cfg(feature = "web-ui")]
+        assert_eq!(parse_tokens(&write_tokens(&tokens)), tokens);
+    }
+
+    #[test]
+    fn test_valid_id() {
+        assert!(valid_id("gitlab-runner_01"));
+        assert!(!valid_id(""));
+        assert!(!valid_id("a.b"));
+        assert!(!valid_id("a,b"));
+        assert!(!valid_id(&"a".repeat(MAX_TOKEN_ID_LENGTH + 1)));
+    }
+
+    #[cfg(feature = "web-ui")]
+    #[test]
+    fn test_tokens() {
+        use crate::PKG_NAME;
+
+        let path = std::env::temp_dir().join(format!("{PKG_NAME}-test-tokens"));
+        let _ = std::fs::remove_file(&path);
+        *TOKENS.write().unwrap() = Some(Arc::new(read_tokens(path.clone()).unwrap()));
+
+        let token = issue_token("runner", Some(100)).unwrap();
+        assert!(token.starts_with("runner."));
+        assert_eq!(token_valid(&token).as_deref(), Some("runner"));
+        assert!(token_valid("runner.guess").is_none());
+        assert!(issue_token("not valid", None).is_none());
+
+        /* Saved so a restart keeps it */
+        let saved = parse_tokens(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(saved["runner"].quota, Some(100));
+
+        assert!(!over_quota("runner"));
+        record_token_usage("runner", 60);
+        assert!(!over_quota("runner"));
+        record_token_usage("runner", 40);
+        assert!(over_quota("runner"));
+        assert_eq!(token_list(), vec![("runner".to_string(), Some(100), 100)]);
+
+        /* Read again on reload with what it was served today kept */
+        let replaced = issue_token("runner", Some(100)).unwrap();
+        *TOKENS.write().unwrap() = Some(Arc::new(read_tokens(path.clone()).unwrap()));
+        assert_eq!(token_valid(&replaced).as_deref(), Some("runner"));
+        assert!(token_valid(&token).is_none());
+        assert!(over_quota("runner"));
+
+        assert!(revoke_token("runner"));
+        assert!(!revoke_token("runner"));
+        assert!(token_valid(&replaced).is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
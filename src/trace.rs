@@ -0,0 +1,230 @@
+use {
+    crate::{cli::setting, digest::to_hex, http::HttpRequestHeader},
+    std::{
+        io::Read,
+        sync::OnceLock,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tracing::{error, info},
+};
+
+pub const X_PROXY_TRACEPARENT: &str = "X_PROXY_TRACEPARENT";
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// The only version of the trace context written, RFC version `00`
+const TRACE_VERSION: &str = "00";
+
+/* Traces started by the proxy are marked sampled, there's no point sending one nothing records */
+const TRACE_FLAGS_SAMPLED: &str = "01";
+
+/// What's done with the W3C `traceparent` header of requests sent to origin servers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum TraceMode {
+    /// Headers from clients are passed on untouched and none are added
+    #[default]
+    Off,
+    /// A trace a client started carries on through the proxy, requests without one are sent without
+    Propagate,
+    /// As `Propagate`, with a new trace started for requests that arrive without one
+    Emit,
+}
+
+impl TraceMode {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "off" => Some(TraceMode::Off),
+            "propagate" => Some(TraceMode::Propagate),
+            "emit" => Some(TraceMode::Emit),
+            _ => None,
+        }
+    }
+}
+
+static MODE: OnceLock<TraceMode> = OnceLock::new();
+
+fn trace_mode() -> TraceMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Read `X_PROXY_TRACEPARENT`, false when it isn't a mode rproxy knows
+pub(crate) fn setup_trace() -> bool {
+    let mode = match setting(X_PROXY_TRACEPARENT) {
+        Err(_) => TraceMode::default(),
+        Ok(s) => match TraceMode::from_name(&s) {
+            Some(m) => m,
+            None => {
+                error!("'{X_PROXY_TRACEPARENT}' must be 'off', 'propagate' or 'emit': '{s}'");
+                return false;
+            }
+        },
+    };
+
+    if mode != TraceMode::Off {
+        info!("traceparent: {mode:?}");
+    }
+    let _ = MODE.set(mode);
+    true
+}
+
+fn is_hex(text: &str, length: usize) -> bool {
+    text.len() == length
+        && text
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// The trace ID and flags of a `traceparent` header, `None` when it isn't one that can be continued
+fn parse_traceparent(value: &str) -> Option<(&str, &str)> {
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+
+    /* Later versions may add fields but must keep these four as they are */
+    if !is_hex(version, 2)
+        || version == "ff"
+        || (version == TRACE_VERSION && fields.next().is_some())
+    {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    if !is_hex(parent_id, 16) || parent_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    if !is_hex(flags, 2) {
+        return None;
+    }
+
+    Some((trace_id, flags))
+}
+
+/* A trace ID only has to be unlikely to be picked twice, so the clock stands in when there's no /dev/urandom */
+fn new_trace_id(id: u64) -> String {
+    let mut bytes = [0u8; 16];
+    if std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .is_err()
+    {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        bytes[..8].copy_from_slice(&now.to_be_bytes());
+        bytes[8..].copy_from_slice(&(id | 1).to_be_bytes());
+    }
+    to_hex(&bytes)
+}
+
+/// The `traceparent` sent upstream for request `id` of the proxy, continuing the trace in `client`
+/// when it's valid. The proxy's request ID is the parent ID so a mirror's logs can be matched to
+/// the request of the same number in `/events`.
+fn traceparent(mode: TraceMode, client: Option<&str>, id: u64) -> Option<String> {
+    let (trace_id, flags) = match client.and_then(parse_traceparent) {
+        Some((t, f)) if mode != TraceMode::Off => (t.to_string(), f.to_string()),
+        _ if mode == TraceMode::Emit => (new_trace_id(id), TRACE_FLAGS_SAMPLED.to_string()),
+        _ => return None,
+    };
+
+    /* A parent ID of all zeros isn't valid and request IDs start from one */
+    Some(format!(
+        "{TRACE_VERSION}-{trace_id}-{:016x}-{flags}",
+        id.max(1)
+    ))
+}
+
+/// Set the `traceparent` that requests made upstream for this client request carry,
+/// removing any the client sent that can't be continued
+pub(crate) fn apply_traceparent(client_request_header: &mut HttpRequestHeader<'_>, id: u64) {
+    let mode = trace_mode();
+    if mode == TraceMode::Off {
+        return;
+    }
+
+    let client = client_request_header.headers.get(TRACEPARENT).cloned();
+    match traceparent(mode, client.as_deref(), id) {
+        Some(t) => {
+            /* The vendor state belongs to the trace the client started, not a new one */
+            if client.as_deref().and_then(parse_traceparent).is_none() {
+                client_request_header.headers.remove(TRACESTATE);
+            }
+            client_request_header
+                .headers
+                .insert(TRACEPARENT.to_string(), t);
+        }
+        None => {
+            client_request_header.headers.remove(TRACEPARENT);
+            client_request_header.headers.remove(TRACESTATE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_trace_mode_from_name() {
+        assert_eq!(TraceMode::from_name(" Emit"), Some(TraceMode::Emit));
+        assert_eq!(
+            TraceMode::from_name("propagate"),
+            Some(TraceMode::Propagate)
+        );
+        assert_eq!(TraceMode::from_name("off"), Some(TraceMode::Off));
+        assert_eq!(TraceMode::from_name("1"), None);
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        assert_eq!(
+            parse_traceparent(CLIENT),
+            Some(("4bf92f3577b34da6a3ce929d0e0e4736", "01"))
+        );
+        /* Fields added by a later version are ignored */
+        assert_eq!(
+            parse_traceparent("cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-future"),
+            Some(("4bf92f3577b34da6a3ce929d0e0e4736", "00"))
+        );
+
+        assert_eq!(parse_traceparent(&format!("{CLIENT}-extra")), None);
+        assert_eq!(
+            parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01"),
+            None
+        );
+        assert_eq!(
+            parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(parse_traceparent("00-4bf92f3577b34da6"), None);
+    }
+
+    #[test]
+    fn test_traceparent() {
+        assert_eq!(
+            traceparent(TraceMode::Propagate, Some(CLIENT), 42).as_deref(),
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-000000000000002a-01")
+        );
+        assert_eq!(traceparent(TraceMode::Propagate, None, 42), None);
+        assert_eq!(traceparent(TraceMode::Off, Some(CLIENT), 42), None);
+
+        let started = traceparent(TraceMode::Emit, Some("garbage"), 42).unwrap();
+        let (trace_id, flags) = parse_traceparent(&started).unwrap();
+        assert_ne!(trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(flags, TRACE_FLAGS_SAMPLED);
+        assert!(started.contains("-000000000000002a-"));
+    }
+}
@@ -0,0 +1,304 @@
+use {
+    crate::{
+        cli::setting,
+        conn::{Uri, UriKind},
+        dns::resolve,
+        http::{HttpRequestHeader, HttpResponseStatus},
+    },
+    std::{io, net::SocketAddr, sync::OnceLock},
+    tokio::net::{TcpListener, TcpSocket, TcpStream},
+};
+
+#[cfg(not(all(target_os = "linux", feature = "transparent")))]
+use {crate::PKG_NAME, tracing::error};
+
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+use tracing::{info, warn};
+
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+use std::{
+    mem::{size_of, zeroed},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    os::fd::AsRawFd,
+};
+
+pub const X_PROXY_TRANSPARENT_LISTEN_ADDRESS: &str = "X_PROXY_TRANSPARENT_LISTEN_ADDRESS";
+
+/// How many connections may wait to be accepted by the transparent listener
+const BACKLOG: u32 = 1024;
+
+/// Where intercepted clients connect to when their `Host` doesn't name a port
+const HTTP_PORT: u16 = 80;
+
+/* Linux's `IPV6_TRANSPARENT` from `<linux/in6.h>` */
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+const IPV6_TRANSPARENT: libc::c_int = 75;
+
+static LISTEN: OnceLock<String> = OnceLock::new();
+
+/// Where connections redirected to rproxy by the firewall arrive, if anywhere
+pub(crate) fn transparent_listen_address() -> Option<&'static str> {
+    LISTEN.get().map(String::as_str)
+}
+
+/// Read `X_PROXY_TRANSPARENT_LISTEN_ADDRESS`, false when rproxy can't intercept connections
+pub(crate) fn setup_transparent() -> bool {
+    let value = match setting(X_PROXY_TRANSPARENT_LISTEN_ADDRESS) {
+        Ok(v) => v.trim().to_string(),
+        Err(_) => return true,
+    };
+
+    #[cfg(all(target_os = "linux", feature = "transparent"))]
+    {
+        info!("transparent proxy listen address: {value}");
+        let _ = LISTEN.set(value);
+        true
+    }
+    #[cfg(not(all(target_os = "linux", feature = "transparent")))]
+    {
+        error!("'{X_PROXY_TRANSPARENT_LISTEN_ADDRESS}' is set to '{value}' but {PKG_NAME} was built without the 'transparent' feature");
+        false
+    }
+}
+
+/* Lets the listener accept connections the firewall sent with TPROXY, whose destination
+ * isn't an address of this machine. Needs `CAP_NET_ADMIN`, REDIRECT works without it. */
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+fn allow_foreign_destinations(socket: &TcpSocket, address: &SocketAddr) -> io::Result<()> {
+    let (level, name) = match address {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, IPV6_TRANSPARENT),
+    };
+    let enable: libc::c_int = 1;
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const _ as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match r {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Bind the listener connections redirected by the firewall arrive on
+pub(crate) async fn bind_transparent(address: &str) -> io::Result<TcpListener> {
+    let address = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no address to listen on",
+        ))?;
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+
+    #[cfg(all(target_os = "linux", feature = "transparent"))]
+    if let Err(e) = allow_foreign_destinations(&socket, &address) {
+        warn!("transparent listener can't take TPROXY connections: {e}");
+    }
+
+    socket.bind(address)?;
+    socket.listen(BACKLOG)
+}
+
+/* The destination a connection had before the firewall's REDIRECT rule sent it here */
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+fn redirected_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let fd = stream.as_raw_fd();
+    match stream.local_addr()? {
+        SocketAddr::V4(_) => {
+            let mut address: libc::sockaddr_in = unsafe { zeroed() };
+            let mut length = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            let r = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IP,
+                    libc::SO_ORIGINAL_DST,
+                    &mut address as *mut _ as *mut libc::c_void,
+                    &mut length,
+                )
+            };
+            match r {
+                0 => Ok(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+                    u16::from_be(address.sin_port),
+                ))),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+        SocketAddr::V6(_) => {
+            let mut address: libc::sockaddr_in6 = unsafe { zeroed() };
+            let mut length = size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            let r = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    libc::IP6T_SO_ORIGINAL_DST,
+                    &mut address as *mut _ as *mut libc::c_void,
+                    &mut length,
+                )
+            };
+            match r {
+                0 => Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(address.sin6_addr.s6_addr),
+                    u16::from_be(address.sin6_port),
+                    0,
+                    0,
+                ))),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+    }
+}
+
+/// Where the client of an intercepted connection meant to connect. A connection sent by
+/// TPROXY keeps its destination as the local address, so that's used when it wasn't redirected.
+pub(crate) fn original_destination(stream: &TcpStream) -> Option<SocketAddr> {
+    #[cfg(all(target_os = "linux", feature = "transparent"))]
+    if let Ok(address) = redirected_destination(stream) {
+        return Some(address);
+    }
+    stream.local_addr().ok()
+}
+
+/* Whether any of the addresses a client's `Host` resolves to is where it was connecting to */
+fn reaches(addresses: &[SocketAddr], destination: SocketAddr) -> bool {
+    addresses.iter().any(|a| {
+        a.ip().to_canonical() == destination.ip().to_canonical() && a.port() == destination.port()
+    })
+}
+
+/// The URL a request on an intercepted connection is for. The client thinks it's talking to
+/// the origin server so it asks for a path, which is on the host it named in `Host`, or the
+/// address it connected to when it didn't name one. `BAD_REQUEST` unless it asked for a path.
+/// A `Host` that doesn't resolve to where the client was connecting to is `FORBIDDEN`,
+/// otherwise anyone who can reach the listener could have rproxy fetch from anywhere.
+pub(crate) async fn transparent_target<'a>(
+    request: &HttpRequestHeader<'_>,
+    destination: Option<SocketAddr>,
+) -> Result<Uri<'a>, HttpResponseStatus> {
+    if request.request.kind() != UriKind::AbsolutePath {
+        return Err(HttpResponseStatus::BAD_REQUEST);
+    }
+    let destination = destination.ok_or(HttpResponseStatus::BAD_REQUEST)?;
+
+    let authority = match request.headers.get("Host").map(|h| h.trim()) {
+        Some(h) if !h.is_empty() => h.to_string(),
+        _ => destination.to_string(),
+    };
+    let target = Uri::from(format!("http://{authority}{}", request.request.uri));
+
+    let host = target.host.ok_or(HttpResponseStatus::BAD_REQUEST)?;
+    let port = target.port.unwrap_or(HTTP_PORT);
+    match resolve(host, port).await {
+        Ok(addresses) if reaches(&addresses, destination) => Ok(Uri::from(&target)),
+        _ => Err(HttpResponseStatus::FORBIDDEN),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::http::HttpHeader};
+
+    fn request(uri: &str, host: Option<&str>) -> HttpRequestHeader<'static> {
+        let mut headers = HttpHeader::new();
+        if let Some(h) = host {
+            headers.insert("Host".to_string(), h.to_string());
+        }
+        HttpRequestHeader {
+            method: crate::http::HttpRequestMethod::Get,
+            request: Uri::from(uri.to_string()),
+            version: crate::http::HttpVersion::HTTP_V11,
+            headers,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transparent_target() {
+        let destination = Some("192.0.2.1:8080".parse().unwrap());
+        let target = |r: HttpRequestHeader<'static>, d| async move {
+            transparent_target(&r, d)
+                .await
+                .map(|t| t.uri)
+                .map_err(|s| s.to_code())
+        };
+
+        assert_eq!(
+            target(
+                request("/debian/README", Some("192.0.2.1:8080")),
+                destination
+            )
+            .await,
+            Ok("http://192.0.2.1:8080/debian/README".to_string())
+        );
+        assert_eq!(
+            target(request("/debian/README", None), destination).await,
+            Ok("http://192.0.2.1:8080/debian/README".to_string())
+        );
+        assert_eq!(
+            target(
+                request("/debian/README", Some("192.0.2.1")),
+                Some("192.0.2.1:80".parse().unwrap())
+            )
+            .await,
+            Ok("http://192.0.2.1/debian/README".to_string())
+        );
+
+        /* A Host somewhere other than where the client was connecting to isn't fetched */
+        assert_eq!(
+            target(
+                request("/debian/README", Some("198.51.100.7:8080")),
+                destination
+            )
+            .await,
+            Err(HttpResponseStatus::FORBIDDEN.to_code())
+        );
+        assert_eq!(
+            target(request("/debian/README", Some("192.0.2.1:22")), destination).await,
+            Err(HttpResponseStatus::FORBIDDEN.to_code())
+        );
+
+        assert_eq!(
+            target(request("/debian/README", Some(" ")), None).await,
+            Err(HttpResponseStatus::BAD_REQUEST.to_code())
+        );
+        assert_eq!(
+            target(
+                request("http://deb.debian.org/debian/README", Some("x")),
+                destination
+            )
+            .await,
+            Err(HttpResponseStatus::BAD_REQUEST.to_code())
+        );
+    }
+
+    #[test]
+    fn test_reaches() {
+        let destination = "192.0.2.1:80".parse().unwrap();
+        assert!(reaches(
+            &["[::ffff:192.0.2.1]:80".parse().unwrap()],
+            destination
+        ));
+        assert!(!reaches(&["192.0.2.2:80".parse().unwrap()], destination));
+        assert!(!reaches(&[], destination));
+    }
+
+    #[tokio::test]
+    async fn test_original_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(address).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        /* A connection that wasn't redirected was meant for where it arrived */
+        assert_eq!(original_destination(&stream), Some(address));
+    }
+}
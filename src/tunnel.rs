@@ -0,0 +1,444 @@
+use {
+    crate::{
+        cancel::{Cancellable, Cancellation},
+        cli::setting,
+        conn::FetchRequestError,
+        dns::resolve,
+        egress::egress_connect,
+        evict::matches_pattern,
+        fetch::respond_connect_error,
+        http::{
+            respond_with, ConnectionReturn, ConnectionReturn::Close, HttpRequestHeader,
+            HttpResponseStatus, BUFFER_SIZE,
+        },
+        policy::address_permitted,
+        sni::{record_length, server_name, MAX_RECORD_LENGTH},
+    },
+    std::{sync::OnceLock, time::Duration},
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    },
+    tracing::{debug, error, info},
+};
+
+pub const X_PROXY_CONNECT_PORTS: &str = "X_PROXY_CONNECT_PORTS";
+pub const X_PROXY_TUNNEL_DENY_HOSTS: &str = "X_PROXY_TUNNEL_DENY_HOSTS";
+pub const X_PROXY_TUNNEL_ROUTES: &str = "X_PROXY_TUNNEL_ROUTES";
+
+/// Ports clients may open a tunnel to when `X_PROXY_CONNECT_PORTS` isn't defined
+const DEFAULT_CONNECT_PORTS: [u16; 1] = [443];
+
+/// A tunnel nothing has been sent through for this long is closed
+pub(crate) const TUNNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a client has to send its ClientHello once a tunnel with rules is open
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ports are separated by `,`, anything that isn't a port is ignored
+pub(crate) fn parse_ports(value: &str) -> Vec<u16> {
+    value
+        .split(',')
+        .filter_map(|p| p.trim().parse().ok())
+        .collect()
+}
+
+fn connect_ports() -> &'static Vec<u16> {
+    static PORTS: OnceLock<Vec<u16>> = OnceLock::new();
+    PORTS.get_or_init(|| match setting(X_PROXY_CONNECT_PORTS) {
+        Ok(s) => parse_ports(&s),
+        Err(_) => DEFAULT_CONNECT_PORTS.to_vec(),
+    })
+}
+
+/// What tunnels may reach, decided by the host named in `CONNECT` and by the server name in the
+/// ClientHello sent through it, so hosts that are never intercepted can still be held to a policy
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct TunnelRules {
+    /* Host patterns */
+    deny: Vec<String>,
+    /* Host patterns and the host and port a tunnel for one goes to instead, in the order given */
+    routes: Vec<(String, String, Option<u16>)>,
+}
+
+impl TunnelRules {
+    /// Rules from a comma separated list of host patterns to deny
+    /// and one of `pattern=host` or `pattern=host:port` routes
+    pub(crate) fn parse(deny: &str, routes: &str) -> Result<Self, String> {
+        let deny = deny
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let mut parsed = Vec::new();
+        for route in routes
+            .split(',')
+            .map(|r| r.trim())
+            .filter(|r| !r.is_empty())
+        {
+            let (pattern, target) = match route.split_once('=') {
+                Some((p, t)) if !p.trim().is_empty() && !t.trim().is_empty() => {
+                    (p.trim(), t.trim())
+                }
+                _ => return Err(route.to_string()),
+            };
+
+            let (host, port) = match target.rsplit_once(':') {
+                /* An IPv6 address without a port */
+                Some((h, _)) if !h.ends_with(']') && h.contains(':') => (target, None),
+                Some((h, p)) => (h, Some(p.parse().map_err(|_| route.to_string())?)),
+                None => (target, None),
+            };
+            parsed.push((
+                pattern.to_lowercase(),
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port,
+            ));
+        }
+
+        Ok(TunnelRules {
+            deny,
+            routes: parsed,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.routes.is_empty()
+    }
+
+    pub(crate) fn denies(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.deny.iter().any(|p| matches_pattern(p, &host))
+    }
+
+    /// Where a tunnel to `host` should go instead, keeping `port` when the route doesn't name one
+    pub(crate) fn route(&self, host: &str, port: u16) -> Option<(&str, u16)> {
+        let host = host.to_lowercase();
+        self.routes
+            .iter()
+            .find(|(p, _, _)| matches_pattern(p, &host))
+            .map(|(_, h, p)| (h.as_str(), p.unwrap_or(port)))
+    }
+}
+
+fn tunnel_rules() -> &'static TunnelRules {
+    static RULES: OnceLock<TunnelRules> = OnceLock::new();
+    RULES.get_or_init(|| {
+        let deny = setting(X_PROXY_TUNNEL_DENY_HOSTS).unwrap_or_default();
+        let routes = setting(X_PROXY_TUNNEL_ROUTES).unwrap_or_default();
+        match TunnelRules::parse(&deny, &routes) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("'{X_PROXY_TUNNEL_ROUTES}' has a route that isn't 'host=target': '{e}'");
+                std::process::exit(1);
+            }
+        }
+    })
+}
+
+/// Check the tunnel rules can be read before any client needs them
+pub(crate) fn setup_tunnel_rules() {
+    let rules = tunnel_rules();
+    if !rules.is_empty() {
+        info!(
+            "checking the server name of tunnels against {} denied hosts and {} routes",
+            rules.deny.len(),
+            rules.routes.len()
+        );
+    }
+}
+
+/* Whatever was read is sent on to the host, a ClientHello or not */
+async fn read_client_hello<T>(stream: &mut T) -> Option<Vec<u8>>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut hello = Vec::with_capacity(BUFFER_SIZE);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+
+    let read = timeout(CLIENT_HELLO_TIMEOUT, async {
+        while let Some(length) = record_length(&hello) {
+            if hello.len() >= length.min(MAX_RECORD_LENGTH) {
+                break;
+            }
+
+            match stream.read(&mut buffer).await {
+                Ok(n) if n > 0 => hello.extend_from_slice(&buffer[..n]),
+                _ => break,
+            }
+        }
+    })
+    .await;
+
+    match read.is_ok() && !hello.is_empty() {
+        true => Some(hello),
+        false => None,
+    }
+}
+
+/* Held to the same network policy as any other upstream connection */
+async fn connect_upstream(host: &str, port: u16) -> Result<TcpStream, FetchRequestError> {
+    let mut addresses = resolve(host, port)
+        .await
+        .map_err(FetchRequestError::resolving)?;
+
+    addresses.retain(|a| address_permitted(a.ip(), true));
+    if addresses.is_empty() {
+        return Err(FetchRequestError::DeniedAddress(host.to_string()));
+    }
+
+    egress_connect(&addresses)
+        .await
+        .map_err(FetchRequestError::connecting)
+}
+
+/* Either side may be TLS, which holds on to what's written until it's flushed */
+async fn write_through<W>(writer: &mut W, buffer: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(buffer).await?;
+    writer.flush().await
+}
+
+/// Copy bytes both ways until both sides have finished sending or nothing has moved for `idle`.
+/// When one side finishes sending the other is told so it can finish too.
+pub(crate) async fn splice<A, B>(a: &mut A, b: &mut B, idle: Duration)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut a_buffer = vec![0u8; BUFFER_SIZE];
+    let mut b_buffer = vec![0u8; BUFFER_SIZE];
+    let (mut a_open, mut b_open) = (true, true);
+
+    while a_open || b_open {
+        let read = timeout(idle, async {
+            tokio::select! {
+                r = a.read(&mut a_buffer), if a_open => (true, r),
+                r = b.read(&mut b_buffer), if b_open => (false, r),
+            }
+        })
+        .await;
+
+        let (from_a, read) = match read {
+            Err(_) => return, /* Idle */
+            Ok(r) => r,
+        };
+
+        let written = match (from_a, read) {
+            (true, Ok(n)) if n > 0 => write_through(b, &a_buffer[..n]).await,
+            (false, Ok(n)) if n > 0 => write_through(a, &b_buffer[..n]).await,
+            (true, _) => {
+                a_open = false;
+                b.shutdown().await
+            }
+            (false, _) => {
+                b_open = false;
+                a.shutdown().await
+            }
+        };
+
+        if written.is_err() {
+            return;
+        }
+    }
+}
+
+/// Answer a `CONNECT` request by opening a TCP connection to the host asked for and passing
+/// bytes between it and the client untouched, so TLS goes through rproxy without being cached.
+pub(crate) async fn open_tunnel<T>(
+    mut stream: T,
+    client_request_header: &HttpRequestHeader<'_>,
+    cancel: &Cancellation,
+) -> ConnectionReturn
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (host, port) = match (
+        client_request_header.request.host,
+        client_request_header.request.port,
+    ) {
+        (Some(h), Some(p)) => (h, p),
+        _ => return respond_with(Close, HttpResponseStatus::BAD_REQUEST, &mut stream).await,
+    };
+
+    let rules = tunnel_rules();
+    if !connect_ports().contains(&port) || rules.denies(host) {
+        return respond_with(Close, HttpResponseStatus::FORBIDDEN, &mut stream).await;
+    }
+
+    if !rules.is_empty() {
+        return open_routed_tunnel(stream, host, port, rules, cancel).await;
+    }
+
+    let mut upstream = match connect_upstream(host, port).await {
+        Ok(u) => Cancellable::new(u, cancel),
+        Err(e) => return respond_connect_error(&e, &mut stream).await,
+    };
+
+    if stream
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await
+        .is_err()
+    {
+        return Close;
+    }
+
+    debug!("Tunnel to {host}:{port} is open");
+    splice(&mut stream, &mut upstream, TUNNEL_IDLE_TIMEOUT).await;
+    debug!("Tunnel to {host}:{port} is closed");
+
+    /* Whatever the tunnel carried, the connection can't go back to being HTTP */
+    Close
+}
+
+/* The host can only be chosen once the client has said which server it wants,
+ * by which time it's been told the tunnel is open so failures can only close it */
+async fn open_routed_tunnel<T>(
+    mut stream: T,
+    host: &str,
+    port: u16,
+    rules: &TunnelRules,
+    cancel: &Cancellation,
+) -> ConnectionReturn
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if stream
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await
+        .is_err()
+    {
+        return Close;
+    }
+
+    let hello = match read_client_hello(&mut stream).await {
+        Some(h) => h,
+        None => return Close,
+    };
+
+    /* Anything that isn't TLS with a server name is held to the host it was opened for */
+    let name = server_name(&hello);
+    let name = name.as_deref().unwrap_or(host);
+    if rules.denies(name) {
+        debug!("Tunnel to {host}:{port} closed, the client asked for {name}");
+        return Close;
+    }
+
+    let (target, target_port) = rules.route(name, port).unwrap_or((host, port));
+    let mut upstream = match connect_upstream(target, target_port).await {
+        Ok(u) => Cancellable::new(u, cancel),
+        Err(_) => return Close,
+    };
+
+    if upstream.write_all(&hello).await.is_err() {
+        return Close;
+    }
+
+    debug!("Tunnel to {target}:{target_port} for {name} is open");
+    splice(&mut stream, &mut upstream, TUNNEL_IDLE_TIMEOUT).await;
+    debug!("Tunnel to {target}:{target_port} for {name} is closed");
+
+    Close
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, tokio::io::duplex};
+
+    #[test]
+    fn test_parse_ports() {
+        assert_eq!(parse_ports("443, 8443,,nope,70000"), vec![443, 8443]);
+        assert!(parse_ports("").is_empty());
+    }
+
+    #[test]
+    fn test_tunnel_rules() {
+        let rules = TunnelRules::parse(
+            "*.tracker.example, Blocked.example",
+            "*.internal.example=gateway.lan, mirror.example=[fd00::1]:8443,other.example=10.0.0.1:443",
+        )
+        .unwrap();
+
+        assert!(rules.denies("ads.tracker.example"));
+        assert!(rules.denies("blocked.EXAMPLE"));
+        assert!(!rules.denies("deb.debian.org"));
+
+        assert_eq!(
+            rules.route("git.internal.example", 443),
+            Some(("gateway.lan", 443))
+        );
+        assert_eq!(rules.route("Mirror.example", 443), Some(("fd00::1", 8443)));
+        assert_eq!(rules.route("other.example", 8443), Some(("10.0.0.1", 443)));
+        assert_eq!(rules.route("deb.debian.org", 443), None);
+
+        assert!(TunnelRules::parse("", "").unwrap().is_empty());
+        assert!(TunnelRules::parse("", "nowhere").is_err());
+        assert!(TunnelRules::parse("", "a.example=b.example:port").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let (mut client, mut client_side) = duplex(64);
+
+        /* A record split over writes is read whole and nothing after it */
+        let mut record = vec![0x16, 3, 1, 0, 6];
+        record.extend_from_slice(b"hello!");
+        client.write_all(&record[..3]).await.unwrap();
+        let reading = tokio::spawn(async move { read_client_hello(&mut client_side).await });
+        client.write_all(&record[3..]).await.unwrap();
+        assert_eq!(reading.await.unwrap(), Some(record));
+
+        let (mut client, mut client_side) = duplex(64);
+        client.write_all(b"SSH-2.0-OpenSSH\r\n").await.unwrap();
+        assert_eq!(
+            read_client_hello(&mut client_side).await.as_deref(),
+            Some(&b"SSH-2.0-OpenSSH\r\n"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_splice() {
+        let (mut client, mut client_side) = duplex(64);
+        let (mut upstream_side, mut upstream) = duplex(64);
+
+        let tunnel = tokio::spawn(async move {
+            splice(&mut client_side, &mut upstream_side, Duration::from_secs(5)).await
+        });
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        upstream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        /* The client finishing first doesn't stop the reply */
+        client.shutdown().await.unwrap();
+        upstream.write_all(b"world").await.unwrap();
+        upstream.shutdown().await.unwrap();
+
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "world");
+
+        tunnel.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_splice_idle() {
+        let (_client, mut client_side) = duplex(64);
+        let (mut upstream_side, _upstream) = duplex(64);
+
+        /* Returns instead of waiting forever on two quiet sides */
+        splice(
+            &mut client_side,
+            &mut upstream_side,
+            Duration::from_millis(10),
+        )
+        .await;
+    }
+}
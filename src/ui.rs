@@ -0,0 +1,593 @@
+use {
+    crate::{
+        cli::setting,
+        conn::{FlightState, Flights},
+        evict::{
+            cache_pins, forget_hits, hit_count, is_pinned, parse_size, pin_entry, unpin_entry,
+        },
+        http::{HttpResponseStatus, X_PROXY_CACHE_PATH},
+        layout::cache_entries,
+        maintenance::{begin_maintenance, end_maintenance, in_maintenance, maintenance_hosts},
+        reload::request_reload,
+        token::{revoke_token, token_list},
+        PKG_NAME, PKG_VERSION,
+    },
+    std::{path::PathBuf, sync::OnceLock},
+    tokio::fs::{metadata, remove_file},
+    tracing::info,
+};
+
+pub const X_PROXY_WEB_UI: &str = "X_PROXY_WEB_UI";
+
+/// Where clients can browse and manage the cache when the web interface is enabled
+pub(crate) const UI_PATH: &str = "/cache";
+
+/// The web interface lets any client purge the cache so it's off unless `X_PROXY_WEB_UI` switches it on
+pub fn web_ui_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| match setting(X_PROXY_WEB_UI) {
+        Ok(v) => matches!(v.trim(), "1" | "true" | "on"),
+        Err(_) => false,
+    })
+}
+
+/// Who may press a button
+#[derive(Debug, PartialEq)]
+pub(crate) enum Permission {
+    /// Any client that may use the proxy
+    Client,
+    /// Only the users in `X_PROXY_AUTH_ADMINS`, nobody when there aren't any
+    Administrator,
+}
+
+/// A button on the page, sent as `POST /cache?<action>=<host/file>`
+#[derive(Debug, PartialEq)]
+pub(crate) enum CacheAction {
+    Purge(String),
+    Pin(String),
+    Unpin(String),
+    /// Stop a download, named by its path in the cache
+    Abort(String),
+    /// Put a host into maintenance
+    Maintain(String),
+    /// Take a host out of maintenance
+    Resume(String),
+    /// Issue a service account token with a daily quota, sent as `?issue=<id>&quota=<size>`
+    Issue {
+        id: String,
+        quota: Option<u64>,
+    },
+    /// Revoke a service account's token
+    Revoke(String),
+    /// Read the configuration again, sent as `?reload=configuration`
+    Reload,
+}
+
+impl CacheAction {
+    pub(crate) fn from_query(query: &str) -> Option<Self> {
+        let mut pairs = query.split('&');
+        let (name, value) = pairs.next()?.split_once('=')?;
+        let key = percent_decode(value)?;
+        if key.is_empty() {
+            return None;
+        }
+
+        match name {
+            "purge" => Some(CacheAction::Purge(key)),
+            "pin" => Some(CacheAction::Pin(key)),
+            "unpin" => Some(CacheAction::Unpin(key)),
+            "abort" => Some(CacheAction::Abort(key)),
+            "maintain" => Some(CacheAction::Maintain(key)),
+            "resume" => Some(CacheAction::Resume(key)),
+            "issue" => {
+                /* No quota, or a quota of 0, lets the token be served without limit */
+                let quota = match pairs.find_map(|p| p.strip_prefix("quota=")) {
+                    Some(q) => Some(parse_size(&percent_decode(q)?)?).filter(|q| *q > 0),
+                    None => None,
+                };
+                Some(CacheAction::Issue { id: key, quota })
+            }
+            "revoke" => Some(CacheAction::Revoke(key)),
+            "reload" if key == "configuration" => Some(CacheAction::Reload),
+            _ => None,
+        }
+    }
+
+    /// Who may carry it out. Tokens and reloading are for administrators alone,
+    /// and once there are administrators nobody else may change anything either.
+    pub(crate) fn permission(&self, administrators: bool) -> Permission {
+        match self {
+            CacheAction::Issue { .. } | CacheAction::Revoke(_) | CacheAction::Reload => {
+                Permission::Administrator
+            }
+            _ if administrators => Permission::Administrator,
+            _ => Permission::Client,
+        }
+    }
+}
+
+struct PageEntry {
+    key: String,
+    length: u64,
+    hits: u64,
+    pinned: bool,
+}
+
+struct PageHost {
+    host: String,
+    maintenance: bool,
+}
+
+struct PageToken {
+    id: String,
+    quota: Option<u64>,
+    used: u64,
+}
+
+struct PageFlight {
+    key: String,
+    state: FlightState,
+    received: u64,
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Sizes in the largest binary unit that keeps them above one
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+fn action_button(action: &str, key: &str, label: &str) -> String {
+    format!(
+        "<form method=\"post\" action=\"{UI_PATH}?{action}={}\"><button>{label}</button></form>",
+        escape_html(&percent_encode(key))
+    )
+}
+
+fn render_page(
+    entries: &[PageEntry],
+    flights: &[PageFlight],
+    hosts: &[PageHost],
+    tokens: &[PageToken],
+) -> String {
+    let total: u64 = entries.iter().map(|e| e.length).sum();
+
+    let mut page = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">");
+    if !flights.is_empty() {
+        page.push_str("<meta http-equiv=\"refresh\" content=\"5\">");
+    }
+    page.push_str(&format!(
+        "<title>{PKG_NAME} cache</title><style>\
+        body{{font-family:sans-serif}}table{{border-collapse:collapse}}\
+        td,th{{padding:2px 8px;text-align:left}}tr:nth-child(even){{background:#eee}}\
+        form{{display:inline}}</style></head><body>\
+        <h1>{PKG_NAME} {PKG_VERSION}</h1>"
+    ));
+    page.push_str(&action_button(
+        "reload",
+        "configuration",
+        "Reload configuration",
+    ));
+
+    page.push_str(&format!("<h2>Downloading ({})</h2>", flights.len()));
+    if !flights.is_empty() {
+        page.push_str("<table><tr><th>File</th><th>Received</th><th>Size</th><th></th></tr>");
+        for flight in flights {
+            let size = match flight.state {
+                FlightState::Length(l) => human_size(l),
+                FlightState::Chunks => "chunked".to_string(),
+                FlightState::Fetching => "waiting".to_string(),
+                FlightState::Complete => "finishing".to_string(),
+                FlightState::Failed => "failed".to_string(),
+            };
+            page.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{size}</td><td>{}</td></tr>",
+                escape_html(&flight.key),
+                human_size(flight.received),
+                action_button("abort", &flight.key, "Abort")
+            ));
+        }
+        page.push_str("</table>");
+    }
+
+    if !hosts.is_empty() {
+        page.push_str("<h2>Hosts</h2><table><tr><th>Host</th><th>State</th><th></th></tr>");
+        for host in hosts {
+            let (state, button) = match host.maintenance {
+                true => ("maintenance", action_button("resume", &host.host, "Resume")),
+                false => ("", action_button("maintain", &host.host, "Maintenance")),
+            };
+            page.push_str(&format!(
+                "<tr><td>{}</td><td>{state}</td><td>{button}</td></tr>",
+                escape_html(&host.host),
+            ));
+        }
+        page.push_str("</table>");
+    }
+
+    if !tokens.is_empty() {
+        page.push_str(
+            "<h2>Service accounts</h2><table><tr><th>Token</th><th>Today</th><th>Quota</th><th></th></tr>",
+        );
+        for token in tokens {
+            page.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&token.id),
+                human_size(token.used),
+                token.quota.map(human_size).unwrap_or_default(),
+                action_button("revoke", &token.id, "Revoke")
+            ));
+        }
+        page.push_str("</table>");
+    }
+
+    page.push_str(&format!(
+        "<h2>Cached ({} files, {})</h2>",
+        entries.len(),
+        human_size(total)
+    ));
+    if !entries.is_empty() {
+        page.push_str("<table><tr><th>File</th><th>Size</th><th>Hits</th><th></th></tr>");
+        for entry in entries {
+            let pin = match entry.pinned {
+                true => action_button("unpin", &entry.key, "Unpin"),
+                false => action_button("pin", &entry.key, "Pin"),
+            };
+            page.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{pin} {}</td></tr>",
+                escape_html(&entry.key),
+                human_size(entry.length),
+                entry.hits,
+                action_button("purge", &entry.key, "Purge")
+            ));
+        }
+        page.push_str("</table>");
+    }
+
+    page.push_str("</body></html>\n");
+    page
+}
+
+fn cache_root() -> Option<PathBuf> {
+    setting(X_PROXY_CACHE_PATH).ok().map(PathBuf::from)
+}
+
+/// The cache contents and downloads in progress as an HTML page
+pub(crate) async fn cache_page(flights: &Flights) -> String {
+    let root = match cache_root() {
+        Some(r) => r,
+        None => return render_page(&[], &[], &[], &[]),
+    };
+
+    let pins = cache_pins();
+    let mut entries = Vec::new();
+
+    for (host, file, path) in cache_entries(&root).await {
+        let hash = path.to_string_lossy().to_string();
+        if flights.is_in_flight(&hash).await {
+            continue;
+        }
+
+        let length = match metadata(&path).await {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+
+        let key = format!("{host}/{file}");
+        entries.push(PageEntry {
+            pinned: is_pinned(&pins, &key),
+            hits: hit_count(&hash),
+            length,
+            key,
+        });
+    }
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut in_flight = Vec::new();
+    for (path, state) in flights.all().await {
+        let path = PathBuf::from(path);
+        in_flight.push(PageFlight {
+            received: metadata(&path).await.map(|m| m.len()).unwrap_or_default(),
+            key: path
+                .strip_prefix(&root)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .to_string(),
+            state,
+        });
+    }
+    in_flight.sort_by(|a, b| a.key.cmp(&b.key));
+
+    /* Hosts in maintenance are listed even when nothing from them is cached */
+    let mut names: Vec<String> = entries
+        .iter()
+        .filter_map(|e| e.key.split_once('/').map(|(h, _)| h.to_string()))
+        .chain(maintenance_hosts())
+        .collect();
+    names.sort();
+    names.dedup();
+    let hosts: Vec<PageHost> = names
+        .into_iter()
+        .map(|host| PageHost {
+            maintenance: in_maintenance(&host),
+            host,
+        })
+        .collect();
+
+    let tokens: Vec<PageToken> = token_list()
+        .into_iter()
+        .map(|(id, quota, used)| PageToken { id, quota, used })
+        .collect();
+
+    render_page(&entries, &in_flight, &hosts, &tokens)
+}
+
+/// Carry out a button press, `SEE_OTHER` means it worked and the page should be shown again
+pub(crate) async fn apply_action(action: CacheAction, flights: &Flights) -> HttpResponseStatus {
+    let key = match action {
+        CacheAction::Pin(key) => {
+            pin_entry(&key);
+            return HttpResponseStatus::SEE_OTHER;
+        }
+        CacheAction::Unpin(key) => {
+            unpin_entry(&key);
+            return HttpResponseStatus::SEE_OTHER;
+        }
+        CacheAction::Maintain(host) => {
+            begin_maintenance(&host);
+            return HttpResponseStatus::SEE_OTHER;
+        }
+        CacheAction::Resume(host) => {
+            return match end_maintenance(&host) {
+                true => HttpResponseStatus::SEE_OTHER,
+                false => HttpResponseStatus::NOT_FOUND,
+            };
+        }
+        CacheAction::Revoke(id) => {
+            return match revoke_token(&id) {
+                true => HttpResponseStatus::SEE_OTHER,
+                false => HttpResponseStatus::NOT_FOUND,
+            };
+        }
+        CacheAction::Reload => {
+            request_reload();
+            return HttpResponseStatus::SEE_OTHER;
+        }
+        /* The token is the response so it's answered before getting here */
+        CacheAction::Issue { .. } => return HttpResponseStatus::BAD_REQUEST,
+        CacheAction::Purge(key) => key,
+        CacheAction::Abort(key) => {
+            /* Only downloads in progress can be aborted so the path can't lead anywhere else */
+            let path = match cache_root() {
+                Some(r) => r.join(&key).to_string_lossy().to_string(),
+                None => return HttpResponseStatus::NOT_FOUND,
+            };
+
+            return match flights.abort(&path).await {
+                true => {
+                    info!("aborted download of '{key}'");
+                    HttpResponseStatus::SEE_OTHER
+                }
+                false => HttpResponseStatus::NOT_FOUND,
+            };
+        }
+    };
+
+    let root = match cache_root() {
+        Some(r) => r,
+        None => return HttpResponseStatus::NOT_FOUND,
+    };
+
+    /* Only paths found in the cache are removed so a key can't point anywhere else */
+    let path = match cache_entries(&root)
+        .await
+        .into_iter()
+        .find(|(host, file, _)| format!("{host}/{file}") == key)
+    {
+        Some((_, _, p)) => p,
+        None => return HttpResponseStatus::NOT_FOUND,
+    };
+
+    let hash = path.to_string_lossy().to_string();
+    if flights.is_in_flight(&hash).await {
+        return HttpResponseStatus::CONFLICT;
+    }
+
+    match remove_file(&path).await {
+        Ok(_) => {
+            forget_hits(&hash);
+            info!("purged '{key}' from the cache");
+            HttpResponseStatus::SEE_OTHER
+        }
+        Err(_) => HttpResponseStatus::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_action() {
+        assert_eq!(
+            CacheAction::from_query("purge=deb.debian.org/foo%2B1.deb"),
+            Some(CacheAction::Purge("deb.debian.org/foo+1.deb".to_string()))
+        );
+        assert_eq!(
+            CacheAction::from_query("pin=example.com/a.iso"),
+            Some(CacheAction::Pin("example.com/a.iso".to_string()))
+        );
+        assert_eq!(
+            CacheAction::from_query("abort=example.com/big.iso"),
+            Some(CacheAction::Abort("example.com/big.iso".to_string()))
+        );
+        assert_eq!(
+            CacheAction::from_query("maintain=mirror.example.com"),
+            Some(CacheAction::Maintain("mirror.example.com".to_string()))
+        );
+        assert_eq!(
+            CacheAction::from_query("issue=gitlab-runner&quota=10G"),
+            Some(CacheAction::Issue {
+                id: "gitlab-runner".to_string(),
+                quota: Some(10 << 30)
+            })
+        );
+        assert_eq!(
+            CacheAction::from_query("issue=ci&quota=0"),
+            Some(CacheAction::Issue {
+                id: "ci".to_string(),
+                quota: None
+            })
+        );
+        assert_eq!(CacheAction::from_query("issue=ci&quota=lots"), None);
+        assert_eq!(
+            CacheAction::from_query("revoke=ci"),
+            Some(CacheAction::Revoke("ci".to_string()))
+        );
+        assert_eq!(
+            CacheAction::from_query("reload=configuration"),
+            Some(CacheAction::Reload)
+        );
+        assert_eq!(CacheAction::from_query("reload=rules"), None);
+        assert_eq!(CacheAction::from_query("explode=example.com/a"), None);
+        assert_eq!(CacheAction::from_query("purge="), None);
+        assert_eq!(CacheAction::from_query("purge=%zz"), None);
+        assert_eq!(CacheAction::from_query("purge"), None);
+    }
+
+    #[test]
+    fn test_permission() {
+        let purge = CacheAction::Purge("example.com/a.iso".to_string());
+        let abort = CacheAction::Abort("example.com/big.iso".to_string());
+        assert_eq!(purge.permission(false), Permission::Client);
+        assert_eq!(abort.permission(false), Permission::Client);
+        assert_eq!(purge.permission(true), Permission::Administrator);
+        assert_eq!(
+            CacheAction::Unpin("example.com/a.iso".to_string()).permission(true),
+            Permission::Administrator
+        );
+
+        /* Maintenance turns a host away for everyone, so it's asked for like any other change */
+        let maintain = CacheAction::Maintain("mirror.example.com".to_string());
+        let resume = CacheAction::Resume("mirror.example.com".to_string());
+        assert_eq!(maintain.permission(false), Permission::Client);
+        assert_eq!(maintain.permission(true), Permission::Administrator);
+        assert_eq!(resume.permission(true), Permission::Administrator);
+
+        assert_eq!(
+            CacheAction::Reload.permission(false),
+            Permission::Administrator
+        );
+        assert_eq!(
+            CacheAction::Revoke("ci".to_string()).permission(false),
+            Permission::Administrator
+        );
+    }
+
+    #[test]
+    fn test_percent_round_trip() {
+        let key = "example.com/a b+c&d=é.deb";
+        assert_eq!(percent_decode(&percent_encode(key)), Some(key.to_string()));
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 << 30), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_render_page_escapes() {
+        let page = render_page(
+            &[PageEntry {
+                key: "example.com/<script>".to_string(),
+                length: 1,
+                hits: 2,
+                pinned: false,
+            }],
+            &[],
+            &[PageHost {
+                host: "<b>.example.com".to_string(),
+                maintenance: true,
+            }],
+            &[PageToken {
+                id: "ci<i>".to_string(),
+                quota: Some(1 << 30),
+                used: 0,
+            }],
+        );
+        assert!(!page.contains("<i>"));
+        assert!(page.contains("?revoke=ci%3Ci%3E"));
+        assert!(!page.contains("<script>"));
+        assert!(!page.contains("<b>"));
+        assert!(page.contains("?resume=%3Cb%3E.example.com"));
+        assert!(page.contains("example.com/&lt;script&gt;"));
+        assert!(page.contains("?purge=example.com/%3Cscript%3E"));
+        assert!(!page.contains("http-equiv"));
+    }
+}
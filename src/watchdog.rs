@@ -0,0 +1,201 @@
+use {
+    crate::{
+        cli::setting,
+        evict::parse_size,
+        http::{HttpResponseHeader, HttpResponseStatus, HttpVersion},
+    },
+    std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    },
+    tokio::{io::AsyncWriteExt, net::TcpStream, time::sleep},
+    tracing::{info, warn},
+};
+
+pub const X_PROXY_MAX_OPEN_FILES: &str = "X_PROXY_MAX_OPEN_FILES";
+pub const X_PROXY_MAX_MEMORY: &str = "X_PROXY_MAX_MEMORY";
+
+/// How often open files and memory are checked
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Without a configured ceiling, load is shed this far into the process's open file limit
+const DEFAULT_OPEN_FILES_PERCENT: u64 = 90;
+
+/// Load shedding stops once usage falls this far below the ceiling, so it doesn't flap
+const RECOVERY_PERCENT: u64 = 90;
+
+/// How long clients turned away while shedding load are asked to wait, in seconds
+const SHEDDING_RETRY_AFTER: u64 = 5;
+
+static SHEDDING: AtomicBool = AtomicBool::new(false);
+
+/// Whether rproxy is close enough to a ceiling that new work should be turned away
+pub(crate) fn shedding() -> bool {
+    SHEDDING.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Ceilings {
+    pub(crate) open_files: Option<u64>,
+    /// Resident memory in bytes
+    pub(crate) memory: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Usage {
+    open_files: Option<u64>,
+    memory: Option<u64>,
+}
+
+/* The resident set size from `/proc/self/status`, which is given in kibibytes */
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kibibytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    kibibytes.checked_mul(1024)
+}
+
+/* The soft limit on open files from `/proc/self/limits` */
+fn parse_open_files_limit(limits: &str) -> Option<u64> {
+    let line = limits.lines().find(|l| l.starts_with("Max open files"))?;
+    line.trim_start_matches("Max open files")
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// The ceilings from `X_PROXY_MAX_OPEN_FILES` and `X_PROXY_MAX_MEMORY`,
+/// or an error naming the variable that couldn't be read
+pub(crate) fn watchdog_ceilings() -> Result<Ceilings, String> {
+    let open_files = match setting(X_PROXY_MAX_OPEN_FILES) {
+        Ok(s) => match s.trim().parse() {
+            Ok(n) => Some(n),
+            Err(_) => return Err(X_PROXY_MAX_OPEN_FILES.to_string()),
+        },
+        Err(_) => std::fs::read_to_string("/proc/self/limits")
+            .ok()
+            .and_then(|l| parse_open_files_limit(&l))
+            .map(|l| l * DEFAULT_OPEN_FILES_PERCENT / 100),
+    };
+
+    let memory = match setting(X_PROXY_MAX_MEMORY) {
+        Ok(s) => match parse_size(&s) {
+            Some(n) => Some(n),
+            None => return Err(X_PROXY_MAX_MEMORY.to_string()),
+        },
+        Err(_) => None,
+    };
+
+    Ok(Ceilings { open_files, memory })
+}
+
+/* Only Linux is asked, elsewhere both are unknown and never cause load to be shed */
+fn current_usage() -> Usage {
+    Usage {
+        open_files: std::fs::read_dir("/proc/self/fd")
+            .ok()
+            .map(|d| d.count() as u64),
+        memory: std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|s| parse_vm_rss(&s)),
+    }
+}
+
+fn over(usage: Option<u64>, ceiling: Option<u64>, percent: u64) -> bool {
+    match (usage, ceiling) {
+        (Some(u), Some(c)) => u >= c * percent / 100,
+        _ => false,
+    }
+}
+
+/* Start shedding at a ceiling and only stop once everything is comfortably below them */
+fn should_shed(shedding: bool, usage: &Usage, ceilings: &Ceilings) -> bool {
+    let percent = match shedding {
+        true => RECOVERY_PERCENT,
+        false => 100,
+    };
+
+    over(usage.open_files, ceilings.open_files, percent)
+        || over(usage.memory, ceilings.memory, percent)
+}
+
+/// Watch open files and memory, shedding load while either is near its ceiling
+/// instead of waiting for the operating system to step in
+pub(crate) async fn watchdog_loop(ceilings: Ceilings) {
+    loop {
+        sleep(WATCHDOG_INTERVAL).await;
+
+        let usage = current_usage();
+        let was_shedding = shedding();
+        let shed = should_shed(was_shedding, &usage, &ceilings);
+
+        if shed != was_shedding {
+            SHEDDING.store(shed, Ordering::Relaxed);
+            match shed {
+                true => warn!(
+                    "is shedding load, {} open files and {} bytes of memory in use",
+                    usage.open_files.unwrap_or_default(),
+                    usage.memory.unwrap_or_default()
+                ),
+                false => info!("has stopped shedding load"),
+            }
+        }
+    }
+}
+
+/// Turn away a connection accepted while shedding load, without reading its request
+pub(crate) async fn refuse_connection(mut stream: TcpStream) {
+    let mut header = HttpResponseHeader {
+        status: HttpResponseStatus::SERVICE_UNAVAILABLE,
+        headers: Default::default(),
+        version: HttpVersion::HTTP_V11,
+    };
+    header
+        .headers
+        .insert("Retry-After".to_string(), SHEDDING_RETRY_AFTER.to_string());
+    header
+        .headers
+        .insert("Content-Length".to_string(), "0".to_string());
+    header
+        .headers
+        .insert("Connection".to_string(), "close".to_string());
+
+    let _ = stream.write_all(header.generate().as_bytes()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc() {
+        let status = "Name:\trproxy\nVmPeak:\t  10000 kB\nVmRSS:\t    2048 kB\nThreads:\t4\n";
+        assert_eq!(parse_vm_rss(status), Some(2 * 1024 * 1024));
+        assert_eq!(parse_vm_rss("Name:\trproxy\n"), None);
+
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max open files            1024                 4096                 files     \n";
+        assert_eq!(parse_open_files_limit(limits), Some(1024));
+        assert_eq!(parse_open_files_limit(""), None);
+    }
+
+    #[test]
+    fn test_should_shed() {
+        let ceilings = Ceilings {
+            open_files: Some(100),
+            memory: None,
+        };
+        let usage = |open_files| Usage {
+            open_files: Some(open_files),
+            memory: Some(u64::MAX),
+        };
+
+        assert!(!should_shed(false, &usage(99), &ceilings));
+        assert!(should_shed(false, &usage(100), &ceilings));
+        /* Keeps shedding until usage is comfortably below the ceiling */
+        assert!(should_shed(true, &usage(95), &ceilings));
+        assert!(!should_shed(true, &usage(89), &ceilings));
+
+        assert!(!should_shed(false, &Usage::default(), &ceilings));
+    }
+}
@@ -0,0 +1,95 @@
+use {
+    std::io,
+    tokio::{
+        fs::File,
+        io::{AsyncRead, BufReader},
+        net::TcpStream,
+    },
+};
+
+/// Streams that can be handed file contents straight from the page cache.
+/// Implementations that can't return `None` and the caller falls back to copying through a buffer.
+pub(crate) trait ZeroCopy {
+    async fn send_file(&mut self, file: &File, offset: u64, length: u64)
+        -> Option<io::Result<u64>>;
+}
+
+impl<T: ZeroCopy + ?Sized> ZeroCopy for &mut T {
+    async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        length: u64,
+    ) -> Option<io::Result<u64>> {
+        (**self).send_file(file, offset, length).await
+    }
+}
+
+/* Reads are buffered but writes go straight through, so files can be too */
+impl<T: ZeroCopy + AsyncRead> ZeroCopy for BufReader<T> {
+    async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        length: u64,
+    ) -> Option<io::Result<u64>> {
+        self.get_mut().send_file(file, offset, length).await
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "sendfile"))]
+impl ZeroCopy for TcpStream {
+    async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        length: u64,
+    ) -> Option<io::Result<u64>> {
+        use {std::os::unix::io::AsRawFd, tokio::io::Interest};
+
+        /* Linux will transfer at most this many bytes per call */
+        const MAX_SENDFILE: u64 = 0x7ffff000;
+
+        let socket = self.as_raw_fd();
+        let source = file.as_raw_fd();
+        let mut position = offset as libc::off_t;
+        let mut remaining = length;
+
+        while remaining > 0 {
+            if let Err(e) = self.writable().await {
+                return Some(Err(e));
+            }
+
+            let count = std::cmp::min(remaining, MAX_SENDFILE) as usize;
+            let sent = self.try_io(Interest::WRITABLE, || {
+                match unsafe { libc::sendfile(socket, source, &mut position, count) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    n => Ok(n as u64),
+                }
+            });
+
+            match sent {
+                Ok(0) => break, /* The file is shorter than expected */
+                Ok(n) => remaining -= n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        Some(Ok(length - remaining))
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "sendfile")))]
+impl ZeroCopy for TcpStream {
+    async fn send_file(&mut self, _: &File, _: u64, _: u64) -> Option<io::Result<u64>> {
+        None
+    }
+}
+
+#[cfg(feature = "https")]
+impl<IO> ZeroCopy for tokio_rustls::server::TlsStream<IO> {
+    async fn send_file(&mut self, _: &File, _: u64, _: u64) -> Option<io::Result<u64>> {
+        None /* Data has to pass through the TLS session so it can't bypass userspace */
+    }
+}